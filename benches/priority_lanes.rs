@@ -0,0 +1,120 @@
+#![feature(test)]
+
+// Measures cache hits while slow cache misses hold every upstream slot, to
+// verify that hits never queue behind misses.
+//
+// Backend and proxy run in-process, no external servers are needed. The
+// backend answers "/slow/..." after SLOW_MS milliseconds and "/hot" right
+// away with a cachable response. The proxy allows 4 requests in flight to
+// the backend, while SLOW_CLIENTS clients keep requesting new slow URLs.
+// Compare the two benchmarks: hits should take about as long with the
+// saturated backend as with the idle one.
+//
+// Execute with `cargo bench --bench priority_lanes`.
+
+extern crate test;
+
+use futures::future::join_all;
+use futures::{Future, Stream};
+use hyper::header::CACHE_CONTROL;
+use hyper::{Body, Client, Response, StatusCode, Uri};
+use rustnish::test_util::{client_get_body, get_free_port, start_dummy_server};
+use rustnish::Config;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+// How long the backend takes to answer a cache miss.
+const SLOW_MS: u64 = 100;
+// Clients that send cache misses, more than the backend has slots.
+const SLOW_CLIENTS: usize = 16;
+
+#[bench]
+fn a_hot_hits_idle_backend(b: &mut test::Bencher) {
+    bench_hot_hits(b, 0);
+}
+
+#[bench]
+fn b_hot_hits_saturated_backend(b: &mut test::Bencher) {
+    bench_hot_hits(b, SLOW_CLIENTS);
+}
+
+fn bench_hot_hits(b: &mut test::Bencher, slow_clients: usize) {
+    let port = get_free_port();
+    let upstream_port = get_free_port();
+
+    let _upstream_server = start_dummy_server(upstream_port, |request| {
+        if request.uri().path().starts_with("/slow/") {
+            thread::sleep(Duration::from_millis(SLOW_MS));
+            return Response::new(Body::from("slow"));
+        }
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from("hot"))
+            .unwrap()
+    });
+    let mut config = Config::default();
+    config.backend.max_requests = Some(4);
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let hot: Uri = format!("http://127.0.0.1:{}/hot", port).parse().unwrap();
+    client_get_body(hot.clone());
+    // Give the cache thread time to store the response.
+    thread::sleep(Duration::from_millis(50));
+
+    // Every slow client requests new URLs one after another, so that all of
+    // them are cache misses.
+    let running = Arc::new(AtomicBool::new(true));
+    let misses = Arc::new(AtomicUsize::new(0));
+    let slow_threads: Vec<_> = (0..slow_clients)
+        .map(|_| {
+            let running = running.clone();
+            let misses = misses.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    let miss = misses.fetch_add(1, Ordering::SeqCst);
+                    let url = format!("http://127.0.0.1:{}/slow/{}", port, miss);
+                    client_get_body(url.parse().unwrap());
+                }
+            })
+        })
+        .collect();
+    if slow_clients > 0 {
+        // Give the misses time to take all upstream slots.
+        thread::sleep(Duration::from_millis(2 * SLOW_MS));
+    }
+
+    let mut runtime = Runtime::new().unwrap();
+    let client = Client::new();
+    let mut slowest = Duration::from_secs(0);
+    b.iter(|| {
+        let hits: Vec<_> = (0..10)
+            .map(|_| {
+                let start = Instant::now();
+                client
+                    .get(hot.clone())
+                    .and_then(|response| {
+                        assert_eq!(response.status(), StatusCode::OK);
+                        response.into_body().concat2()
+                    })
+                    .map(move |_| start.elapsed())
+            })
+            .collect();
+        let durations = runtime.block_on(join_all(hits)).unwrap();
+        slowest = durations.into_iter().fold(slowest, Duration::max);
+    });
+
+    running.store(false, Ordering::SeqCst);
+    for slow_thread in slow_threads {
+        slow_thread.join().unwrap();
+    }
+    // A hit that waited for an upstream slot would take at least as long as
+    // a miss.
+    assert!(
+        slowest < Duration::from_millis(SLOW_MS),
+        "A cache hit took {:?} while the backend was saturated",
+        slowest
+    );
+}
@@ -0,0 +1,856 @@
+//! Administration API for inspecting the cache, served by the proxy itself
+//! under a configurable path prefix.
+
+use crate::audit::{self, AuditEntry};
+use crate::ban::Ban;
+use crate::cache::MemorySizable;
+use crate::conformance;
+use crate::director::Pools;
+use crate::failover::{FailoverState, FailoverStatus};
+use crate::history::Filter;
+use crate::hit_ratio::HitRatiosStatus;
+use crate::metrics::Metrics;
+use crate::origin::{OriginStatus, OriginsStatus};
+use crate::query::QueryNormalization;
+use crate::simulator::Scenario;
+use crate::state::ProxyState;
+use crate::{hash_key, policy, split_host, vary, with_host, Backend, Cache, Config, KeyPattern};
+use error_chain::ChainedError;
+use futures::sync::oneshot;
+use futures::Future;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A credential for the administration API.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminToken {
+    /// Who uses the token, for example "monitoring". Written to the audit
+    /// log.
+    pub name: String,
+    /// The secret, sent as "Authorization: Bearer <token>".
+    pub token: String,
+    /// The commands the token may use.
+    pub scopes: Vec<AdminScope>,
+}
+
+/// Groups of admin commands that a token can be allowed to use.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminScope {
+    /// Reading cache statistics, entries and previews.
+    ReadStats,
+    /// Changing cache entries: purging, pinning and expiry.
+    Purge,
+    /// Changing the configuration of the proxy.
+    Config,
+}
+
+/// Metadata about the cached response for a URL.
+#[derive(Debug, Serialize)]
+struct Preview {
+    cache_key: String,
+    hit: bool,
+    // Cache keys of the stored variants of the URL.
+    variants: Vec<String>,
+    // Hosts with an entry for the URL.
+    hosts: Vec<String>,
+    // Seconds until the entry expires.
+    ttl_remaining: Option<u64>,
+    // The cache policy that applies to the stored response, "cache-control"
+    // if the upstream header decided.
+    policy: Option<String>,
+}
+
+/// Memory statistics of the cache, for validating the memory estimation of
+/// cached responses.
+#[derive(Debug, Serialize)]
+struct Stats {
+    entries: usize,
+    // Configured memory limit in bytes.
+    memory_limit: usize,
+    // Bytes of all entries as estimated by `MemorySizable`.
+    accounted_memory: usize,
+    // Estimated entry sizes in bytes.
+    entry_size_p50: usize,
+    entry_size_p90: usize,
+    entry_size_p99: usize,
+    entry_size_max: usize,
+    // Heap bytes in use according to the allocator, only known with glibc.
+    allocator_memory: Option<usize>,
+    // Accounted memory divided by allocator memory. Far below 1 means that
+    // the estimation misses overhead or the heap is fragmented.
+    accounted_ratio: Option<f64>,
+    // Responses that were not cached because the insert queue was full.
+    dropped_inserts: usize,
+    // Variants that made room for newer variants of their URL.
+    variant_evictions: usize,
+    // Bans that are still checked on lookup.
+    bans: usize,
+    // Which backend is active, if failover is configured.
+    failover: Option<FailoverStatus>,
+    // Cache hits and misses by route and by content type.
+    hit_ratios: HitRatiosStatus,
+}
+
+/// Usage statistics of one cache entry.
+#[derive(Debug, Serialize)]
+struct Entry {
+    // The key without host, like a URL.
+    cache_key: String,
+    host: Option<String>,
+    hits: u64,
+    // Unix timestamp of the last hit, or of the insert if there was none.
+    last_access: u64,
+    // Estimated memory size in bytes.
+    size: usize,
+    pinned: bool,
+}
+
+/// Result of changing the expiry of an entry.
+#[derive(Debug, Serialize)]
+struct Expiry {
+    // Seconds until the entry expires now.
+    ttl_remaining: u64,
+}
+
+/// Result of purging.
+#[derive(Debug, Serialize)]
+struct Purged {
+    // Number of removed cache entries.
+    entries: usize,
+}
+
+/// Result of adding a ban.
+#[derive(Debug, Serialize)]
+struct Banned {
+    id: u64,
+}
+
+/// The backend origin and the pools of every director.
+#[derive(Debug, Serialize)]
+struct BackendStatus {
+    #[serde(flatten)]
+    origins: OriginsStatus,
+    directors: BTreeMap<String, OriginsStatus>,
+}
+
+/// Result of switching the backend.
+#[derive(Debug, Serialize)]
+struct Switched {
+    // The new origin, or the pools of a director.
+    origin: String,
+    // The replaced origin, with the requests that are still in flight to it.
+    previous: OriginStatus,
+    // Number of cache entries purged for the switch.
+    purged: usize,
+}
+
+/// Result of pinning or unpinning.
+#[derive(Debug, Serialize)]
+struct Pinned {
+    // Number of cached entries whose pin changed.
+    entries: usize,
+}
+
+/// Checks if the request is addressed to the administration API. With the
+/// admin path "/admin" that is "/admin" and "/admin/stats", but not the
+/// public page "/administrator".
+pub(crate) fn is_admin_request(request: &Request<Body>, config: &Config) -> bool {
+    match config.admin_path {
+        Some(ref prefix) => is_below(request.uri().path(), prefix.trim_end_matches('/')),
+        None => false,
+    }
+}
+
+/// Checks if a path is the prefix itself or a path below it.
+fn is_below(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Checks if a client may see what the proxy reports about itself, like
+/// dry-run reports: with a token that has the read-stats scope, or from the
+/// local host when there are no tokens.
+pub(crate) fn may_read_stats(
+    request: &Request<Body>,
+    source_address: SocketAddr,
+    config: &Config,
+) -> bool {
+    if config.admin_tokens.is_empty() {
+        return source_address.ip().is_loopback();
+    }
+    bearer_token(request)
+        .and_then(|secret| find_token(&config.admin_tokens, secret))
+        .is_some_and(|token| token.scopes.contains(&AdminScope::ReadStats))
+}
+
+/// Answers a request to the administration API.
+pub(crate) fn response(
+    request: &Request<Body>,
+    source_address: SocketAddr,
+    cache: &Cache,
+    metrics: &Metrics,
+    state: &ProxyState,
+) -> Response<Body> {
+    let config = &state.config;
+    let prefix = config
+        .admin_path
+        .as_ref()
+        .map_or("", |prefix| prefix.trim_end_matches('/'));
+    let verb = request.uri().path()[prefix.len()..].trim_start_matches('/');
+    let token = if config.admin_tokens.is_empty() {
+        None
+    } else {
+        bearer_token(request).and_then(|secret| find_token(&config.admin_tokens, secret))
+    };
+    let response = if config.admin_tokens.is_empty() {
+        // Without tokens only local users may administrate the proxy.
+        if source_address.ip().is_loopback() {
+            command(request, verb, cache, metrics, state)
+        } else {
+            error(StatusCode::FORBIDDEN, "Access denied")
+        }
+    } else {
+        match (token, required_scope(request.method(), verb)) {
+            (None, _) => error(StatusCode::UNAUTHORIZED, "Missing or invalid token"),
+            (Some(token), Some(scope)) if !token.scopes.contains(&scope) => error(
+                StatusCode::FORBIDDEN,
+                "Token lacks the scope for this command",
+            ),
+            _ => command(request, verb, cache, metrics, state),
+        }
+    };
+    // Everything but GET changes the cache and is a management action.
+    if let Some(ref path) = config.admin_audit_log {
+        if request.method() != Method::GET {
+            let entry = AuditEntry {
+                timestamp: unix_time(config.clock.system_time()),
+                client: source_address.ip(),
+                identity: token.map(|token| token.name.as_str()),
+                action: verb,
+                parameters: request.uri().query().unwrap_or(""),
+                status: response.status().as_u16(),
+            };
+            if let Err(e) = audit::record(path, &entry) {
+                eprintln!("Failed to write audit log {}: {}", path, e);
+            }
+        }
+    }
+    response
+}
+
+/// The scope a token needs for a command, None for unknown commands.
+fn required_scope(method: &Method, verb: &str) -> Option<AdminScope> {
+    match (method, verb) {
+        (&Method::GET, "preview")
+        | (&Method::GET, "stats")
+        | (&Method::GET, "metrics")
+        | (&Method::GET, "entries")
+        | (&Method::GET, "bans")
+        | (&Method::GET, "simulate")
+        | (&Method::GET, "history")
+        | (&Method::GET, "conformance")
+        | (&Method::GET, "backend") => Some(AdminScope::ReadStats),
+        (&Method::POST, "pin")
+        | (&Method::POST, "unpin")
+        | (&Method::POST, "purge")
+        | (&Method::POST, "ban")
+        | (&Method::POST, "expiry") => Some(AdminScope::Purge),
+        (&Method::POST, "switch") => Some(AdminScope::Config),
+        _ => None,
+    }
+}
+
+/// Reads the secret from an "Authorization: Bearer" header.
+fn bearer_token(request: &Request<Body>) -> Option<&str> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(secret)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(secret.trim())
+        }
+        _ => None,
+    }
+}
+
+/// Looks up the token with the secret. All tokens are compared in full, so
+/// the response time tells nothing about how close a guess was.
+fn find_token<'a>(tokens: &'a [AdminToken], secret: &str) -> Option<&'a AdminToken> {
+    let mut found = None;
+    for token in tokens {
+        let equal = token.token.len() == secret.len()
+            && token
+                .token
+                .bytes()
+                .zip(secret.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0;
+        if equal && found.is_none() {
+            found = Some(token);
+        }
+    }
+    found
+}
+
+/// Carries out an admin command.
+fn command(
+    request: &Request<Body>,
+    verb: &str,
+    cache: &Cache,
+    metrics: &Metrics,
+    state: &ProxyState,
+) -> Response<Body> {
+    let config = &state.config;
+    match (request.method(), verb) {
+        (&Method::GET, "preview") => match query_parameter(request, "url") {
+            Some(url) => json(&preview(
+                &url,
+                query_parameter(request, "host").as_deref(),
+                cache,
+                config,
+            )),
+            None => error(StatusCode::BAD_REQUEST, "Missing url parameter"),
+        },
+        (&Method::GET, "stats") => json(&stats(cache, state)),
+        (&Method::GET, "metrics") => json(&metrics.snapshot()),
+        (&Method::POST, "pin") => match key_pattern_parameter(request) {
+            Some(pin) => json(&Pinned {
+                entries: cache.pin(pin),
+            }),
+            None => error(StatusCode::BAD_REQUEST, "Missing key or prefix parameter"),
+        },
+        (&Method::POST, "unpin") => match key_pattern_parameter(request) {
+            Some(pin) => json(&Pinned {
+                entries: cache.unpin(&pin),
+            }),
+            None => error(StatusCode::BAD_REQUEST, "Missing key or prefix parameter"),
+        },
+        (&Method::POST, "purge") => match key_pattern_parameter(request) {
+            Some(pattern) => json(&Purged {
+                entries: cache.purge(&pattern),
+            }),
+            None => error(StatusCode::BAD_REQUEST, "Missing key or prefix parameter"),
+        },
+        (&Method::POST, "ban") => {
+            let key = query_parameter(request, "key");
+            let header = query_parameter(request, "header");
+            let value = query_parameter(request, "value");
+            let header = match (&header, &value) {
+                (Some(header), Some(value)) => Some((header.as_str(), value.as_str())),
+                (None, None) => None,
+                _ => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "Header bans need a header and a value parameter",
+                    )
+                }
+            };
+            match Ban::new(key.as_deref(), header) {
+                Ok(ban) => json(&Banned { id: cache.ban(ban) }),
+                Err(message) => error(StatusCode::BAD_REQUEST, message),
+            }
+        }
+        (&Method::GET, "bans") => json(&cache.bans.read().unwrap().info()),
+        (&Method::GET, "backend") => json(&BackendStatus {
+            origins: state.origins.status(),
+            directors: state
+                .config
+                .directors
+                .iter()
+                .zip(&state.director_pools)
+                .map(|(director, pools)| (director.name.clone(), pools.status()))
+                .collect(),
+        }),
+        (&Method::POST, "switch") => switch(request, cache, state),
+        (&Method::POST, "expiry") => {
+            let ttl = query_parameter(request, "ttl").and_then(|ttl| ttl.parse().ok());
+            match (query_parameter(request, "key"), ttl) {
+                (Some(key), Some(ttl)) => {
+                    let expires = cache.clock.now() + Duration::from_secs(ttl);
+                    let key = match query_parameter(request, "host") {
+                        Some(host) => with_host(&key, &host),
+                        None => key,
+                    };
+                    let changed = cache
+                        .keys(&KeyPattern::Key(key))
+                        .iter()
+                        .filter(|key| cache.set_expiry(key, expires))
+                        .count();
+                    if changed > 0 {
+                        json(&Expiry { ttl_remaining: ttl })
+                    } else {
+                        error(StatusCode::NOT_FOUND, "No cache entry for the key")
+                    }
+                }
+                _ => error(StatusCode::BAD_REQUEST, "Missing key or ttl parameter"),
+            }
+        }
+        (&Method::GET, "entries") => match query_parameter(request, "limit") {
+            None => json(&entries(cache, 100)),
+            Some(limit) => match limit.parse() {
+                Ok(limit) => json(&entries(cache, limit)),
+                Err(_) => error(StatusCode::BAD_REQUEST, "Invalid limit parameter"),
+            },
+        },
+        (&Method::GET, "conformance") => conformance(config),
+        (&Method::GET, "simulate") => match scenario(request) {
+            Some(scenario) => json(&state.request_log.simulate(&scenario)),
+            None => error(StatusCode::BAD_REQUEST, "Invalid ttl parameter"),
+        },
+        (&Method::GET, "history") => {
+            let limit = match query_parameter(request, "limit") {
+                None => 100,
+                Some(limit) => match limit.parse() {
+                    Ok(limit) => limit,
+                    Err(_) => return error(StatusCode::BAD_REQUEST, "Invalid limit parameter"),
+                },
+            };
+            match history_filter(request) {
+                Some(filter) => json(&state.history.list(&filter, limit)),
+                None => error(StatusCode::BAD_REQUEST, "Invalid filter parameter"),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "Unknown admin command"),
+    }
+}
+
+/// Switches the backend origin to "host" and "port". With a "director" or
+/// a "route" parameter the pools of that director are switched to
+/// "backends" instead, like "10.0.0.1:80,10.0.0.2:80;backup:8080". The
+/// entries of the old backends can be purged by a key prefix in "purge".
+fn switch(request: &Request<Body>, cache: &Cache, state: &ProxyState) -> Response<Body> {
+    let config = &state.config;
+    let director = match (
+        query_parameter(request, "director"),
+        query_parameter(request, "route"),
+    ) {
+        (Some(name), _) => Some(name),
+        (None, Some(prefix)) => {
+            match config
+                .routes
+                .iter()
+                .find(|route| route.path_prefix == prefix)
+            {
+                Some(route) => match route.director {
+                    Some(ref name) => Some(name.clone()),
+                    None => {
+                        return error(
+                            StatusCode::BAD_REQUEST,
+                            "The route has no director, switch the backend instead",
+                        )
+                    }
+                },
+                None => return error(StatusCode::NOT_FOUND, "Unknown route"),
+            }
+        }
+        (None, None) => None,
+    };
+    let (origin, previous) = match director {
+        Some(name) => {
+            let index = match config
+                .directors
+                .iter()
+                .position(|director| director.name == name)
+            {
+                Some(index) => index,
+                None => return error(StatusCode::NOT_FOUND, "Unknown director"),
+            };
+            let pools = match query_parameter(request, "backends").and_then(|b| Pools::parse(&b)) {
+                Some(pools) => pools,
+                None => return error(StatusCode::BAD_REQUEST, "Invalid backends parameter"),
+            };
+            (pools.to_string(), state.director_pools[index].switch(pools))
+        }
+        None => {
+            let port = query_parameter(request, "port").and_then(|port| port.parse().ok());
+            let (host, port) = match (query_parameter(request, "host"), port) {
+                (Some(host), Some(port)) => (host, port),
+                _ => return error(StatusCode::BAD_REQUEST, "Missing host or port parameter"),
+            };
+            // Scheme and TLS settings stay those of the configured backend.
+            let backend = Backend {
+                host,
+                ..config.backend.clone()
+            };
+            let origin = backend.origin(port);
+            if backend.host.is_empty()
+                || origin.parse::<Uri>().map_or(true, |uri| uri.path() != "/")
+            {
+                return error(StatusCode::BAD_REQUEST, "Invalid host parameter");
+            }
+            (origin.clone(), state.origins.switch(origin))
+        }
+    };
+    // Entries of the old backend, for example all under a route.
+    let purged = query_parameter(request, "purge")
+        .map_or(0, |prefix| cache.purge(&KeyPattern::Prefix(prefix)));
+    json(&Switched {
+        origin,
+        previous,
+        purged,
+    })
+}
+
+/// Reads the filter of the history command: "decision" like "miss",
+/// "status" like "404" or "5xx", "path" prefix and "min_ms" duration.
+fn history_filter(request: &Request<Body>) -> Option<Filter> {
+    let mut filter = Filter {
+        status: query_parameter(request, "status"),
+        path_prefix: query_parameter(request, "path"),
+        ..Filter::default()
+    };
+    if let Some(decision) = query_parameter(request, "decision") {
+        if !filter.decision(&decision) {
+            return None;
+        }
+    }
+    if let Some(min) = query_parameter(request, "min_ms") {
+        filter.min_duration_ms = Some(min.parse().ok()?);
+    }
+    Some(filter)
+}
+
+/// Reads the hypothetical cache settings of the simulate command: "ttl" in
+/// seconds, "sort_query", and comma separated "strip_query" and
+/// "keep_query" parameter names. An empty "keep_query" ignores the query.
+fn scenario(request: &Request<Body>) -> Option<Scenario> {
+    let ttl = match query_parameter(request, "ttl") {
+        Some(ttl) => Some(ttl.parse().ok()?),
+        None => None,
+    };
+    let names = |list: String| {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    Some(Scenario {
+        ttl,
+        query: QueryNormalization {
+            sort: query_parameter(request, "sort_query").is_some_and(|sort| sort != "0"),
+            strip: query_parameter(request, "strip_query").map_or_else(Vec::new, names),
+            keep: query_parameter(request, "keep_query").map(names),
+        },
+    })
+}
+
+/// Describes what the cache holds for a URL, without returning the body. The
+/// entries of all hosts are described unless a host is given.
+fn preview(url: &str, host: Option<&str>, cache: &Cache, config: &Config) -> Preview {
+    // The cache key has the normalized query.
+    let url = config.query_normalization.normalize(url);
+    let key = match host {
+        Some(host) => with_host(&url, host),
+        None => url.into_owned(),
+    };
+    let mut preview = Preview {
+        cache_key: key.clone(),
+        hit: false,
+        variants: Vec::new(),
+        hosts: Vec::new(),
+        ttl_remaining: None,
+        policy: None,
+    };
+    for full_key in cache.keys(&KeyPattern::Key(key)) {
+        if let Some((ttl, policy, mut variants)) = describe_entry(&full_key, cache, config) {
+            preview.hit = true;
+            preview.variants.append(&mut variants);
+            if let (_, Some(host)) = split_host(&full_key) {
+                preview.hosts.push(host.to_string());
+            }
+            preview.ttl_remaining = preview.ttl_remaining.max(Some(ttl));
+            preview.policy.get_or_insert(policy);
+        }
+    }
+    preview
+}
+
+/// The remaining seconds, the policy and the fresh variants of an entry,
+/// None if there is no fresh variant.
+fn describe_entry(key: &str, cache: &Cache, config: &Config) -> Option<(u64, String, Vec<String>)> {
+    let now = cache.clock.now();
+    let bans = cache.bans.read().unwrap();
+    let (url, _) = split_host(key);
+    cache
+        .lru_cache
+        .peek_with_expiry(&hash_key(key), |entry, expires| {
+            if entry.key != key {
+                return None;
+            }
+            let (_, latest) = entry.variants.iter().next()?;
+            let policy = match policy::find_policy(&config.cache_policies, &latest.headers) {
+                Some(policy) => policy.content_type.clone(),
+                None => "cache-control".to_string(),
+            };
+            let variants = entry
+                .variants
+                .iter()
+                .filter(|(_, variant)| {
+                    variant.expires > now
+                        && !bans.is_banned(variant.checked_ban, key, &variant.headers)
+                })
+                .map(|(vary, _)| {
+                    if vary.is_empty() {
+                        url.to_string()
+                    } else {
+                        format!("{} ({})", url, vary::describe(vary))
+                    }
+                })
+                .collect::<Vec<_>>();
+            if variants.is_empty() {
+                return None;
+            }
+            // The entry itself is kept longer for its grace period.
+            let expires = entry
+                .variants
+                .iter()
+                .map(|(_, variant)| variant.expires)
+                .max()
+                .unwrap_or(expires);
+            Some((
+                expires.saturating_duration_since(now).as_secs(),
+                policy,
+                variants,
+            ))
+        })
+        .and_then(|entry| entry)
+}
+
+/// Collects the size distribution of the cached entries.
+fn stats(cache: &Cache, state: &ProxyState) -> Stats {
+    let mut sizes = Vec::new();
+    cache
+        .lru_cache
+        .peek_each(|_, entry| sizes.push(entry.get_memory_size()));
+    sizes.sort_unstable();
+    let accounted_memory = sizes.iter().sum();
+    let allocator_memory = allocator_memory();
+    Stats {
+        entries: sizes.len(),
+        memory_limit: cache.lru_cache.max_memory_size(),
+        accounted_memory,
+        entry_size_p50: percentile(&sizes, 50),
+        entry_size_p90: percentile(&sizes, 90),
+        entry_size_p99: percentile(&sizes, 99),
+        entry_size_max: sizes.last().cloned().unwrap_or(0),
+        allocator_memory,
+        accounted_ratio: allocator_memory
+            .filter(|allocated| *allocated > 0)
+            .map(|allocated| accounted_memory as f64 / allocated as f64),
+        dropped_inserts: cache.dropped_inserts.load(Ordering::Relaxed),
+        variant_evictions: cache.variant_evictions.load(Ordering::Relaxed),
+        bans: cache.bans.read().unwrap().len(),
+        failover: state.failover.as_ref().map(FailoverState::status),
+        hit_ratios: state.hit_ratios.status(&state.config.routes),
+    }
+}
+
+/// Lists the most requested cache entries, most hits first.
+fn entries(cache: &Cache, limit: usize) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    cache.lru_cache.peek_each(|hash, entry| {
+        let (cache_key, host) = split_host(&entry.key);
+        entries.push((
+            *hash,
+            Entry {
+                cache_key: cache_key.into_owned(),
+                host: host.map(str::to_string),
+                hits: entry.hits,
+                last_access: unix_time(entry.last_access),
+                size: entry.get_memory_size(),
+                pinned: false,
+            },
+        ))
+    });
+    entries.sort_by(|(_, a), (_, b)| {
+        b.hits
+            .cmp(&a.hits)
+            .then_with(|| a.cache_key.cmp(&b.cache_key))
+    });
+    entries.truncate(limit);
+    // The shards are unlocked now, so pins can be looked up.
+    entries
+        .into_iter()
+        .map(|(hash, entry)| Entry {
+            pinned: cache.lru_cache.is_pinned(&hash),
+            ..entry
+        })
+        .collect()
+}
+
+/// Returns the nearest-rank percentile of sorted values, 0 if there are
+/// none.
+fn percentile(sorted: &[usize], percent: usize) -> usize {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Returns the number of heap bytes in use as reported by glibc.
+/// mallinfo2() only exists since glibc 2.33, so it is looked up at runtime
+/// instead of linked, and older versions report no allocator memory.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn allocator_memory() -> Option<usize> {
+    type MallInfo2 = unsafe extern "C" fn() -> libc::mallinfo2;
+    let name = b"mallinfo2\0".as_ptr() as *const libc::c_char;
+    // Safe because the name is NUL terminated, and the symbol found under it
+    // is glibc's mallinfo2(), which only reads allocator statistics.
+    unsafe {
+        let symbol = libc::dlsym(libc::RTLD_DEFAULT, name);
+        if symbol.is_null() {
+            return None;
+        }
+        let mallinfo2 = std::mem::transmute::<*mut libc::c_void, MallInfo2>(symbol);
+        Some(mallinfo2().uordblks)
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn allocator_memory() -> Option<usize> {
+    None
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Reads the cache key or key prefix to work on from the query. A key
+/// without "host" parameter selects the entries of all hosts.
+fn key_pattern_parameter(request: &Request<Body>) -> Option<KeyPattern> {
+    match query_parameter(request, "key") {
+        Some(key) => Some(KeyPattern::Key(match query_parameter(request, "host") {
+            Some(host) => with_host(&key, &host),
+            None => key,
+        })),
+        None => query_parameter(request, "prefix").map(KeyPattern::Prefix),
+    }
+}
+
+/// Returns the percent-decoded value of a query parameter.
+fn query_parameter(request: &Request<Body>, name: &str) -> Option<String> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            percent_decode(parts.next().unwrap_or(""))
+        } else {
+            None
+        }
+    })
+}
+
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                // from_str_radix() alone would accept a sign like in "%+1".
+                let hex = value.get(i + 1..i + 3)?;
+                if !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+                    return None;
+                }
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Runs the HTTP cache conformance tests with the caching settings of the
+/// proxy. They take a few seconds, so the results are sent as the body once
+/// they are done. A failed run aborts the response.
+fn conformance(config: &Config) -> Response<Body> {
+    let (sender, receiver) = oneshot::channel();
+    let config = config.clone();
+    thread::spawn(move || {
+        let _ = sender.send(conformance::run(&config));
+    });
+    let report = receiver
+        .then(|result| match result {
+            Ok(Ok(report)) => Ok(serde_json::to_string_pretty(&report).unwrap()),
+            Ok(Err(e)) => {
+                eprintln!("Conformance run failed: {}", e.display_chain());
+                Err(e.to_string())
+            }
+            Err(_) => Err("The conformance run stopped".to_string()),
+        })
+        .into_stream();
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::wrap_stream(report))
+        .unwrap()
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string_pretty(value).unwrap()))
+        .unwrap()
+}
+
+fn error<M: Into<Body>>(status: StatusCode, message: M) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(message.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_below, percent_decode, percentile};
+
+    #[test]
+    fn admin_paths() {
+        assert!(is_below("/admin", "/admin"));
+        assert!(is_below("/admin/", "/admin"));
+        assert!(is_below("/admin/stats", "/admin"));
+        assert!(!is_below("/administrator", "/admin"));
+        assert!(!is_below("/admin-panel/", "/admin"));
+        assert!(!is_below("/", "/admin"));
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            Some("/a b?c=d".to_string()),
+            percent_decode("%2Fa+b%3fc%3Dd")
+        );
+        assert_eq!(None, percent_decode("%2"));
+        assert_eq!(None, percent_decode("%zz"));
+        assert_eq!(None, percent_decode("%+1"));
+        assert_eq!(None, percent_decode("%-1"));
+    }
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(0, percentile(&[], 50));
+        let sizes: Vec<usize> = (1..=100).collect();
+        assert_eq!(50, percentile(&sizes, 50));
+        assert_eq!(99, percentile(&sizes, 99));
+        assert_eq!(1, percentile(&sizes, 0));
+        assert_eq!(7, percentile(&[7], 90));
+    }
+}
@@ -0,0 +1,33 @@
+//! Append-only log of management actions on the administration API, one JSON
+//! object per line. The file is opened for every entry, so it can be rotated
+//! by moving it away.
+
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+/// One management action, recorded whether it succeeded or not.
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditEntry<'a> {
+    // Unix timestamp.
+    pub(crate) timestamp: u64,
+    pub(crate) client: IpAddr,
+    // Who did it according to the credentials of the request, None if the
+    // request had none.
+    pub(crate) identity: Option<&'a str>,
+    pub(crate) action: &'a str,
+    // The query string with the parameters of the action.
+    pub(crate) parameters: &'a str,
+    // Status code of the response, tells if the action was carried out.
+    pub(crate) status: u16,
+}
+
+/// Appends the entry to the log file, creating it if needed.
+pub(crate) fn record(path: &str, entry: &AuditEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // A single write, so concurrent entries don't get mixed up.
+    file.write_all(&line)
+}
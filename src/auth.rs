@@ -0,0 +1,201 @@
+//! Credentials that are added to upstream requests, for origins that only
+//! answer other services. The secrets stay between rustnish and the backend,
+//! clients never see them.
+
+use crate::sigv4::Signer;
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderValue, AUTHORIZATION};
+use hyper::{Body, Request};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How rustnish authenticates to the backend. An Authorization header of the
+/// client is replaced.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum BackendAuth {
+    /// A static token, sent as "Authorization: Bearer <token>".
+    Bearer { token: String },
+    /// HTTP basic authentication.
+    Basic { username: String, password: String },
+    /// Every request is signed with an HMAC-SHA256 of its method, path with
+    /// query and the current Unix time, one per line. The Authorization
+    /// header looks like `HMAC-SHA256 keyId="app", timestamp=1700000000,
+    /// signature="<base64>"`. Bodies are not signed, so that they can be
+    /// streamed.
+    Hmac { key_id: String, secret: String },
+    /// AWS Signature Version 4, for a private S3 bucket as backend. Google
+    /// Cloud Storage accepts it with HMAC keys, the host
+    /// storage.googleapis.com and the region "auto". The Host header is
+    /// set to the backend host.
+    Sigv4 {
+        access_key_id: String,
+        secret_access_key: String,
+        region: String,
+        #[serde(default = "default_sigv4_service")]
+        service: String,
+        /// For temporary credentials.
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+}
+
+fn default_sigv4_service() -> String {
+    "s3".to_string()
+}
+
+impl BackendAuth {
+    /// Checks that the credentials fit into a header.
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            BackendAuth::Bearer { token } => {
+                !token.is_empty() && HeaderValue::from_str(token).is_ok()
+            }
+            BackendAuth::Basic { username, .. } => !username.is_empty() && !username.contains(':'),
+            BackendAuth::Hmac { key_id, secret } => {
+                !key_id.is_empty() && !key_id.contains('"') && !secret.is_empty()
+            }
+            BackendAuth::Sigv4 {
+                access_key_id,
+                secret_access_key,
+                region,
+                service,
+                session_token,
+            } => {
+                let scope_part = |value: &str| {
+                    !value.is_empty()
+                        && !value.contains(|c: char| c == '/' || !c.is_ascii_graphic())
+                };
+                scope_part(access_key_id)
+                    && scope_part(region)
+                    && scope_part(service)
+                    && !secret_access_key.is_empty()
+                    && session_token
+                        .as_ref()
+                        .is_none_or(|token| HeaderValue::from_str(token).is_ok())
+            }
+        }
+    }
+
+    /// Adds the credentials to a request that is about to be sent upstream.
+    pub(crate) fn authorize(&self, request: &mut Request<Body>, now: SystemTime) {
+        let value = match self {
+            BackendAuth::Bearer { token } => format!("Bearer {}", token),
+            BackendAuth::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            BackendAuth::Hmac { key_id, secret } => {
+                let timestamp = now
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_secs());
+                let path = request
+                    .uri()
+                    .path_and_query()
+                    .map_or("/", |path| path.as_str());
+                format!(
+                    "HMAC-SHA256 keyId=\"{}\", timestamp={}, signature=\"{}\"",
+                    key_id,
+                    timestamp,
+                    hmac_signature(secret, request.method().as_str(), path, timestamp)
+                )
+            }
+            BackendAuth::Sigv4 {
+                access_key_id,
+                secret_access_key,
+                region,
+                service,
+                session_token,
+            } => {
+                let signer = Signer {
+                    access_key_id,
+                    secret_access_key,
+                    region,
+                    service,
+                };
+                signer.sign(request, session_token.as_deref(), now)
+            }
+        };
+        // Checked by is_valid() when the config was loaded.
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+    }
+}
+
+/// The base64 encoded HMAC-SHA256 of a request.
+fn hmac_signature(secret: &str, method: &str, path: &str, timestamp: u64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}\n{}", method, path, timestamp).as_bytes());
+    base64::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_signature, BackendAuth};
+    use hyper::header::AUTHORIZATION;
+    use hyper::{Body, Request};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn signatures() {
+        assert_eq!(
+            "7z9hhrpqvUTAg81VSkF9dH0NVY/vAvzHZ5Z6bZpA2tw=",
+            hmac_signature("secret", "GET", "/a?b=c", 1_700_000_000)
+        );
+
+        let auth = BackendAuth::Hmac {
+            key_id: "app".to_string(),
+            secret: "secret".to_string(),
+        };
+        let mut request = Request::get("http://backend/a?b=c")
+            .header(AUTHORIZATION, "Bearer client")
+            .body(Body::empty())
+            .unwrap();
+        auth.authorize(
+            &mut request,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        );
+        assert_eq!(
+            "HMAC-SHA256 keyId=\"app\", timestamp=1700000000, \
+             signature=\"7z9hhrpqvUTAg81VSkF9dH0NVY/vAvzHZ5Z6bZpA2tw=\"",
+            request.headers()[AUTHORIZATION]
+        );
+    }
+
+    #[test]
+    fn invalid_credentials() {
+        let bearer = |token: &str| BackendAuth::Bearer {
+            token: token.to_string(),
+        };
+        assert!(bearer("abc").is_valid());
+        assert!(!bearer("").is_valid());
+        assert!(!bearer("a\nb").is_valid());
+        let basic = BackendAuth::Basic {
+            username: "a:b".to_string(),
+            password: String::new(),
+        };
+        assert!(!basic.is_valid());
+    }
+
+    #[test]
+    fn parse_toml() {
+        let auth: BackendAuth =
+            toml::from_str("type = \"basic\"\nusername = \"app\"\npassword = \"pw\"").unwrap();
+        assert!(auth.is_valid());
+        assert!(toml::from_str::<BackendAuth>("type = \"bearer\"\nsecret = \"x\"").is_err());
+
+        let auth: BackendAuth = toml::from_str(
+            "type = \"sigv4\"\naccess_key_id = \"GOOG1E\"\n\
+             secret_access_key = \"secret\"\nregion = \"auto\"",
+        )
+        .unwrap();
+        assert!(auth.is_valid());
+        match auth {
+            BackendAuth::Sigv4 { service, .. } => assert_eq!("s3", service),
+            _ => panic!("Wrong auth type"),
+        }
+    }
+}
@@ -0,0 +1,733 @@
+//! Settings for the upstream server that requests are forwarded to.
+
+use crate::auth::BackendAuth;
+use crate::checksum::{self, ChecksumAlgorithm};
+use crate::errors::*;
+use error_chain::bail;
+use futures::future::{self, Either, Loop};
+use futures::{Future, Poll, Stream};
+use hyper::client::connect::dns::{Name, Resolve, TokioThreadpoolGaiResolver};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::client::HttpConnector;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CONNECTION, CONTENT_LENGTH, PROXY_AUTHORIZATION,
+    TRANSFER_ENCODING,
+};
+use hyper::{Body, Client, HeaderMap, Request, Uri, Version};
+use native_tls::{Certificate, TlsConnector};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::reactor::Handle;
+use tokio_tls::TlsStream;
+
+/// The HTTP client for upstream requests.
+pub(crate) type UpstreamClient = Client<UpstreamConnector>;
+
+/// Connection settings for an upstream server.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backend {
+    /// Host name or IP address of the backend, for example "10.0.0.5" or
+    /// "app.internal". Host names are resolved when connecting.
+    pub host: String,
+    /// Port of the backend, 80 if not set. `Builder::upstream()`, the
+    /// --upstream option and the upstream port argument of the
+    /// `start_server_*()` functions take precedence.
+    pub port: Option<u16>,
+    /// Send header names in Title-Case like "Content-Type" instead of
+    /// lowercase, for legacy backends that are sensitive to header casing.
+    /// Headers are always forwarded in the order they were received.
+    pub title_case_headers: bool,
+    /// Speak HTTP/1.0 to ancient origins: no keep-alive and no chunked
+    /// request bodies. Bodies without a known length are buffered first.
+    pub http_1_0: bool,
+    /// Speak HTTP/2 with prior knowledge to the backend, needed for gRPC
+    /// services.
+    pub http2: bool,
+    /// Outbound HTTP proxy that upstream connections go through, for example
+    /// a corporate egress proxy. HTTPS backends are reached through a tunnel
+    /// that is opened with a CONNECT request.
+    pub proxy: Option<OutboundProxy>,
+    /// When a host name resolves to IPv6 and IPv4 addresses, the first
+    /// address family is tried for this many milliseconds before the other
+    /// one is dialed in parallel (Happy Eyeballs, RFC 6555). With 0 the
+    /// addresses are tried one after another.
+    pub happy_eyeballs_ms: u64,
+    /// Local IP address that upstream connections are made from, for hosts
+    /// with several addresses.
+    pub source_address: Option<IpAddr>,
+    /// Network device that upstream connections are bound to, like "eth1"
+    /// (`SO_BINDTODEVICE`, Linux only, needs `CAP_NET_RAW`).
+    pub bind_device: Option<String>,
+    /// Maximum number of requests in flight to the backend. Further cache
+    /// misses wait for a free slot, while cache hits are still answered
+    /// right away. Unlimited if not set.
+    pub max_requests: Option<usize>,
+    /// Find the number of parallel requests the backend can sustain instead
+    /// of using a fixed limit. The limit grows slowly while responses arrive
+    /// in time and is cut by 10% on errors, 429, 502, 503 and 504 responses
+    /// and responses slower than `adaptive_latency_ms`. It starts at 20 and
+    /// never goes over `max_requests`.
+    pub adaptive_concurrency: bool,
+    /// Latency in milliseconds above which a response counts as a sign of
+    /// overload for `adaptive_concurrency`.
+    pub adaptive_latency_ms: u64,
+    /// Milliseconds that the backend has to answer a request, including
+    /// the wait for a slot under `max_requests`. Clients can ask for less
+    /// with a grpc-timeout or X-Request-Timeout header. Late responses are
+    /// answered with a 504. No limit if not set.
+    pub timeout_ms: Option<u64>,
+    /// Send health checks and the revalidation of expired responses as HEAD
+    /// instead of GET requests, for backends that answer HEAD like GET. A
+    /// changed response then costs a second request, but unchanged large
+    /// ones are not downloaded.
+    pub head_probes: bool,
+    /// What happens when the backend breaks off a response body.
+    pub upstream_abort: UpstreamAbort,
+    /// Always ask the backend for gzip, whatever the client accepts, so
+    /// that only one encoding of every object is cached. Clients that don't
+    /// accept gzip get the body decompressed.
+    pub normalize_accept_encoding: bool,
+    /// Speak HTTPS to the backend with these TLS settings. Plain HTTP if not
+    /// set.
+    pub tls: Option<BackendTls>,
+    /// Credentials for backends that require service-to-service
+    /// authentication.
+    pub auth: Option<BackendAuth>,
+    /// Checksums that are added to request bodies, for object storage
+    /// backends that verify uploads. Bodies are buffered to compute them.
+    pub request_checksums: Vec<ChecksumAlgorithm>,
+    /// Check the Content-MD5, x-amz-checksum-sha256 and x-goog-hash headers
+    /// of responses against their bodies. Responses that don't match are
+    /// still passed on, but not cached.
+    pub verify_response_checksums: bool,
+}
+
+/// TLS settings for an HTTPS backend.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendTls {
+    /// PEM file with root certificates that are trusted in addition to the
+    /// system ones, for backends with certificates from an internal CA.
+    pub ca_file: Option<String>,
+    /// Host name that is sent with SNI and that the certificate must be valid
+    /// for, when it differs from the backend host. For example when the
+    /// backend is addressed by IP address.
+    pub server_name: Option<String>,
+    /// Oldest TLS version that the backend may negotiate.
+    pub min_version: TlsVersion,
+    /// Accept any certificate for any host name. Connections can then be
+    /// intercepted, so this is only meant for internal staging origins with
+    /// self-signed certificates and a warning is logged at startup.
+    pub insecure_skip_verify: bool,
+}
+
+/// TLS protocol versions, written like "1.2" in the config file.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    #[default]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl BackendTls {
+    /// Builds the TLS connector, which fails if the CA file can't be read.
+    pub(crate) fn connector(&self, http2: bool) -> Result<TlsConnector> {
+        let mut builder = TlsConnector::builder();
+        if let Some(ref path) = self.ca_file {
+            let pem = fs::read(path).chain_err(|| format!("Failed to read CA file {}", path))?;
+            let certificates = Certificate::stack_from_pem(&pem)
+                .chain_err(|| format!("Invalid certificates in CA file {}", path))?;
+            if certificates.is_empty() {
+                bail!("No certificates in CA file {}", path);
+            }
+            for certificate in certificates {
+                builder.add_root_certificate(certificate);
+            }
+        }
+        builder.min_protocol_version(Some(match self.min_version {
+            TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+            TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+            TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+            TlsVersion::Tls13 => native_tls::Protocol::Tlsv13,
+        }));
+        if http2 {
+            builder.request_alpns(&["h2"]);
+        }
+        builder
+            .danger_accept_invalid_certs(self.insecure_skip_verify)
+            .danger_accept_invalid_hostnames(self.insecure_skip_verify);
+        builder
+            .build()
+            .chain_err(|| "Failed to set up TLS for the backend")
+    }
+}
+
+/// Ways to deal with response bodies that upstream breaks off.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamAbort {
+    /// Abort the response to the client as well, so that it cannot mistake
+    /// the truncated body for the complete one.
+    Abort,
+    /// Request the rest of the body with a Range request, if the response
+    /// has a strong ETag or a Last-Modified date to make sure the parts
+    /// match. GET requests only, other responses are aborted.
+    Resume,
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend {
+            host: "127.0.0.1".to_string(),
+            port: None,
+            title_case_headers: false,
+            http_1_0: false,
+            http2: false,
+            proxy: None,
+            happy_eyeballs_ms: 300,
+            source_address: None,
+            bind_device: None,
+            max_requests: None,
+            adaptive_concurrency: false,
+            adaptive_latency_ms: 1000,
+            timeout_ms: None,
+            head_probes: false,
+            upstream_abort: UpstreamAbort::Abort,
+            normalize_accept_encoding: false,
+            tls: None,
+            auth: None,
+            request_checksums: Vec::new(),
+            verify_response_checksums: false,
+        }
+    }
+}
+
+/// An HTTP proxy for upstream connections.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundProxy {
+    /// URL of the proxy, for example "http://proxy.local:3128". The proxy
+    /// itself is spoken to in plain HTTP, also for HTTPS backends.
+    pub url: String,
+    /// User name for basic proxy authentication.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password for basic proxy authentication.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl OutboundProxy {
+    /// Returns the host and port of the proxy, if the URL is usable.
+    pub(crate) fn address(&self) -> Option<(String, u16)> {
+        let uri: Uri = self.url.parse().ok()?;
+        if uri.scheme_str() != Some("http") {
+            return None;
+        }
+        let authority = uri.authority_part()?;
+        Some((
+            authority.host().to_string(),
+            authority.port_u16().unwrap_or(80),
+        ))
+    }
+
+    /// Returns the Proxy-Authorization header value, if credentials are set.
+    fn authorization(&self) -> Option<HeaderValue> {
+        let username = self.username.as_ref()?;
+        let password = self
+            .password
+            .as_ref()
+            .map_or("", |password| password.as_str());
+        let credentials = base64::encode(format!("{}:{}", username, password));
+        HeaderValue::from_str(&format!("Basic {}", credentials)).ok()
+    }
+}
+
+/// Connects to upstream directly or through the outbound proxy.
+#[derive(Clone)]
+pub(crate) struct UpstreamConnector {
+    http: HttpConnector,
+    proxy: Option<(String, u16)>,
+    // Sent with the CONNECT requests for HTTPS backends behind the proxy.
+    proxy_authorization: Option<HeaderValue>,
+    source_address: Option<IpAddr>,
+    bind_device: Option<String>,
+    // The TLS connector and the server name for HTTPS backends.
+    tls: Option<(tokio_tls::TlsConnector, Option<String>)>,
+}
+
+impl Connect for UpstreamConnector {
+    type Transport = UpstreamStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (UpstreamStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, mut destination: Destination) -> Self::Future {
+        // Certificates of IPv6 backends are checked without the brackets.
+        let host = destination
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        // The proxy only sees a tunnel to HTTPS backends, plain HTTP requests
+        // are sent to it with absolute URIs.
+        let tunnel_to = match (&self.proxy, &self.tls) {
+            (Some(_), Some(_)) => Some(authority(&host, destination.port().unwrap_or(443))),
+            _ => None,
+        };
+        let proxied = self.proxy.is_some() && tunnel_to.is_none();
+        if let Some((ref host, port)) = self.proxy {
+            if let Err(error) = destination.set_host(host) {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    error,
+                )));
+            }
+            destination.set_port(port);
+        }
+        let mut connecting: TcpConnecting = match self.bind_device {
+            Some(ref device) => connect_on_device(device, self.source_address, &destination),
+            None => Box::new(self.http.connect(destination)),
+        };
+        if let Some(authority) = tunnel_to {
+            let authorization = self.proxy_authorization.clone();
+            connecting = Box::new(connecting.and_then(move |(stream, connected)| {
+                open_tunnel(stream, &authority, authorization)
+                    .map(move |stream| (stream, connected))
+            }));
+        }
+        let connecting: Self::Future = match self.tls {
+            Some((ref tls, ref server_name)) => {
+                let tls = tls.clone();
+                let server_name = server_name.clone().unwrap_or(host);
+                Box::new(connecting.and_then(move |(stream, connected)| {
+                    tls.connect(&server_name, stream)
+                        .map(|stream| (UpstreamStream::Tls(Box::new(stream)), connected))
+                        .map_err(|error| {
+                            io::Error::other(format!(
+                                "TLS handshake with backend failed: {}",
+                                error
+                            ))
+                        })
+                }))
+            }
+            None => Box::new(
+                connecting.map(|(stream, connected)| (UpstreamStream::Plain(stream), connected)),
+            ),
+        };
+        // Marking the connection as proxied makes hyper send absolute URIs.
+        Box::new(connecting.map(move |(stream, connected)| {
+            let connected = connected
+                .proxy(proxied)
+                .extra(UpstreamConnection::default());
+            (stream, connected)
+        }))
+    }
+}
+
+type TcpConnecting = Box<dyn Future<Item = (TcpStream, Connected), Error = io::Error> + Send>;
+
+/// Host and port for a CONNECT request, IPv6 addresses in brackets.
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// Longest response header of a proxy to a CONNECT request.
+const MAX_TUNNEL_HEADER: usize = 8192;
+
+/// Asks the outbound proxy for a tunnel to the backend with a CONNECT
+/// request. The stream can be used for the TLS handshake with the backend
+/// once the proxy answered with a 2xx status.
+fn open_tunnel(
+    stream: TcpStream,
+    authority: &str,
+    authorization: Option<HeaderValue>,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    let mut head = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority).into_bytes();
+    if let Some(authorization) = authorization {
+        head.extend_from_slice(b"Proxy-Authorization: ");
+        head.extend_from_slice(authorization.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    tokio::io::write_all(stream, head)
+        .and_then(|(stream, _)| {
+            // The backend does not send anything before the TLS handshake,
+            // so everything up to the end of the header is from the proxy.
+            future::loop_fn((stream, Vec::new()), |(stream, mut header)| {
+                tokio::io::read(stream, vec![0; 1024]).and_then(move |(stream, buffer, read)| {
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Proxy closed the connection before answering CONNECT",
+                        ));
+                    }
+                    header.extend_from_slice(&buffer[..read]);
+                    if header.windows(4).any(|window| window == b"\r\n\r\n") {
+                        Ok(Loop::Break((stream, header)))
+                    } else if header.len() > MAX_TUNNEL_HEADER {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Proxy response to CONNECT is too long",
+                        ))
+                    } else {
+                        Ok(Loop::Continue((stream, header)))
+                    }
+                })
+            })
+        })
+        .and_then(|(stream, header)| {
+            let status = header
+                .split(|byte| *byte == b' ')
+                .nth(1)
+                .unwrap_or_default();
+            if status.len() == 3 && status[0] == b'2' {
+                Ok(stream)
+            } else {
+                let line = header
+                    .split(|byte| *byte == b'\r')
+                    .next()
+                    .unwrap_or_default();
+                Err(io::Error::other(format!(
+                    "Proxy refused the tunnel: {}",
+                    String::from_utf8_lossy(line)
+                )))
+            }
+        })
+}
+
+/// A connection to upstream, encrypted for HTTPS backends.
+pub(crate) enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for UpstreamStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            UpstreamStream::Plain(stream) => stream.read(buffer),
+            UpstreamStream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            UpstreamStream::Plain(stream) => stream.write(buffer),
+            UpstreamStream::Tls(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            UpstreamStream::Plain(stream) => stream.flush(),
+            UpstreamStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {}
+
+impl AsyncWrite for UpstreamStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            UpstreamStream::Plain(stream) => AsyncWrite::shutdown(stream),
+            UpstreamStream::Tls(stream) => stream.shutdown(),
+        }
+    }
+}
+
+/// Hyper attaches this to every response of an upstream connection, so it
+/// can be told whether a request was the first on its connection.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UpstreamConnection {
+    requests: Arc<AtomicUsize>,
+}
+
+impl UpstreamConnection {
+    /// Counts a response, returns true if the connection was reused from the
+    /// pool for it.
+    pub(crate) fn count_response(&self) -> bool {
+        self.requests.fetch_add(1, Ordering::Relaxed) > 0
+    }
+}
+
+/// Opens a connection that is bound to a network device, which the hyper
+/// connector cannot do. The host name is resolved on the blocking pool of
+/// the runtime and its addresses are tried one after another.
+fn connect_on_device(
+    device: &str,
+    source_address: Option<IpAddr>,
+    destination: &Destination,
+) -> TcpConnecting {
+    let default_port = if destination.scheme() == "https" {
+        443
+    } else {
+        80
+    };
+    let port = destination.port().unwrap_or(default_port);
+    let host = destination
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let name = match host.parse::<Name>() {
+        Ok(name) => name,
+        Err(error) => {
+            return Box::new(future::err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                error,
+            )))
+        }
+    };
+    let device = device.to_string();
+    Box::new(
+        TokioThreadpoolGaiResolver::new()
+            .resolve(name)
+            .and_then(move |addresses| {
+                let addresses = addresses
+                    .map(|address| SocketAddr::new(address, port))
+                    .collect::<Vec<_>>();
+                let not_found = io::Error::new(io::ErrorKind::NotFound, "Upstream host not found");
+                // The error of the last address is passed on if none works.
+                future::loop_fn(
+                    (addresses.into_iter(), not_found),
+                    move |(mut addresses, error)| match addresses.next() {
+                        None => Either::A(future::err(error)),
+                        Some(address) => {
+                            Either::B(connect_from_device(address, &device, source_address).then(
+                                move |result| match result {
+                                    Ok(stream) => Ok(Loop::Break(stream)),
+                                    Err(error) => Ok(Loop::Continue((addresses, error))),
+                                },
+                            ))
+                        }
+                    },
+                )
+            })
+            .map(|stream| (stream, Connected::new())),
+    )
+}
+
+/// Connects to one address with a socket that is bound to a network device
+/// and optionally to a source address.
+fn connect_from_device(
+    address: SocketAddr,
+    device: &str,
+    source_address: Option<IpAddr>,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    let socket = (|| {
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        bind_device(&socket, device)?;
+        if let Some(source_address) = source_address {
+            socket.bind(&SocketAddr::new(source_address, 0).into())?;
+        }
+        Ok(socket.into())
+    })();
+    match socket {
+        Ok(stream) => Either::A(TcpStream::connect_std(stream, &address, &Handle::default())),
+        Err(error) => Either::B(future::err(error)),
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Binding to a device is only supported on Linux",
+    ))
+}
+
+impl Backend {
+    /// The scheme, host and port that requests are forwarded to, for
+    /// example "http://127.0.0.1:8080". IPv6 addresses are put in brackets.
+    pub(crate) fn origin(&self, port: u16) -> String {
+        let scheme = self.scheme();
+        if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("{}://[{}]:{}", scheme, self.host, port)
+        } else {
+            format!("{}://{}:{}", scheme, self.host, port)
+        }
+    }
+
+    /// "https" for backends with TLS, "http" otherwise.
+    pub(crate) fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Builds the HTTP client that talks to this backend.
+    pub(crate) fn client(&self) -> Result<UpstreamClient> {
+        let mut http = HttpConnector::new(4);
+        http.set_happy_eyeballs_timeout(match self.happy_eyeballs_ms {
+            0 => None,
+            milliseconds => Some(Duration::from_millis(milliseconds)),
+        });
+        http.set_local_address(self.source_address);
+        let tls = match self.tls {
+            Some(ref tls) => {
+                if tls.insecure_skip_verify {
+                    eprintln!(
+                        "WARNING: TLS certificates of backend {} are not verified, \
+                         connections to it can be intercepted",
+                        self.host
+                    );
+                }
+                // The TLS connector takes over the https URIs.
+                http.enforce_http(false);
+                let connector = tls.connector(self.http2)?;
+                Some((connector.into(), tls.server_name.clone()))
+            }
+            None => None,
+        };
+        let connector = UpstreamConnector {
+            http,
+            proxy: self.proxy.as_ref().and_then(|proxy| proxy.address()),
+            proxy_authorization: self.proxy.as_ref().and_then(|proxy| proxy.authorization()),
+            source_address: self.source_address,
+            bind_device: self.bind_device.clone(),
+            tls,
+        };
+        Ok(Client::builder()
+            .http1_title_case_headers(self.title_case_headers)
+            .keep_alive(!self.http_1_0)
+            .http2_only(self.http2)
+            .build(connector))
+    }
+
+    /// Adapts a request to the protocol version that this backend speaks.
+    pub(crate) fn prepare_request(
+        &self,
+        mut request: Request<Body>,
+    ) -> impl Future<Item = Request<Body>, Error = hyper::Error> {
+        // Requests to HTTPS backends go through a tunnel, the credentials
+        // are sent with the CONNECT request instead.
+        let authorization = self.proxy.as_ref().and_then(|proxy| proxy.authorization());
+        if let (Some(authorization), None) = (authorization, &self.tls) {
+            request
+                .headers_mut()
+                .insert(PROXY_AUTHORIZATION, authorization);
+        }
+        if self.normalize_accept_encoding {
+            request
+                .headers_mut()
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+        if self.http_1_0 {
+            *request.version_mut() = Version::HTTP_10;
+            request.headers_mut().remove(CONNECTION);
+        }
+
+        // HTTP/1.0 has no chunked encoding, so the length must be known
+        // before the body is sent.
+        let needs_length = self.http_1_0 && !request.headers().contains_key(CONTENT_LENGTH);
+        let needs_checksums = !self.request_checksums.is_empty() && has_body(request.headers());
+        if !needs_length && !needs_checksums {
+            if let Some(ref auth) = self.auth {
+                auth.authorize(&mut request, SystemTime::now());
+            }
+            return Either::B(future::ok(request));
+        }
+
+        let (mut parts, body) = request.into_parts();
+        let checksums = self.request_checksums.clone();
+        // Signatures may cover the checksums, so they come last.
+        let auth = self.auth.clone();
+        Either::A(body.concat2().map(move |chunk| {
+            if needs_length {
+                parts.headers.remove(TRANSFER_ENCODING);
+                if !chunk.is_empty() {
+                    parts
+                        .headers
+                        .insert(CONTENT_LENGTH, chunk.len().to_string().parse().unwrap());
+                }
+            }
+            if needs_checksums {
+                checksum::add_checksums(&mut parts.headers, &chunk, &checksums);
+            }
+            let mut request = Request::from_parts(parts, Body::from(chunk));
+            if let Some(auth) = auth {
+                auth.authorize(&mut request, SystemTime::now());
+            }
+            request
+        }))
+    }
+}
+
+/// Whether a request has a body, going by its headers.
+fn has_body(headers: &HeaderMap) -> bool {
+    headers.contains_key(TRANSFER_ENCODING)
+        || headers
+            .get(CONTENT_LENGTH)
+            .is_some_and(|length| length != "0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authority, Backend, BackendTls, OutboundProxy};
+
+    #[test]
+    fn origin() {
+        let backend = |host: &str| Backend {
+            host: host.to_string(),
+            ..Backend::default()
+        };
+        assert_eq!("http://127.0.0.1:8080", Backend::default().origin(8080));
+        assert_eq!("http://app.internal:80", backend("app.internal").origin(80));
+        assert_eq!("http://[::1]:80", backend("::1").origin(80));
+        assert_eq!("http://[::1]:80", backend("[::1]").origin(80));
+        let https = Backend {
+            tls: Some(BackendTls::default()),
+            ..Backend::default()
+        };
+        assert_eq!("https://127.0.0.1:443", https.origin(443));
+    }
+
+    #[test]
+    fn tunnel_authority() {
+        assert_eq!("example.com:443", authority("example.com", 443));
+        assert_eq!("[::1]:8443", authority("::1", 8443));
+    }
+
+    #[test]
+    fn proxy_address() {
+        let mut proxy = OutboundProxy {
+            url: "http://proxy.local:3128".to_string(),
+            username: None,
+            password: None,
+        };
+        assert_eq!(Some(("proxy.local".to_string(), 3128)), proxy.address());
+        proxy.url = "https://proxy.local".to_string();
+        assert_eq!(None, proxy.address());
+    }
+}
@@ -0,0 +1,166 @@
+//! Bans like in Varnish: expressions that invalidate all cached responses
+//! stored before the ban that match them. A ban costs nothing when it is
+//! added, matching responses are discarded when they are looked up next.
+//! Responses remember the last ban they were checked against, so every ban
+//! is evaluated at most once per response.
+
+use crate::split_host;
+use hyper::header::HeaderName;
+use hyper::HeaderMap;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Conditions of a ban, all of them must match.
+#[derive(Debug)]
+pub(crate) struct Ban {
+    id: u64,
+    // Regular expression for the cache key without its host, so that it
+    // applies to all hosts.
+    key: Option<Regex>,
+    // Regular expression for a header of the cached response. Any of its
+    // values may match.
+    header: Option<(HeaderName, Regex)>,
+}
+
+/// A ban as listed by the administration API.
+#[derive(Debug, Serialize)]
+pub(crate) struct BanInfo {
+    id: u64,
+    key: Option<String>,
+    header: Option<String>,
+    value: Option<String>,
+}
+
+impl Ban {
+    /// Builds a ban from regular expressions, or fails with a message for
+    /// the client.
+    pub(crate) fn new(key: Option<&str>, header: Option<(&str, &str)>) -> Result<Ban, String> {
+        if key.is_none() && header.is_none() {
+            return Err("A ban needs a key or a header condition".to_string());
+        }
+        let regex = |pattern: &str| {
+            Regex::new(pattern).map_err(|e| format!("Invalid regular expression: {}", e))
+        };
+        let key = key.map(regex).transpose()?;
+        let header = match header {
+            Some((name, value)) => Some((
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("Invalid header name {:?}", name))?,
+                regex(value)?,
+            )),
+            None => None,
+        };
+        Ok(Ban { id: 0, key, header })
+    }
+
+    fn matches(&self, cache_key: &str, headers: &HeaderMap) -> bool {
+        self.key
+            .as_ref()
+            .is_none_or(|key| key.is_match(&split_host(cache_key).0))
+            && self.header.as_ref().is_none_or(|(name, value)| {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|header| header.to_str().ok())
+                    .any(|header| value.is_match(header))
+            })
+    }
+
+    fn info(&self) -> BanInfo {
+        BanInfo {
+            id: self.id,
+            key: self.key.as_ref().map(|key| key.as_str().to_string()),
+            header: self.header.as_ref().map(|(name, _)| name.to_string()),
+            value: self
+                .header
+                .as_ref()
+                .map(|(_, value)| value.as_str().to_string()),
+        }
+    }
+}
+
+/// The bans in the order they were added.
+#[derive(Debug, Default)]
+pub(crate) struct BanList {
+    bans: VecDeque<Ban>,
+    // Id of the latest ban. Responses stored now are not affected by it.
+    latest: u64,
+}
+
+impl BanList {
+    /// The id that responses get when they are stored.
+    pub(crate) fn latest(&self) -> u64 {
+        self.latest
+    }
+
+    /// Adds a ban and returns its id.
+    pub(crate) fn add(&mut self, mut ban: Ban) -> u64 {
+        self.latest += 1;
+        ban.id = self.latest;
+        self.bans.push_back(ban);
+        self.latest
+    }
+
+    /// Removes the oldest ban, for applying it right away.
+    pub(crate) fn pop_oldest(&mut self) -> Option<Ban> {
+        self.bans.pop_front()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bans.len()
+    }
+
+    /// Checks if a response that was checked up to the ban `checked` is
+    /// banned by a later ban.
+    pub(crate) fn is_banned(&self, checked: u64, cache_key: &str, headers: &HeaderMap) -> bool {
+        self.bans
+            .iter()
+            .rev()
+            .take_while(|ban| ban.id > checked)
+            .any(|ban| ban.matches(cache_key, headers))
+    }
+
+    pub(crate) fn info(&self) -> Vec<BanInfo> {
+        self.bans.iter().map(Ban::info).collect()
+    }
+}
+
+/// Checks a response against a single ban that is applied eagerly.
+pub(crate) fn is_banned_by(ban: &Ban, checked: u64, cache_key: &str, headers: &HeaderMap) -> bool {
+    ban.id > checked && ban.matches(cache_key, headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ban, BanList};
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+
+    #[test]
+    fn bans() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-tags", HeaderValue::from_static("home"));
+        headers.append("x-tags", HeaderValue::from_static("product-12"));
+
+        let mut bans = BanList::default();
+        assert!(!bans.is_banned(0, "/products/12", &headers));
+        bans.add(Ban::new(Some("^/products/"), None).unwrap());
+        assert!(bans.is_banned(0, "/products/12", &headers));
+        assert!(!bans.is_banned(0, "/news", &headers));
+        // Responses stored after the ban are not affected.
+        assert!(!bans.is_banned(1, "/products/12", &headers));
+
+        bans.add(Ban::new(None, Some(("X-Tags", "^product-12$"))).unwrap());
+        assert!(bans.is_banned(1, "/news", &headers));
+        assert!(!bans.is_banned(1, "/news", &HeaderMap::new()));
+
+        bans.add(Ban::new(Some("^/a"), Some(("x-tags", "home"))).unwrap());
+        assert!(!bans.is_banned(2, "/b", &headers));
+        assert!(bans.is_banned(2, "/a", &headers));
+
+        assert!(Ban::new(None, None).is_err());
+        assert!(Ban::new(Some("("), None).is_err());
+        assert!(Ban::new(None, Some(("a b", "x"))).is_err());
+    }
+}
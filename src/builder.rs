@@ -0,0 +1,169 @@
+//! Typed setup of the proxy for embedders, instead of positional port
+//! arguments.
+
+use crate::errors::*;
+use crate::Config;
+use error_chain::bail;
+use futures::sync::oneshot;
+use futures::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// Collects the settings of a proxy and starts it.
+///
+/// ```no_run
+/// let server = rustnish::Builder::new()
+///     .listen(([0, 0, 0, 0], 8080).into())
+///     .upstream("app.internal", 3000)
+///     .memory_size(64 * 1024 * 1024)
+///     .start()
+///     .unwrap();
+/// server.wait().unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct Builder {
+    config: Config,
+    // The settings of the methods below, which take precedence over the
+    // ones passed to `config()`, in whatever order they are called.
+    listen: Option<SocketAddr>,
+    upstream: Option<(String, u16)>,
+    memory_size: Option<usize>,
+    workers: Option<usize>,
+    session_cookies: Option<Vec<String>>,
+    client_delivery_timeout: Option<Duration>,
+}
+
+impl Builder {
+    /// A proxy on 127.0.0.1:9090 for a backend on 127.0.0.1:80, with the
+    /// default configuration.
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Address the proxy listens on. Port 0 picks a free port, see
+    /// `ServerHandle::local_addr()`.
+    pub fn listen(mut self, address: SocketAddr) -> Builder {
+        self.listen = Some(address);
+        self
+    }
+
+    /// Host name or IP address and port of the backend.
+    pub fn upstream(mut self, host: &str, port: u16) -> Builder {
+        self.upstream = Some((host.to_string(), port));
+        self
+    }
+
+    /// Maximum memory in bytes that the cache may use for responses.
+    pub fn memory_size(mut self, memory_size: usize) -> Builder {
+        self.memory_size = Some(memory_size);
+        self
+    }
+
+    /// Number of worker threads that handle client connections, one per CPU
+    /// core by default.
+    pub fn workers(mut self, workers: usize) -> Builder {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Regular expressions for the names of session cookies, whose requests
+    /// bypass the cache. Replaces the default that matches Drupal's session
+    /// cookies.
+    pub fn session_cookies(mut self, patterns: &[&str]) -> Builder {
+        self.session_cookies = Some(patterns.iter().map(|pattern| pattern.to_string()).collect());
+        self
+    }
+
+    /// Time a client may take to receive a response before its connection
+    /// is aborted.
+    pub fn client_delivery_timeout(mut self, timeout: Duration) -> Builder {
+        self.client_delivery_timeout = Some(timeout);
+        self
+    }
+
+    /// Uses the settings of a configuration, for example one read from a
+    /// file. The settings of the other methods of the builder still apply,
+    /// also if they were called before.
+    pub fn config(mut self, config: Config) -> Builder {
+        self.config = config;
+        self
+    }
+
+    /// Validates the configuration and starts the proxy on a runtime in the
+    /// background.
+    pub fn start(self) -> Result<ServerHandle> {
+        let mut config = self.config;
+        if let Some((host, port)) = self.upstream {
+            config.backend.host = host;
+            config.backend.port = Some(port);
+        }
+        if let Some(memory_size) = self.memory_size {
+            config.memory_size = memory_size;
+        }
+        if let Some(workers) = self.workers {
+            config.workers = Some(workers);
+        }
+        if let Some(session_cookies) = self.session_cookies {
+            config.session_cookies = session_cookies;
+        }
+        if let Some(timeout) = self.client_delivery_timeout {
+            config.client_delivery_timeout_ms = Some(timeout.as_millis() as u64);
+        }
+        let address = self
+            .listen
+            .or(config.listen)
+            .unwrap_or_else(|| ([127, 0, 0, 1], 9090).into());
+        let upstream_port = config.backend.port.unwrap_or(80);
+        let (runtime, local_addr, shutdown) = crate::start_server(address, upstream_port, config)?;
+        Ok(ServerHandle {
+            runtime,
+            local_addr,
+            shutdown: Some(shutdown),
+        })
+    }
+}
+
+/// A running proxy. Dropping it stops the proxy right away, `shutdown()`
+/// followed by `await_terminated()` stops it cleanly.
+pub struct ServerHandle {
+    runtime: Runtime,
+    local_addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl ServerHandle {
+    /// The address the proxy listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Blocks the current thread while the proxy runs, which is forever
+    /// unless it fails.
+    pub fn wait(self) -> Result<()> {
+        self.runtime.shutdown_on_idle().wait().unwrap();
+        bail!("The server thread finished unexpectedly");
+    }
+
+    /// Starts a graceful shutdown: the proxy stops accepting connections,
+    /// closes idle ones and closes the others after their in-flight
+    /// requests.
+    pub fn shutdown(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            // The accept loop may be gone already after an error.
+            let _ = shutdown.send(());
+        }
+    }
+
+    /// Blocks the current thread until the proxy has stopped, after
+    /// `shutdown()`. Open tunnels, like WebSocket connections, are waited
+    /// for as well.
+    pub fn await_terminated(self) {
+        self.runtime.shutdown_on_idle().wait().unwrap();
+    }
+
+    /// Stops the proxy right away, open connections are closed.
+    pub fn shutdown_now(self) {
+        self.runtime.shutdown_now().wait().unwrap();
+    }
+}
@@ -9,27 +9,32 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-#![doc(
-    html_logo_url = "https://raw.githubusercontent.com/maidsafe/QA/master/Images/maidsafe_logo.png",
-    html_favicon_url = "https://maidsafe.net/img/favicon.ico",
-    test(attr(forbid(warnings)))
-)]
+//! A least recently used (LRU) cache that is limited by memory size instead
+//! of number of entries. Every entry additionally has an expiry time after
+//! which it is not returned anymore.
+//!
+//! This module is part of the public API of rustnish and follows semantic
+//! versioning, so it can also be used on its own.
+//!
+//! ```
+//! use rustnish::cache::LruCache;
+//! use std::time::{Duration, Instant};
+//!
+//! let mut cache = LruCache::<String, usize>::with_memory_size(1024);
+//! let _ = cache.insert("a".to_string(), 1, Instant::now() + Duration::from_secs(60));
+//! assert_eq!(Some(&1), cache.get("a"));
+//! assert!(cache.current_memory_size() <= cache.max_memory_size());
+//! ```
+
 // For explanation of lint checks, run `rustc -W help` or see
 // https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
-#![forbid(
-    bad_style,
-    exceeding_bitshifts,
-    mutable_transmutes,
-    no_mangle_const_items,
-    unknown_crate_types
-)]
+#![forbid(bad_style, mutable_transmutes, no_mangle_const_items)]
 #![deny(
     deprecated,
     improper_ctypes,
     missing_docs,
     non_shorthand_field_patterns,
     overflowing_literals,
-    plugin_as_library,
     stable_features,
     unconditional_recursion,
     unknown_lints,
@@ -50,26 +55,25 @@
     unused_results
 )]
 #![allow(
-    box_pointers,
     missing_copy_implementations,
     missing_debug_implementations,
-    variant_size_differences,
-    dead_code
+    variant_size_differences
 )]
 
-// During testing we use a mock clock to be time independent.
-#[cfg(test)]
-use fake_clock::FakeClock as Instant;
+use crate::clock::{Clock, SystemClock};
 use std::borrow::Borrow;
-use std::collections::{btree_map, BTreeMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{btree_map, BTreeMap, BTreeSet, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::mem::size_of;
-#[cfg(not(test))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Instant;
-use std::usize;
 
 /// All values that the cache can store must implement this trait.
 /// Returns the approximate memory size in bytes a cache value takes up.
 pub trait MemorySizable {
+    /// Returns the number of bytes this value occupies in memory.
     fn get_memory_size(&self) -> usize;
 }
 
@@ -85,6 +89,7 @@ impl MemorySizable for usize {
 pub struct Iter<'a, Key: 'a, Value: 'a> {
     map_iter_mut: btree_map::IterMut<'a, Key, (Value, Instant, usize)>,
     list: &'a mut VecDeque<Key>,
+    now: Instant,
 }
 
 impl<'a, Key, Value> Iterator for Iter<'a, Key, Value>
@@ -95,13 +100,13 @@ where
     type Item = (&'a Key, &'a Value);
 
     fn next(&mut self) -> Option<(&'a Key, &'a Value)> {
-        let now = Instant::now();
+        let now = self.now;
         let not_expired = self
             .map_iter_mut
             .find(|&(_, &mut (_, instant, _))| instant > now);
 
         not_expired.map(|(key, &mut (ref value, _, _))| {
-            LruCache::<Key, Value>::update_key(self.list, key);
+            update_key(self.list, key);
             (key, value)
         })
     }
@@ -110,6 +115,7 @@ where
 /// An iterator over an `LruCache`'s entries that does not modify the timestamp.
 pub struct PeekIter<'a, Key: 'a, Value: 'a> {
     map_iter: btree_map::Iter<'a, Key, (Value, Instant, usize)>,
+    now: Instant,
 }
 
 impl<'a, Key, Value> Iterator for PeekIter<'a, Key, Value>
@@ -119,15 +125,18 @@ where
     type Item = (&'a Key, &'a Value);
 
     fn next(&mut self) -> Option<(&'a Key, &'a Value)> {
-        let now = Instant::now();
+        let now = self.now;
         let not_expired = self.map_iter.find(|&(_, &(_, instant, _))| instant > now);
-        not_expired.map(|(key, &(ref value, _, _))| (key, value))
+        not_expired.map(|(key, (value, _, _))| (key, value))
     }
 }
 
-/// Implementation of [LRU cache](index.html#least-recently-used-lru-cache).
+/// A least recently used cache that evicts entries when its memory limit is reached.
+///
+/// The current time for expiring entries is taken from a `Clock`, which is the
+/// system clock by default.
 #[derive(Debug)]
-pub struct LruCache<Key, Value> {
+pub struct LruCache<Key, Value, C = SystemClock> {
     // Store the value itself, the expires date and a memory size of the value.
     // @todo make this a proper struct instead of an anonymous tuple.
     map: BTreeMap<Key, (Value, Instant, usize)>,
@@ -137,6 +146,10 @@ pub struct LruCache<Key, Value> {
     // Current memory usage, initialized with 0. Increased whenever an item is
     // inserted into the cache. Decreases when an item is removed or expires.
     current_memory_size: usize,
+    // Keys that are never evicted to make room, they are only removed when
+    // they expire.
+    pinned: BTreeSet<Key>,
+    clock: C,
 }
 
 impl<Key, Value> LruCache<Key, Value>
@@ -146,21 +159,42 @@ where
 {
     /// Constructor for a mmemory constrained cache.
     pub fn with_memory_size(memory_size: usize) -> LruCache<Key, Value> {
+        LruCache::with_memory_size_and_clock(memory_size, SystemClock)
+    }
+}
+
+impl<Key, Value, C> LruCache<Key, Value, C>
+where
+    Key: Ord + Clone,
+    Value: MemorySizable,
+    C: Clock,
+{
+    /// Constructor for a memory constrained cache that uses the given clock to
+    /// expire entries.
+    pub fn with_memory_size_and_clock(memory_size: usize, clock: C) -> LruCache<Key, Value, C> {
         LruCache {
             map: BTreeMap::new(),
             list: VecDeque::new(),
             max_memory_size: memory_size,
             current_memory_size: 0,
+            pinned: BTreeSet::new(),
+            clock,
         }
     }
 
+    /// Returns the clock that is used to expire entries.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
     /// Inserts a key-value pair into the cache.
     ///
     /// If the key already existed in the cache, the existing value is returned and overwritten in
-    /// the cache.  Otherwise, the key-value pair is inserted and `None` is returned.
+    /// the cache.  Otherwise, the key-value pair is inserted and `None` is returned. A pinned key
+    /// stays pinned. If other pinned entries take up the memory that would be needed, or a pinned
+    /// value grew larger than the cache, the value is not inserted and an existing value is kept.
     pub fn insert(&mut self, key: Key, value: Value, expires: Instant) -> Option<Value> {
         self.remove_expired();
-        let old_value = self.remove(&key);
 
         // @todo should we also add some bytes for the key size? Oh noes, we
         // also own the key in self.list so the key will also have to implement
@@ -173,12 +207,35 @@ where
             // Size of the memory count.
             + size_of::<usize>();
 
+        // Pinned entries are never evicted, so check that there is room next
+        // to them before anything is removed.
+        let pinned_size: usize = self
+            .pinned
+            .iter()
+            .filter(|pinned| **pinned != key)
+            .filter_map(|pinned| self.map.get(pinned))
+            .map(|(_, _, size)| size)
+            .sum();
+        let was_pinned = self.pinned.contains(&key);
+        if (memory_size <= self.max_memory_size || was_pinned)
+            && pinned_size + memory_size > self.max_memory_size
+        {
+            return None;
+        }
+        let old_value = self.remove(&key);
+
         if memory_size <= self.max_memory_size {
             // Remove old cache entries until we have room to insert the new item.
             while self.max_memory_size < self.current_memory_size + memory_size {
+                let pinned = &self.pinned;
+                // The check above leaves room next to pinned entries.
+                let position = match self.list.iter().position(|key| !pinned.contains(key)) {
+                    Some(position) => position,
+                    None => return old_value,
+                };
                 let remove_key = self
                     .list
-                    .pop_front()
+                    .remove(position)
                     .expect("Queue is empty but current memory size > 0");
                 let (_, _, removed_size) = self
                     .map
@@ -189,18 +246,69 @@ where
             self.list.push_back(key.clone());
 
             self.current_memory_size += memory_size;
+            if was_pinned {
+                let _ = self.pinned.insert(key.clone());
+            }
             let _ = self.map.insert(key, (value, expires, memory_size));
         }
         old_value
     }
 
+    /// Changes when the entry for `key` expires, to extend or shorten its
+    /// lifetime without replacing the value. Returns `false` if there is no
+    /// such entry or it already expired.
+    pub fn set_expiry<Q>(&mut self, key: &Q, expires: Instant) -> bool
+    where
+        Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let now = self.clock.now();
+        match self.map.get_mut(key) {
+            Some((_, current, _)) if *current >= now => {
+                *current = expires;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Exempts the entry for `key` from eviction when room is needed, but not
+    /// from expiry. Returns `false` if there is no such entry.
+    pub fn pin(&mut self, key: &Key) -> bool {
+        if !self.map.contains_key(key) {
+            return false;
+        }
+        let _ = self.pinned.insert(key.clone());
+        true
+    }
+
+    /// Makes the entry for `key` evictable again. Returns `false` if it was
+    /// not pinned.
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
+    where
+        Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.pinned.remove(key)
+    }
+
+    /// Returns whether the entry for `key` is pinned.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.pinned.contains(key)
+    }
+
     /// Removes a key-value pair from the cache.
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<Value>
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<Value>
     where
         Key: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         self.map.remove(key).map(|(value, _, memory_size)| {
+            let _ = self.pinned.remove(key);
             let _ = self
                 .list
                 .iter()
@@ -215,54 +323,85 @@ where
     pub fn clear(&mut self) {
         self.map.clear();
         self.list.clear();
+        self.pinned.clear();
         self.current_memory_size = 0;
     }
 
     /// Retrieves a reference to the value stored under `key`, or `None` if the key doesn't exist.
     /// Also removes expired elements and updates the time.
-    pub fn get<Q: ?Sized>(&mut self, key: &Q) -> Option<&Value>
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&Value>
     where
         Key: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         self.remove_expired();
 
         let list = &mut self.list;
         self.map.get_mut(key).map(|result| {
-            Self::update_key(list, key);
+            update_key(list, key);
             &result.0
         })
     }
 
+    /// Retrieves a mutable reference to the value stored under `key`, or `None` if the key doesn't
+    /// exist. Also removes expired elements and updates the time.
+    ///
+    /// The memory size of the value is not re-calculated, so changes to the value should not
+    /// change its memory size.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut Value>
+    where
+        Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_expired();
+
+        let list = &mut self.list;
+        self.map.get_mut(key).map(|result| {
+            update_key(list, key);
+            &mut result.0
+        })
+    }
+
     /// Returns a reference to the value with the given `key`, if present and not expired, without
     /// updating the timestamp.
-    pub fn peek<Q: ?Sized>(&self, key: &Q) -> Option<&Value>
+    pub fn peek<Q>(&self, key: &Q) -> Option<&Value>
     where
         Key: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         self.map
             .get(key)
             .into_iter()
-            .find(|&(_, t, _)| *t >= Instant::now())
-            .map(|&(ref value, _, _)| value)
+            .find(|&(_, t, _)| *t >= self.clock.now())
+            .map(|(value, _, _)| value)
+    }
+
+    /// Same as `peek()`, but also returns the expiry time of the value.
+    pub fn peek_with_expiry<Q>(&self, key: &Q) -> Option<(&Value, Instant)>
+    where
+        Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.map
+            .get(key)
+            .into_iter()
+            .find(|&(_, t, _)| *t >= self.clock.now())
+            .map(|(value, expires, _)| (value, *expires))
     }
 
     /// Returns whether `key` exists in the cache or not.
-    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
     where
         Key: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         self.peek(key).is_some()
     }
 
     /// Returns the size of the cache, i.e. the number of cached non-expired key-value pairs.
     pub fn len(&self) -> usize {
-        self.map
-            .iter()
-            .filter(|&(_, (_, t, _))| *t >= Instant::now())
-            .count()
+        let now = self.clock.now();
+        self.map.iter().filter(|&(_, (_, t, _))| *t >= now).count()
     }
 
     /// Returns `true` if there are no non-expired entries in the cache.
@@ -270,42 +409,45 @@ where
         self.len() == 0
     }
 
+    /// Returns the maximum memory size in bytes the cache may use.
+    pub fn max_memory_size(&self) -> usize {
+        self.max_memory_size
+    }
+
+    /// Returns the memory size in bytes that is currently used by cache entries, including
+    /// entries that are expired but not yet removed.
+    pub fn current_memory_size(&self) -> usize {
+        self.current_memory_size
+    }
+
     /// Returns an iterator over all entries that updates the timestamps as values are
     /// traversed. Also removes expired elements before creating the iterator.
-    pub fn iter(&mut self) -> Iter<Key, Value> {
+    pub fn iter(&mut self) -> Iter<'_, Key, Value> {
         self.remove_expired();
 
         Iter {
             map_iter_mut: self.map.iter_mut(),
             list: &mut self.list,
+            now: self.clock.now(),
         }
     }
 
     /// Returns an iterator over all entries that does not modify the timestamps.
-    pub fn peek_iter(&self) -> PeekIter<Key, Value> {
+    pub fn peek_iter(&self) -> PeekIter<'_, Key, Value> {
         PeekIter {
             map_iter: self.map.iter(),
-        }
-    }
-
-    // Move `key` in the ordered list to the last
-    fn update_key<Q: ?Sized>(list: &mut VecDeque<Key>, key: &Q)
-    where
-        Key: Borrow<Q>,
-        Q: Ord,
-    {
-        if let Some(pos) = list.iter().position(|k| k.borrow() == key) {
-            let _ = list.remove(pos).map(|it| list.push_back(it));
+            now: self.clock.now(),
         }
     }
 
     fn remove_expired(&mut self) {
         // Because of the borrow checker we need to clone the keys to be removed
         // while accessing the map. Any better ideas how to simplify this?
+        let now = self.clock.now();
         let remove_entries = self
             .map
             .iter()
-            .filter(|(_, (_, t, _))| *t < Instant::now())
+            .filter(|(_, (_, t, _))| *t < now)
             .map(|(key, _)| key.clone())
             .collect::<Vec<_>>();
         for key in remove_entries {
@@ -314,31 +456,255 @@ where
     }
 }
 
-impl<Key, Value> Clone for LruCache<Key, Value>
+// Move `key` in the ordered list to the last
+fn update_key<Key, Q>(list: &mut VecDeque<Key>, key: &Q)
+where
+    Key: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    if let Some(pos) = list.iter().position(|k| k.borrow() == key) {
+        let _ = list.remove(pos).map(|it| list.push_back(it));
+    }
+}
+
+impl<Key, Value, C> Clone for LruCache<Key, Value, C>
 where
     Key: Clone,
     Value: Clone,
+    C: Clone,
 {
-    fn clone(&self) -> LruCache<Key, Value> {
+    fn clone(&self) -> LruCache<Key, Value, C> {
         LruCache {
             map: self.map.clone(),
             list: self.list.clone(),
             max_memory_size: self.max_memory_size,
             current_memory_size: self.current_memory_size,
+            pinned: self.pinned.clone(),
+            clock: self.clock.clone(),
         }
     }
 }
 
+/// A thread-safe variant of `LruCache` for concurrent use.
+///
+/// Entries are distributed over a number of shards by the hash of their key.
+/// Every shard is an `LruCache` behind its own lock, so threads working on
+/// different shards do not block each other. The memory limit is split evenly
+/// between the shards, which means that the largest value that can be stored
+/// is `memory_size / shards`.
+#[derive(Debug)]
+pub struct ShardedLruCache<Key, Value, C = SystemClock> {
+    shards: Vec<Mutex<LruCache<Key, Value, C>>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<Key, Value> ShardedLruCache<Key, Value>
+where
+    Key: Ord + Clone + Hash,
+    Value: MemorySizable,
+{
+    /// Constructor for a memory constrained cache with the given number of
+    /// shards.
+    pub fn with_memory_size(shards: usize, memory_size: usize) -> ShardedLruCache<Key, Value> {
+        ShardedLruCache::with_memory_size_and_clock(shards, memory_size, SystemClock)
+    }
+}
+
+impl<Key, Value, C> ShardedLruCache<Key, Value, C>
+where
+    Key: Ord + Clone + Hash,
+    Value: MemorySizable,
+    C: Clock + Clone,
+{
+    /// Constructor for a memory constrained cache with the given number of
+    /// shards that uses the given clock to expire entries.
+    pub fn with_memory_size_and_clock(
+        shards: usize,
+        memory_size: usize,
+        clock: C,
+    ) -> ShardedLruCache<Key, Value, C> {
+        let shards = shards.max(1);
+        ShardedLruCache {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(LruCache::with_memory_size_and_clock(
+                        memory_size / shards,
+                        clock.clone(),
+                    ))
+                })
+                .collect(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Inserts a key-value pair into the cache, see `LruCache::insert()`.
+    pub fn insert(&self, key: Key, value: Value, expires: Instant) -> Option<Value> {
+        self.shard(&key).lock().unwrap().insert(key, value, expires)
+    }
+
+    /// Removes a key-value pair from the cache.
+    pub fn remove(&self, key: &Key) -> Option<Value> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    /// Looks up the value stored under `key` and passes it to `f` while the
+    /// shard is locked. Marks the entry as recently used and counts a hit or a
+    /// miss.
+    pub fn get_with<F, R>(&self, key: &Key, f: F) -> Option<R>
+    where
+        F: FnOnce(&Value) -> R,
+    {
+        let mut shard = self.shard(key).lock().unwrap();
+        match shard.get(key) {
+            Some(value) => {
+                let _ = self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(f(value))
+            }
+            None => {
+                let _ = self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Same as `get_with()`, but `f` may modify the value.
+    pub fn get_mut_with<F, R>(&self, key: &Key, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Value) -> R,
+    {
+        let mut shard = self.shard(key).lock().unwrap();
+        match shard.get_mut(key) {
+            Some(value) => {
+                let _ = self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(f(value))
+            }
+            None => {
+                let _ = self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Same as `get_with()`, but does not update the LRU order or the hit and
+    /// miss counters.
+    pub fn peek_with<F, R>(&self, key: &Key, f: F) -> Option<R>
+    where
+        F: FnOnce(&Value) -> R,
+    {
+        self.shard(key).lock().unwrap().peek(key).map(f)
+    }
+
+    /// Same as `peek_with()`, but also passes the expiry time of the value to
+    /// `f`.
+    pub fn peek_with_expiry<F, R>(&self, key: &Key, f: F) -> Option<R>
+    where
+        F: FnOnce(&Value, Instant) -> R,
+    {
+        self.shard(key)
+            .lock()
+            .unwrap()
+            .peek_with_expiry(key)
+            .map(|(value, expires)| f(value, expires))
+    }
+
+    /// Changes the expiry of an entry, see `LruCache::set_expiry()`.
+    pub fn set_expiry(&self, key: &Key, expires: Instant) -> bool {
+        self.shard(key).lock().unwrap().set_expiry(key, expires)
+    }
+
+    /// Pins the entry for `key`, see `LruCache::pin()`.
+    pub fn pin(&self, key: &Key) -> bool {
+        self.shard(key).lock().unwrap().pin(key)
+    }
+
+    /// Unpins the entry for `key`, see `LruCache::unpin()`.
+    pub fn unpin(&self, key: &Key) -> bool {
+        self.shard(key).lock().unwrap().unpin(key)
+    }
+
+    /// Returns whether the entry for `key` is pinned.
+    pub fn is_pinned(&self, key: &Key) -> bool {
+        self.shard(key).lock().unwrap().is_pinned(key)
+    }
+
+    /// Passes all non-expired entries to `f`, one shard after the other.
+    /// Does not update the LRU order.
+    pub fn peek_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&Key, &Value),
+    {
+        for shard in &self.shards {
+            for (key, value) in shard.lock().unwrap().peek_iter() {
+                f(key, value);
+            }
+        }
+    }
+
+    /// Clears all shards.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    /// Returns the number of non-expired entries in all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    /// Returns `true` if there are no non-expired entries in the cache.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the maximum memory size in bytes of all shards together.
+    pub fn max_memory_size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().max_memory_size())
+            .sum()
+    }
+
+    /// Returns the memory size in bytes that is currently used in all shards.
+    pub fn current_memory_size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().current_memory_size())
+            .sum()
+    }
+
+    /// Returns how many lookups with `get_with()` found an entry.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many lookups with `get_with()` did not find an entry.
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn shard(&self, key: &Key) -> &Mutex<LruCache<Key, Value, C>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use fake_clock::FakeClock as Instant;
+    use super::MemorySizable;
+    use crate::clock::{Clock, ManualClock};
     use std::mem::size_of;
-    use std::time::Duration;
-
-    fn sleep(time: u64) {
-        use fake_clock::FakeClock;
-        FakeClock::advance_time(time);
-    }
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     fn generate_random_vec<T>(len: usize) -> Vec<T>
     where
@@ -351,20 +717,129 @@ mod test {
         vec
     }
 
+    #[test]
+    fn set_expiry() {
+        let clock = ManualClock::new();
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(1024, clock.clone());
+        let _ = lru_cache.insert(1, 1, clock.now() + Duration::from_secs(10));
+        assert!(!lru_cache.set_expiry(&2, clock.now()));
+
+        assert!(lru_cache.set_expiry(&1, clock.now() + Duration::from_secs(100)));
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(Some(&1), lru_cache.peek(&1));
+        assert_eq!(
+            Some(clock.now() + Duration::from_secs(50)),
+            lru_cache.peek_with_expiry(&1).map(|(_, expires)| expires)
+        );
+
+        // Shortening works as well.
+        assert!(lru_cache.set_expiry(&1, clock.now() + Duration::from_secs(1)));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(None, lru_cache.peek(&1));
+        assert!(!lru_cache.set_expiry(&1, clock.now() + Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn pinned() {
+        let clock = ManualClock::new();
+        let size = 3 * (size_of::<usize>() * 2 + size_of::<Instant>());
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(size, clock.clone());
+        let expires = clock.now() + Duration::from_secs(100);
+        assert!(!lru_cache.pin(&0));
+
+        for i in 0..3 {
+            let _ = lru_cache.insert(i, i, expires);
+        }
+        assert!(lru_cache.pin(&0));
+        // The oldest entry is pinned, so the next one is evicted.
+        let _ = lru_cache.insert(3, 3, expires);
+        assert!(lru_cache.contains_key(&0));
+        assert!(!lru_cache.contains_key(&1));
+
+        // Replacing a pinned entry keeps the pin.
+        let _ = lru_cache.insert(0, 10, expires);
+        assert!(lru_cache.is_pinned(&0));
+
+        // Nothing is evicted if everything is pinned.
+        assert!(lru_cache.pin(&2));
+        assert!(lru_cache.pin(&3));
+        let _ = lru_cache.insert(4, 4, expires);
+        assert!(!lru_cache.contains_key(&4));
+        assert_eq!(lru_cache.len(), 3);
+
+        assert!(lru_cache.unpin(&2));
+        let _ = lru_cache.insert(4, 4, expires);
+        assert!(lru_cache.contains_key(&4));
+        assert!(!lru_cache.contains_key(&2));
+
+        // Pinned entries still expire.
+        clock.advance(Duration::from_secs(200));
+        let _ = lru_cache.insert(5, 5, clock.now() + Duration::from_secs(100));
+        assert!(!lru_cache.contains_key(&0));
+        assert!(!lru_cache.is_pinned(&0));
+    }
+
+    /// A value that takes up the given number of bytes.
+    #[derive(Debug, PartialEq)]
+    struct Bytes(usize);
+
+    impl MemorySizable for Bytes {
+        fn get_memory_size(&self) -> usize {
+            self.0
+        }
+    }
+
+    #[test]
+    fn pinned_reinsert() {
+        let clock = ManualClock::new();
+        let overhead = size_of::<usize>() + size_of::<Instant>();
+        let mut lru_cache = super::LruCache::<usize, Bytes, _>::with_memory_size_and_clock(
+            3 * (8 + overhead),
+            clock.clone(),
+        );
+        let expires = clock.now() + Duration::from_secs(100);
+        for i in 0..3 {
+            let _ = lru_cache.insert(i, Bytes(8), expires);
+            assert!(lru_cache.pin(&i));
+        }
+
+        // A pinned entry that grew does not fit next to the other pinned
+        // ones, so the old value stays.
+        assert_eq!(None, lru_cache.insert(0, Bytes(16), expires));
+        assert_eq!(Some(&Bytes(8)), lru_cache.peek(&0));
+        assert!(lru_cache.is_pinned(&0));
+        assert_eq!(lru_cache.len(), 3);
+        // Also if it grew larger than the whole cache.
+        assert_eq!(None, lru_cache.insert(0, Bytes(1000), expires));
+        assert_eq!(Some(&Bytes(8)), lru_cache.peek(&0));
+
+        // It fits once an unpinned entry is evicted.
+        assert!(lru_cache.unpin(&2));
+        assert_eq!(Some(Bytes(8)), lru_cache.insert(0, Bytes(16), expires));
+        assert_eq!(Some(&Bytes(16)), lru_cache.peek(&0));
+        assert!(lru_cache.is_pinned(&0));
+        assert!(lru_cache.contains_key(&1));
+        assert!(!lru_cache.contains_key(&2));
+    }
+
     #[test]
     fn memory_size() {
+        let clock = ManualClock::new();
         // 1x usize value, 1x usize memory size.
         let size = 10 * (size_of::<usize>() * 2 + size_of::<Instant>());
-        let mut lru_cache = super::LruCache::<usize, usize>::with_memory_size(size);
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(size, clock.clone());
 
         for i in 0..10 {
             assert_eq!(lru_cache.len(), i);
-            let _ = lru_cache.insert(i, i, Instant::now() + Duration::from_secs(1000));
+            let _ = lru_cache.insert(i, i, clock.now() + Duration::from_secs(1000));
             assert_eq!(lru_cache.len(), i + 1);
         }
 
         for i in 10..1000 {
-            let _ = lru_cache.insert(i, i, Instant::now() + Duration::from_secs(1000));
+            let _ = lru_cache.insert(i, i, clock.now() + Duration::from_secs(1000));
             assert_eq!(lru_cache.current_memory_size, size);
         }
 
@@ -377,46 +852,52 @@ mod test {
 
     #[test]
     fn expiration_time() {
+        let clock = ManualClock::new();
         let time_to_live = Duration::from_millis(100);
-        let mut lru_cache = super::LruCache::<usize, usize>::with_memory_size(10000);
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(10000, clock.clone());
 
         for i in 0..10 {
             assert_eq!(lru_cache.len(), i);
-            let _ = lru_cache.insert(i, i, Instant::now() + time_to_live);
+            let _ = lru_cache.insert(i, i, clock.now() + time_to_live);
             assert_eq!(lru_cache.len(), i + 1);
         }
 
-        sleep(101);
-        let _ = lru_cache.insert(11, 11, Instant::now() + time_to_live);
+        clock.advance(Duration::from_millis(101));
+        let _ = lru_cache.insert(11, 11, clock.now() + time_to_live);
 
         assert_eq!(lru_cache.len(), 1);
 
         for i in 0..10 {
             assert!(!lru_cache.is_empty());
             assert_eq!(lru_cache.len(), i + 1);
-            let _ = lru_cache.insert(i, i, Instant::now() + time_to_live);
+            let _ = lru_cache.insert(i, i, clock.now() + time_to_live);
             assert_eq!(lru_cache.len(), i + 2);
         }
 
-        sleep(101);
+        clock.advance(Duration::from_millis(101));
         assert_eq!(0, lru_cache.len());
         assert!(lru_cache.is_empty());
     }
 
     #[test]
     fn time_and_size() {
+        let clock = ManualClock::new();
         let size = 10;
         // 1x usize value, 1x usize memory size.
         let memory_size = 10 * (size_of::<usize>() * 2 + size_of::<Instant>());
         let time_to_live = Duration::from_millis(100);
-        let mut lru_cache = super::LruCache::<usize, usize>::with_memory_size(memory_size);
+        let mut lru_cache = super::LruCache::<usize, usize, _>::with_memory_size_and_clock(
+            memory_size,
+            clock.clone(),
+        );
 
         for i in 0..1000 {
             if i < size {
                 assert_eq!(lru_cache.len(), i);
             }
 
-            let _ = lru_cache.insert(i, i, Instant::now() + time_to_live);
+            let _ = lru_cache.insert(i, i, clock.now() + time_to_live);
 
             if i < size {
                 assert_eq!(lru_cache.len(), i + 1);
@@ -425,8 +906,8 @@ mod test {
             }
         }
 
-        sleep(101);
-        let _ = lru_cache.insert(1, 1, Instant::now() + time_to_live);
+        clock.advance(Duration::from_millis(101));
+        let _ = lru_cache.insert(1, 1, clock.now() + time_to_live);
 
         assert_eq!(lru_cache.len(), 1);
     }
@@ -438,12 +919,16 @@ mod test {
 
     #[test]
     fn time_size_struct_value() {
+        let clock = ManualClock::new();
         let size = 100usize;
         // 1x usize value, 1x usize memory size.
         let memory_size = 100 * (size_of::<usize>() * 2 + size_of::<Instant>());
         let time_to_live = Duration::from_millis(100);
 
-        let mut lru_cache = super::LruCache::<Temp, usize>::with_memory_size(memory_size);
+        let mut lru_cache = super::LruCache::<Temp, usize, _>::with_memory_size_and_clock(
+            memory_size,
+            clock.clone(),
+        );
 
         for i in 0..1000 {
             if i < size {
@@ -455,7 +940,7 @@ mod test {
                     id: generate_random_vec::<u8>(64),
                 },
                 i,
-                Instant::now() + time_to_live,
+                clock.now() + time_to_live,
             );
 
             if i < size {
@@ -465,13 +950,13 @@ mod test {
             }
         }
 
-        sleep(101);
+        clock.advance(Duration::from_millis(101));
         let _ = lru_cache.insert(
             Temp {
                 id: generate_random_vec::<u8>(64),
             },
             1,
-            Instant::now() + time_to_live,
+            clock.now() + time_to_live,
         );
 
         assert_eq!(lru_cache.len(), 1);
@@ -479,59 +964,252 @@ mod test {
 
     #[test]
     fn peek_iter() {
+        let clock = ManualClock::new();
         let time_to_live = Duration::from_millis(100);
-        let mut lru_cache = super::LruCache::<usize, usize>::with_memory_size(10000);
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(10000, clock.clone());
 
-        let _ = lru_cache.insert(0, 0, Instant::now() + time_to_live);
-        let _ = lru_cache.insert(2, 2, Instant::now() + time_to_live);
-        let _ = lru_cache.insert(3, 3, Instant::now() + time_to_live);
+        let _ = lru_cache.insert(0, 0, clock.now() + time_to_live);
+        let _ = lru_cache.insert(2, 2, clock.now() + time_to_live);
+        let _ = lru_cache.insert(3, 3, clock.now() + time_to_live);
 
-        sleep(50);
+        clock.advance(Duration::from_millis(50));
         assert_eq!(
             vec![(&0, &0), (&2, &2), (&3, &3)],
             lru_cache.peek_iter().collect::<Vec<_>>()
         );
         assert_eq!(Some(&2), lru_cache.get(&2));
-        let _ = lru_cache.insert(1, 1, Instant::now() + time_to_live);
-        let _ = lru_cache.insert(4, 4, Instant::now() + time_to_live);
+        let _ = lru_cache.insert(1, 1, clock.now() + time_to_live);
+        let _ = lru_cache.insert(4, 4, clock.now() + time_to_live);
 
-        sleep(51);
+        clock.advance(Duration::from_millis(51));
         assert_eq!(
             vec![(&1, &1), (&4, &4)],
             lru_cache.peek_iter().collect::<Vec<_>>()
         );
 
-        sleep(50);
+        clock.advance(Duration::from_millis(50));
         assert!(lru_cache.is_empty());
     }
 
     #[test]
     fn peek_time_check() {
+        let clock = ManualClock::new();
         let time_to_live = Duration::from_millis(100);
-        let mut lru_cache = super::LruCache::<usize, usize>::with_memory_size(10000);
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(10000, clock.clone());
 
         assert_eq!(lru_cache.len(), 0);
-        let _ = lru_cache.insert(0, 0, Instant::now() + time_to_live);
+        let _ = lru_cache.insert(0, 0, clock.now() + time_to_live);
         assert_eq!(lru_cache.len(), 1);
 
-        sleep(50);
+        clock.advance(Duration::from_millis(50));
         assert_eq!(Some(&0), lru_cache.get(&0));
         assert_eq!(Some(&0), lru_cache.peek(&0));
-        sleep(51);
+        let expires = clock.now() + Duration::from_millis(50);
+        assert_eq!(Some((&0, expires)), lru_cache.peek_with_expiry(&0));
+        clock.advance(Duration::from_millis(51));
         assert_eq!(None, lru_cache.peek(&0));
+        assert_eq!(None, lru_cache.peek_with_expiry(&0));
     }
 
     #[test]
     fn deref_coercions() {
-        let mut lru_cache = super::LruCache::<String, usize>::with_memory_size(100);
+        let clock = ManualClock::new();
+        let mut lru_cache =
+            super::LruCache::<String, usize, _>::with_memory_size_and_clock(100, clock.clone());
         let _ = lru_cache.insert(
             "foo".to_string(),
             0,
-            Instant::now() + Duration::from_secs(1000),
+            clock.now() + Duration::from_secs(1000),
         );
-        assert_eq!(true, lru_cache.contains_key("foo"));
+        assert!(lru_cache.contains_key("foo"));
         assert_eq!(Some(&0), lru_cache.get("foo"));
         assert_eq!(Some(&0), lru_cache.peek("foo"));
         assert_eq!(Some(0), lru_cache.remove("foo"));
     }
+
+    #[test]
+    fn lru_eviction_order() {
+        let clock = ManualClock::new();
+        let memory_size = 3 * (size_of::<usize>() * 2 + size_of::<Instant>());
+        let mut lru_cache = super::LruCache::<usize, usize, _>::with_memory_size_and_clock(
+            memory_size,
+            clock.clone(),
+        );
+        let expires = clock.now() + Duration::from_secs(1000);
+
+        let _ = lru_cache.insert(0, 0, expires);
+        let _ = lru_cache.insert(1, 1, expires);
+        let _ = lru_cache.insert(2, 2, expires);
+        // Accessing 0 makes 1 the least recently used entry.
+        assert_eq!(Some(&0), lru_cache.get(&0));
+        let _ = lru_cache.insert(3, 3, expires);
+
+        assert!(lru_cache.contains_key(&0));
+        assert!(!lru_cache.contains_key(&1));
+        assert!(lru_cache.contains_key(&2));
+        assert!(lru_cache.contains_key(&3));
+    }
+
+    #[test]
+    fn get_mut() {
+        let clock = ManualClock::new();
+        let memory_size = 2 * (size_of::<usize>() * 2 + size_of::<Instant>());
+        let mut lru_cache = super::LruCache::<usize, usize, _>::with_memory_size_and_clock(
+            memory_size,
+            clock.clone(),
+        );
+        let expires = clock.now() + Duration::from_secs(1000);
+
+        let _ = lru_cache.insert(0, 0, expires);
+        let _ = lru_cache.insert(1, 1, expires);
+        *lru_cache.get_mut(&0).unwrap() = 10;
+        assert_eq!(None, lru_cache.get_mut(&5));
+
+        // 0 was used most recently, so 1 gets evicted.
+        let _ = lru_cache.insert(2, 2, expires);
+        assert_eq!(Some(&10), lru_cache.peek(&0));
+        assert_eq!(None, lru_cache.peek(&1));
+    }
+
+    #[test]
+    fn iter() {
+        let clock = ManualClock::new();
+        let memory_size = 3 * (size_of::<usize>() * 2 + size_of::<Instant>());
+        let mut lru_cache = super::LruCache::<usize, usize, _>::with_memory_size_and_clock(
+            memory_size,
+            clock.clone(),
+        );
+        let expires = clock.now() + Duration::from_secs(1000);
+
+        let _ = lru_cache.insert(0, 0, expires);
+        let _ = lru_cache.insert(1, 1, expires);
+        let _ = lru_cache.insert(2, 2, expires);
+        // Iterating over the first entry marks it as recently used.
+        assert_eq!(Some((&0, &0)), lru_cache.iter().next());
+
+        let _ = lru_cache.insert(3, 3, expires);
+        assert_eq!(
+            vec![(&0, &0), (&2, &2), (&3, &3)],
+            lru_cache.peek_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn remove_and_clear() {
+        let clock = ManualClock::new();
+        let entry_size = size_of::<usize>() * 2 + size_of::<Instant>();
+        let mut lru_cache = super::LruCache::<usize, usize, _>::with_memory_size_and_clock(
+            10 * entry_size,
+            clock.clone(),
+        );
+        let expires = clock.now() + Duration::from_secs(1000);
+        assert_eq!(10 * entry_size, lru_cache.max_memory_size());
+
+        let _ = lru_cache.insert(0, 0, expires);
+        let _ = lru_cache.insert(1, 1, expires);
+        assert_eq!(2 * entry_size, lru_cache.current_memory_size());
+
+        assert_eq!(Some(0), lru_cache.remove(&0));
+        assert_eq!(None, lru_cache.remove(&0));
+        assert_eq!(entry_size, lru_cache.current_memory_size());
+
+        lru_cache.clear();
+        assert!(lru_cache.is_empty());
+        assert_eq!(0, lru_cache.current_memory_size());
+    }
+
+    #[test]
+    fn insert_existing_key() {
+        let clock = ManualClock::new();
+        let entry_size = size_of::<usize>() * 2 + size_of::<Instant>();
+        let mut lru_cache = super::LruCache::<usize, usize, _>::with_memory_size_and_clock(
+            10 * entry_size,
+            clock.clone(),
+        );
+        let expires = clock.now() + Duration::from_secs(1000);
+
+        assert_eq!(None, lru_cache.insert(0, 0, expires));
+        assert_eq!(Some(0), lru_cache.insert(0, 1, expires));
+        assert_eq!(Some(&1), lru_cache.get(&0));
+        assert_eq!(entry_size, lru_cache.current_memory_size());
+    }
+
+    #[test]
+    fn value_too_big() {
+        let clock = ManualClock::new();
+        let mut lru_cache =
+            super::LruCache::<usize, usize, _>::with_memory_size_and_clock(1, clock.clone());
+        let _ = lru_cache.insert(0, 0, clock.now() + Duration::from_secs(1000));
+        assert!(lru_cache.is_empty());
+        assert_eq!(0, lru_cache.current_memory_size());
+    }
+
+    #[test]
+    fn sharded_memory_split() {
+        let clock = ManualClock::new();
+        let entry_size = size_of::<usize>() * 2 + size_of::<Instant>();
+        let lru_cache = super::ShardedLruCache::<usize, usize, _>::with_memory_size_and_clock(
+            4,
+            40 * entry_size,
+            clock.clone(),
+        );
+        assert_eq!(4, lru_cache.shard_count());
+        assert_eq!(40 * entry_size, lru_cache.max_memory_size());
+
+        for i in 0..1000 {
+            let _ = lru_cache.insert(i, i, clock.now() + Duration::from_secs(1000));
+        }
+        // Every shard is full, but none of them holds more than its share.
+        assert_eq!(40, lru_cache.len());
+        assert_eq!(40 * entry_size, lru_cache.current_memory_size());
+
+        clock.advance(Duration::from_secs(1001));
+        assert!(lru_cache.is_empty());
+    }
+
+    #[test]
+    fn sharded_hits_and_misses() {
+        let clock = ManualClock::new();
+        let lru_cache = super::ShardedLruCache::<usize, usize, _>::with_memory_size_and_clock(
+            4,
+            10000,
+            clock.clone(),
+        );
+        let _ = lru_cache.insert(1, 1, clock.now() + Duration::from_secs(1000));
+
+        assert_eq!(Some(2), lru_cache.get_with(&1, |value| value * 2));
+        assert_eq!(None, lru_cache.get_with(&2, |value| value * 2));
+        assert_eq!(Some(1), lru_cache.peek_with(&1, |value| *value));
+        assert_eq!(1, lru_cache.hits());
+        assert_eq!(1, lru_cache.misses());
+
+        assert_eq!(Some(1), lru_cache.remove(&1));
+        assert!(lru_cache.is_empty());
+    }
+
+    #[test]
+    fn sharded_concurrent_inserts() {
+        let lru_cache = Arc::new(super::ShardedLruCache::<usize, usize>::with_memory_size(
+            8, 1_000_000,
+        ));
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let lru_cache = lru_cache.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = thread * 100 + i;
+                        let _ =
+                            lru_cache.insert(key, key, Instant::now() + Duration::from_secs(1000));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(400, lru_cache.len());
+        assert_eq!(Some(399), lru_cache.get_with(&399, |value| *value));
+    }
 }
@@ -0,0 +1,95 @@
+//! Fault injection for testing how clients cope with a misbehaving upstream,
+//! through the real proxy path. Only active if configured.
+
+use crate::{bad_gateway, ResponseFuture};
+use futures::Future;
+use hyper::StatusCode;
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Percentages of upstream responses that are tampered with.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chaos {
+    /// Percentage of responses that are delayed.
+    pub delay_percentage: u8,
+    /// How long delayed responses are held back, in milliseconds.
+    pub delay_ms: u64,
+    /// Percentage of responses that are dropped and answered as if upstream
+    /// had failed.
+    pub drop_percentage: u8,
+    /// Percentage of responses whose status code is replaced.
+    pub rewrite_percentage: u8,
+    /// Status code of rewritten responses.
+    pub rewrite_status: u16,
+}
+
+impl Default for Chaos {
+    fn default() -> Chaos {
+        Chaos {
+            delay_percentage: 0,
+            delay_ms: 1000,
+            drop_percentage: 0,
+            rewrite_percentage: 0,
+            rewrite_status: 500,
+        }
+    }
+}
+
+impl Chaos {
+    /// Checks that the percentages and the status code are usable.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.delay_percentage <= 100
+            && self.drop_percentage <= 100
+            && self.rewrite_percentage <= 100
+            && StatusCode::from_u16(self.rewrite_status).is_ok()
+    }
+
+    /// Wraps the upstream response in the configured faults.
+    pub(crate) fn inject(&self, response: ResponseFuture) -> ResponseFuture {
+        let response: ResponseFuture = if chance(self.drop_percentage) {
+            Box::new(response.then(|_| Ok(bad_gateway())))
+        } else if chance(self.rewrite_percentage) {
+            let status = StatusCode::from_u16(self.rewrite_status).unwrap();
+            Box::new(response.map(move |mut response| {
+                *response.status_mut() = status;
+                response
+            }))
+        } else {
+            response
+        };
+        if chance(self.delay_percentage) {
+            let delay = Delay::new(Instant::now() + Duration::from_millis(self.delay_ms));
+            Box::new(delay.then(|_| response))
+        } else {
+            response
+        }
+    }
+}
+
+/// Returns true with the given probability in percent.
+fn chance(percentage: u8) -> bool {
+    if percentage == 0 {
+        return false;
+    }
+    // Every RandomState is seeded differently, which is random enough for
+    // fault injection without pulling in a random number generator.
+    let random = RandomState::new().build_hasher().finish();
+    random % 100 < u64::from(percentage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::chance;
+
+    #[test]
+    fn chances() {
+        assert!(!(0..100).any(|_| chance(0)));
+        assert!((0..100).all(|_| chance(100)));
+        let hits = (0..10000).filter(|_| chance(30)).count();
+        assert!(hits > 2500 && hits < 3500, "{}", hits);
+    }
+}
@@ -0,0 +1,151 @@
+//! Checksums of bodies for object storage backends like S3 and Google Cloud
+//! Storage. Request bodies get checksums that the backend verifies, and
+//! checksums that the backend sends with responses are verified before the
+//! response is cached, so a corrupted transfer is never served from cache.
+
+use crate::hex_encode;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use md5::Md5;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+const CONTENT_MD5: &str = "content-md5";
+const AMZ_CONTENT_SHA256: &str = "x-amz-content-sha256";
+const AMZ_CHECKSUM_SHA256: &str = "x-amz-checksum-sha256";
+const GOOG_HASH: &str = "x-goog-hash";
+
+/// Checksums that can be added to request bodies.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    /// A base64 encoded MD5 in the Content-MD5 header.
+    Md5,
+    /// A hex encoded SHA-256 in the x-amz-content-sha256 header. Requests
+    /// signed with `sigv4` then sign the body as well.
+    Sha256,
+}
+
+/// Sets the checksum headers for a request body, replacing the ones of the
+/// client.
+pub(crate) fn add_checksums(
+    headers: &mut HeaderMap,
+    body: &[u8],
+    algorithms: &[ChecksumAlgorithm],
+) {
+    for algorithm in algorithms {
+        let (name, value) = match algorithm {
+            ChecksumAlgorithm::Md5 => (CONTENT_MD5, base64::encode(Md5::digest(body))),
+            ChecksumAlgorithm::Sha256 => (AMZ_CONTENT_SHA256, hex_encode(&Sha256::digest(body))),
+        };
+        headers.insert(
+            HeaderName::from_static(name),
+            HeaderValue::from_str(&value).unwrap(),
+        );
+    }
+}
+
+/// Checks a response body against the checksums in its headers. Returns the
+/// header that doesn't match. Responses without checksums always pass.
+pub(crate) fn verify(headers: &HeaderMap, body: &[u8]) -> Result<(), &'static str> {
+    let mut md5 = None;
+    let mut md5_matches = |expected: &str| {
+        let actual = md5.get_or_insert_with(|| base64::encode(Md5::digest(body)));
+        expected.trim() == actual
+    };
+    for value in headers.get_all(CONTENT_MD5) {
+        if !value.to_str().is_ok_and(&mut md5_matches) {
+            return Err(CONTENT_MD5);
+        }
+    }
+    // Like "crc32c=n03x6A==, md5=Ojk9c3dhfxgoKVVHYwFbHQ==", CRC32C is not
+    // checked.
+    for value in headers.get_all(GOOG_HASH) {
+        let value = value.to_str().map_err(|_| GOOG_HASH)?;
+        for hash in value.split(',') {
+            if let Some(expected) = hash.trim().strip_prefix("md5=") {
+                if !md5_matches(expected) {
+                    return Err(GOOG_HASH);
+                }
+            }
+        }
+    }
+    let mut sha256 = None;
+    for value in headers.get_all(AMZ_CHECKSUM_SHA256) {
+        let actual = sha256.get_or_insert_with(|| base64::encode(Sha256::digest(body)));
+        if !value
+            .to_str()
+            .is_ok_and(|expected| expected.trim() == actual)
+        {
+            return Err(AMZ_CHECKSUM_SHA256);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_checksums, verify, ChecksumAlgorithm};
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+
+    #[test]
+    fn request_checksums() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-md5", HeaderValue::from_static("client"));
+        add_checksums(
+            &mut headers,
+            b"hello",
+            &[ChecksumAlgorithm::Md5, ChecksumAlgorithm::Sha256],
+        );
+        assert_eq!("XUFAKrxLKna5cZ2REBfFkg==", headers["content-md5"]);
+        assert_eq!(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            headers["x-amz-content-sha256"]
+        );
+    }
+
+    #[test]
+    fn verify_responses() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        assert_eq!(Ok(()), verify(&HeaderMap::new(), b"hello"));
+        assert_eq!(
+            Ok(()),
+            verify(
+                &headers(&[
+                    ("content-md5", "XUFAKrxLKna5cZ2REBfFkg=="),
+                    ("x-goog-hash", "crc32c=mnG7TA=="),
+                    ("x-goog-hash", "md5=XUFAKrxLKna5cZ2REBfFkg=="),
+                    (
+                        "x-amz-checksum-sha256",
+                        "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+                    ),
+                ]),
+                b"hello"
+            )
+        );
+        assert_eq!(
+            Err("content-md5"),
+            verify(
+                &headers(&[("content-md5", "XUFAKrxLKna5cZ2REBfFkg==")]),
+                b"hellO"
+            )
+        );
+        assert_eq!(
+            Err("x-goog-hash"),
+            verify(
+                &headers(&[("x-goog-hash", "crc32c=mnG7TA==,md5=AAAA")]),
+                b"hello"
+            )
+        );
+        assert_eq!(
+            Err("x-amz-checksum-sha256"),
+            verify(&headers(&[("x-amz-checksum-sha256", "AAAA")]), b"hello")
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! Caching of large files in fixed-size chunks. Every chunk is a cache entry
+//! of its own that is fetched from upstream with a Range request when a
+//! client needs it, and client responses are stitched together from chunks.
+//! So a video of several gigabytes is never loaded or cached as a whole,
+//! only the parts that are watched.
+
+use crate::ranges::{self, Ranges};
+use crate::state::{self, ProxyState};
+use crate::vary;
+use crate::{
+    bad_gateway, filter_response_headers, limited_upstream_request, Cache, ResponseFuture, Upstream,
+};
+use bytes::Bytes;
+use futures::future::Either;
+use futures::sync::oneshot;
+use futures::{stream, Future, Stream};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MATCH,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, RANGE, SERVER, VIA,
+};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+/// A chunk of the upstream file.
+struct Part {
+    index: u64,
+    headers: HeaderMap,
+    body: Bytes,
+    // Length of the whole file.
+    total: u64,
+}
+
+/// Fetches the chunks of one file, from the cache or from upstream.
+struct Fetcher {
+    uri: Uri,
+    headers: HeaderMap,
+    cache_key: String,
+    chunk_size: u64,
+    // Chunks after the one a client reads that are fetched ahead.
+    prefetch: u64,
+    cache: Cache,
+    upstream: Upstream,
+    state: Arc<ProxyState>,
+}
+
+/// A chunk, or the response of an upstream that did not answer with the
+/// requested range.
+enum Fetched {
+    Chunk(Part),
+    Other(Response<Body>),
+}
+
+impl Fetcher {
+    fn chunk(&self, index: u64) -> Box<dyn Future<Item = Fetched, Error = hyper::Error> + Send> {
+        let key = format!("{}#chunk{}", self.cache_key, index);
+        if let Some(response) = self.cache.clone().lookup(&Some(key.clone()), &self.headers) {
+            let (parts, body) = response.into_parts();
+            return Box::new(body.concat2().map(move |body| {
+                let body = body.into_bytes();
+                match content_range(&parts.headers) {
+                    Some((_, _, total)) => Fetched::Chunk(Part {
+                        index,
+                        headers: parts.headers,
+                        body,
+                        total,
+                    }),
+                    None => Fetched::Other(Response::from_parts(parts, Body::from(body))),
+                }
+            }));
+        }
+
+        let first = index * self.chunk_size;
+        let mut request = Request::get(self.uri.clone()).body(Body::empty()).unwrap();
+        *request.headers_mut() = self.headers.clone();
+        {
+            let headers = request.headers_mut();
+            // The chunk is requested unconditionally, a 304 has no bytes.
+            for name in &[
+                IF_RANGE,
+                IF_MATCH,
+                IF_NONE_MATCH,
+                IF_MODIFIED_SINCE,
+                IF_UNMODIFIED_SINCE,
+            ] {
+                headers.remove(name);
+            }
+            let range = format!(
+                "bytes={}-{}",
+                first,
+                first.saturating_add(self.chunk_size - 1)
+            );
+            headers.insert(RANGE, HeaderValue::from_str(&range).unwrap());
+        }
+        let upstream = self.upstream.clone();
+        let state = self.state.clone();
+        let cache = self.cache.clone();
+        let vary_headers = self.headers.clone();
+        Box::new(
+            self.state
+                .config
+                .backend
+                .prepare_request(request)
+                .and_then(move |request| {
+                    limited_upstream_request(request, upstream, state.clone())
+                        .map(move |response| (response, state))
+                })
+                .and_then(move |(mut response, state)| {
+                    let range = content_range(response.headers());
+                    let (start, end, total) = match range {
+                        Some(range) if response.status() == StatusCode::PARTIAL_CONTENT => range,
+                        _ => return Either::A(futures::future::ok(Fetched::Other(response))),
+                    };
+                    if start != first {
+                        return Either::A(futures::future::ok(Fetched::Other(bad_gateway())));
+                    }
+                    filter_response_headers(response.headers_mut(), &state.config);
+                    let max_age = cache.get_max_age(&response, &state.config);
+                    let vary = vary::vary_values(
+                        response.headers(),
+                        &vary_headers,
+                        state.config.backend.normalize_accept_encoding,
+                    );
+                    let (parts, body) = response.into_parts();
+                    Either::B(body.concat2().map(move |body| {
+                        let body = body.into_bytes();
+                        // A chunk that broke off is not cached.
+                        if body.len() as u64 != end - start + 1 {
+                            eprintln!("Incomplete chunk {} of {}", index, key);
+                            return Fetched::Other(bad_gateway());
+                        }
+                        if let (Some(max_age), Some(vary)) = (max_age, vary) {
+                            cache.insert(key, vary, &parts, body.clone(), max_age);
+                        }
+                        Fetched::Chunk(Part {
+                            index,
+                            headers: parts.headers,
+                            body,
+                            total,
+                        })
+                    }))
+                }),
+        )
+    }
+}
+
+/// Parses "bytes first-last/total".
+fn content_range(headers: &HeaderMap) -> Option<(u64, u64, u64)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let value = value.trim().strip_prefix("bytes ")?;
+    let (range, total) = value.split_at(value.find('/')?);
+    let (first, last) = range.split_at(range.find('-')?);
+    let (first, last, total) = (
+        first.trim().parse().ok()?,
+        last[1..].trim().parse().ok()?,
+        total[1..].trim().parse().ok()?,
+    );
+    if first > last || last >= total {
+        return None;
+    }
+    Some((first, last, total))
+}
+
+/// The first byte a Range header asks for, if it is a single range with a
+/// start.
+fn first_position(range: &str) -> Option<u64> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    spec[..spec.find('-')?].trim().parse().ok()
+}
+
+/// Answers a GET request on a route with chunked caching. Requests for one
+/// range get a 206, all others the whole file.
+pub(crate) fn respond(
+    request: Request<Body>,
+    cache_key: String,
+    chunk_size: u64,
+    prefetch: u64,
+    cache: Cache,
+    upstream: Upstream,
+    state: Arc<ProxyState>,
+) -> ResponseFuture {
+    let range = request
+        .headers()
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(str::to_string);
+    // The chunk with the start of the range, so that the most likely needed
+    // chunk tells the length of the file.
+    let first_index = range
+        .as_ref()
+        .and_then(|range| first_position(range))
+        .map_or(0, |first| first / chunk_size);
+    let (parts, _) = request.into_parts();
+    let fetcher = Arc::new(Fetcher {
+        uri: parts.uri,
+        headers: parts.headers,
+        cache_key,
+        chunk_size,
+        prefetch,
+        cache,
+        upstream,
+        state,
+    });
+    Box::new(
+        fetcher
+            .chunk(first_index)
+            .map(move |fetched| match fetched {
+                Fetched::Chunk(part) => stitch(part, range, fetcher),
+                Fetched::Other(mut response) => {
+                    let version = response.version();
+                    add_headers(response.headers_mut(), version);
+                    response
+                }
+            }),
+    )
+}
+
+/// Builds the client response from the chunks that overlap the requested
+/// range. The first fetched chunk is the one with the start of the range or
+/// the first one.
+fn stitch(part: Part, range: Option<String>, fetcher: Arc<Fetcher>) -> Response<Body> {
+    let total = part.total;
+    let (first, last, status) = match range.and_then(|range| ranges::parse(&range, total)) {
+        Some(Ranges::Satisfiable(ref ranges)) if ranges.len() == 1 => {
+            (ranges[0].0, ranges[0].1, StatusCode::PARTIAL_CONTENT)
+        }
+        Some(Ranges::Unsatisfiable) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", total))
+                .body(Body::empty())
+                .unwrap();
+        }
+        _ => (0, total - 1, StatusCode::OK),
+    };
+
+    let mut headers = part.headers.clone();
+    headers.remove(CONTENT_RANGE);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(last - first + 1));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{}", first, last, total);
+        headers.insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&content_range).unwrap(),
+        );
+    }
+    add_headers(&mut headers, hyper::Version::HTTP_11);
+
+    // Chunks of another version of the file must not be mixed in.
+    let etag = part.headers.get(ETAG).cloned();
+    let chunk_size = fetcher.chunk_size;
+    let last_index = (total - 1) / chunk_size;
+    let mut fetched = Some(part);
+    // Chunks that are fetched ahead in the background, also past the end of
+    // the range for the next request of a player. They go into the cache in
+    // any case.
+    let mut prefetched = HashMap::new();
+    let mut prefetched_until = first / chunk_size;
+    let chunks = stream::iter_ok(first / chunk_size..=last / chunk_size).and_then(
+        move |index| -> Box<dyn Future<Item = Fetched, Error = io::Error> + Send> {
+            while prefetched_until < (index + fetcher.prefetch).min(last_index) {
+                prefetched_until += 1;
+                let (sender, receiver) = oneshot::channel();
+                tokio::spawn(fetcher.chunk(prefetched_until).then(move |result| {
+                    if let Ok(fetched) = result {
+                        // The client may be gone, the chunk is cached anyway.
+                        let _ = sender.send(fetched);
+                    }
+                    Ok(())
+                }));
+                prefetched.insert(prefetched_until, receiver);
+            }
+            match fetched.take() {
+                Some(part) if part.index == index => {
+                    return Box::new(futures::future::ok(Fetched::Chunk(part)));
+                }
+                _ => {}
+            }
+            match prefetched.remove(&index) {
+                Some(receiver) => {
+                    Box::new(receiver.map_err(|_| io::Error::other("Prefetching failed")))
+                }
+                None => Box::new(fetcher.chunk(index).map_err(io::Error::other)),
+            }
+        },
+    );
+    let chunks = chunks.and_then(move |fetched| match fetched {
+        Fetched::Chunk(ref part)
+            if part.total == total && part.headers.get(ETAG) == etag.as_ref() =>
+        {
+            let start = part.index * chunk_size;
+            let from = first.saturating_sub(start) as usize;
+            let to = ((last - start + 1) as usize).min(part.body.len());
+            Ok(hyper::Chunk::from(part.body.slice(from, to)))
+        }
+        _ => {
+            eprintln!("Upstream failed to send a chunk or the file changed meanwhile");
+            Err(io::Error::other("Chunk mismatch"))
+        }
+    });
+    let mut response = Response::new(Body::wrap_stream(chunks));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+fn add_headers(headers: &mut HeaderMap, version: hyper::Version) {
+    headers.append(VIA, state::via(version));
+    if !headers.contains_key(SERVER) {
+        headers.insert(SERVER, HeaderValue::from_static("rustnish"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_range, first_position};
+    use hyper::header::{HeaderValue, CONTENT_RANGE};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn positions() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 10-19/100"));
+        assert_eq!(Some((10, 19, 100)), content_range(&headers));
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 10-19/*"));
+        assert_eq!(None, content_range(&headers));
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 10-100/100"));
+        assert_eq!(None, content_range(&headers));
+
+        assert_eq!(Some(1000), first_position("bytes=1000-"));
+        assert_eq!(Some(5), first_position("bytes=5-9"));
+        assert_eq!(None, first_position("bytes=-500"));
+        assert_eq!(None, first_position("bytes=0-1,5-9"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Time sources for expiring cache entries.
+//!
+//! The cache asks a `Clock` for the current time instead of calling
+//! `Instant::now()` directly, so that tests can control time with a
+//! `ManualClock`.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of the current point in time.
+pub trait Clock: Debug {
+    /// Returns the current point in time.
+    fn now(&self) -> Instant;
+
+    /// Returns the current wall clock time, for things that happen at a time
+    /// of day.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        (**self).system_time()
+    }
+}
+
+/// The real monotonic system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves forward when it is advanced explicitly. Clones
+/// share the same time, so a clone can be handed to the cache while the test
+/// keeps another one to advance time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+    start: Instant,
+    start_system_time: SystemTime,
+}
+
+impl ManualClock {
+    /// Creates a clock that starts at the current system time.
+    pub fn new() -> ManualClock {
+        ManualClock::at(SystemTime::now())
+    }
+
+    /// Creates a clock that starts at the given wall clock time.
+    pub fn at(system_time: SystemTime) -> ManualClock {
+        let start = Instant::now();
+        ManualClock {
+            now: Arc::new(Mutex::new(start)),
+            start,
+            start_system_time: system_time,
+        }
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + (self.now() - self.start)
+    }
+}
@@ -0,0 +1,788 @@
+use crate::admin::AdminToken;
+use crate::backend::Backend;
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::director::Director;
+use crate::errors::*;
+use crate::failover::Failover;
+use crate::listener::Listener;
+use crate::mirror::Mirror;
+use crate::path::TrailingSlash;
+use crate::policy::{self, CachePolicy, StatusTtl};
+use crate::query::QueryNormalization;
+use crate::readiness::Readiness;
+use crate::routes::{LinkRewrite, Route};
+use crate::schedule::Schedule;
+use crate::security::SecurityHeaders;
+use crate::throttle::ClientClass;
+use error_chain::bail;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{StatusCode, Uri};
+use regex::RegexSet;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Settings that control the behavior of the reverse proxy.
+///
+/// The settings can also be read from a TOML file with `Config::from_file()`,
+/// all keys are optional and fall back to the defaults.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Maximum memory in bytes that the cache may use for HTTP responses.
+    pub memory_size: usize,
+    /// Number of independently locked shards the cache is split into. The
+    /// memory size is divided evenly between them, so a single response can
+    /// use at most `memory_size / cache_shards` bytes.
+    pub cache_shards: usize,
+    /// Maximum number of variants of one URL that are cached for a Vary
+    /// response header. The least recently used variant makes room for a
+    /// new one.
+    pub max_variants: usize,
+    /// Maximum number of bans that are checked lazily on lookup. Beyond
+    /// that the oldest ban is applied to the whole cache at once.
+    pub max_bans: usize,
+    /// Seconds that responses are kept after they expired, to be served with
+    /// "Warning: 111" when upstream fails or answers with 500, 502, 503 or
+    /// 504, like grace mode in Varnish. A stale-if-error directive of the
+    /// response overrides it. Disabled with 0.
+    pub grace: u64,
+    /// Seconds that expired responses with an ETag or Last-Modified header
+    /// are kept after their grace period, like keep in Varnish. A request
+    /// for them asks upstream with If-None-Match or If-Modified-Since, and a
+    /// 304 Not Modified renews them without downloading the body again.
+    /// Disabled with 0.
+    pub keep: u64,
+    /// Cache responses to HEAD requests that could not be answered from a
+    /// cached GET response. They are kept apart from the GET responses since
+    /// they have no body.
+    pub cache_head: bool,
+    /// Seconds that the Date header of upstream responses may differ from
+    /// our clock before it counts as age of the response, which shortens
+    /// its max-age. Larger differences are logged, as the clock of the
+    /// backend or the proxy is probably wrong.
+    pub clock_skew_tolerance: u64,
+    /// Cache responses without max-age and Expires for a tenth of the time
+    /// since their Last-Modified date, at most a day. Off by default because
+    /// backends that don't expect it may see stale pages served.
+    pub heuristic_freshness: bool,
+    /// Let clients skip the cache or ask for fresher responses with the
+    /// Cache-Control request directives no-cache, max-age, min-fresh and
+    /// max-stale, or with "Pragma: no-cache". Off by default because every
+    /// client could then send its requests to the backend.
+    pub request_cache_control: bool,
+    /// Number of worker threads that handle client connections. One per CPU
+    /// core if not set.
+    pub workers: Option<usize>,
+    /// Names of cookies that are removed from incoming requests before they
+    /// are passed on. Typically analytics cookies that the backend does not
+    /// care about.
+    pub strip_cookies: Vec<String>,
+    /// Regular expressions for the names of session cookies. Requests with a
+    /// session cookie bypass the cache, or are micro-cached per session with
+    /// `micro_cache_ttl`. By default Drupal's "SESS" and "SSESS" cookies
+    /// followed by a hash.
+    pub session_cookies: Vec<String>,
+    /// Normalization of the query strings of cache keys, so that URLs that
+    /// only differ in the order of their parameters or in marketing
+    /// parameters share a cache entry.
+    pub query_normalization: QueryNormalization,
+    /// Add the Host header of the request to cache keys, lowercase and
+    /// without default port, like "/index.html host:example.com". Needed
+    /// when the backend serves several virtual hosts.
+    pub host_in_cache_key: bool,
+    /// Merge runs of slashes in request paths, so "//foo///bar" is cached
+    /// and forwarded as "/foo/bar".
+    pub merge_slashes: bool,
+    /// Strip or add trailing slashes of request paths, for backends that
+    /// serve "/foo" and "/foo/" alike, so they share one cache entry.
+    pub trailing_slash: TrailingSlash,
+    /// Answer every request with a JSON description of what the proxy would
+    /// do, without contacting upstream. Only useful for debugging. Like the
+    /// administration API, the description is only given to local clients,
+    /// or with an admin token with the read-stats scope if there are tokens.
+    /// Other clients get a 403.
+    pub dry_run: bool,
+    /// Name of a request header that enables dry-run mode for a single
+    /// request. Disabled if not set.
+    pub dry_run_header: Option<String>,
+    /// Public URL of the site when the proxy runs behind a TLS terminator or
+    /// on another port, for example "https://www.example.com". Used for
+    /// X-Forwarded-Port and X-Forwarded-Proto, rewritten Location headers and
+    /// to mark cookies as Secure on https sites.
+    pub public_base_url: Option<String>,
+    /// Only these upstream response headers are passed on to clients and the
+    /// cache if the list is not empty. A trailing "*" matches any header name
+    /// with that prefix.
+    pub response_header_allowlist: Vec<String>,
+    /// Upstream response headers that are removed, for example "X-Debug-*".
+    /// A trailing "*" matches any header name with that prefix.
+    pub response_header_denylist: Vec<String>,
+    /// Caching rules by content type that override the Cache-Control header
+    /// of upstream. The first matching policy is used.
+    pub cache_policies: Vec<CachePolicy>,
+    /// Status codes of responses that may be cached. Responses with other
+    /// status codes are never cached, whatever their Cache-Control header
+    /// says.
+    pub cacheable_statuses: Vec<u16>,
+    /// Cache lifetimes by status code for responses without Cache-Control
+    /// max-age or Expires header. By default 404s are cached for 10 and 410s
+    /// for 60 seconds.
+    pub status_ttls: Vec<StatusTtl>,
+    /// Time windows with different cache behavior, for example longer TTLs
+    /// during a known traffic spike. The first active schedule is used.
+    pub schedules: Vec<Schedule>,
+    /// Name of the request header that forces a cache refresh for a URL, so
+    /// that editors can see their changes immediately.
+    pub refresh_header: String,
+    /// Secret value the refresh header must have. Refreshing is disabled if
+    /// not set.
+    pub refresh_token: Option<String>,
+    /// Seconds for which responses to requests with a session cookie are
+    /// cached, separately for every session. Protects the backend from users
+    /// hammering reload without sharing their pages with others. Must be
+    /// between 1 and 5, disabled if not set.
+    pub micro_cache_ttl: Option<u64>,
+    /// Shadow upstream that receives copies of requests. Its responses are
+    /// discarded.
+    pub mirror: Option<Mirror>,
+    /// Directory where requests and responses from upstream are written to
+    /// as JSON files, with secrets like cookies removed. For building test
+    /// fixtures.
+    pub record_dir: Option<String>,
+    /// Directory with recordings that are served instead of contacting
+    /// upstream.
+    pub replay_dir: Option<String>,
+    /// Fault injection into upstream responses, for resilience testing.
+    /// Never enable this in production.
+    pub chaos: Option<Chaos>,
+    /// Destinations that clients may tunnel to with CONNECT, for using the
+    /// proxy as an egress proxy in test environments. Patterns look like
+    /// "example.com:443", "*.example.com:443" or "example.com:*". CONNECT is
+    /// refused if the list is empty.
+    pub connect_allowlist: Vec<String>,
+    /// Path prefix of the administration API, for example "/_rustnish". Only
+    /// requests from localhost are allowed, unless `admin_tokens` are set.
+    /// Disabled if not set.
+    pub admin_path: Option<String>,
+    /// Bearer tokens for the administration API. If there are any, every
+    /// admin request needs a token with the scope of the command, from
+    /// localhost or not.
+    pub admin_tokens: Vec<AdminToken>,
+    /// File that every management action on the administration API is
+    /// appended to, with time, client address and outcome. Disabled if not
+    /// set.
+    pub admin_audit_log: Option<String>,
+    /// Number of recent requests that are kept for the administration API:
+    /// for the history command with their cache decision, backend, status
+    /// and duration, and the cachable ones for the simulate command, which
+    /// predicts the hit ratio of other cache settings. Disabled with 0.
+    pub request_log_size: usize,
+    /// Rewrite Location headers that point at the upstream address (the
+    /// backend host with the upstream port, or localhost for a backend on
+    /// 127.0.0.1) to the host the client requested, so that redirects don't
+    /// leak the internal address.
+    pub rewrite_upstream_location: bool,
+    /// Additional URL prefix mappings for Location headers of all upstream
+    /// responses, for example from an internal hostname to the public one.
+    pub location_rewrites: Vec<LinkRewrite>,
+    /// Readiness endpoint that waits for cache warm-up and a healthy
+    /// backend. Disabled if not set.
+    pub readiness: Option<Readiness>,
+    /// A standby backend that takes over while the backend fails its health
+    /// checks. Disabled if not set.
+    pub failover: Option<Failover>,
+    /// Milliseconds a client may take to receive a response before its
+    /// connection is aborted, against clients that read very slowly or not
+    /// at all. Disabled if not set.
+    pub client_delivery_timeout_ms: Option<u64>,
+    /// Bandwidth limits for clients by their address. The first class that
+    /// contains the client is used, together with the limit of the route.
+    pub client_classes: Vec<ClientClass>,
+    /// Security headers like X-Frame-Options that are added to HTML
+    /// responses, with defaults for all but the Content-Security-Policy.
+    /// Routes can override them. Disabled if not set.
+    pub security_headers: Option<SecurityHeaders>,
+    /// Address the proxy listens on, like "0.0.0.0:8080". 127.0.0.1:9090 if
+    /// not set. `Builder::listen()`, the --listen option and the port
+    /// argument of the `start_server_*()` functions take precedence.
+    pub listen: Option<SocketAddr>,
+    /// Socket options of the listener for client connections.
+    pub listener: Listener,
+    /// Connection settings for the upstream server.
+    pub backend: Backend,
+    /// Groups of backend pools with fallbacks that routes can use instead of
+    /// the backend.
+    pub directors: Vec<Director>,
+    /// Settings that only apply to some URL paths. The first matching route
+    /// is used.
+    pub routes: Vec<Route>,
+    /// Time source for cache expiry. Tests can inject a `ManualClock` here to
+    /// control time.
+    #[serde(skip)]
+    pub clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            // 256 MB memory cache as a default.
+            memory_size: 256 * 1024 * 1024,
+            cache_shards: 16,
+            max_variants: 8,
+            max_bans: 1000,
+            grace: 0,
+            keep: 0,
+            cache_head: false,
+            clock_skew_tolerance: 60,
+            heuristic_freshness: false,
+            request_cache_control: false,
+            workers: None,
+            strip_cookies: vec!["_ga".to_string(), "_gid".to_string()],
+            session_cookies: vec!["SESS[A-Za-z0-9_]+$".to_string()],
+            query_normalization: QueryNormalization::default(),
+            host_in_cache_key: true,
+            merge_slashes: false,
+            trailing_slash: TrailingSlash::Keep,
+            dry_run: false,
+            dry_run_header: None,
+            public_base_url: None,
+            response_header_allowlist: Vec::new(),
+            response_header_denylist: Vec::new(),
+            cache_policies: Vec::new(),
+            cacheable_statuses: vec![
+                200, 203, 204, 300, 301, 302, 307, 308, 404, 405, 410, 414, 501,
+            ],
+            status_ttls: policy::default_status_ttls(),
+            schedules: Vec::new(),
+            refresh_header: "x-rustnish-refresh".to_string(),
+            refresh_token: None,
+            micro_cache_ttl: None,
+            mirror: None,
+            record_dir: None,
+            replay_dir: None,
+            chaos: None,
+            connect_allowlist: Vec::new(),
+            admin_path: None,
+            admin_tokens: Vec::new(),
+            admin_audit_log: None,
+            request_log_size: 10_000,
+            rewrite_upstream_location: true,
+            location_rewrites: Vec::new(),
+            readiness: None,
+            failover: None,
+            client_delivery_timeout_ms: None,
+            client_classes: Vec::new(),
+            security_headers: None,
+            listen: None,
+            listener: Listener::default(),
+            backend: Backend::default(),
+            directors: Vec::new(),
+            routes: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings from a TOML file and validates them.
+    pub fn from_file(path: &str) -> Result<Config> {
+        let contents = fs::read_to_string(path)
+            .chain_err(|| format!("Failed to read config file {}", path))?;
+        let config: Config =
+            toml::from_str(&contents).chain_err(|| format!("Invalid config file {}", path))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the settings are usable for running the proxy.
+    pub fn validate(&self) -> Result<()> {
+        if self.cache_shards == 0 {
+            bail!("cache_shards must be at least 1");
+        }
+        if self.workers == Some(0) {
+            bail!("workers must be at least 1");
+        }
+        if self.max_variants == 0 {
+            bail!("max_variants must be at least 1");
+        }
+        if self.max_bans == 0 {
+            bail!("max_bans must be at least 1");
+        }
+        for name in &self.strip_cookies {
+            if name.is_empty() || name.contains(|c: char| c == ';' || c == '=' || c.is_whitespace())
+            {
+                bail!("Invalid cookie name in strip_cookies: {:?}", name);
+            }
+        }
+        if let Err(error) = RegexSet::new(&self.session_cookies) {
+            bail!("Invalid session_cookies pattern: {}", error);
+        }
+        if let Some(ref header) = self.dry_run_header {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                bail!("Invalid header name in dry_run_header: {:?}", header);
+            }
+        }
+        if let Some(ref url) = self.public_base_url {
+            if self.public_base().is_none() {
+                bail!(
+                    "public_base_url must be an absolute http or https URL: {:?}",
+                    url
+                );
+            }
+        }
+        for pattern in self
+            .response_header_allowlist
+            .iter()
+            .chain(&self.response_header_denylist)
+        {
+            let name = pattern.trim_end_matches('*');
+            if pattern.is_empty()
+                || (!name.is_empty() && HeaderName::from_bytes(name.as_bytes()).is_err())
+            {
+                bail!("Invalid response header pattern: {:?}", pattern);
+            }
+        }
+        if HeaderName::from_bytes(self.refresh_header.as_bytes()).is_err() {
+            bail!(
+                "Invalid header name in refresh_header: {:?}",
+                self.refresh_header
+            );
+        }
+        if let Some(ref token) = self.refresh_token {
+            if token.is_empty() || HeaderValue::from_str(token).is_err() {
+                bail!("refresh_token must be a non-empty header value");
+            }
+        }
+        if let Some(ttl) = self.micro_cache_ttl {
+            if !(1..=5).contains(&ttl) {
+                bail!("micro_cache_ttl must be between 1 and 5 seconds");
+            }
+        }
+        if let Some(ref security_headers) = self.security_headers {
+            if security_headers.header_values().is_none() {
+                bail!("Invalid header value in security_headers");
+            }
+        }
+        if self.client_delivery_timeout_ms == Some(0) {
+            bail!("client_delivery_timeout_ms must be at least 1");
+        }
+        let scheduled_policies = self
+            .schedules
+            .iter()
+            .flat_map(|schedule| &schedule.cache_policies);
+        for policy in self.cache_policies.iter().chain(scheduled_policies) {
+            if policy.content_type.is_empty() {
+                bail!("Cache policy content_type must not be empty");
+            }
+        }
+        if let Some(status) = self
+            .cacheable_statuses
+            .iter()
+            .find(|status| StatusCode::from_u16(**status).is_err())
+        {
+            bail!("Invalid status code in cacheable_statuses: {}", status);
+        }
+        for status_ttl in &self.status_ttls {
+            if !self.cacheable_statuses.contains(&status_ttl.status) {
+                bail!(
+                    "status_ttls has a TTL for {}, which is not in cacheable_statuses",
+                    status_ttl.status
+                );
+            }
+        }
+        if let Some(ref mirror) = self.mirror {
+            if !mirror.is_valid() {
+                bail!(
+                    "Invalid mirror, the address needs a host and port and the percentage must be at most 100: {:?}",
+                    mirror
+                );
+            }
+        }
+        if self.record_dir.is_some() && self.replay_dir.is_some() {
+            bail!("record_dir and replay_dir cannot be used at the same time");
+        }
+        if let Some(ref chaos) = self.chaos {
+            if !chaos.is_valid() {
+                bail!("Invalid chaos settings: {:?}", chaos);
+            }
+        }
+        if self.backend.http_1_0 && self.backend.http2 {
+            bail!("The backend cannot use HTTP/1.0 and HTTP/2 at the same time");
+        }
+        let valid_host = match self.backend.origin(80).parse::<Uri>() {
+            Ok(uri) => !self.backend.host.is_empty() && uri.path() == "/",
+            Err(_) => false,
+        };
+        if !valid_host {
+            bail!(
+                "backend host must be a host name or IP address: {:?}",
+                self.backend.host
+            );
+        }
+        if self.backend.port == Some(0) {
+            bail!("backend port must be at least 1");
+        }
+        if self.backend.max_requests == Some(0) {
+            bail!("backend max_requests must be at least 1");
+        }
+        if self.backend.timeout_ms == Some(0) {
+            bail!("backend timeout_ms must be at least 1");
+        }
+        if let Some(ref proxy) = self.backend.proxy {
+            if proxy.address().is_none() {
+                bail!(
+                    "Invalid backend proxy URL, it must be http: {:?}",
+                    proxy.url
+                );
+            }
+        }
+        if let Some(ref auth) = self.backend.auth {
+            if !auth.is_valid() {
+                bail!("Invalid backend auth credentials");
+            }
+        }
+        if let Some(ref tls) = self.backend.tls {
+            tls.connector(self.backend.http2)?;
+        }
+        if let Some(ref readiness) = self.readiness {
+            let paths = std::iter::once(&readiness.path)
+                .chain(&readiness.warmup_urls)
+                .chain(&readiness.health_check_path);
+            for path in paths {
+                if !path.starts_with('/') || path.parse::<Uri>().is_err() {
+                    bail!("Readiness paths must start with /: {:?}", path);
+                }
+            }
+        }
+        if let Some(ref failover) = self.failover {
+            if !failover.is_valid() || failover.health_check_path.parse::<Uri>().is_err() {
+                bail!(
+                    "Failover needs a health check path starting with / and nonzero values: {:?}",
+                    failover
+                );
+            }
+        }
+        for token in &self.admin_tokens {
+            if token.name.is_empty() || token.token.is_empty() || token.token.contains(' ') {
+                bail!(
+                    "Admin tokens need a name and a token without spaces: {:?}",
+                    token.name
+                );
+            }
+        }
+        if let Some(ref prefix) = self.admin_path {
+            if !prefix.starts_with('/') || prefix.len() < 2 {
+                bail!(
+                    "admin_path must start with / and not be the root: {:?}",
+                    prefix
+                );
+            }
+        }
+        for (index, director) in self.directors.iter().enumerate() {
+            if !director.is_valid() {
+                bail!(
+                    "Invalid pools of director {:?}: backends need a host:port address, valid header overrides and a timeout of at least 1 ms",
+                    director.name
+                );
+            }
+            if self.directors[..index]
+                .iter()
+                .any(|other| other.name == director.name)
+            {
+                bail!("Duplicate director name: {:?}", director.name);
+            }
+        }
+        for route in &self.routes {
+            if !route.path_prefix.starts_with('/') {
+                bail!(
+                    "Route path prefix must start with /: {:?}",
+                    route.path_prefix
+                );
+            }
+            if route
+                .upstream_path_prefix
+                .as_ref()
+                .is_some_and(|prefix| !prefix.starts_with('/'))
+            {
+                bail!(
+                    "Route upstream path prefix must start with /: {:?}",
+                    route.path_prefix
+                );
+            }
+            if let Some(ref name) = route.director {
+                if !self.directors.iter().any(|director| &director.name == name) {
+                    bail!(
+                        "Unknown director of route {:?}: {:?}",
+                        route.path_prefix,
+                        name
+                    );
+                }
+            }
+            for placeholder in &route.placeholders {
+                if placeholder.marker.is_empty()
+                    || !placeholder.path.starts_with('/')
+                    || placeholder.path.parse::<Uri>().is_err()
+                {
+                    bail!(
+                        "Placeholders need a marker and a path starting with /: {:?}",
+                        placeholder
+                    );
+                }
+            }
+            if let Some(ref security_headers) = route.security_headers {
+                if security_headers.header_values().is_none() {
+                    bail!(
+                        "Invalid header value in the security headers of route {:?}",
+                        route.path_prefix
+                    );
+                }
+            }
+            if route.bytes_per_second == Some(0) {
+                bail!(
+                    "Route bytes_per_second must be at least 1: {:?}",
+                    route.path_prefix
+                );
+            }
+            if route.chunk_size == Some(0) {
+                bail!(
+                    "Route chunk_size must be at least 1: {:?}",
+                    route.path_prefix
+                );
+            }
+            if route.prefetch_chunks > 0 && route.chunk_size.is_none() {
+                bail!(
+                    "Route prefetch_chunks needs a chunk_size: {:?}",
+                    route.path_prefix
+                );
+            }
+        }
+        for class in &self.client_classes {
+            if !class.is_valid() {
+                bail!(
+                    "Invalid client class, networks must be addresses with an optional prefix length and bytes_per_second at least 1: {:?}",
+                    class
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if an upstream response header may be passed on.
+    pub(crate) fn allows_response_header(&self, name: &HeaderName) -> bool {
+        let matches = |pattern: &String| header_name_matches(pattern, name.as_str());
+        (self.response_header_allowlist.is_empty()
+            || self.response_header_allowlist.iter().any(matches))
+            && !self.response_header_denylist.iter().any(matches)
+    }
+
+    /// Returns the parsed `public_base_url`, if it is set and valid.
+    pub(crate) fn public_base(&self) -> Option<PublicBase> {
+        let url = self.public_base_url.as_ref()?;
+        let uri: Uri = url.parse().ok()?;
+        let secure = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return None,
+        };
+        let authority = uri.authority_part()?;
+        let port = authority
+            .port_u16()
+            .unwrap_or(if secure { 443 } else { 80 });
+        Some(PublicBase {
+            url: url.trim_end_matches('/').to_string(),
+            port,
+            secure,
+        })
+    }
+}
+
+/// Checks if a lowercase header name matches a pattern like "X-Debug-*",
+/// ignoring case.
+fn header_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name
+            .as_bytes()
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix.as_bytes())),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// The public URL of the site that clients see.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PublicBase {
+    /// The URL without a trailing slash.
+    pub url: String,
+    /// The explicit port or the default port of the scheme.
+    pub port: u16,
+    /// Whether the site is served over https.
+    pub secure: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::backend::TlsVersion;
+    use hyper::header::HeaderName;
+    use regex::RegexSet;
+
+    #[test]
+    fn parse_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            memory_size = 1024
+            strip_cookies = ["_ga", "_fbp"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(1024, config.memory_size);
+        assert_eq!(16, config.cache_shards);
+        assert_eq!(vec!["_ga", "_fbp"], config.strip_cookies);
+        assert!(config.validate().is_ok());
+
+        let config: Config = toml::from_str(
+            r#"
+            listen = "0.0.0.0:8080"
+            [backend]
+            host = "app.internal"
+            port = 3000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Some(([0, 0, 0, 0], 8080).into()), config.listen);
+        assert_eq!("app.internal", config.backend.host);
+        assert_eq!(Some(3000), config.backend.port);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn session_cookies() {
+        let patterns = RegexSet::new(&Config::default().session_cookies).unwrap();
+        assert!(patterns.is_match("SESSabc123"));
+        assert!(patterns.is_match("SSESS_1"));
+        assert!(patterns.is_match("SESS-SESSa"));
+        assert!(!patterns.is_match("SESS"));
+        assert!(!patterns.is_match("SESSa-b"));
+        assert!(!patterns.is_match("sessabc"));
+        assert!(!patterns.is_match("_ga"));
+
+        let config = Config {
+            session_cookies: vec!["^wordpress_logged_in_".to_string(), "(".to_string()],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn parse_backend_tls() {
+        let config: Config = toml::from_str(
+            r#"
+            [backend.tls]
+            server_name = "app.internal"
+            min_version = "1.3"
+            "#,
+        )
+        .unwrap();
+        let tls = config.backend.tls.unwrap();
+        assert_eq!(Some("app.internal".to_string()), tls.server_name);
+        assert_eq!(TlsVersion::Tls13, tls.min_version);
+        assert!(!tls.insecure_skip_verify);
+        assert!(toml::from_str::<Config>("[backend.tls]\nmin_version = \"1.4\"").is_err());
+    }
+
+    #[test]
+    fn unknown_key() {
+        assert!(toml::from_str::<Config>("memory = 1024").is_err());
+    }
+
+    #[test]
+    fn invalid_values() {
+        let config = Config {
+            cache_shards: 0,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            workers: Some(0),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            max_variants: 0,
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            strip_cookies: vec!["_ga; x".to_string()],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            public_base_url: Some("ftp://example.com".to_string()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            cacheable_statuses: vec![200, 1000],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        // The default TTL for 404s would never be used.
+        let config = Config {
+            cacheable_statuses: vec![200],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.backend.port = Some(0);
+        assert!(config.validate().is_err());
+
+        for host in &["", "app/x", "a b"] {
+            let mut config = Config::default();
+            config.backend.host = host.to_string();
+            assert!(config.validate().is_err(), "{}", host);
+        }
+    }
+
+    #[test]
+    fn response_header_lists() {
+        let mut config = Config {
+            response_header_denylist: vec!["X-Debug-*".to_string(), "x-trace".to_string()],
+            ..Config::default()
+        };
+        assert!(config.allows_response_header(&HeaderName::from_static("content-type")));
+        assert!(!config.allows_response_header(&HeaderName::from_static("x-debug-token")));
+        assert!(!config.allows_response_header(&HeaderName::from_static("x-trace")));
+
+        config.response_header_allowlist = vec!["Content-*".to_string()];
+        assert!(config.allows_response_header(&HeaderName::from_static("content-type")));
+        assert!(!config.allows_response_header(&HeaderName::from_static("etag")));
+    }
+
+    #[test]
+    fn public_base() {
+        let mut config = Config::default();
+        assert_eq!(None, config.public_base());
+
+        config.public_base_url = Some("https://www.example.com/".to_string());
+        let base = config.public_base().unwrap();
+        assert_eq!("https://www.example.com", base.url);
+        assert_eq!(443, base.port);
+        assert!(base.secure);
+
+        config.public_base_url = Some("http://example.com:8080".to_string());
+        let base = config.public_base().unwrap();
+        assert_eq!(8080, base.port);
+        assert!(!base.secure);
+    }
+}
@@ -0,0 +1,324 @@
+//! HTTP cache conformance: runs the tests of the http-cache-tests suite
+//! (https://cache-tests.fyi/) against a throwaway proxy with the caching
+//! settings of the running one, for the conformance admin command. The test
+//! definitions are vendored in the cache-tests directory.
+
+use crate::clock::{Clock, ManualClock};
+use crate::errors::*;
+use crate::parse::format_http_date;
+use crate::Config;
+use futures::{Future, Stream};
+use hyper::header::{HeaderValue, DATE, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use hyper::http::response::Parts;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Client, HeaderMap, Request, Response, Server, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// The vendored test modules of the suite.
+const MODULES: &[&str] = &[
+    include_str!("../cache-tests/cc-freshness.json"),
+    include_str!("../cache-tests/cc-response.json"),
+    include_str!("../cache-tests/expires.json"),
+    include_str!("../cache-tests/status.json"),
+];
+
+// The suite waits that long after requests with pause_after. The clock of
+// the proxy is advanced instead.
+const PAUSE: Duration = Duration::from_secs(3);
+
+// Time for the cache thread to store a response before the next request.
+const INSERT_DELAY: Duration = Duration::from_millis(50);
+
+/// Results by test ID, in the format of the suite's result files: true for
+/// passed tests, the kind of failure and a message for the others.
+pub(crate) type Report = BTreeMap<String, Value>;
+
+#[derive(Deserialize)]
+struct Module {
+    tests: Vec<Test>,
+}
+
+#[derive(Deserialize)]
+struct Test {
+    id: String,
+    #[serde(default)]
+    browser_only: bool,
+    requests: Vec<Step>,
+}
+
+/// A request of a test and the response upstream sends if it gets it.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+struct Step {
+    request_method: Option<String>,
+    request_headers: Vec<(String, String)>,
+    response_status: Option<(u16, String)>,
+    // Numbers are dates that many seconds from now.
+    response_headers: Vec<(String, Value)>,
+    response_body: Option<String>,
+    expected_type: Option<String>,
+    expected_status: Option<u16>,
+    expected_response_headers: Vec<(String, String)>,
+    setup: bool,
+    pause_after: bool,
+}
+
+/// The steps of a test and the headers of the requests that upstream got
+/// for it. Like in the suite, upstream answers its nth request with the
+/// response of the nth step, requests answered from the cache don't count.
+struct Exchange {
+    steps: Vec<Step>,
+    received: Vec<HeaderMap>,
+}
+
+type Exchanges = Arc<Mutex<HashMap<String, Exchange>>>;
+
+/// Runs the tests against a proxy with the caching settings of `config`.
+/// Takes a few seconds, as every request waits for the cache to store the
+/// response.
+pub(crate) fn run(config: &Config) -> Result<Report> {
+    let mut tests = Vec::new();
+    for module in MODULES {
+        let module: Module =
+            serde_json::from_str(module).chain_err(|| "Invalid cache test definitions")?;
+        tests.extend(module.tests.into_iter().filter(|test| !test.browser_only));
+    }
+
+    let clock = ManualClock::new();
+    let exchanges = Exchanges::default();
+    let mut runtime = Runtime::new().chain_err(|| "Failed to start the runtime")?;
+    let upstream_exchanges = exchanges.clone();
+    let upstream_clock = clock.clone();
+    let upstream = Server::try_bind(&([127, 0, 0, 1], 0).into())
+        .chain_err(|| "Failed to bind the test upstream")?
+        .serve(move || {
+            let exchanges = upstream_exchanges.clone();
+            let clock = upstream_clock.clone();
+            service_fn_ok(move |request| upstream_response(&request, &exchanges, &clock))
+        });
+    let upstream_port = upstream.local_addr().port();
+    runtime.spawn(upstream.map_err(|_| ()));
+    // The proxy stops when the shutdown sender is dropped at the end.
+    let (_proxy, address, _shutdown) = crate::start_server(
+        ([127, 0, 0, 1], 0).into(),
+        upstream_port,
+        test_config(config, clock.clone()),
+    )?;
+
+    let client = Client::new();
+    let mut report = Report::new();
+    for test in tests {
+        exchanges.lock().unwrap().insert(
+            test.id.clone(),
+            Exchange {
+                steps: test.requests.clone(),
+                received: Vec::new(),
+            },
+        );
+        let result = run_test(&test, address, &exchanges, &clock, &client, &mut runtime);
+        report.insert(test.id, result);
+    }
+    Ok(report)
+}
+
+/// The caching settings of `config`, for a proxy that only talks to the
+/// upstream of the tests.
+fn test_config(config: &Config, clock: ManualClock) -> Config {
+    Config {
+        memory_size: 16 * 1024 * 1024,
+        cache_shards: 1,
+        max_variants: config.max_variants,
+        grace: config.grace,
+        keep: config.keep,
+        cache_head: config.cache_head,
+        clock_skew_tolerance: config.clock_skew_tolerance,
+        heuristic_freshness: config.heuristic_freshness,
+        request_cache_control: config.request_cache_control,
+        cache_policies: config.cache_policies.clone(),
+        cacheable_statuses: config.cacheable_statuses.clone(),
+        status_ttls: config.status_ttls.clone(),
+        workers: Some(1),
+        clock: Arc::new(clock),
+        ..Config::default()
+    }
+}
+
+/// Sends the requests of a test to the proxy and checks the responses.
+fn run_test(
+    test: &Test,
+    address: SocketAddr,
+    exchanges: &Exchanges,
+    clock: &ManualClock,
+    client: &Client<hyper::client::HttpConnector>,
+    runtime: &mut Runtime,
+) -> Value {
+    for (index, step) in test.requests.iter().enumerate() {
+        let number = index + 1;
+        let failure =
+            |message: String| json!([if step.setup { "Setup" } else { "Assertion" }, message]);
+        let mut request = Request::builder();
+        request
+            .method(step.request_method.as_deref().unwrap_or("GET"))
+            .uri(format!("http://{}/{}", address, test.id));
+        for (name, value) in &step.request_headers {
+            request.header(name.as_str(), value.as_str());
+        }
+        let request = match request.body(Body::empty()) {
+            Ok(request) => request,
+            Err(e) => return failure(format!("Request {} is invalid: {}", number, e)),
+        };
+        let received_before = exchanges.lock().unwrap()[&test.id].received.len();
+        let response = client.request(request).and_then(|response| {
+            let (parts, body) = response.into_parts();
+            body.concat2().map(|_| parts)
+        });
+        let response = match runtime.block_on(response) {
+            Ok(response) => response,
+            Err(e) => return failure(format!("Request {} failed: {}", number, e)),
+        };
+        thread::sleep(INSERT_DELAY);
+        let received = exchanges.lock().unwrap()[&test.id].received[received_before..].to_vec();
+        if let Some(message) = check(step, number, &response, &received) {
+            return failure(message);
+        }
+        if step.pause_after {
+            clock.advance(PAUSE);
+        }
+    }
+    Value::Bool(true)
+}
+
+/// Checks a response against the expectations of its step, returns what is
+/// wrong. `received` are the requests that upstream got meanwhile.
+fn check(step: &Step, number: usize, response: &Parts, received: &[HeaderMap]) -> Option<String> {
+    let validated_with = |name| {
+        received
+            .last()
+            .is_some_and(|headers| headers.contains_key(name))
+    };
+    match step.expected_type.as_deref() {
+        Some("cached") if !received.is_empty() => {
+            return Some(format!("Response {} does not come from cache", number));
+        }
+        Some("not_cached") if received.is_empty() => {
+            return Some(format!("Response {} comes from cache", number));
+        }
+        Some("etag_validated") if !validated_with(IF_NONE_MATCH) => {
+            return Some(format!(
+                "Response {} was not validated with its ETag",
+                number
+            ));
+        }
+        Some("lm_validated") if !validated_with(IF_MODIFIED_SINCE) => {
+            return Some(format!(
+                "Response {} was not validated with its Last-Modified date",
+                number
+            ));
+        }
+        _ => {}
+    }
+    if let Some(status) = step.expected_status {
+        if response.status.as_u16() != status {
+            return Some(format!(
+                "Response {} status is {}, not {}",
+                number,
+                response.status.as_u16(),
+                status
+            ));
+        }
+    }
+    for (name, value) in &step.expected_response_headers {
+        let actual = response.headers.get(name.as_str());
+        if actual.is_none_or(|actual| actual != value.as_str()) {
+            return Some(format!(
+                "Response {} header {} is {:?}, not {:?}",
+                number, name, actual, value
+            ));
+        }
+    }
+    None
+}
+
+/// Answers a request of the proxy with the response of the test step that
+/// is due, or 304 Not Modified if the step expects a validation.
+fn upstream_response(
+    request: &Request<Body>,
+    exchanges: &Exchanges,
+    clock: &ManualClock,
+) -> Response<Body> {
+    let id = request.uri().path().trim_start_matches('/');
+    let mut exchanges = exchanges.lock().unwrap();
+    let exchange = match exchanges.get_mut(id) {
+        Some(exchange) => exchange,
+        None => return status_response(StatusCode::NOT_FOUND),
+    };
+    exchange.received.push(request.headers().clone());
+    let number = exchange.received.len();
+    let step = match exchange.steps.get(number - 1) {
+        Some(step) => step,
+        None => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let validated = match step.expected_type.as_deref() {
+        Some("etag_validated") => request.headers().contains_key(IF_NONE_MATCH),
+        Some("lm_validated") => request.headers().contains_key(IF_MODIFIED_SINCE),
+        _ => false,
+    };
+    let mut response = Response::builder();
+    match step.response_status {
+        _ if validated => response.status(StatusCode::NOT_MODIFIED),
+        Some((status, _)) => response.status(status),
+        None => response.status(StatusCode::OK),
+    };
+    // Upstream dates follow the clock of the proxy.
+    let now = clock.system_time();
+    if !step
+        .response_headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("date"))
+    {
+        response.header(DATE, format_http_date(now).as_str());
+    }
+    for (name, value) in &step.response_headers {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Number(offset) => {
+                let offset = offset.as_i64().unwrap_or(0);
+                let seconds = Duration::from_secs(offset.unsigned_abs());
+                format_http_date(if offset < 0 {
+                    now - seconds
+                } else {
+                    now + seconds
+                })
+            }
+            value => value.to_string(),
+        };
+        match HeaderValue::from_str(&value) {
+            Ok(value) => {
+                response.header(name.as_str(), value);
+            }
+            Err(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+    let body = match step.response_body {
+        _ if validated => Body::empty(),
+        Some(ref body) => Body::from(body.clone()),
+        None => Body::from(format!("{} {}", id, number)),
+    };
+    response
+        .body(body)
+        .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
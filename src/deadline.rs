@@ -0,0 +1,214 @@
+//! Deadlines of upstream requests. Clients can send the time they are still
+//! willing to wait in a grpc-timeout or X-Request-Timeout header, the backend
+//! can have a timeout of its own. Upstream requests are given up at the
+//! earlier of both, and upstream is told the time that is left, so deadline
+//! budgets work across the proxy.
+
+use crate::is_grpc;
+use futures::Future;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
+
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+const REQUEST_TIMEOUT: &str = "x-request-timeout";
+
+/// When a request must be answered, kept in the request extensions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Deadline(pub(crate) Instant);
+
+/// A timeout in milliseconds for the backend of a request, instead of the
+/// configured one. Kept in the request extensions.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BackendTimeout(pub(crate) u64);
+
+/// The deadline that a client asked for, None if it didn't send one.
+pub(crate) fn from_headers(headers: &HeaderMap, now: Instant) -> Option<Deadline> {
+    let grpc = headers
+        .get(GRPC_TIMEOUT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_grpc_timeout);
+    let seconds = headers
+        .get(REQUEST_TIMEOUT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_seconds);
+    grpc.into_iter()
+        .chain(seconds)
+        .min()
+        .map(|timeout| Deadline(now + timeout))
+}
+
+/// The earlier of the client's deadline and the backend timeout, which
+/// starts now.
+pub(crate) fn effective(
+    client: Option<Deadline>,
+    backend_timeout_ms: Option<u64>,
+    now: Instant,
+) -> Option<Deadline> {
+    let backend = backend_timeout_ms.map(|timeout| Deadline(now + Duration::from_millis(timeout)));
+    match (client, backend) {
+        (Some(client), Some(backend)) => Some(if client.0 < backend.0 {
+            client
+        } else {
+            backend
+        }),
+        (client, backend) => client.or(backend),
+    }
+}
+
+/// Sets the deadline headers of an upstream request to the time that is
+/// left. The headers that the client sent are updated, gRPC requests always
+/// get a grpc-timeout.
+pub(crate) fn propagate(headers: &mut HeaderMap, deadline: Deadline, now: Instant) {
+    // Rounded down, so upstream gives up before we do.
+    let millis = deadline.0.saturating_duration_since(now).as_millis() as u64;
+    if headers.contains_key(GRPC_TIMEOUT) || is_grpc(headers) {
+        // At most 8 digits.
+        let value = if millis < 100_000_000 {
+            format!("{}m", millis)
+        } else {
+            format!("{}S", millis / 1000)
+        };
+        headers.insert(
+            HeaderName::from_static(GRPC_TIMEOUT),
+            HeaderValue::from_str(&value).unwrap(),
+        );
+    }
+    if headers.contains_key(REQUEST_TIMEOUT) {
+        let value = format!("{}.{:03}", millis / 1000, millis % 1000);
+        headers.insert(
+            HeaderName::from_static(REQUEST_TIMEOUT),
+            HeaderValue::from_str(&value).unwrap(),
+        );
+    }
+}
+
+/// Gives up on a response at the deadline and answers with a 504 instead,
+/// or the gRPC status DEADLINE_EXCEEDED for gRPC calls.
+pub(crate) fn limit<F>(
+    response: F,
+    deadline: Deadline,
+    grpc: bool,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error>
+where
+    F: Future<Item = Response<Body>, Error = hyper::Error>,
+{
+    Timeout::new_at(response, deadline.0).or_else(move |error| {
+        if error.is_inner() {
+            return Err(error.into_inner().unwrap());
+        }
+        if error.is_timer() {
+            eprintln!("Timer for the upstream deadline failed: {}", error);
+        }
+        Ok(exceeded(grpc))
+    })
+}
+
+fn exceeded(grpc: bool) -> Response<Body> {
+    if grpc {
+        // A trailers-only response.
+        Response::builder()
+            .header(CONTENT_TYPE, "application/grpc")
+            .header("grpc-status", "4")
+            .header("grpc-message", "Deadline exceeded")
+            .body(Body::empty())
+            .unwrap()
+    } else {
+        Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .body("The backend did not answer in time.".into())
+            .unwrap()
+    }
+}
+
+/// Parses a grpc-timeout like "250m": at most 8 digits and a unit of hours,
+/// minutes, seconds, milli-, micro- or nanoseconds.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Parses seconds with an optional fraction like "2.5".
+fn parse_seconds(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.is_empty()
+        || !value
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || byte == b'.')
+    {
+        return None;
+    }
+    let seconds: f64 = value.parse().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{effective, from_headers, parse_grpc_timeout, parse_seconds, propagate, Deadline};
+    use hyper::header::HeaderValue;
+    use hyper::HeaderMap;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn parse() {
+        assert_eq!(Some(Duration::from_millis(250)), parse_grpc_timeout("250m"));
+        assert_eq!(Some(Duration::from_secs(7200)), parse_grpc_timeout("2H"));
+        assert_eq!(Some(Duration::from_nanos(5)), parse_grpc_timeout("5n"));
+        assert_eq!(None, parse_grpc_timeout("123456789S"));
+        assert_eq!(None, parse_grpc_timeout("10"));
+        assert_eq!(None, parse_grpc_timeout("-1S"));
+
+        assert_eq!(Some(Duration::from_millis(2500)), parse_seconds("2.5"));
+        assert_eq!(Some(Duration::from_secs(3)), parse_seconds(" 3 "));
+        assert_eq!(None, parse_seconds("1e3"));
+        assert_eq!(None, parse_seconds("soon"));
+    }
+
+    #[test]
+    fn deadlines() {
+        let now = Instant::now();
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, from_headers(&headers, now));
+        assert_eq!(None, effective(None, None, now));
+
+        headers.insert("grpc-timeout", HeaderValue::from_static("3S"));
+        headers.insert("x-request-timeout", HeaderValue::from_static("2"));
+        let client = from_headers(&headers, now);
+        assert_eq!(Some(Deadline(now + Duration::from_secs(2))), client);
+        assert_eq!(client, effective(client, Some(5000), now));
+        assert_eq!(
+            Some(Deadline(now + Duration::from_secs(1))),
+            effective(client, Some(1000), now)
+        );
+
+        propagate(
+            &mut headers,
+            Deadline(now + Duration::from_millis(1500)),
+            now + Duration::from_millis(250),
+        );
+        assert_eq!("1250m", headers["grpc-timeout"]);
+        assert_eq!("1.250", headers["x-request-timeout"]);
+
+        // Nothing is added for requests without deadline headers.
+        let mut headers = HeaderMap::new();
+        propagate(&mut headers, Deadline(now), now);
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+//! Aborts client connections that take too long to receive their responses,
+//! for example because a client stopped reading and its TCP window is full.
+//! Such slow readers would otherwise hold connections and response bodies in
+//! memory forever.
+
+use crate::metrics::Metrics;
+use futures::{Async, Future, Poll, Stream};
+use hyper::body::Payload;
+use hyper::{Body, Chunk, Response};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Responses of one connection that are being delivered.
+#[derive(Default)]
+struct Deliveries {
+    count: usize,
+    // Since when the client made no progress: the start of the first response
+    // or the end of the last completed one.
+    since: Option<Instant>,
+}
+
+/// Tracks the response deliveries of one client connection.
+#[derive(Clone, Default)]
+pub(crate) struct DeliveryTracker {
+    deliveries: Arc<Mutex<Deliveries>>,
+}
+
+impl DeliveryTracker {
+    /// Tracks the delivery of a response until its body was handed to the
+    /// connection completely.
+    pub(crate) fn track(&self, response: Response<Body>) -> Response<Body> {
+        if response.body().is_end_stream() {
+            return response;
+        }
+        {
+            let mut deliveries = self.deliveries.lock().unwrap();
+            if deliveries.count == 0 {
+                deliveries.since = Some(Instant::now());
+            }
+            deliveries.count += 1;
+        }
+        let guard = DeliveryGuard {
+            deliveries: self.deliveries.clone(),
+        };
+        // The Content-Length header still applies to the wrapped body.
+        response.map(|body| {
+            Body::wrap_stream(TrackedBody {
+                body,
+                guard: Some(guard),
+            })
+        })
+    }
+
+    /// The point in time when the connection must be aborted, if a response
+    /// is being delivered.
+    fn deadline(&self, limit: Duration) -> Option<Instant> {
+        let deliveries = self.deliveries.lock().unwrap();
+        match deliveries.since {
+            Some(since) if deliveries.count > 0 => Some(since + limit),
+            _ => None,
+        }
+    }
+}
+
+/// Marks a delivery as finished when the body ends or is dropped.
+struct DeliveryGuard {
+    deliveries: Arc<Mutex<Deliveries>>,
+}
+
+impl Drop for DeliveryGuard {
+    fn drop(&mut self) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.count -= 1;
+        deliveries.since = Some(Instant::now());
+    }
+}
+
+struct TrackedBody {
+    body: Body,
+    guard: Option<DeliveryGuard>,
+}
+
+impl Stream for TrackedBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let result = self.body.poll();
+        if let Ok(Async::Ready(None)) = result {
+            self.guard.take();
+        }
+        result
+    }
+}
+
+/// A client connection that is dropped, and so closed, when a response
+/// delivery exceeds the limit.
+pub(crate) struct DeliveryTimeout<F> {
+    connection: F,
+    tracker: DeliveryTracker,
+    limit: Duration,
+    delay: Option<Delay>,
+    metrics: Arc<Metrics>,
+}
+
+impl<F> DeliveryTimeout<F> {
+    pub(crate) fn new(
+        connection: F,
+        tracker: DeliveryTracker,
+        limit: Duration,
+        metrics: Arc<Metrics>,
+    ) -> DeliveryTimeout<F> {
+        DeliveryTimeout {
+            connection,
+            tracker,
+            limit,
+            delay: None,
+            metrics,
+        }
+    }
+}
+
+impl<F: Future<Item = ()>> Future for DeliveryTimeout<F> {
+    type Item = ();
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<(), F::Error> {
+        if let Async::Ready(()) = self.connection.poll()? {
+            return Ok(Async::Ready(()));
+        }
+        let deadline = match self.tracker.deadline(self.limit) {
+            Some(deadline) => deadline,
+            None => {
+                self.delay = None;
+                return Ok(Async::NotReady);
+            }
+        };
+        let delay = self.delay.get_or_insert_with(|| Delay::new(deadline));
+        if delay.deadline() != deadline {
+            delay.reset(deadline);
+        }
+        match delay.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // Timer errors only happen on shutdown, close the connection
+            // then as well.
+            _ => {
+                eprintln!("Aborting connection to a slow client");
+                self.metrics.slow_client_aborted();
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
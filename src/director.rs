@@ -0,0 +1,422 @@
+//! Directors like in Varnish: groups of backends for a route that are tried
+//! in order. A request goes to the primary pool, on failure to the next pool
+//! and so on, and when all of them failed a static fallback is served.
+
+use crate::deadline::BackendTimeout;
+use crate::origin::Origin;
+use crate::state::ProxyState;
+use crate::{is_upstream_error, limited_upstream_request, Config, ResponseFuture, Upstream};
+use futures::future;
+use futures::Future;
+use hyper::body::Payload;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE, HOST};
+use hyper::http::request::Parts;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A named group of backend pools that routes can send their requests to.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Director {
+    /// The name that routes refer to.
+    pub name: String,
+    /// Pools of backends, tried in order when a backend fails with a
+    /// connection error or a 500, 502, 503 or 504. The backends of a pool
+    /// take turns. Scheme and settings are those of the configured backend.
+    /// Requests with a body only go to the first pool, as their body cannot
+    /// be sent again.
+    pub pools: Vec<Vec<PoolBackend>>,
+    /// Answer when all pools failed. Without it the failure of the last pool
+    /// is passed on.
+    #[serde(default)]
+    pub fallback: Option<FallbackContent>,
+}
+
+/// A backend in a pool. In the config file either just the address like
+/// "10.0.0.5:8080", or a table with the address and overrides for this
+/// backend, for example for a legacy server that needs a longer timeout.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(from = "PoolBackendConfig")]
+pub struct PoolBackend {
+    /// Host and port like "10.0.0.5:8080".
+    pub address: String,
+    /// Host header sent to this backend instead of the client's.
+    pub host_header: Option<String>,
+    /// Headers that are added to requests for this backend, replacing
+    /// headers of the same name.
+    pub headers: BTreeMap<String, String>,
+    /// Time in milliseconds that this backend may take to answer, instead
+    /// of the timeout of the configured backend.
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PoolBackendConfig {
+    Address(String),
+    Overrides {
+        address: String,
+        #[serde(default)]
+        host_header: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+}
+
+impl From<PoolBackendConfig> for PoolBackend {
+    fn from(config: PoolBackendConfig) -> PoolBackend {
+        match config {
+            PoolBackendConfig::Address(address) => PoolBackend::from(address.as_str()),
+            PoolBackendConfig::Overrides {
+                address,
+                host_header,
+                headers,
+                timeout_ms,
+            } => PoolBackend {
+                address,
+                host_header,
+                headers,
+                timeout_ms,
+            },
+        }
+    }
+}
+
+impl From<&str> for PoolBackend {
+    fn from(address: &str) -> PoolBackend {
+        PoolBackend {
+            address: address.to_string(),
+            ..PoolBackend::default()
+        }
+    }
+}
+
+impl PoolBackend {
+    /// Checks the address, the header overrides and the timeout.
+    fn is_valid(&self) -> bool {
+        format!("http://{}", self.address)
+            .parse::<Uri>()
+            .is_ok_and(|uri| uri.port_u16().is_some() && uri.path() == "/")
+            && self
+                .host_header
+                .as_ref()
+                .is_none_or(|host| HeaderValue::from_str(host).is_ok())
+            && self.headers.iter().all(|(name, value)| {
+                HeaderName::from_bytes(name.as_bytes()).is_ok()
+                    && HeaderValue::from_str(value).is_ok()
+            })
+            && self.timeout_ms != Some(0)
+    }
+
+    /// The origin of this backend, like "http://10.0.0.1:80".
+    fn origin(&self, config: &Config) -> String {
+        format!("{}://{}", config.backend.scheme(), self.address)
+    }
+
+    /// Applies the overrides to a request for this backend.
+    fn apply(&self, request: &mut Request<Body>) {
+        let headers = request.headers_mut();
+        if let Some(ref host) = self.host_header {
+            headers.insert(HOST, HeaderValue::from_str(host).unwrap());
+        }
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        if let Some(timeout) = self.timeout_ms {
+            request.extensions_mut().insert(BackendTimeout(timeout));
+        }
+    }
+}
+
+/// Static content for when no backend of a director could answer.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackContent {
+    /// HTTP status code, 503 if not set.
+    #[serde(default = "default_fallback_status")]
+    pub status: u16,
+    /// Content type of the body, "text/html; charset=utf-8" if not set.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+fn default_fallback_status() -> u16 {
+    503
+}
+
+impl Director {
+    /// Checks that the pools are not empty and hold valid backends.
+    pub(crate) fn is_valid(&self) -> bool {
+        !self.pools.is_empty()
+            && self
+                .pools
+                .iter()
+                .all(|pool| !pool.is_empty() && pool.iter().all(PoolBackend::is_valid))
+            && self
+                .fallback
+                .as_ref()
+                .is_none_or(|fallback| StatusCode::from_u16(fallback.status).is_ok())
+    }
+}
+
+impl FallbackContent {
+    fn response(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::from_u16(self.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE))
+            .header(
+                CONTENT_TYPE,
+                self.content_type
+                    .as_deref()
+                    .unwrap_or("text/html; charset=utf-8"),
+            )
+            .body(Body::from(self.body.clone()))
+            .unwrap()
+    }
+}
+
+/// The pools that a director sends requests to, as configured or switched
+/// to by the administration API, and whose turn it is in every pool.
+#[derive(Debug)]
+pub(crate) struct Pools {
+    pools: Vec<Vec<PoolBackend>>,
+    turns: Vec<AtomicUsize>,
+}
+
+impl Pools {
+    pub(crate) fn new(pools: Vec<Vec<PoolBackend>>) -> Pools {
+        Pools {
+            turns: pools.iter().map(|_| AtomicUsize::new(0)).collect(),
+            pools,
+        }
+    }
+
+    /// Parses pools like "10.0.0.1:80,10.0.0.2:80;backup:8080", where pools
+    /// are separated by semicolons and their backends by commas. None if a
+    /// pool is empty or a backend address is invalid.
+    pub(crate) fn parse(pools: &str) -> Option<Pools> {
+        let pools = pools
+            .split(';')
+            .map(|pool| {
+                pool.split(',')
+                    .map(|address| PoolBackend::from(address.trim()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let valid = pools
+            .iter()
+            .all(|pool| pool.iter().all(PoolBackend::is_valid));
+        Some(Pools::new(pools)).filter(|_| valid)
+    }
+}
+
+impl fmt::Display for Pools {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pools = self
+            .pools
+            .iter()
+            .map(|pool| {
+                pool.iter()
+                    .map(|backend| backend.address.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>();
+        f.write_str(&pools.join(";"))
+    }
+}
+
+/// The origin like "http://10.0.0.1:80" that the next request through a
+/// director goes to if its first pool answers, without taking the turn.
+pub(crate) fn next_origin(state: &ProxyState, director: usize) -> String {
+    let pools = state.director_pools[director].current();
+    let backends = &pools.backends.pools[0];
+    let turn = pools.backends.turns[0].load(Ordering::Relaxed);
+    backends[turn % backends.len()].origin(&state.config)
+}
+
+/// What is needed to send a request without body again.
+struct Template {
+    director: usize,
+    // The pools when the request arrived, it stays with them when they are
+    // switched.
+    pools: Arc<Origin<Pools>>,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    parts: Option<Parts>,
+}
+
+/// Sends a request through the pools of a director until one answers.
+pub(crate) fn send(
+    request: Request<Body>,
+    director: usize,
+    upstream: Upstream,
+    state: Arc<ProxyState>,
+) -> ResponseFuture {
+    let (parts, body) = request.into_parts();
+    let pools = state.director_pools[director].current();
+    let in_flight = pools.start();
+    let template = Template {
+        director,
+        pools,
+        method: parts.method.clone(),
+        uri: parts.uri.clone(),
+        version: parts.version,
+        headers: parts.headers.clone(),
+        parts: Some(parts),
+    };
+    Box::new(
+        attempt(template, 0, Some(body), upstream, state).then(move |result| {
+            drop(in_flight);
+            result
+        }),
+    )
+}
+
+fn attempt(
+    mut template: Template,
+    pool: usize,
+    body: Option<Body>,
+    upstream: Upstream,
+    state: Arc<ProxyState>,
+) -> ResponseFuture {
+    let config = &state.config;
+    let pools = template.pools.clone();
+    let backends = &pools.backends.pools[pool];
+    let turn = pools.backends.turns[pool].fetch_add(1, Ordering::Relaxed);
+    let backend = &backends[turn % backends.len()];
+    let origin = backend.origin(config);
+    let path = template
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let uri: Uri = match format!("{}{}", origin, path).parse() {
+        Ok(uri) => uri,
+        // Checked when the config was loaded.
+        Err(_) => return Box::new(future::ok(crate::bad_gateway())),
+    };
+    // The first attempt keeps the extensions of the request, like its
+    // deadline.
+    let mut request = match (template.parts.take(), body) {
+        (Some(parts), Some(body)) => Request::from_parts(parts, body),
+        _ => {
+            let mut request = Request::new(Body::empty());
+            *request.method_mut() = template.method.clone();
+            *request.version_mut() = template.version;
+            *request.headers_mut() = template.headers.clone();
+            request
+        }
+    };
+    *request.uri_mut() = uri;
+    backend.apply(&mut request);
+    // Only requests without a body can be sent to another pool.
+    let replayable = request.body().is_end_stream();
+    let upstream_state = state.clone();
+    let retry_upstream = upstream.clone();
+    Box::new(
+        config
+            .backend
+            .prepare_request(request)
+            .and_then(move |request| limited_upstream_request(request, upstream, upstream_state))
+            .then(move |result| {
+                let failed = match result {
+                    Ok(ref response) => is_upstream_error(response.status()),
+                    Err(_) => true,
+                };
+                if !failed {
+                    return Box::new(future::result(result)) as ResponseFuture;
+                }
+                let director = &state.config.directors[template.director];
+                if replayable && pool + 1 < template.pools.backends.pools.len() {
+                    return attempt(template, pool + 1, None, retry_upstream, state.clone());
+                }
+                match director.fallback {
+                    Some(ref fallback) => {
+                        eprintln!("All pools of director {} failed", director.name);
+                        Box::new(future::ok(fallback.response()))
+                    }
+                    None => Box::new(future::result(result)),
+                }
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Director, PoolBackend};
+
+    #[test]
+    fn validation() {
+        let director: Director = toml::from_str(
+            "name = \"app\"\npools = [[\"10.0.0.1:80\", \"10.0.0.2:80\"], [\"backup:8080\"]]\n\
+             [fallback]\nbody = \"<p>Down for maintenance</p>\"",
+        )
+        .unwrap();
+        assert!(director.is_valid());
+        assert_eq!(503, director.fallback.as_ref().unwrap().status);
+
+        let invalid = |pools: Vec<Vec<&str>>| {
+            !Director {
+                name: "app".to_string(),
+                pools: pools
+                    .into_iter()
+                    .map(|pool| pool.into_iter().map(PoolBackend::from).collect())
+                    .collect(),
+                fallback: None,
+            }
+            .is_valid()
+        };
+        assert!(invalid(vec![]));
+        assert!(invalid(vec![vec![]]));
+        assert!(invalid(vec![vec!["10.0.0.1"]]));
+        assert!(invalid(vec![vec!["10.0.0.1:80/path"]]));
+    }
+
+    #[test]
+    fn overrides() {
+        let director: Director = toml::from_str(
+            r#"
+            name = "app"
+            pools = [[
+                "10.0.0.1:80",
+                { address = "legacy:8080", host_header = "legacy.example.com", timeout_ms = 30000, headers = { x-legacy = "1" } },
+            ]]
+            "#,
+        )
+        .unwrap();
+        assert!(director.is_valid());
+        let legacy = &director.pools[0][1];
+        assert_eq!("legacy:8080", legacy.address);
+        assert_eq!(Some("legacy.example.com"), legacy.host_header.as_deref());
+        assert_eq!("1", legacy.headers["x-legacy"]);
+        assert_eq!(Some(30000), legacy.timeout_ms);
+        assert_eq!(None, director.pools[0][0].timeout_ms);
+
+        let valid = |backend: PoolBackend| backend.is_valid();
+        let backend = || PoolBackend::from("10.0.0.1:80");
+        assert!(!valid(PoolBackend {
+            timeout_ms: Some(0),
+            ..backend()
+        }));
+        assert!(!valid(PoolBackend {
+            host_header: Some("bad\nhost".to_string()),
+            ..backend()
+        }));
+        let mut bad_header = backend();
+        bad_header
+            .headers
+            .insert("bad header".to_string(), "1".to_string());
+        assert!(!valid(bad_header));
+    }
+}
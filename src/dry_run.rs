@@ -0,0 +1,116 @@
+//! Dry-run mode that describes what the proxy would do with a request instead
+//! of forwarding it, for debugging the configuration.
+
+use crate::admin;
+use crate::director;
+use crate::state::ProxyState;
+use crate::{split_host, upstream_uri, Cache, Route};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use serde::Serialize;
+use std::net::SocketAddr;
+
+/// Everything the proxy decided about a request.
+#[derive(Debug, Serialize)]
+struct Report {
+    method: String,
+    uri: String,
+    upstream_uri: String,
+    // Path prefix of the matching route, if any.
+    route: Option<String>,
+    // Name of the director that the route sends requests to, if any.
+    director: Option<String>,
+    // None if the request cannot be cached.
+    cache_key: Option<String>,
+    // The host that is part of the cache key.
+    cache_key_host: Option<String>,
+    // "pass" if the request cannot be cached, "refresh" if the refresh
+    // header replaces the cached response, "hit" or "miss" otherwise.
+    cache_decision: &'static str,
+    cache_hit: bool,
+    stripped_cookies: Vec<String>,
+}
+
+/// Checks if the request should be answered with a dry-run report, either
+/// because dry-run mode is enabled globally or the request carries the
+/// configured debug header.
+pub(crate) fn is_dry_run(request: &Request<Body>, state: &ProxyState) -> bool {
+    if state.config.dry_run {
+        return true;
+    }
+    match state.dry_run_header {
+        Some(ref header) => request.headers().contains_key(header),
+        None => false,
+    }
+}
+
+/// How the request was handled up to the point where it would be sent
+/// upstream.
+pub(crate) struct Decisions<'a> {
+    pub(crate) route: Option<&'a Route>,
+    pub(crate) director: Option<usize>,
+    pub(crate) origin: &'a str,
+    pub(crate) cache_key: &'a Option<String>,
+    pub(crate) refresh: bool,
+    pub(crate) stripped_cookies: Vec<String>,
+}
+
+/// Builds a JSON response describing how the request would be handled,
+/// without contacting upstream. The report names backends, so only clients
+/// that may use the administration API get it.
+pub(crate) fn response(
+    request: &Request<Body>,
+    source_address: SocketAddr,
+    cache: &Cache,
+    state: &ProxyState,
+    decisions: Decisions,
+) -> Response<Body> {
+    let config = &state.config;
+    if !admin::may_read_stats(request, source_address, config) {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("Dry-run reports need an admin token"))
+            .unwrap();
+    }
+    let route = decisions.route;
+    let mut upstream_uri = upstream_uri(request.uri(), route, decisions.origin);
+    if let Some(director) = decisions.director {
+        let path = upstream_uri
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.path_and_query().map(|path| path.to_string()))
+            .unwrap_or_else(|| "/".to_string());
+        upstream_uri = format!("{}{}", director::next_origin(state, director), path);
+    }
+    let cache_key = decisions.cache_key;
+    let (key, host) = cache_key
+        .as_deref()
+        .map(split_host)
+        .map(|(key, host)| (key.into_owned(), host.map(str::to_string)))
+        .unzip();
+    let cache_decision = match cache_key {
+        None => "pass",
+        Some(_) if decisions.refresh => "refresh",
+        Some(key) if cache.would_hit(key, request.headers()) => "hit",
+        Some(_) => "miss",
+    };
+    let report = Report {
+        method: request.method().to_string(),
+        uri: request.uri().to_string(),
+        upstream_uri,
+        route: route.map(|route| route.path_prefix.clone()),
+        director: decisions
+            .director
+            .map(|director| config.directors[director].name.clone()),
+        cache_key: key,
+        cache_key_host: host.flatten(),
+        cache_decision,
+        cache_hit: cache_decision == "hit",
+        stripped_cookies: decisions.stripped_cookies,
+    };
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&report).unwrap()))
+        .unwrap()
+}
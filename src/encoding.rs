@@ -0,0 +1,104 @@
+//! Serving gzip responses to clients that don't accept gzip. Backends with
+//! `normalize_accept_encoding` are always asked for gzip, so the cache holds
+//! one encoding per object, and the body is decompressed on the way out for
+//! the clients that need it. Routes that rewrite bodies decompress gzip
+//! responses for the rewrite and compress them again afterwards.
+
+use crate::{bad_gateway, ResponseFuture};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{Future, Stream};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, ETAG};
+use hyper::{Body, HeaderMap, Response};
+use std::io::{self, Read, Write};
+
+/// Decompresses the body of a gzip response.
+pub(crate) fn gunzip(response: Response<Body>) -> ResponseFuture {
+    if !is_gzip(response.headers()) {
+        return Box::new(futures::future::ok(response));
+    }
+    let (mut parts, body) = response.into_parts();
+    Box::new(body.concat2().map(move |chunk| {
+        let decoded = match decompress(&chunk) {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                eprintln!("Failed to decompress upstream response: {}", error);
+                return bad_gateway();
+            }
+        };
+        parts.headers.remove(CONTENT_ENCODING);
+        parts.headers.remove(CONTENT_LENGTH);
+        // The decoded body is a different representation, its validator can
+        // only be weak.
+        if let Some(etag) = parts.headers.get(ETAG).cloned() {
+            if !etag.as_bytes().starts_with(b"W/") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                parts
+                    .headers
+                    .insert(ETAG, HeaderValue::from_bytes(&weak).unwrap());
+            }
+        }
+        Response::from_parts(parts, Body::from(decoded))
+    }))
+}
+
+/// Decodes a gzip body.
+pub(crate) fn decompress(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Encodes a body with gzip.
+pub(crate) fn compress(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec never fails.
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub(crate) fn is_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let value = value.trim();
+            value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gunzip;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use futures::{Future, Stream};
+    use hyper::header::{CONTENT_ENCODING, ETAG};
+    use hyper::{Body, Response, StatusCode};
+    use std::io::Write;
+
+    #[test]
+    fn decompress() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"hello world").unwrap();
+        let response = Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .header(ETAG, "\"abc\"")
+            .body(Body::from(encoder.finish().unwrap()))
+            .unwrap();
+        let response = gunzip(response).wait().unwrap();
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(response.headers()[ETAG], "W/\"abc\"");
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"hello world");
+
+        let response = Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from("not gzip"))
+            .unwrap();
+        let response = gunzip(response).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,251 @@
+//! Active-passive failover: all requests go to the primary backend while a
+//! health check watches it. After a number of failed checks in a row the
+//! requests go to a standby backend, and they only go back once the primary
+//! passed a number of checks in a row, so a flapping primary doesn't move
+//! the traffic back and forth.
+
+use crate::backend::{Backend, UpstreamClient};
+use crate::origin::Origin;
+use crate::readiness;
+use crate::shutdown::{Signal, UntilShutdown};
+use crate::state::ProxyState;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+/// Settings of the standby backend and of the health check of the primary.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Failover {
+    /// Host name or IP address of the standby backend. Its other settings
+    /// are those of the configured backend.
+    pub standby_host: String,
+    pub standby_port: u16,
+    /// Path of the primary backend that must answer with a 2xx status, for
+    /// example "/health".
+    pub health_check_path: String,
+    /// Time between health checks in milliseconds.
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// How long a health check may take in milliseconds.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Failed health checks in a row after which the standby takes over.
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+    /// Passed health checks in a row after which the primary takes over
+    /// again.
+    #[serde(default = "default_recoveries")]
+    pub recoveries: u32,
+}
+
+fn default_interval_ms() -> u64 {
+    2000
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_failures() -> u32 {
+    3
+}
+
+fn default_recoveries() -> u32 {
+    5
+}
+
+impl Failover {
+    /// Checks the health check path and that the thresholds are not 0.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.health_check_path.starts_with('/')
+            && self.interval_ms > 0
+            && self.timeout_ms > 0
+            && self.failures > 0
+            && self.recoveries > 0
+    }
+}
+
+/// Which backend answers requests and the recent health checks.
+#[derive(Debug)]
+pub(crate) struct FailoverState {
+    settings: Failover,
+    standby: Arc<Origin>,
+    checks: Mutex<Checks>,
+    failovers: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Checks {
+    standby_active: bool,
+    // Health checks in a row with the same result.
+    failed: u32,
+    passed: u32,
+    // When the active backend last changed.
+    changed: Option<Instant>,
+}
+
+/// The failover state as reported by the administration API.
+#[derive(Debug, Serialize)]
+pub(crate) struct FailoverStatus {
+    // "primary" or "standby".
+    active: &'static str,
+    standby: String,
+    failed_checks: u32,
+    passed_checks: u32,
+    // Seconds since the active backend last changed.
+    changed_seconds_ago: Option<u64>,
+    failovers: usize,
+}
+
+impl FailoverState {
+    pub(crate) fn new(settings: &Failover, backend: &Backend) -> FailoverState {
+        let standby = Backend {
+            host: settings.standby_host.clone(),
+            ..backend.clone()
+        };
+        FailoverState {
+            settings: settings.clone(),
+            standby: Arc::new(Origin::new(standby.origin(settings.standby_port))),
+            checks: Mutex::new(Checks::default()),
+            failovers: AtomicUsize::new(0),
+        }
+    }
+
+    /// The standby backend while it answers requests instead of the primary.
+    pub(crate) fn active_standby(&self) -> Option<Arc<Origin>> {
+        if self.checks.lock().unwrap().standby_active {
+            Some(self.standby.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Counts the result of a health check of the primary and switches the
+    /// active backend when a threshold is reached.
+    fn record(&self, healthy: bool) {
+        let mut checks = self.checks.lock().unwrap();
+        if healthy {
+            checks.passed += 1;
+            checks.failed = 0;
+        } else {
+            checks.failed += 1;
+            checks.passed = 0;
+        }
+        if !checks.standby_active && checks.failed >= self.settings.failures {
+            eprintln!(
+                "Primary backend failed {} health checks, failing over to {}",
+                checks.failed,
+                self.standby.url()
+            );
+            checks.standby_active = true;
+            checks.changed = Some(Instant::now());
+            self.failovers.fetch_add(1, Ordering::Relaxed);
+        } else if checks.standby_active && checks.passed >= self.settings.recoveries {
+            eprintln!(
+                "Primary backend passed {} health checks, failing back",
+                checks.passed
+            );
+            checks.standby_active = false;
+            checks.changed = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn status(&self) -> FailoverStatus {
+        let checks = self.checks.lock().unwrap();
+        FailoverStatus {
+            active: if checks.standby_active {
+                "standby"
+            } else {
+                "primary"
+            },
+            standby: self.standby.url().to_string(),
+            failed_checks: checks.failed,
+            passed_checks: checks.passed,
+            changed_seconds_ago: checks.changed.map(|changed| changed.elapsed().as_secs()),
+            failovers: self.failovers.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Checks the health of the primary backend in an interval until the
+/// shutdown starts.
+pub(crate) fn monitor(
+    state: Arc<ProxyState>,
+    client: UpstreamClient,
+    signal: Signal,
+) -> impl Future<Item = (), Error = ()> {
+    let interval = state
+        .failover
+        .as_ref()
+        .map_or(1000, |failover| failover.settings.interval_ms);
+    let ticks = Interval::new_interval(Duration::from_millis(interval))
+        .map_err(|error| eprintln!("Failover timer failed: {}", error));
+    UntilShutdown::new(ticks, signal).for_each(move |_| {
+        let state = state.clone();
+        let failover = match state.failover {
+            Some(ref failover) => failover,
+            None => return Either::A(future::ok(())),
+        };
+        // The primary is the current origin, which the administration API
+        // can switch.
+        let check = readiness::health_check(
+            &client,
+            &state.config.backend,
+            state.origins.current().url(),
+            &failover.settings.health_check_path,
+            Duration::from_millis(failover.settings.timeout_ms),
+        );
+        Either::B(check.map(move |healthy| {
+            if let Some(ref failover) = state.failover {
+                failover.record(healthy);
+            }
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failover, FailoverState};
+    use crate::backend::Backend;
+
+    #[test]
+    fn hysteresis() {
+        let settings = Failover {
+            standby_host: "standby.internal".to_string(),
+            standby_port: 8080,
+            health_check_path: "/health".to_string(),
+            interval_ms: 1000,
+            timeout_ms: 1000,
+            failures: 2,
+            recoveries: 3,
+        };
+        assert!(settings.is_valid());
+        let state = FailoverState::new(&settings, &Backend::default());
+        state.record(false);
+        state.record(true);
+        state.record(false);
+        assert!(state.active_standby().is_none());
+        state.record(false);
+        assert_eq!(
+            "http://standby.internal:8080",
+            state.active_standby().unwrap().url()
+        );
+
+        state.record(true);
+        state.record(true);
+        state.record(false);
+        state.record(true);
+        state.record(true);
+        assert!(state.active_standby().is_some());
+        state.record(true);
+        assert!(state.active_standby().is_none());
+        let status = state.status();
+        assert_eq!("primary", status.active);
+        assert_eq!(1, status.failovers);
+    }
+}
@@ -0,0 +1,198 @@
+//! How long upstream responses stay fresh in the cache: their lifetime from
+//! the Expires header or a heuristic when there is no max-age, and their
+//! age, which shortens it. The Date header is compared to our own clock, and
+//! small differences are taken as clock skew between the backend and the
+//! proxy.
+
+use crate::parse;
+use hyper::header::{AGE, DATE, EXPIRES, LAST_MODIFIED};
+use hyper::{HeaderMap, StatusCode};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Upper bound of heuristic lifetimes in seconds, a day.
+const HEURISTIC_MAX: u64 = 24 * 60 * 60;
+
+// Unix time of the last clock skew warning, so that a backend with a wrong
+// clock doesn't flood the log.
+static LAST_SKEW_WARNING: AtomicU64 = AtomicU64::new(0);
+
+/// How old a response is in seconds when it arrives: the larger of its Age
+/// header and the time since its Date header, like RFC 7234 section 4.2.3.
+/// Dates that differ from `now` by up to `skew_tolerance` seconds count as
+/// just sent.
+pub(crate) fn current_age(headers: &HeaderMap, now: SystemTime, skew_tolerance: u64) -> u64 {
+    let age = headers
+        .get(AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| parse::seconds(age.trim()));
+    let apparent_age = match date(headers).map(|date| now.duration_since(date)) {
+        None => 0,
+        Some(Ok(behind)) if behind.as_secs() > skew_tolerance => {
+            // Responses from other caches state their age, without it this
+            // is most likely the clock of the backend.
+            if age.is_none() {
+                warn_skew(now, behind.as_secs(), "behind");
+            }
+            behind.as_secs()
+        }
+        Some(Ok(_)) => 0,
+        Some(Err(ahead)) => {
+            if ahead.duration().as_secs() > skew_tolerance {
+                warn_skew(now, ahead.duration().as_secs(), "ahead of");
+            }
+            0
+        }
+    };
+    age.unwrap_or(0).max(apparent_age)
+}
+
+/// Seconds between the Date and Expires headers, None without Expires. An
+/// invalid Expires like "0" means already expired. Without a Date header the
+/// time of arrival counts.
+pub(crate) fn expires_lifetime(headers: &HeaderMap, now: SystemTime) -> Option<u64> {
+    let expires = headers.get(EXPIRES)?;
+    let expires = match expires.to_str().ok().and_then(parse::http_date) {
+        Some(expires) => expires,
+        None => return Some(0),
+    };
+    let date = date(headers).unwrap_or(now);
+    Some(
+        expires
+            .duration_since(date)
+            .map_or(0, |lifetime| lifetime.as_secs()),
+    )
+}
+
+/// A tenth of the time since the response was last modified, like RFC 7234
+/// section 4.2.2 suggests, at most a day. None for responses without
+/// Last-Modified and status codes that are not cachable by default.
+pub(crate) fn heuristic_lifetime(
+    headers: &HeaderMap,
+    status: StatusCode,
+    now: SystemTime,
+) -> Option<u64> {
+    if ![200, 203, 204, 206, 300, 301, 404, 405, 410, 414, 501].contains(&status.as_u16()) {
+        return None;
+    }
+    let last_modified = headers
+        .get(LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse::http_date)?;
+    let date = date(headers).unwrap_or(now);
+    let unchanged = date.duration_since(last_modified).ok()?.as_secs();
+    Some((unchanged / 10).min(HEURISTIC_MAX))
+}
+
+fn date(headers: &HeaderMap) -> Option<SystemTime> {
+    headers
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(parse::http_date)
+}
+
+/// Logs a clock skew at most once a minute.
+fn warn_skew(now: SystemTime, seconds: u64, direction: &str) {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let last = LAST_SKEW_WARNING.load(Ordering::Relaxed);
+    if now < last + 60 {
+        return;
+    }
+    if LAST_SKEW_WARNING
+        .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        eprintln!(
+            "Upstream Date header is {} seconds {} our clock, check the backend clock or raise clock_skew_tolerance",
+            seconds, direction
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_age, expires_lifetime, heuristic_lifetime};
+    use hyper::header::{HeaderValue, AGE, DATE, EXPIRES, LAST_MODIFIED};
+    use hyper::{HeaderMap, StatusCode};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn ages() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let date = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let mut headers = HeaderMap::new();
+        assert_eq!(0, current_age(&headers, date, 60));
+
+        headers.insert(
+            DATE,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        assert_eq!(0, current_age(&headers, date + Duration::from_secs(60), 60));
+        assert_eq!(
+            61,
+            current_age(&headers, date + Duration::from_secs(61), 60)
+        );
+        // Dates in the future count as now.
+        assert_eq!(
+            0,
+            current_age(&headers, date - Duration::from_secs(3600), 60)
+        );
+
+        headers.insert(AGE, HeaderValue::from_static("100"));
+        assert_eq!(
+            100,
+            current_age(&headers, date + Duration::from_secs(61), 60)
+        );
+        assert_eq!(
+            200,
+            current_age(&headers, date + Duration::from_secs(200), 60)
+        );
+
+        headers.insert(AGE, HeaderValue::from_static("-1"));
+        headers.insert(DATE, HeaderValue::from_static("yesterday"));
+        assert_eq!(0, current_age(&headers, date, 60));
+    }
+
+    #[test]
+    fn lifetimes() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, expires_lifetime(&headers, now));
+        assert_eq!(None, heuristic_lifetime(&headers, StatusCode::OK, now));
+
+        // Relative to Date, whatever our clock says.
+        headers.insert(
+            DATE,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:00:00 GMT"),
+        );
+        headers.insert(
+            EXPIRES,
+            HeaderValue::from_static("Sun, 06 Nov 1994 09:00:00 GMT"),
+        );
+        assert_eq!(Some(3600), expires_lifetime(&headers, now));
+        headers.insert(
+            EXPIRES,
+            HeaderValue::from_static("Sun, 06 Nov 1994 07:00:00 GMT"),
+        );
+        assert_eq!(Some(0), expires_lifetime(&headers, now));
+        headers.insert(EXPIRES, HeaderValue::from_static("0"));
+        assert_eq!(Some(0), expires_lifetime(&headers, now));
+
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Sun, 06 Nov 1994 07:00:00 GMT"),
+        );
+        assert_eq!(Some(360), heuristic_lifetime(&headers, StatusCode::OK, now));
+        assert_eq!(None, heuristic_lifetime(&headers, StatusCode::FOUND, now));
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Sun, 06 Nov 1984 07:00:00 GMT"),
+        );
+        assert_eq!(
+            Some(24 * 60 * 60),
+            heuristic_lifetime(&headers, StatusCode::OK, now)
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Cache keys for GraphQL persisted queries, where clients send the hash of
+//! a query the server already knows instead of the query text (Apollo
+//! automatic persisted queries). The key is built from the operation hash,
+//! the operation name and the normalized variables, so GET and POST requests
+//! for the same operation share a cache entry.
+
+use crate::admin::percent_decode;
+use serde_json::{Map, Value};
+
+/// Builds the cache key of a persisted query sent with GET, from the query
+/// string parameters "extensions", "variables" and "operationName".
+pub(crate) fn key_from_query(path: &str, query: &str) -> Option<String> {
+    let mut request = Map::new();
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = percent_decode(parts.next().unwrap_or(""))?;
+        match name {
+            "extensions" | "variables" => {
+                let _ = request.insert(name.to_string(), serde_json::from_str(&value).ok()?);
+            }
+            "operationName" => {
+                let _ = request.insert(name.to_string(), Value::String(value));
+            }
+            _ => {}
+        }
+    }
+    key(path, &Value::Object(request))
+}
+
+/// Builds the cache key of a persisted query sent as JSON body with POST.
+pub(crate) fn key_from_body(path: &str, body: &[u8]) -> Option<String> {
+    key(path, &serde_json::from_slice(body).ok()?)
+}
+
+fn key(path: &str, request: &Value) -> Option<String> {
+    let hash = request
+        .pointer("/extensions/persistedQuery/sha256Hash")?
+        .as_str()?;
+    let operation = request
+        .get("operationName")
+        .and_then(Value::as_str)
+        .unwrap_or("");
+    // serde_json sorts object keys, so equal variables serialize equally.
+    let variables = request.get("variables").unwrap_or(&Value::Null);
+    Some(format!(
+        "GRAPHQL {} {} {} {}",
+        path, hash, operation, variables
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_from_body, key_from_query};
+
+    #[test]
+    fn persisted_query_keys() {
+        let from_get = key_from_query(
+            "/graphql",
+            "operationName=Items&variables=%7B%22b%22%3A1%2C%22a%22%3A2%7D\
+             &extensions=%7B%22persistedQuery%22%3A%7B%22version%22%3A1%2C%22sha256Hash%22%3A%22abc%22%7D%7D",
+        );
+        let from_post = key_from_body(
+            "/graphql",
+            br#"{"operationName": "Items", "variables": {"a": 2, "b": 1},
+                "extensions": {"persistedQuery": {"version": 1, "sha256Hash": "abc"}}}"#,
+        );
+        assert_eq!(
+            Some(r#"GRAPHQL /graphql abc Items {"a":2,"b":1}"#.to_string()),
+            from_get
+        );
+        assert_eq!(from_get, from_post);
+
+        // Plain queries are not persisted queries.
+        assert_eq!(None, key_from_query("/graphql", "query=%7B+a+%7D"));
+        assert_eq!(None, key_from_body("/graphql", br#"{"query": "{ a }"}"#));
+        assert_eq!(None, key_from_query("/graphql", "extensions=invalid"));
+    }
+}
@@ -0,0 +1,266 @@
+//! History of the last requests in memory, with their cache decision, the
+//! backend, the status and how long they took. The administration API can
+//! list and filter them, so transient problems can be looked into without
+//! access logging.
+
+use crate::ring::Ring;
+use crate::split_host;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the cache did with a request.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Decision {
+    Hit,
+    Miss,
+    // An expired response that upstream confirmed with a 304.
+    Revalidated,
+    // An expired response served because upstream failed.
+    Stale,
+    // Not cachable.
+    Pass,
+}
+
+impl Decision {
+    fn parse(decision: &str) -> Option<Decision> {
+        Some(match decision {
+            "hit" => Decision::Hit,
+            "miss" => Decision::Miss,
+            "revalidated" => Decision::Revalidated,
+            "stale" => Decision::Stale,
+            "pass" => Decision::Pass,
+            _ => return None,
+        })
+    }
+}
+
+/// What is found out about a request while it is handled. Kept in the
+/// request extensions.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Outcome(Arc<Mutex<Details>>);
+
+#[derive(Clone, Debug, Default)]
+struct Details {
+    cache_key: Option<String>,
+    decision: Option<Decision>,
+    backend: Option<String>,
+}
+
+impl Outcome {
+    /// Notes the cache key, requests without one are passed to upstream.
+    pub(crate) fn cache_key(&self, cache_key: &Option<String>) {
+        let mut details = self.0.lock().unwrap();
+        details.cache_key = cache_key.clone();
+        details.decision = Some(match cache_key {
+            Some(_) => Decision::Miss,
+            None => Decision::Pass,
+        });
+    }
+
+    pub(crate) fn decide(&self, decision: Decision) {
+        self.0.lock().unwrap().decision = Some(decision);
+    }
+
+    /// Notes the backend origin or the director that the request was sent
+    /// to.
+    pub(crate) fn backend(&self, backend: &str) {
+        self.0.lock().unwrap().backend = Some(backend.to_string());
+    }
+}
+
+/// A request in the history.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Entry {
+    // Unix timestamp of the request.
+    timestamp: u64,
+    method: String,
+    uri: String,
+    // The key without host, like a URL.
+    cache_key: Option<String>,
+    host: Option<String>,
+    decision: Option<Decision>,
+    backend: Option<String>,
+    // None if the response failed.
+    status: Option<u16>,
+    duration_ms: u64,
+}
+
+impl Entry {
+    pub(crate) fn new(
+        started: SystemTime,
+        method: String,
+        uri: String,
+        outcome: &Outcome,
+        status: Option<u16>,
+        duration: Duration,
+    ) -> Entry {
+        let details = outcome.0.lock().unwrap().clone();
+        let (cache_key, host) = details
+            .cache_key
+            .as_deref()
+            .map(split_host)
+            .map(|(key, host)| (key.into_owned(), host.map(str::to_string)))
+            .unzip();
+        Entry {
+            timestamp: started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |timestamp| timestamp.as_secs()),
+            method,
+            uri,
+            cache_key,
+            host: host.flatten(),
+            decision: details.decision,
+            backend: details.backend,
+            status,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// Conditions that listed entries must meet.
+#[derive(Debug, Default)]
+pub(crate) struct Filter {
+    pub(crate) decision: Option<Decision>,
+    /// A status code like "404" or a class like "5xx".
+    pub(crate) status: Option<String>,
+    pub(crate) path_prefix: Option<String>,
+    pub(crate) min_duration_ms: Option<u64>,
+}
+
+impl Filter {
+    /// Sets the decision from its name, false if it is unknown.
+    pub(crate) fn decision(&mut self, decision: &str) -> bool {
+        self.decision = Decision::parse(decision);
+        self.decision.is_some()
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.decision
+            .is_none_or(|decision| entry.decision == Some(decision))
+            && self.status.as_ref().is_none_or(|status| {
+                let code = entry
+                    .status
+                    .map_or_else(String::new, |code| code.to_string());
+                match status.strip_suffix("xx") {
+                    Some(class) => code.starts_with(class) && !class.is_empty(),
+                    None => code == *status,
+                }
+            })
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| entry.uri.starts_with(prefix.as_str()))
+            && self
+                .min_duration_ms
+                .is_none_or(|min| entry.duration_ms >= min)
+    }
+}
+
+/// The last requests.
+#[derive(Debug)]
+pub(crate) struct History {
+    entries: Ring<Entry>,
+}
+
+impl History {
+    /// A history of at most `capacity` requests, disabled with 0.
+    pub(crate) fn new(capacity: usize) -> History {
+        History {
+            entries: Ring::new(capacity),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.entries.is_enabled()
+    }
+
+    /// Adds a request and drops the oldest one when the history is full.
+    pub(crate) fn record(&self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    /// The newest entries that match the filter, at most `limit`.
+    pub(crate) fn list(&self, filter: &Filter, limit: usize) -> Vec<Entry> {
+        self.entries
+            .items()
+            .iter()
+            .rev()
+            .filter(|entry| filter.matches(entry))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decision, Entry, Filter, History, Outcome};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn history() {
+        let history = History::new(3);
+        let record = |uri: &str, cache_key: Option<&str>, status: u16, millis: u64| {
+            let outcome = Outcome::default();
+            outcome.cache_key(&cache_key.map(str::to_string));
+            if status == 200 && millis == 0 {
+                outcome.decide(Decision::Hit);
+            }
+            history.record(Entry::new(
+                UNIX_EPOCH + Duration::from_secs(1_000),
+                "GET".to_string(),
+                uri.to_string(),
+                &outcome,
+                Some(status),
+                Duration::from_millis(millis),
+            ));
+        };
+        // Dropped once the history is full.
+        record("/old", None, 200, 5);
+        record("/blog/a", Some("/blog/a"), 200, 0);
+        record("/blog/b", Some("/blog/b"), 503, 250);
+        record("/login", None, 302, 20);
+
+        let uris = |filter: &Filter, limit: usize| -> Vec<String> {
+            history
+                .list(filter, limit)
+                .into_iter()
+                .map(|entry| entry.uri)
+                .collect()
+        };
+        assert_eq!(
+            vec!["/login", "/blog/b", "/blog/a"],
+            uris(&Filter::default(), 10)
+        );
+        assert_eq!(vec!["/login"], uris(&Filter::default(), 1));
+
+        let mut filter = Filter::default();
+        assert!(filter.decision("miss"));
+        assert_eq!(vec!["/blog/b"], uris(&filter, 10));
+        assert!(!filter.decision("bypass"));
+
+        let filter = Filter {
+            status: Some("5xx".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(vec!["/blog/b"], uris(&filter, 10));
+        let filter = Filter {
+            path_prefix: Some("/blog/".to_string()),
+            min_duration_ms: Some(100),
+            ..Filter::default()
+        };
+        assert_eq!(vec!["/blog/b"], uris(&filter, 10));
+        let filter = Filter {
+            status: Some("200".to_string()),
+            decision: Some(Decision::Hit),
+            ..Filter::default()
+        };
+        assert_eq!(vec!["/blog/a"], uris(&filter, 10));
+
+        let entry = &history.list(&Filter::default(), 1)[0];
+        assert_eq!(Some(Decision::Pass), entry.decision);
+        assert_eq!(1_000, entry.timestamp);
+    }
+}
@@ -0,0 +1,171 @@
+//! Cache hits and misses by route and by content type, so operators can see
+//! which parts of the site benefit from caching and which rules need tuning.
+
+use crate::routes::Route;
+use hyper::header::CONTENT_TYPE;
+use hyper::HeaderMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Upstream decides the content types, so their number is capped. Further
+// ones are counted together.
+const MAX_CONTENT_TYPES: usize = 100;
+const OTHER_CONTENT_TYPES: &str = "(other)";
+const NO_CONTENT_TYPE: &str = "(none)";
+const NO_ROUTE: &str = "(no route)";
+
+#[derive(Debug, Default)]
+struct Counts {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// Hits and misses of cachable requests.
+#[derive(Debug)]
+pub(crate) struct HitRatios {
+    // By route index, the last one counts requests without a route.
+    routes: Vec<Counts>,
+    content_types: Mutex<BTreeMap<String, Counts>>,
+}
+
+/// Hits and misses as reported by the administration API.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct HitRatio {
+    hits: usize,
+    misses: usize,
+    // Hits divided by all requests, None before the first request.
+    ratio: Option<f64>,
+}
+
+/// The hit ratios by route path prefix and by content type.
+#[derive(Debug, Serialize)]
+pub(crate) struct HitRatiosStatus {
+    routes: BTreeMap<String, HitRatio>,
+    content_types: BTreeMap<String, HitRatio>,
+}
+
+impl Counts {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn status(&self) -> HitRatio {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        HitRatio {
+            hits,
+            misses,
+            ratio: Some(hits + misses)
+                .filter(|total| *total > 0)
+                .map(|total| hits as f64 / total as f64),
+        }
+    }
+}
+
+impl HitRatios {
+    pub(crate) fn new(routes: &[Route]) -> HitRatios {
+        HitRatios {
+            routes: (0..=routes.len()).map(|_| Counts::default()).collect(),
+            content_types: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Counts a hit or miss of a request on a route, by the content type of
+    /// its response.
+    pub(crate) fn record(&self, route_index: Option<usize>, headers: &HeaderMap, hit: bool) {
+        let index = route_index.unwrap_or(self.routes.len() - 1);
+        self.routes[index].record(hit);
+        let content_type = media_type(headers);
+        let mut content_types = self.content_types.lock().unwrap();
+        if let Some(counts) = content_types.get(&content_type) {
+            counts.record(hit);
+            return;
+        }
+        let label = if content_types.len() < MAX_CONTENT_TYPES {
+            content_type
+        } else {
+            OTHER_CONTENT_TYPES.to_string()
+        };
+        content_types.entry(label).or_default().record(hit);
+    }
+
+    pub(crate) fn status(&self, routes: &[Route]) -> HitRatiosStatus {
+        let labels = routes
+            .iter()
+            .map(|route| route.path_prefix.as_str())
+            .chain(Some(NO_ROUTE));
+        HitRatiosStatus {
+            routes: labels
+                .zip(&self.routes)
+                .map(|(label, counts)| (label.to_string(), counts.status()))
+                .collect(),
+            content_types: self
+                .content_types
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(content_type, counts)| (content_type.clone(), counts.status()))
+                .collect(),
+        }
+    }
+}
+
+/// The content type without parameters like the charset, in lower case.
+fn media_type(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| NO_CONTENT_TYPE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HitRatio, HitRatios, MAX_CONTENT_TYPES};
+    use crate::routes::Route;
+    use hyper::header::{HeaderValue, CONTENT_TYPE};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn hit_ratios() {
+        let routes = vec![Route {
+            path_prefix: "/blog/".to_string(),
+            ..Route::default()
+        }];
+        let ratios = HitRatios::new(&routes);
+        let html = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+            headers
+        };
+        ratios.record(Some(0), &html("text/html; charset=utf-8"), false);
+        ratios.record(Some(0), &html("Text/HTML"), true);
+        ratios.record(Some(0), &html("text/html"), true);
+        ratios.record(None, &HeaderMap::new(), false);
+
+        let status = ratios.status(&routes);
+        assert_eq!(
+            HitRatio {
+                hits: 2,
+                misses: 1,
+                ratio: Some(2.0 / 3.0),
+            },
+            status.routes["/blog/"]
+        );
+        assert_eq!(1, status.routes["(no route)"].misses);
+        assert_eq!(2, status.content_types["text/html"].hits);
+        assert_eq!(Some(0.0), status.content_types["(none)"].ratio);
+
+        for index in 0..MAX_CONTENT_TYPES {
+            ratios.record(None, &html(&format!("application/x-{}", index)), true);
+        }
+        let status = ratios.status(&routes);
+        assert_eq!(MAX_CONTENT_TYPES + 1, status.content_types.len());
+        assert_eq!(2, status.content_types["(other)"].hits);
+    }
+}
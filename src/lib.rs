@@ -1,31 +1,130 @@
-use crate::cache::LruCache;
+use crate::backend::UpstreamClient;
+use crate::ban::{Ban, BanList};
 use crate::cache::MemorySizable;
+use crate::cache::ShardedLruCache;
+use crate::clock::Clock;
+use crate::config::PublicBase;
+use crate::deadline::BackendTimeout;
+use crate::delivery::{DeliveryTimeout, DeliveryTracker};
 use crate::errors::ResultExt;
 use crate::errors::*;
+use crate::history::{Decision, Entry, Outcome};
+use crate::limiter::UpstreamLimiter;
+use crate::metrics::Metrics;
+use crate::mirror::MirrorClient;
+use crate::parse::CacheControl;
+use crate::state::ProxyState;
+use crate::vary::{Variants, VaryValues};
+use bytes::Bytes;
 use error_chain::bail;
-#[cfg(test)]
-use fake_clock::FakeClock as Instant;
+use futures::future::Either;
+use futures::sync::oneshot;
 use futures::{Future, Stream};
 use http::Method;
-use hyper::client::HttpConnector;
 use hyper::header::HeaderName;
-use hyper::header::{HeaderValue, CACHE_CONTROL, COOKIE, SERVER, VIA};
-use hyper::server::conn::AddrStream;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::Client;
+use hyper::header::{
+    HeaderValue, AGE, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, ETAG, HOST, IF_MATCH,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE, SERVER,
+    SET_COOKIE, TRANSFER_ENCODING, VIA, WARNING, X_CONTENT_TYPE_OPTIONS,
+};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
 use hyper::StatusCode;
 use hyper::Version;
-use hyper::{Body, HeaderMap, Request, Response, Server};
-use regex::Regex;
+use hyper::{Body, HeaderMap, Request, Response, Uri};
+use regex::RegexSet;
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::mem::size_of_val;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-#[cfg(not(test))]
-use std::time::Instant;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
 use tokio::runtime::Runtime;
+use tokio::timer::Delay;
+use twox_hash::XxHash3_128;
+
+mod admin;
+mod audit;
+mod auth;
+mod backend;
+mod ban;
+mod builder;
+pub mod cache;
+mod chaos;
+mod checksum;
+mod chunks;
+pub mod clock;
+mod config;
+mod conformance;
+mod deadline;
+mod delivery;
+mod director;
+mod dry_run;
+mod encoding;
+mod failover;
+mod freshness;
+mod graphql;
+mod history;
+mod hit_ratio;
+mod limiter;
+mod listener;
+mod metrics;
+mod mirror;
+mod origin;
+mod parse;
+mod path;
+mod placeholder;
+mod policy;
+mod post;
+mod query;
+mod ranges;
+mod readiness;
+mod recorder;
+mod resume;
+mod ring;
+mod routes;
+mod schedule;
+mod security;
+mod shutdown;
+mod sigv4;
+mod simulator;
+mod sniff;
+mod state;
+mod tee;
+#[cfg(feature = "test_util")]
+pub mod test_util;
+mod throttle;
+mod tunnel;
+mod vary;
 
-mod cache;
+pub use crate::admin::{AdminScope, AdminToken};
+pub use crate::auth::BackendAuth;
+pub use crate::backend::{Backend, BackendTls, OutboundProxy, TlsVersion, UpstreamAbort};
+pub use crate::builder::{Builder, ServerHandle};
+pub use crate::chaos::Chaos;
+pub use crate::checksum::ChecksumAlgorithm;
+pub use crate::config::Config;
+pub use crate::director::{Director, FallbackContent, PoolBackend};
+pub use crate::failover::Failover;
+pub use crate::listener::Listener;
+pub use crate::mirror::Mirror;
+pub use crate::path::TrailingSlash;
+pub use crate::placeholder::Placeholder;
+pub use crate::policy::{CachePolicy, StatusTtl};
+pub use crate::query::QueryNormalization;
+pub use crate::readiness::Readiness;
+pub use crate::routes::{BodyHook, BodyTransform, LinkRewrite, Route};
+pub use crate::schedule::{Cron, Schedule};
+pub use crate::security::SecurityHeaders;
+pub use crate::sniff::ContentTypeGuard;
+pub use crate::throttle::ClientClass;
 
 mod errors {
     use error_chain::*;
@@ -34,45 +133,149 @@ mod errors {
     error_chain! {}
 }
 
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
 fn proxy_request(
     mut request: Request<Body>,
     source_address: SocketAddr,
     port: u16,
-    upstream_port: u16,
-    client: &Client<HttpConnector>,
+    upstream: &Upstream,
     mut cache: Cache,
-) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
-    let cache_key = cache.cache_key(&request);
-
-    if let Some(response) = cache.lookup(&cache_key) {
-        return Box::new(futures::future::ok(response));
-    }
-
-    let upstream_uri = {
-        // 127.0.0.1 is hard coded here for now because we assume that upstream
-        // is on the same host. Should be made configurable later.
-        let mut upstream_uri =
-            format!("http://127.0.0.1:{}{}", upstream_port, request.uri().path());
-        if let Some(query) = request.uri().query() {
-            upstream_uri.push('?');
-            upstream_uri.push_str(query);
-        }
-        match upstream_uri.parse() {
-            Ok(u) => u,
-            _ => {
-                // We can't actually test this because parsing the URI never
-                // fails. However, should that change at any point this is the
-                // right thing to do.
-                return Box::new(futures::future::ok(
-                    Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body("Invalid upstream URI".into())
-                        .unwrap(),
-                ));
-            }
+    state: &Arc<ProxyState>,
+) -> ResponseFuture {
+    let config = &state.config;
+    if request.method() == Method::CONNECT {
+        return tunnel::connect(request, config);
+    }
+
+    let route = routes::find_route(&config.routes, request.uri().path());
+    // The cache key of a POST request depends on its body, so that is read
+    // first and the request is handled again.
+    if post::wants_body_hash(&request, route) {
+        let graphql = route.is_some_and(|route| route.graphql);
+        let upstream = upstream.clone();
+        let state = state.clone();
+        return Box::new(post::hash_body(request, graphql).and_then(move |request| {
+            proxy_request(request, source_address, port, &upstream, cache, &state)
+        }));
+    }
+
+    if request.method() == Method::GET && route.is_some_and(|route| route.graphql) {
+        let key = request
+            .uri()
+            .query()
+            .and_then(|query| graphql::key_from_query(request.uri().path(), query));
+        if let Some(key) = key {
+            request.extensions_mut().insert(CacheKey(key));
+        }
+    }
+
+    if request.method() == Method::GET && request.extensions().get::<CacheKey>().is_none() {
+        if let Some(key) = route.and_then(|route| route.cache_busting_key(request.uri())) {
+            request.extensions_mut().insert(CacheKey(key));
+        }
+    }
+
+    let stripped_cookies = strip_cookies(&mut request, &config.strip_cookies);
+    let refresh = take_refresh_header(&mut request, state);
+
+    let session = session_hash(request.headers(), &state.session_cookies);
+    let secure = state.public_base.as_ref().is_some_and(|base| base.secure);
+    let cache_key = cache.cache_key(&request, session.as_ref(), config, secure);
+    let micro_cache_ttl = config.micro_cache_ttl.filter(|_| session.is_some());
+    let outcome = request
+        .extensions()
+        .get::<Outcome>()
+        .cloned()
+        .unwrap_or_default();
+    outcome.cache_key(&cache_key);
+
+    // Requests that are already on their way stay with their origin when it
+    // is switched.
+    let origin = state.origin();
+    // gRPC bodies must stream through unbuffered with their trailers, so
+    // routes don't transform them. Routes match the public path.
+    let route_index = if is_grpc(request.headers()) {
+        None
+    } else {
+        config
+            .routes
+            .iter()
+            .position(|route| route.matches(request.uri().path()))
+    };
+    let director = route_index
+        .and_then(|index| config.routes[index].director.as_ref())
+        .and_then(|name| {
+            config
+                .directors
+                .iter()
+                .position(|director| &director.name == name)
+        });
+    if dry_run::is_dry_run(&request, state) {
+        let decisions = dry_run::Decisions {
+            route,
+            director,
+            origin: origin.url(),
+            cache_key: &cache_key,
+            refresh,
+            stripped_cookies,
+        };
+        return Box::new(futures::future::ok(dry_run::response(
+            &request,
+            source_address,
+            &cache,
+            state,
+            decisions,
+        )));
+    }
+
+    // HEAD requests are answered from the cached GET response, hyper leaves
+    // out the body. Responses to HEAD requests have no body, so they are only
+    // cached apart from that.
+    let head = request.method() == Method::HEAD;
+    let head_key = cache_key
+        .as_ref()
+        .filter(|_| head && config.cache_head)
+        .map(|key| format!("{} HEAD", key));
+    if refresh {
+        // Drop the old entry even if the new response is not cachable.
+        for key in cache_key.iter().chain(&head_key) {
+            cache.remove(key);
+        }
+    } else if let Some(response) = cache
+        .lookup(&cache_key, request.headers())
+        .or_else(|| cache.lookup(&head_key, request.headers()))
+    {
+        record_cache_use(state, &cache, route_index, &cache_key, &response, true);
+        outcome.decide(Decision::Hit);
+        return ranges::respond(&request, response);
+    }
+    let store_key = if head { head_key } else { cache_key.clone() };
+    // The variant of a response is chosen by the client's headers, not by
+    // the ones we add for upstream.
+    let vary_headers = request.headers().clone();
+    // The client's time budget started when its request arrived.
+    if let Some(deadline) = deadline::from_headers(request.headers(), Instant::now()) {
+        request.extensions_mut().insert(deadline);
+    }
+
+    let upstream_uri = match upstream_uri(request.uri(), route, origin.url()).parse() {
+        Ok(u) => u,
+        _ => {
+            // We can't actually test this because parsing the URI never
+            // fails. However, should that change at any point this is the
+            // right thing to do.
+            return Box::new(futures::future::ok(
+                Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("Invalid upstream URI".into())
+                    .unwrap(),
+            ));
         }
     };
 
+    let public_base = &state.public_base;
+    let location_rewrites = location_rewrites(&request, origin.url(), config, public_base, route);
     *request.uri_mut() = upstream_uri;
 
     {
@@ -81,55 +284,751 @@ fn proxy_request(
             HeaderName::from_static("x-forwarded-for"),
             source_address.ip().to_string().parse().unwrap(),
         );
+        // Behind a TLS terminator the public port and protocol differ from
+        // our own listener.
+        let (public_port, proto) = match public_base {
+            Some(ref base) => (base.port, if base.secure { "https" } else { "http" }),
+            None => (port, "http"),
+        };
         headers.append(
             HeaderName::from_static("x-forwarded-port"),
-            port.to_string().parse().unwrap(),
+            public_port.to_string().parse().unwrap(),
+        );
+        headers.append(
+            HeaderName::from_static("x-forwarded-proto"),
+            HeaderValue::from_static(proto),
         );
     }
+    let secure_cookies = public_base.as_ref().is_some_and(|base| base.secure);
 
-    let mut cloned_cache = cache.clone();
+    if let (Some(route), Some(key)) = (route, &cache_key) {
+        if let (Some(chunk_size), &Method::GET) = (route.chunk_size, request.method()) {
+            return chunks::respond(
+                request,
+                key.clone(),
+                chunk_size,
+                route.prefetch_chunks,
+                cache,
+                upstream.clone(),
+                state.clone(),
+            );
+        }
+    }
+
+    let cloned_cache = cache.clone();
+    let stale_cache = cache.clone();
 
-    Box::new(client.request(request).then(move |result| {
-        let our_response = match result {
+    let upstream = upstream.clone();
+    let state = state.clone();
+    let config = &state.config;
+    let route = route_index.map(|index| &config.routes[index]);
+    // Built up front because the request is gone when upstream fails.
+    let error_response = route
+        .and_then(|route| route.error_response(&request_id(request.headers())))
+        .unwrap_or_else(bad_gateway);
+    match director {
+        Some(director) => outcome.backend(&config.directors[director].name),
+        None => outcome.backend(origin.url()),
+    }
+    // An expired response is revalidated with its validators, unless the
+    // client has conditions of its own. With HEAD probes the backend is
+    // asked with a HEAD request first, the GET only follows if the response
+    // changed.
+    let expired = if request.method() == Method::GET && !has_conditions(request.headers()) {
+        cache.lookup_expired(&cache_key, &vary_headers)
+    } else {
+        None
+    };
+    let mut probe = None;
+    if let Some(ref expired) = expired {
+        let mut conditions = HeaderMap::new();
+        let validators = [(ETAG, IF_NONE_MATCH), (LAST_MODIFIED, IF_MODIFIED_SINCE)];
+        for (validator, condition) in validators.iter() {
+            if let Some(value) = expired.headers().get(validator) {
+                conditions.insert(condition, value.clone());
+            }
+        }
+        if config.backend.head_probes && director.is_none() {
+            let mut head = Request::head(request.uri().clone())
+                .body(Body::empty())
+                .unwrap();
+            *head.headers_mut() = request.headers().clone();
+            head.headers_mut().extend(conditions);
+            let upstream = upstream.clone();
+            let state = state.clone();
+            probe = Some(
+                config
+                    .backend
+                    .prepare_request(head)
+                    .and_then(move |head| limited_upstream_request(head, upstream, state)),
+            );
+        } else {
+            request.headers_mut().extend(conditions);
+        }
+    }
+
+    let upstream_state = state.clone();
+    // Only misses wait for a slot, hits were answered above.
+    let response: ResponseFuture =
+        match director {
+            Some(director) => Box::new(routes::transform_request(route, request).and_then(
+                move |request| director::send(request, director, upstream, upstream_state),
+            )),
+            None => {
+                let backend = config.backend.clone();
+                let in_flight = origin.start();
+                Box::new(
+                    routes::transform_request(route, request)
+                        .and_then(move |request| backend.prepare_request(request))
+                        .and_then(move |request| {
+                            limited_upstream_request(request, upstream, upstream_state)
+                        })
+                        .then(move |result| {
+                            drop(in_flight);
+                            result
+                        }),
+                )
+            }
+        };
+    let response: ResponseFuture = match probe {
+        Some(probe) => Box::new(probe.then(move |result| match result {
+            Ok(ref probed) if probed.status() == StatusCode::NOT_MODIFIED => {
+                Either::A(futures::future::result(result))
+            }
+            _ => Either::B(response),
+        })),
+        None => response,
+    };
+    Box::new(response.then(move |result| {
+        match result {
             Ok(mut response) => {
-                let version = match response.version() {
-                    Version::HTTP_09 => "0.9",
-                    Version::HTTP_10 => "1.0",
-                    Version::HTTP_11 => "1.1",
-                    Version::HTTP_2 => "2.0",
+                if let (StatusCode::NOT_MODIFIED, Some(expired)) = (response.status(), expired) {
+                    filter_response_headers(response.headers_mut(), &state.config);
+                    let response = stale_cache.revalidated(
+                        &cache_key,
+                        expired,
+                        response.headers(),
+                        &vary_headers,
+                        &state.config,
+                    );
+                    // The body came from the cache.
+                    outcome.decide(Decision::Revalidated);
+                    record_cache_use(
+                        &state,
+                        &stale_cache,
+                        route_index,
+                        &cache_key,
+                        &response,
+                        true,
+                    );
+                    return Either::B(futures::future::ok(response));
+                }
+                // Like grace mode in Varnish, an expired response is
+                // better than an error.
+                if is_upstream_error(response.status()) {
+                    if let Some(stale) = stale_cache.lookup_stale(&cache_key, &vary_headers) {
+                        outcome.decide(Decision::Stale);
+                        return Either::B(futures::future::ok(stale));
+                    }
+                }
+                let config = &state.config;
+                let route = route_index.map(|index| &config.routes[index]);
+                let mut response = match route {
+                    Some(route) => match route.cap_response_size(response) {
+                        Some(response) => response,
+                        None => {
+                            eprintln!("Upstream response exceeds the maximum response size");
+                            return Either::B(futures::future::ok(error_response));
+                        }
+                    },
+                    None => response,
                 };
+                let via = state::via(response.version());
                 {
                     let headers = response.headers_mut();
 
-                    headers.append(VIA, format!("{} rustnish-0.0.1", version).parse().unwrap());
+                    filter_response_headers(headers, config);
+                    routes::rewrite_location(&location_rewrites, headers);
+                    if secure_cookies {
+                        secure_set_cookies(headers);
+                    }
+
+                    headers.append(VIA, via);
 
                     // Append a "Server" header if not already present.
                     if !headers.contains_key(SERVER) {
-                        headers.insert(SERVER, "rustnish".parse().unwrap());
+                        headers.insert(SERVER, HeaderValue::from_static("rustnish"));
+                    }
+                    security::add_headers(state.security_headers(route_index), headers);
+                    if route.is_some_and(|route| route.nosniff) {
+                        headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
                     }
                 }
 
-                // Put the response into the cache if possible.
-                cloned_cache.store(cache_key, response)
+                record_cache_use(
+                    &state,
+                    &cloned_cache,
+                    route_index,
+                    &cache_key,
+                    &response,
+                    false,
+                );
+
+                // Put the response into the cache if possible. If the body
+                // breaks off while we read it there is nothing we can send.
+                let route_state = state.clone();
+                Either::A(
+                    sniff::guard(route, response)
+                        .and_then(move |response| {
+                            let route = route_index.map(|index| &route_state.config.routes[index]);
+                            let link_patterns = route_index
+                                .map_or(&[][..], |index| &route_state.link_patterns[index]);
+                            routes::transform_response(route, link_patterns, response)
+                                .and_then(move |response| {
+                                    cloned_cache.store(
+                                        store_key,
+                                        head,
+                                        response,
+                                        &vary_headers,
+                                        &route_state.config,
+                                        micro_cache_ttl,
+                                    )
+                                })
+                                .map_err(|_| ())
+                        })
+                        .or_else(|_| Ok(error_response)),
+                )
             }
-            Err(_) => {
-                // For security reasons do not show the exact error to end users.
-                // @todo Log the error.
-                Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body("Something went wrong, please try again later.".into())
-                    .unwrap()
+            Err(_) => Either::B(futures::future::ok(
+                match stale_cache.lookup_stale(&cache_key, &vary_headers) {
+                    Some(stale) => {
+                        outcome.decide(Decision::Stale);
+                        stale
+                    }
+                    None => error_response,
+                },
+            )),
+        }
+    }))
+}
+
+// Separates the host from the URL in cache keys.
+const KEY_HOST: &str = " host:";
+
+/// The host of a cache key: the Host header, which is normalized already,
+/// without the default port of the scheme that clients use.
+fn key_host(host: &HeaderValue, secure: bool) -> &str {
+    let host = host.to_str().unwrap_or("");
+    let default_port = if secure { ":443" } else { ":80" };
+    host.strip_suffix(default_port).unwrap_or(host)
+}
+
+/// Adds a host to a cache key, as given to an admin command.
+fn with_host(key: &str, host: &str) -> String {
+    format!("{}{}{}", key, KEY_HOST, host.to_ascii_lowercase())
+}
+
+/// Splits a cache key into the key without its host and the host. Admin
+/// commands and bans see keys like URLs, so that they match all hosts.
+pub(crate) fn split_host(cache_key: &str) -> (Cow<'_, str>, Option<&str>) {
+    let start = match cache_key.rfind(KEY_HOST) {
+        Some(start) => start,
+        None => return (Cow::Borrowed(cache_key), None),
+    };
+    let host_start = start + KEY_HOST.len();
+    let end = cache_key[host_start..]
+        .find(' ')
+        .map_or(cache_key.len(), |end| host_start + end);
+    let without_host = if end == cache_key.len() {
+        Cow::Borrowed(&cache_key[..start])
+    } else {
+        Cow::Owned(format!("{}{}", &cache_key[..start], &cache_key[end..]))
+    };
+    (without_host, Some(&cache_key[host_start..end]))
+}
+
+/// Identifies the session of a request by a hash of its session cookies, so
+/// that session IDs don't show up in cache keys.
+fn session_hash(headers: &HeaderMap, session_cookies: &RegexSet) -> Option<String> {
+    let mut cookies: Vec<(&str, &str)> = parse::cookies(headers)
+        .filter(|(name, _)| session_cookies.is_match(name))
+        .collect();
+    if cookies.is_empty() {
+        return None;
+    }
+    cookies.sort_unstable();
+    let mut session = String::new();
+    for (name, value) in cookies {
+        session.push_str(name);
+        session.push('=');
+        session.push_str(value);
+        session.push(';');
+    }
+    Some(format!("{:032x}", XxHash3_128::oneshot(session.as_bytes())))
+}
+
+/// Checks if a response to a request with a session may be micro-cached.
+fn is_session_cachable(headers: &HeaderMap) -> bool {
+    !headers.contains_key(SET_COOKIE) && !CacheControl::parse(headers).no_store
+}
+
+/// Turns a panic while handling a request, for example in a body hook, into
+/// a 500 response for that request. The connection and other requests are
+/// not affected.
+fn isolate_panics(
+    response: ResponseFuture,
+    description: String,
+    metrics: Arc<Metrics>,
+) -> ResponseFuture {
+    Box::new(
+        AssertUnwindSafe(response)
+            .catch_unwind()
+            .then(move |result| match result {
+                Ok(result) => result,
+                Err(panic) => {
+                    metrics.request_panicked();
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .copied()
+                        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                        .unwrap_or("unknown cause");
+                    eprintln!("Panic while handling {}: {}", description, message);
+                    Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body("Internal Server Error".into())
+                        .unwrap())
+                }
+            }),
+    )
+}
+
+/// The client for upstream requests, the limit on how many of them may be in
+/// flight and the metrics about their connections.
+#[derive(Clone)]
+struct Upstream {
+    client: UpstreamClient,
+    limiter: Arc<UpstreamLimiter>,
+    metrics: Arc<Metrics>,
+}
+
+/// Sends the request to upstream once the concurrency limiter has a slot,
+/// and gives up at the deadline of the request or the backend timeout.
+fn limited_upstream_request(
+    mut request: Request<Body>,
+    upstream: Upstream,
+    state: Arc<ProxyState>,
+) -> ResponseFuture {
+    let backend_timeout = request
+        .extensions()
+        .get::<BackendTimeout>()
+        .map(|timeout| timeout.0)
+        .or(state.config.backend.timeout_ms);
+    let deadline = deadline::effective(
+        request.extensions().get().cloned(),
+        backend_timeout,
+        Instant::now(),
+    );
+    let grpc = is_grpc(request.headers());
+    let response = upstream.limiter.acquire().and_then(move |permit| {
+        if let Some(deadline) = deadline {
+            deadline::propagate(request.headers_mut(), deadline, Instant::now());
+        }
+        let metrics = upstream.metrics.clone();
+        send_upstream(request, &upstream.client, &state.config).then(move |result| {
+            permit.finish(match result {
+                Ok(ref response) => {
+                    metrics.upstream_response(response);
+                    is_overloaded(response.status())
+                }
+                Err(_) => true,
+            });
+            result
+        })
+    });
+    match deadline {
+        Some(deadline) => Box::new(deadline::limit(response, deadline, grpc)),
+        None => Box::new(response),
+    }
+}
+
+/// Sends the request to upstream, or answers it from recordings in replay
+/// mode. In record mode the exchange is written to disk. Configured faults
+/// are injected last.
+fn send_upstream(
+    request: Request<Body>,
+    client: &UpstreamClient,
+    config: &Config,
+) -> ResponseFuture {
+    if let Some(ref directory) = config.replay_dir {
+        return Box::new(futures::future::ok(recorder::replay(directory, &request)));
+    }
+    let recorded_request = config
+        .record_dir
+        .as_ref()
+        .map(|directory| (directory.clone(), recorder::RecordedRequest::new(&request)));
+    let response = resume::request(client, request, config.backend.upstream_abort);
+    let response: ResponseFuture = match recorded_request {
+        Some((directory, recorded_request)) => Box::new(
+            response
+                .and_then(move |response| recorder::record(directory, recorded_request, response)),
+        ),
+        None => response,
+    };
+    match config.chaos {
+        Some(ref chaos) => chaos.inject(response),
+        None => response,
+    }
+}
+
+/// Checks that a body has the length announced in the Content-Length header,
+/// if there is one.
+fn is_complete(headers: &HeaderMap, length: usize) -> bool {
+    match headers.get(CONTENT_LENGTH) {
+        Some(value) => {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                == Some(length)
+        }
+        None => true,
+    }
+}
+
+/// Checks if a cached variant is fresh enough for the Cache-Control
+/// directives of a request.
+fn is_acceptable(variant: &Variant, directives: &CacheControl, now: Instant) -> bool {
+    let age = now.saturating_duration_since(variant.created);
+    if directives
+        .max_age
+        .is_some_and(|max_age| age > Duration::from_secs(max_age))
+    {
+        return false;
+    }
+    if let Some(min_fresh) = directives.min_fresh {
+        return variant.expires > now + Duration::from_secs(min_fresh);
+    }
+    let max_stale = Duration::from_secs(directives.max_stale.unwrap_or(0));
+    variant
+        .expires
+        .checked_add(max_stale)
+        .is_none_or(|stale_until| stale_until > now)
+}
+
+/// Checks if an upstream status code counts as error for stale-if-error.
+fn is_upstream_error(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Checks if an upstream status code means that the backend cannot keep up.
+fn is_overloaded(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Counts a hit or miss of a cachable request for the hit ratios and logs it
+/// for the simulator.
+fn record_cache_use(
+    state: &ProxyState,
+    cache: &Cache,
+    route_index: Option<usize>,
+    cache_key: &Option<String>,
+    response: &Response<Body>,
+    hit: bool,
+) {
+    if let Some(ref key) = cache_key {
+        state
+            .hit_ratios
+            .record(route_index, response.headers(), hit);
+        let ttl = cache.get_max_age(response, &state.config);
+        state.request_log.record(key, cache.clock.now(), hit, ttl);
+    }
+}
+
+/// Builds the URI that an incoming request is forwarded to.
+fn upstream_uri(uri: &Uri, route: Option<&Route>, upstream_origin: &str) -> String {
+    let path = match route {
+        Some(route) => route.upstream_path(uri.path()),
+        None => uri.path().to_string(),
+    };
+    let mut upstream_uri = format!("{}{}", upstream_origin, path);
+    if let Some(query) = uri.query() {
+        upstream_uri.push('?');
+        upstream_uri.push_str(query);
+    }
+    upstream_uri
+}
+
+/// Returns the mappings for Location headers of upstream responses, so that
+/// redirects do not leak the internal upstream address.
+fn location_rewrites(
+    request: &Request<Body>,
+    upstream_origin: &str,
+    config: &Config,
+    public_base: &Option<PublicBase>,
+    route: Option<&Route>,
+) -> Vec<LinkRewrite> {
+    let mut rewrites = config.location_rewrites.clone();
+    let mut origins = Vec::new();
+    if config.rewrite_upstream_location {
+        let public_origin = match public_base {
+            Some(base) => Some(base.url.clone()),
+            None => match request.headers().get(HOST).map(|host| host.to_str()) {
+                Some(Ok(host)) => Some(format!("http://{}", host)),
+                _ => None,
+            },
+        };
+        if let Some(public_origin) = public_origin {
+            // A backend on this machine might also call itself localhost.
+            // The origin can be switched, so its own host and port count.
+            let uri = upstream_origin.parse::<Uri>().ok();
+            if let Some(uri) = uri.filter(|uri| uri.host() == Some("127.0.0.1")) {
+                origins.push(LinkRewrite {
+                    from: format!(
+                        "{}://localhost:{}",
+                        uri.scheme_str().unwrap_or("http"),
+                        uri.port_u16().unwrap_or(80)
+                    ),
+                    to: public_origin.clone(),
+                });
             }
+            origins.push(LinkRewrite {
+                from: upstream_origin.to_string(),
+                to: public_origin,
+            });
+        }
+    }
+    // The first match wins, so the more specific ones with the route's path
+    // prefix come first.
+    if let Some(route) = route {
+        rewrites.extend(route.location_rewrites(&origins));
+    }
+    rewrites.extend(origins);
+    rewrites
+}
+
+/// Removes upstream response headers that the configured allowlist and
+/// denylist do not let through, before they reach clients or the cache.
+fn filter_response_headers(headers: &mut HeaderMap, config: &Config) {
+    let removed: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| !config.allows_response_header(name))
+        .cloned()
+        .collect();
+    for name in removed {
+        headers.remove(&name);
+    }
+}
+
+/// Adds the Secure attribute to all cookies that upstream sets, so that
+/// browsers only send them back over https.
+fn secure_set_cookies(headers: &mut HeaderMap) {
+    if let http::header::Entry::Occupied(mut entry) = headers.entry(SET_COOKIE).unwrap() {
+        for value in entry.iter_mut() {
+            let cookie = match value.to_str() {
+                Ok(cookie) => cookie,
+                Err(_) => continue,
+            };
+            let secure = cookie
+                .split(';')
+                .skip(1)
+                .any(|attribute| attribute.trim().eq_ignore_ascii_case("secure"));
+            if !secure {
+                if let Ok(new_value) = HeaderValue::from_str(&format!("{}; Secure", cookie)) {
+                    *value = new_value;
+                }
+            }
+        }
+    }
+}
+
+/// Removes the refresh header from the request and checks if it carries the
+/// configured secret, which bypasses the cache lookup.
+fn take_refresh_header(request: &mut Request<Body>, state: &ProxyState) -> bool {
+    let value = match request.headers_mut().remove(&state.refresh_header) {
+        Some(value) => value,
+        None => return false,
+    };
+    match state.config.refresh_token {
+        Some(ref token) => constant_time_eq(value.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares secrets without leaking through timing how many bytes matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |result, (x, y)| result | (x ^ y)) == 0
+}
+
+/// Checks if a request is a gRPC call, which has its status in trailers.
+fn is_grpc(headers: &HeaderMap) -> bool {
+    match headers.get(CONTENT_TYPE).map(|value| value.to_str()) {
+        Some(Ok(content_type)) => content_type.starts_with("application/grpc"),
+        _ => false,
+    }
+}
+
+/// Builds the response that is sent when upstream cannot be reached.
+fn bad_gateway() -> Response<Body> {
+    // For security reasons do not show the exact error to end users.
+    // @todo Log the error.
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body("Something went wrong, please try again later.".into())
+        .unwrap()
+}
+
+/// Returns the ID of a request for error messages, from the X-Request-Id
+/// header or a new random one. IDs with other characters than letters,
+/// digits, "-", "_" and "." are replaced, so they are safe to put into JSON
+/// or HTML.
+fn request_id(headers: &HeaderMap) -> String {
+    match headers.get("x-request-id").and_then(|id| id.to_str().ok()) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= 128
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') =>
+        {
+            id.to_string()
+        }
+        _ => format!("{:016x}", RandomState::new().build_hasher().finish()),
+    }
+}
+
+/// Removes cookies with the given names from the request, for example analytics
+/// cookies that would otherwise be passed on to upstream. Returns the names of
+/// the removed cookies.
+fn strip_cookies(request: &mut Request<Body>, names: &[String]) -> Vec<String> {
+    let mut stripped = Vec::new();
+    if names.is_empty() {
+        return stripped;
+    }
+
+    let mut kept_cookies = Vec::new();
+    for cookie_header in request.headers().get_all(COOKIE) {
+        let cookie_string = match cookie_header.to_str() {
+            Ok(cookie_string) => cookie_string,
+            // Leave cookie headers alone that we don't understand.
+            Err(_) => return Vec::new(),
         };
-        futures::future::ok(our_response)
-    }))
+        for cookie in cookie_string.split(';') {
+            let cookie = cookie.trim();
+            let name = cookie.split('=').next().unwrap_or("");
+            if names.iter().any(|strip_name| strip_name == name) {
+                stripped.push(name.to_string());
+            } else if !cookie.is_empty() {
+                kept_cookies.push(cookie.to_string());
+            }
+        }
+    }
+
+    if stripped.is_empty() {
+        return stripped;
+    }
+
+    let headers = request.headers_mut();
+    headers.remove(COOKIE);
+    if !kept_cookies.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&kept_cookies.join("; ")) {
+            headers.insert(COOKIE, value);
+        }
+    }
+    stripped
 }
 
+/// The cached responses for a cache key. All variants of a URL live in one
+/// entry, so that removing the entry drops all of them.
+#[derive(Clone)]
 struct CachedResponse {
+    // The human readable cache key, the LRU cache itself only knows the hash.
+    key: String,
+    // One response per combination of the values of the request headers
+    // named by Vary. Without Vary there is only one.
+    variants: Variants<Variant>,
+    // How often the entry was served from the cache and when it was served
+    // last, to see which entries are worth their memory.
+    hits: u64,
+    last_access: SystemTime,
+}
+
+/// A cached response for some values of the request headers named by Vary.
+#[derive(Clone)]
+struct Variant {
     status: StatusCode,
     version: Version,
     headers: HeaderMap<HeaderValue>,
-    body: Vec<u8>,
+    // Shared with the responses of cache hits, which don't copy it.
+    body: Bytes,
+    // When upstream created the response, to tell its age.
+    created: Instant,
+    // Variants expire on their own, the entry expires with the last one.
+    expires: Instant,
+    // Until then an expired variant may be served when upstream fails.
+    stale_until: Instant,
+    // Until then an expired variant with a validator is kept to be
+    // revalidated with upstream.
+    kept_until: Instant,
+    // Id of the latest ban that the variant was checked against.
+    checked_ban: u64,
+}
+
+impl CachedResponse {
+    /// Adds a variant for the values of the request headers named by Vary,
+    /// after dropping the variants that are not kept anymore. Returns how
+    /// many variants made room for it, see `Variants::insert`.
+    fn add_variant(
+        &mut self,
+        vary: VaryValues,
+        variant: Variant,
+        max_variants: usize,
+        now: Instant,
+    ) -> usize {
+        self.variants.retain(|other| other.kept_until > now);
+        self.variants.insert(vary, variant, max_variants)
+    }
+}
+
+impl Variant {
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(self.status)
+            .version(self.version)
+            .body(Body::from(self.body.clone()))
+            .unwrap();
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// Checks if a response has a validator for conditional requests.
+fn has_validator(headers: &HeaderMap) -> bool {
+    headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED)
+}
+
+/// Checks if a request has conditions of the client.
+fn has_conditions(headers: &HeaderMap) -> bool {
+    [
+        IF_MATCH,
+        IF_NONE_MATCH,
+        IF_MODIFIED_SINCE,
+        IF_UNMODIFIED_SINCE,
+        IF_RANGE,
+    ]
+    .iter()
+    .any(|name| headers.contains_key(name))
 }
 
 /// Calculates the memory space that is used up by a cached HTTP response.
@@ -139,12 +1038,20 @@ impl MemorySizable for CachedResponse {
         // Memory usage of the struct itself.
         let mut memory_size = size_of_val(self);
 
-        // Memory usage of the header key value pairs.
-        for (key, value) in self.headers.iter() {
-            memory_size += key.as_str().as_bytes().len() + value.len();
+        for (vary, variant) in self.variants.iter() {
+            memory_size += size_of_val(vary) + size_of_val(variant);
+            // Memory usage of the header key value pairs.
+            for (key, value) in variant.headers.iter() {
+                memory_size += key.as_str().len() + value.len();
+            }
+            for (name, value) in vary {
+                memory_size += name.as_str().len() + value.as_ref().map_or(0, HeaderValue::len);
+            }
+            // Memory usage of the body bytes.
+            memory_size += variant.body.len();
         }
-        // Memory usage of the body bytes.
-        memory_size += self.body.capacity();
+        // Memory usage of the cache key.
+        memory_size += self.key.capacity();
 
         memory_size
     }
@@ -152,122 +1059,759 @@ impl MemorySizable for CachedResponse {
 
 #[derive(Clone)]
 struct Cache {
-    lru_cache: Arc<Mutex<LruCache<String, CachedResponse>>>,
+    // Cache keys are stored as hashes to not waste memory on long URLs.
+    lru_cache: Arc<ShardedLruCache<u128, CachedResponse, SharedClock>>,
+    clock: SharedClock,
+    // Entries matching these are exempt from LRU eviction, also when they
+    // are stored later.
+    pins: Arc<RwLock<Vec<KeyPattern>>>,
+    // Responses are inserted by a background thread, so that clients don't
+    // wait for the cache bookkeeping.
+    inserts: SyncSender<Insert>,
+    // Responses that were not cached because the insert queue was full.
+    dropped_inserts: Arc<AtomicUsize>,
+    // Variants that were dropped to make room for a newer variant of their
+    // URL. Many of them hint at a Vary header with too many values.
+    variant_evictions: Arc<AtomicUsize>,
+    // Bans that are checked when entries are looked up.
+    bans: Arc<RwLock<BanList>>,
+    max_bans: usize,
+    // Default seconds that expired responses are kept for upstream errors.
+    grace: u64,
+    // Seconds that expired responses with a validator are kept after that
+    // for revalidation.
+    keep: u64,
+    // Whether the Cache-Control directives of requests are followed.
+    request_cache_control: bool,
+}
+
+// How many responses may wait to be inserted into the cache.
+const INSERT_QUEUE_SIZE: usize = 1024;
+
+/// A response on its way into the cache.
+struct Insert {
+    key: String,
+    vary: VaryValues,
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    created: Instant,
+    expires: Instant,
+    stale_until: Instant,
+    kept_until: Instant,
+    stored: SystemTime,
+    pinned: bool,
+    checked_ban: u64,
+}
+
+/// A cache key that was determined before the request is handled, attached
+/// to the request as extension. Used for keys that are not just the URI.
+#[derive(Clone, Debug)]
+struct CacheKey(String);
+
+/// Selects cache entries by their full key or a key prefix, for pinning and
+/// purging.
+#[derive(Clone, Debug, PartialEq)]
+enum KeyPattern {
+    Key(String),
+    Prefix(String),
+}
+
+impl KeyPattern {
+    fn matches(&self, cache_key: &str) -> bool {
+        match self {
+            // Keys without host match the entries of all hosts.
+            KeyPattern::Key(key) => key == cache_key || *key == split_host(cache_key).0,
+            KeyPattern::Prefix(prefix) => cache_key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+type SharedClock = Arc<dyn Clock + Send + Sync>;
+
+/// Hashes a cache key for internal storage in the LRU cache.
+fn hash_key(cache_key: &str) -> u128 {
+    XxHash3_128::oneshot(cache_key.as_bytes())
+}
+
+/// Lower case hex encoding, for digests and signatures and for bodies that
+/// are not valid UTF-8.
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 impl Cache {
+    /// Wraps the LRU cache and starts the thread that inserts responses.
+    fn new(
+        lru_cache: ShardedLruCache<u128, CachedResponse, SharedClock>,
+        clock: SharedClock,
+        max_variants: usize,
+        max_bans: usize,
+        grace: u64,
+        keep: u64,
+        request_cache_control: bool,
+    ) -> Cache {
+        let (inserts, queue) = sync_channel(INSERT_QUEUE_SIZE);
+        let cache = Cache {
+            lru_cache: Arc::new(lru_cache),
+            clock,
+            pins: Arc::new(RwLock::new(Vec::new())),
+            inserts,
+            dropped_inserts: Arc::new(AtomicUsize::new(0)),
+            variant_evictions: Arc::new(AtomicUsize::new(0)),
+            bans: Arc::new(RwLock::new(BanList::default())),
+            max_bans,
+            grace,
+            keep,
+            request_cache_control,
+        };
+        let lru_cache = cache.lru_cache.clone();
+        let clock = cache.clock.clone();
+        let variant_evictions = cache.variant_evictions.clone();
+        // The thread ends when the last sender is dropped with the server.
+        thread::spawn(move || {
+            for insert in queue {
+                let hash = hash_key(&insert.key);
+                let variant = Variant {
+                    status: insert.status,
+                    version: insert.version,
+                    headers: insert.headers,
+                    body: insert.body,
+                    created: insert.created,
+                    expires: insert.expires,
+                    stale_until: insert.stale_until,
+                    kept_until: insert.kept_until,
+                    checked_ban: insert.checked_ban,
+                };
+                // A copy of the entry is changed and inserted again, so that
+                // its memory size is accounted anew. The entry stays as it is
+                // if the insert is refused, for example when it is pinned and
+                // grew too large.
+                let key = &insert.key;
+                let existing = lru_cache
+                    .peek_with(&hash, |entry| {
+                        if entry.key == *key {
+                            Some(entry.clone())
+                        } else {
+                            None
+                        }
+                    })
+                    .flatten();
+                let mut entry = match existing {
+                    Some(entry) => entry,
+                    None => CachedResponse {
+                        key: insert.key,
+                        variants: Variants::new(),
+                        hits: 0,
+                        last_access: insert.stored,
+                    },
+                };
+                let now = clock.now();
+                let evicted = entry.add_variant(insert.vary, variant, max_variants, now);
+                variant_evictions.fetch_add(evicted, Ordering::Relaxed);
+                let expires = entry
+                    .variants
+                    .iter()
+                    .map(|(_, variant)| variant.kept_until)
+                    .max()
+                    .unwrap_or(now);
+                lru_cache.insert(hash, entry, expires);
+                if insert.pinned {
+                    lru_cache.pin(&hash);
+                }
+            }
+        });
+        cache
+    }
+
     /// Convert an incoming request into a cache key that we can then lookup.
-    fn cache_key(&self, request: &Request<Body>) -> Option<String> {
-        // Only GET requests are cachable.
-        if request.method() != Method::GET {
+    /// Requests with a session cookie are only cachable in micro-caching
+    /// mode, with the session as part of the key. `secure` tells if clients
+    /// use https, which has another default port in the Host header.
+    fn cache_key(
+        &self,
+        request: &Request<Body>,
+        session: Option<&String>,
+        config: &Config,
+        secure: bool,
+    ) -> Option<String> {
+        // Only GET and HEAD requests are cachable, and POST requests on
+        // routes that opted in once their body was hashed. HEAD requests
+        // share the key of GET requests.
+        let custom_key = request.extensions().get::<CacheKey>();
+        if request.method() != Method::GET
+            && request.method() != Method::HEAD
+            && !(request.method() == Method::POST && custom_key.is_some())
+        {
             return None;
         }
-        // Requests with a session cookie cannot be cached.
-        if let Some(cookie_header) = request.headers().get(COOKIE) {
-            if let Ok(cookie_string) = cookie_header.to_str() {
-                let regex = Regex::new(r"SESS[A-Za-z0-9_]+=").unwrap();
-                if regex.is_match(cookie_string) {
-                    return None;
-                }
+        // gRPC calls are never cached.
+        if is_grpc(request.headers()) {
+            return None;
+        }
+        let key = match custom_key {
+            Some(CacheKey(key)) => key.clone(),
+            None => config
+                .query_normalization
+                .normalize(&request.uri().to_string())
+                .into_owned(),
+        };
+        // Virtual hosts behind the proxy can have the same paths. Absolute
+        // URIs name their host already.
+        let key = match request.headers().get(HOST) {
+            Some(host) if config.host_in_cache_key && request.uri().host().is_none() => {
+                format!("{}{}{}", key, KEY_HOST, key_host(host, secure))
+            }
+            _ => key,
+        };
+        match session {
+            None => Some(key),
+            Some(session) if config.micro_cache_ttl.is_some() => {
+                Some(format!("{} session:{}", key, session))
             }
+            Some(_) => None,
         }
-        Some(request.uri().to_string())
     }
 
-    /// Check if we have a response for this request in memory.
-    fn lookup(&mut self, cache_key: &Option<String>) -> Option<Response<Body>> {
+    /// Check if we have a response for this request in memory, in the
+    /// variant that the request headers select. The Cache-Control headers
+    /// of the request can ask for a fresher response or accept a stale one,
+    /// no-cache skips the cache.
+    fn lookup(
+        &mut self,
+        cache_key: &Option<String>,
+        request_headers: &HeaderMap,
+    ) -> Option<Response<Body>> {
+        let directives = self.request_directives(request_headers);
         match cache_key {
             None => None,
+            Some(_) if directives.no_cache => None,
             Some(cache_key) => {
-                let mut inner_cache = self.lru_cache.lock().unwrap();
-                match inner_cache.get(cache_key) {
-                    Some(entry) => {
-                        let mut response = Response::builder()
-                            .status(entry.status)
-                            .version(entry.version)
-                            .body(Body::from(entry.body.clone()))
-                            .unwrap();
-                        *response.headers_mut() = entry.headers.clone();
+                let now = self.clock.system_time();
+                let instant = self.clock.now();
+                let hash = hash_key(cache_key);
+                let bans = self.bans.read().unwrap();
+                let mut banned = false;
+                let response = self
+                    .lru_cache
+                    .get_mut_with(&hash, |entry| {
+                        // Compare the full key to rule out hash collisions.
+                        if entry.key != *cache_key {
+                            return None;
+                        }
+                        let key = &entry.key;
+                        entry.variants.retain(|variant| {
+                            !bans.is_banned(variant.checked_ban, key, &variant.headers)
+                        });
+                        for variant in entry.variants.values_mut() {
+                            variant.checked_ban = bans.latest();
+                        }
+                        banned = entry.variants.is_empty();
+                        // The least recently selected variant is evicted
+                        // first.
+                        let variant = entry.variants.select(request_headers)?;
+                        if !is_acceptable(variant, &directives, instant) {
+                            return None;
+                        }
+                        let response = variant.response();
+                        entry.hits += 1;
+                        entry.last_access = now;
                         Some(response)
-                    }
-                    None => None,
+                    })
+                    .and_then(|response| response);
+                // Banned variants were dropped above, without correcting the
+                // memory size of the entry until it is stored again.
+                if banned {
+                    self.lru_cache.remove(&hash);
                 }
+                response
             }
         }
     }
 
-    // @todo should we take the cache key as option or not?
-    fn store(&mut self, cache_key: Option<String>, response: Response<Body>) -> Response<Body> {
-        match cache_key {
-            None => response,
-            Some(key) => {
-                // Only cache the response if it has a max-age.
-                match self.get_max_age(&response) {
-                    None => response,
-                    Some(max_age) => {
-                        // In order to be able to cache the response we have to fully
-                        // consume it, clone it and rebuild it. Super ugly, any better
-                        // ideas?
-                        let (header_part, body) = response.into_parts();
-                        let body_bytes = body.concat2().wait().unwrap().to_vec();
-
-                        let mut inner_cache = self.lru_cache.lock().unwrap();
-                        let entry = CachedResponse {
-                            status: header_part.status,
-                            version: header_part.version,
-                            headers: header_part.headers.clone(),
-                            body: body_bytes.clone(),
-                        };
-                        // Store an expiry date for this repsponse. After
-                        // that point in time we need to discard it.
-                        inner_cache.insert(
-                            key,
-                            entry,
-                            Instant::now() + Duration::from_secs(max_age),
-                        );
-
-                        Response::from_parts(header_part, Body::from(body_bytes))
-                    }
+    /// The Cache-Control directives of a request, none unless clients may
+    /// control the cache.
+    fn request_directives(&self, request_headers: &HeaderMap) -> CacheControl {
+        if self.request_cache_control {
+            CacheControl::parse_request(request_headers)
+        } else {
+            CacheControl::default()
+        }
+    }
+
+    /// Looks for an expired response that may still be served because
+    /// upstream failed, marked with a Warning header.
+    fn lookup_stale(
+        &self,
+        cache_key: &Option<String>,
+        request_headers: &HeaderMap,
+    ) -> Option<Response<Body>> {
+        let cache_key = cache_key.as_ref()?;
+        let instant = self.clock.now();
+        let bans = self.bans.read().unwrap();
+        self.lru_cache
+            .peek_with(&hash_key(cache_key), |entry| {
+                if entry.key != *cache_key {
+                    return None;
                 }
+                let variant = entry.variants.get(request_headers).filter(|variant| {
+                    variant.stale_until > instant
+                        && !bans.is_banned(variant.checked_ban, cache_key, &variant.headers)
+                })?;
+                let mut response = variant.response();
+                response.headers_mut().append(
+                    WARNING,
+                    HeaderValue::from_static("111 - \"Revalidation Failed\""),
+                );
+                Some(response)
+            })
+            .and_then(|response| response)
+    }
+
+    /// Looks for an expired response with a validator that can be
+    /// revalidated with upstream.
+    fn lookup_expired(
+        &self,
+        cache_key: &Option<String>,
+        request_headers: &HeaderMap,
+    ) -> Option<Response<Body>> {
+        let cache_key = cache_key.as_ref()?;
+        let instant = self.clock.now();
+        let bans = self.bans.read().unwrap();
+        self.lru_cache
+            .peek_with(&hash_key(cache_key), |entry| {
+                if entry.key != *cache_key {
+                    return None;
+                }
+                entry
+                    .variants
+                    .get(request_headers)
+                    .filter(|variant| {
+                        variant.kept_until > instant
+                            && has_validator(&variant.headers)
+                            && !bans.is_banned(variant.checked_ban, cache_key, &variant.headers)
+                    })
+                    .map(Variant::response)
+            })
+            .and_then(|response| response)
+    }
+
+    /// Updates an expired response with the headers of a 304 Not Modified
+    /// from upstream and stores it with its new expiry, without downloading
+    /// the body again.
+    fn revalidated(
+        &self,
+        cache_key: &Option<String>,
+        expired: Response<Body>,
+        not_modified: &HeaderMap,
+        request_headers: &HeaderMap,
+        config: &Config,
+    ) -> Response<Body> {
+        let (mut parts, body) = expired.into_parts();
+        for name in not_modified.keys() {
+            if name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+                continue;
+            }
+            parts.headers.remove(name);
+            for value in not_modified.get_all(name) {
+                parts.headers.append(name, value.clone());
+            }
+        }
+        let response = Response::from_parts(parts, body);
+        let (cache_key, max_age) = match (cache_key, self.get_max_age(&response, config)) {
+            (Some(cache_key), Some(max_age)) => (cache_key, max_age),
+            _ => return response,
+        };
+        let age = response
+            .headers()
+            .get(AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse::seconds)
+            .unwrap_or(0);
+        let now = self.clock.now();
+        let expires = now + Duration::from_secs(max_age);
+        let hash = hash_key(cache_key);
+        let kept_until = self
+            .lru_cache
+            .get_mut_with(&hash, |entry| {
+                if entry.key != *cache_key {
+                    return None;
+                }
+                let variant = entry.variants.get_mut(request_headers)?;
+                variant.headers = response.headers().clone();
+                variant.created = now.checked_sub(Duration::from_secs(age)).unwrap_or(now);
+                variant.stale_until = expires + (variant.stale_until - variant.expires);
+                variant.kept_until = expires + (variant.kept_until - variant.expires);
+                variant.expires = expires;
+                entry
+                    .variants
+                    .iter()
+                    .map(|(_, variant)| variant.kept_until)
+                    .max()
+            })
+            .and_then(|kept_until| kept_until);
+        if let Some(kept_until) = kept_until {
+            self.lru_cache.set_expiry(&hash, kept_until);
+        }
+        response
+    }
+
+    /// Adds a ban and returns its id. When there are too many bans the
+    /// oldest one is applied to all entries right away and dropped.
+    fn ban(&self, ban: Ban) -> u64 {
+        let (id, oldest) = {
+            let mut bans = self.bans.write().unwrap();
+            let id = bans.add(ban);
+            let oldest = if bans.len() > self.max_bans {
+                bans.pop_oldest()
+            } else {
+                None
+            };
+            (id, oldest)
+        };
+        if let Some(oldest) = oldest {
+            let mut keys = Vec::new();
+            self.lru_cache.peek_each(|hash, entry| {
+                let banned = entry.variants.iter().any(|(_, variant)| {
+                    ban::is_banned_by(&oldest, variant.checked_ban, &entry.key, &variant.headers)
+                });
+                if banned {
+                    keys.push(*hash);
+                }
+            });
+            for hash in keys {
+                self.lru_cache.remove(&hash);
+            }
+        }
+        id
+    }
+
+    /// Removes all entries that match. Returns the number of removed entries.
+    fn purge(&self, pattern: &KeyPattern) -> usize {
+        let mut keys = Vec::new();
+        self.lru_cache.peek_each(|hash, entry| {
+            if pattern.matches(&entry.key) {
+                keys.push(*hash);
+            }
+        });
+        keys.iter()
+            .filter(|hash| self.lru_cache.remove(hash).is_some())
+            .count()
+    }
+
+    /// The full keys of the entries that match, for commands on single
+    /// entries that were given without host.
+    fn keys(&self, pattern: &KeyPattern) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.lru_cache.peek_each(|_, entry| {
+            if pattern.matches(&entry.key) {
+                keys.push(entry.key.clone());
+            }
+        });
+        keys
+    }
+
+    /// Removes the entry for the key, if any.
+    fn remove(&self, cache_key: &str) {
+        self.lru_cache.remove(&hash_key(cache_key));
+    }
+
+    /// Pins all current and future entries that match. Returns the number of
+    /// entries that are pinned now.
+    fn pin(&self, pin: KeyPattern) -> usize {
+        let mut keys = Vec::new();
+        self.lru_cache.peek_each(|hash, entry| {
+            if pin.matches(&entry.key) {
+                keys.push(*hash);
             }
+        });
+        let mut pins = self.pins.write().unwrap();
+        if !pins.contains(&pin) {
+            pins.push(pin);
         }
+        keys.iter().filter(|hash| self.lru_cache.pin(hash)).count()
     }
 
-    fn get_max_age(&self, response: &Response<Body>) -> Option<u64> {
-        let mut public = false;
-        let mut max_age: u64 = 0;
+    /// Removes a pin. Entries stay pinned if another pin matches them.
+    /// Returns the number of entries that are evictable again.
+    fn unpin(&self, pin: &KeyPattern) -> usize {
+        let mut pins = self.pins.write().unwrap();
+        pins.retain(|other| other != pin);
+        let mut keys = Vec::new();
+        self.lru_cache.peek_each(|hash, entry| {
+            if pin.matches(&entry.key) && !pins.iter().any(|other| other.matches(&entry.key)) {
+                keys.push(*hash);
+            }
+        });
+        keys.iter()
+            .filter(|hash| self.lru_cache.unpin(hash))
+            .count()
+    }
+
+    fn is_pinned(&self, cache_key: &str) -> bool {
+        self.pins
+            .read()
+            .unwrap()
+            .iter()
+            .any(|pin| pin.matches(cache_key))
+    }
+
+    /// Checks if a lookup would answer the request from the cache, without
+    /// counting it as a hit.
+    fn would_hit(&self, cache_key: &str, request_headers: &HeaderMap) -> bool {
+        let directives = self.request_directives(request_headers);
+        if directives.no_cache {
+            return false;
+        }
+        let instant = self.clock.now();
+        let bans = self.bans.read().unwrap();
+        self.lru_cache
+            .peek_with(&hash_key(cache_key), |entry| {
+                // Compare the full key to rule out hash collisions.
+                entry.key == cache_key
+                    && entry.variants.get(request_headers).is_some_and(|variant| {
+                        is_acceptable(variant, &directives, instant)
+                            && !bans.is_banned(variant.checked_ban, cache_key, &variant.headers)
+                    })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Changes the expiry of an entry and all of its variants, which keep
+    /// their grace period. False if there is no entry for the key.
+    fn set_expiry(&self, cache_key: &str, expires: Instant) -> bool {
+        let hash = hash_key(cache_key);
+        let stale_until = self
+            .lru_cache
+            .get_mut_with(&hash, |entry| {
+                // Compare the full key to rule out hash collisions.
+                if entry.key != cache_key {
+                    return None;
+                }
+                for variant in entry.variants.values_mut() {
+                    variant.stale_until = expires + (variant.stale_until - variant.expires);
+                    variant.kept_until = expires + (variant.kept_until - variant.expires);
+                    variant.expires = expires;
+                }
+                entry
+                    .variants
+                    .iter()
+                    .map(|(_, variant)| variant.kept_until)
+                    .max()
+            })
+            .and_then(|stale_until| stale_until);
+        match stale_until {
+            Some(stale_until) => self.lru_cache.set_expiry(&hash, stale_until),
+            None => false,
+        }
+    }
+
+    /// Puts the response into the cache if it is cachable. The body streams
+    /// on to the client and the insert is left to a background thread once
+    /// it ended, so the response is passed on without waiting for the cache.
+    // @todo should we take the cache key as option or not?
+    fn store(
+        &self,
+        cache_key: Option<String>,
+        head_only: bool,
+        response: Response<Body>,
+        request_headers: &HeaderMap,
+        config: &Config,
+        micro_cache_ttl: Option<u64>,
+    ) -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+        let key = match cache_key {
+            None => return futures::future::ok(response),
+            Some(key) => key,
+        };
+        // Parts of a body would be served as the whole.
+        if response.status() == StatusCode::PARTIAL_CONTENT
+            || !config
+                .cacheable_statuses
+                .contains(&response.status().as_u16())
         {
-            // Make sure that the response is cachable.
-            let cache_control = response.headers().get_all(CACHE_CONTROL);
-            for header_value in cache_control {
-                if let Ok(header_string) = header_value.to_str() {
-                    let comma_values = header_string.split(',');
-                    for comma_value in comma_values {
-                        if comma_value == "public" {
-                            public = true;
-                            continue;
-                        }
-                        let equal_value: Vec<&str> = comma_value.split('=').collect();
-                        if equal_value.len() == 2 && equal_value[0] == "max-age" {
-                            max_age = match equal_value[1].parse() {
-                                Ok(value) => value,
-                                Err(_) => 0,
-                            };
-                        }
-                    }
+            return futures::future::ok(response);
+        }
+        let vary = match vary::vary_values(
+            response.headers(),
+            request_headers,
+            config.backend.normalize_accept_encoding,
+        ) {
+            Some(vary) => vary,
+            None => return futures::future::ok(response),
+        };
+        // The active schedule is determined once, so a response is stored
+        // either completely with or without its overrides.
+        let schedule = schedule::active_schedule(&config.schedules, self.clock.system_time());
+        let policy = schedule
+            .and_then(|schedule| policy::find_policy(&schedule.cache_policies, response.headers()))
+            .or_else(|| policy::find_policy(&config.cache_policies, response.headers()));
+        if policy.is_some_and(|policy| !policy.cache) {
+            return futures::future::ok(response);
+        }
+        let max_age = match micro_cache_ttl {
+            // Responses for a session are cached briefly whatever upstream
+            // says, unless they must not be stored or change the session.
+            Some(ttl) if is_session_cachable(response.headers()) => ttl,
+            Some(_) => return futures::future::ok(response),
+            None => {
+                // Only cache the response if it has a max-age or the content
+                // type policy forces one.
+                let max_age = match policy
+                    .and_then(|policy| policy.ttl)
+                    .or_else(|| self.get_max_age(&response, config))
+                {
+                    None => return futures::future::ok(response),
+                    Some(max_age) => max_age,
+                };
+                match schedule.and_then(|schedule| schedule.min_ttl) {
+                    Some(min_ttl) => max_age.max(min_ttl),
+                    None => max_age,
+                }
+            }
+        };
+        // The response to a HEAD request has no body to wait for.
+        if head_only {
+            let (parts, body) = response.into_parts();
+            self.insert(key, vary, &parts, Bytes::new(), max_age);
+            return futures::future::ok(Response::from_parts(parts, body));
+        }
+        // Bigger responses would not fit into a shard of the cache.
+        let max_size = policy
+            .and_then(|policy| policy.max_size)
+            .unwrap_or(self.lru_cache.max_memory_size() / self.lru_cache.shard_count());
+        let length = resume::content_length(&response);
+        if length.is_some_and(|length| length > max_size as u64) {
+            return futures::future::ok(response);
+        }
+        let verify_checksums = config.backend.verify_response_checksums;
+
+        // The body streams to the client while a copy is collected for the
+        // cache, which only gets it if it arrived completely.
+        let (parts, body) = response.into_parts();
+        let mut head = Response::new(());
+        *head.status_mut() = parts.status;
+        *head.version_mut() = parts.version;
+        *head.headers_mut() = parts.headers.clone();
+        let (head, ()) = head.into_parts();
+        let cache = self.clone();
+        let tee = tee::Tee::new(body, length, max_size, move |body: Bytes| {
+            // Never cache a truncated body, even if upstream ended it
+            // without an error.
+            if !is_complete(&head.headers, body.len()) {
+                return;
+            }
+            if verify_checksums {
+                if let Err(header) = checksum::verify(&head.headers, &body) {
+                    eprintln!(
+                        "Not caching {}: the body does not match its {} header",
+                        key, header
+                    );
+                    return;
                 }
             }
+            cache.insert(key, vary, &head, body, max_age);
+        });
+        futures::future::ok(Response::from_parts(parts, Body::wrap_stream(tee)))
+    }
+
+    /// Queues a complete response for insertion into the cache.
+    fn insert(
+        &self,
+        key: String,
+        vary: VaryValues,
+        parts: &http::response::Parts,
+        body: Bytes,
+        max_age: u64,
+    ) {
+        // The age that upstream reports counts as well.
+        let age = parts
+            .headers
+            .get(AGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse::seconds)
+            .unwrap_or(0);
+        let now = self.clock.now();
+        let stale_until = now
+            + Duration::from_secs(max_age)
+            + Duration::from_secs(
+                CacheControl::parse(&parts.headers)
+                    .stale_if_error
+                    .unwrap_or(self.grace),
+            );
+        let keep = if has_validator(&parts.headers) {
+            self.keep
+        } else {
+            0
+        };
+        let insert = Insert {
+            pinned: self.is_pinned(&key),
+            // Bans from now on apply to the response.
+            checked_ban: self.bans.read().unwrap().latest(),
+            key,
+            vary,
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body,
+            created: now.checked_sub(Duration::from_secs(age)).unwrap_or(now),
+            // Store an expiry date for this repsponse. After that point in
+            // time we need to discard it.
+            expires: now + Duration::from_secs(max_age),
+            stale_until,
+            kept_until: stale_until + Duration::from_secs(keep),
+            stored: self.clock.system_time(),
+        };
+        // Under too much load responses are just not cached.
+        if let Err(TrySendError::Full(_)) = self.inserts.try_send(insert) {
+            self.dropped_inserts.fetch_add(1, Ordering::Relaxed);
         }
+    }
 
-        if public && max_age > 0 {
-            return Some(max_age);
+    /// Seconds that a response stays fresh from now on, None if it is not
+    /// cachable. Without max-age the Expires header decides, and with
+    /// `heuristic_freshness` the Last-Modified header.
+    fn get_max_age(&self, response: &Response<Body>, config: &Config) -> Option<u64> {
+        // Make sure that the response is cachable.
+        let headers = response.headers();
+        let cache_control = CacheControl::parse(headers);
+        let now = self.clock.system_time();
+        // Responses with no-cache would have to be revalidated on every
+        // request, which this cache does not do, so they are not stored.
+        if !cache_control.is_shared_cachable() || cache_control.no_cache {
+            return None;
         }
-        None
+        let lifetime = match cache_control.shared_max_age() {
+            Some(max_age) => max_age,
+            None => match freshness::expires_lifetime(headers, now) {
+                Some(lifetime) => lifetime,
+                None => match config
+                    .status_ttls
+                    .iter()
+                    .find(|status_ttl| status_ttl.status == response.status().as_u16())
+                {
+                    Some(status_ttl) => status_ttl.ttl,
+                    None if config.heuristic_freshness => {
+                        freshness::heuristic_lifetime(headers, response.status(), now)?
+                    }
+                    None => return None,
+                },
+            },
+        };
+        let age = freshness::current_age(headers, now, config.clock_skew_tolerance);
+        Some(lifetime.saturating_sub(age)).filter(|max_age| *max_age > 0)
     }
 }
 
+/// The version of rustnish.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 pub fn start_server_blocking(port: u16, upstream_port: u16) -> Result<()> {
-    let runtime = start_server_background(port, upstream_port)
+    start_server_blocking_config(port, upstream_port, Config::default())
+}
+
+pub fn start_server_blocking_config(port: u16, upstream_port: u16, config: Config) -> Result<()> {
+    let runtime = start_server_background_config(port, upstream_port, config)
         .chain_err(|| "Spawning server thread failed")?;
 
     runtime.shutdown_on_idle().wait().unwrap();
@@ -276,8 +1820,7 @@ pub fn start_server_blocking(port: u16, upstream_port: u16) -> Result<()> {
 }
 
 pub fn start_server_background(port: u16, upstream_port: u16) -> Result<Runtime> {
-    // 256 MB memory cache as a default.
-    start_server_background_memory(port, upstream_port, 256 * 1024 * 1024)
+    start_server_background_config(port, upstream_port, Config::default())
 }
 
 pub fn start_server_background_memory(
@@ -285,80 +1828,543 @@ pub fn start_server_background_memory(
     upstream_port: u16,
     memory_size: usize,
 ) -> Result<Runtime> {
-    let address: SocketAddr = ([127, 0, 0, 1], port).into();
-    let mut runtime = Runtime::new().unwrap();
+    let config = Config {
+        memory_size,
+        ..Config::default()
+    };
+    start_server_background_config(port, upstream_port, config)
+}
+
+/// Starts the proxy for a backend on another machine, given by host name or
+/// IP address.
+pub fn start_server_background_host(
+    port: u16,
+    upstream_host: &str,
+    upstream_port: u16,
+) -> Result<Runtime> {
+    let mut config = Config::default();
+    config.backend.host = upstream_host.to_string();
+    start_server_background_config(port, upstream_port, config)
+}
 
-    let client = Client::new();
+/// Starts the proxy on 127.0.0.1. `Builder` offers more settings, like the
+/// listen address.
+pub fn start_server_background_config(
+    port: u16,
+    upstream_port: u16,
+    config: Config,
+) -> Result<Runtime> {
+    // Without the sender the proxy runs until the runtime is dropped.
+    start_server(([127, 0, 0, 1], port).into(), upstream_port, config)
+        .map(|(runtime, _, _)| runtime)
+}
 
-    let inner_cache = LruCache::<String, CachedResponse>::with_memory_size(memory_size);
-    let cache = Cache {
-        lru_cache: Arc::new(Mutex::new(inner_cache)),
-    };
+/// Starts the proxy on a runtime in the background. Returns the runtime, the
+/// address the proxy listens on, which has the actual port if port 0 was
+/// requested, and the sender that starts a graceful shutdown.
+pub(crate) fn start_server(
+    address: SocketAddr,
+    upstream_port: u16,
+    config: Config,
+) -> Result<(Runtime, SocketAddr, oneshot::Sender<()>)> {
+    config.validate().chain_err(|| "Invalid configuration")?;
 
-    let make_service = make_service_fn(move |socket: &AddrStream| {
-        let source_address = socket.remote_addr();
-        let client = client.clone();
-        let cache = cache.clone();
+    let mut runtime = match config.workers {
+        Some(workers) => tokio::runtime::Builder::new().core_threads(workers).build(),
+        None => Runtime::new(),
+    }
+    .chain_err(|| "Failed to start the runtime")?;
 
-        service_fn(move |request| {
-            proxy_request(
-                request,
-                source_address,
-                port,
-                upstream_port,
-                &client,
-                cache.clone(),
+    let metrics = Arc::new(Metrics::default());
+    let upstream = Upstream {
+        client: config.backend.client()?,
+        limiter: Arc::new(if config.backend.adaptive_concurrency {
+            UpstreamLimiter::adaptive(
+                config.backend.max_requests,
+                Duration::from_millis(config.backend.adaptive_latency_ms),
             )
+        } else {
+            UpstreamLimiter::new(config.backend.max_requests)
+        }),
+        metrics: metrics.clone(),
+    };
+
+    let inner_cache = ShardedLruCache::with_memory_size_and_clock(
+        config.cache_shards,
+        config.memory_size,
+        config.clock.clone(),
+    );
+    let cache = Cache::new(
+        inner_cache,
+        config.clock.clone(),
+        config.max_variants,
+        config.max_bans,
+        config.grace,
+        config.keep,
+        config.request_cache_control,
+    );
+    let mirror = config
+        .mirror
+        .clone()
+        .map(|mirror| Arc::new(MirrorClient::new(mirror)));
+
+    let listener = config
+        .listener
+        .bind(address)
+        .chain_err(|| "Error creating server listener")
+        .and_then(|listener| {
+            TcpListener::from_std(listener, &Handle::default())
+                .chain_err(|| "Error creating server")
         })
-    });
+        .chain_err(|| format!("Failed to bind server to address {}", address))?;
+    let address = listener
+        .local_addr()
+        .chain_err(|| "Failed to get the server address")?;
+    let port = address.port();
 
-    let server = Server::try_bind(&address)
-        .chain_err(|| format!("Failed to bind server to address {}", address))?
-        .serve(make_service)
-        .map_err(|e| eprintln!("server error: {}", e));
+    let warmup_urls = config
+        .readiness
+        .as_ref()
+        .map_or_else(Vec::new, |readiness| readiness.warmup_urls.clone());
+    let (warm, warm_up) = readiness::warm_up(address, warmup_urls);
+    let state = Arc::new(ProxyState::new(config, upstream_port));
+    let (shutdown, signal) = shutdown::Signal::new();
+    let failover_state = state.clone();
+    let failover_client = upstream.client.clone();
+    let failover_signal = signal.clone();
+    let connections = listener
+        .incoming()
+        .then(|socket| match socket {
+            Ok(socket) => Either::A(futures::future::ok::<_, std::io::Error>(Some(socket))),
+            Err(e) => {
+                // Usually out of file descriptors, give other connections
+                // time to close.
+                eprintln!("accept error: {}", e);
+                Either::B(
+                    Delay::new(Instant::now() + Duration::from_millis(100)).then(|_| Ok(None)),
+                )
+            }
+        })
+        .filter_map(|socket| socket);
+    // The listener is closed when the shutdown starts.
+    let connections = shutdown::UntilShutdown::new(connections, signal.clone());
+    let server = connections.for_each(move |socket: TcpStream| {
+        let source_address = match socket.peer_addr() {
+            Ok(address) => address,
+            Err(_) => return Ok(()),
+        };
+        metrics.connection_opened();
+        let upstream = upstream.clone();
+        let cache = cache.clone();
+        let state = state.clone();
+        let mirror = mirror.clone();
+        let warm = warm.clone();
+        let service_metrics = metrics.clone();
+        let first_request = AtomicBool::new(true);
+        let deliveries = DeliveryTracker::default();
+        let service_deliveries = deliveries.clone();
+
+        let delivery_timeout = state
+            .config
+            .client_delivery_timeout_ms
+            .map(Duration::from_millis);
+        let respond = move |mut request: Request<Body>| -> ResponseFuture {
+            if first_request.swap(false, Ordering::Relaxed) {
+                service_metrics.connection_protocol(request.version());
+            }
+            let config = &state.config;
+            match config.readiness {
+                Some(ref readiness) if request.uri().path() == readiness.path => {
+                    return Box::new(readiness::response(
+                        &upstream.client,
+                        &config.backend,
+                        state.origins.current().url(),
+                        readiness,
+                        &warm,
+                    )) as ResponseFuture;
+                }
+                _ => {}
+            }
+            if admin::is_admin_request(&request, config) {
+                return Box::new(futures::future::ok(admin::response(
+                    &request,
+                    source_address,
+                    &cache,
+                    &service_metrics,
+                    &state,
+                )));
+            }
+            // Routes, the cache key and upstream all see the normalized URI.
+            if let Some(uri) =
+                path::normalize(request.uri(), config.merge_slashes, config.trailing_slash)
+            {
+                *request.uri_mut() = uri;
+            }
+            path::normalize_host(request.headers_mut());
+            // Handling the request fills in the outcome for the history.
+            let history = if state.history.is_enabled() {
+                let outcome = Outcome::default();
+                request.extensions_mut().insert(outcome.clone());
+                Some((
+                    outcome,
+                    config.clock.system_time(),
+                    Instant::now(),
+                    request.method().to_string(),
+                    request.uri().to_string(),
+                ))
+            } else {
+                None
+            };
+            let history_state = state.clone();
+            let placeholders = match config
+                .routes
+                .iter()
+                .position(|route| route.matches(request.uri().path()))
+            {
+                Some(index)
+                    if request.method() == Method::GET
+                        && !config.routes[index].placeholders.is_empty() =>
+                {
+                    Some((index, placeholder::client_headers(request.headers())))
+                }
+                _ => None,
+            };
+            let placeholder_upstream = upstream.clone();
+            let placeholder_state = state.clone();
+            // CONNECT requests have no body to copy.
+            let skip_mirror = request.method() == Method::CONNECT;
+            let upstream = upstream.clone();
+            let cache = cache.clone();
+            let state = state.clone();
+            let handle = move |request| {
+                proxy_request(request, source_address, port, &upstream, cache, &state)
+            };
+            let description = format!("{} {}", request.method(), request.uri());
+            // Cached and upstream responses are gzip then.
+            let gunzip = config.backend.normalize_accept_encoding
+                && request.method() != Method::HEAD
+                && !parse::accepts_encoding(request.headers(), "gzip");
+            if gunzip {
+                // Ranges of the gzip body are no ranges of the decoded one.
+                request.headers_mut().remove(RANGE);
+            }
+            let rate_limit = throttle::rate_limit(
+                routes::find_route(&config.routes, request.uri().path()),
+                &config.client_classes,
+                source_address.ip(),
+            );
+            // Handling starts when the future is polled, so that panics are
+            // caught.
+            let response = match mirror {
+                Some(ref mirror) if !skip_mirror && mirror.should_mirror() => {
+                    Box::new(mirror.mirror(request).and_then(handle)) as ResponseFuture
+                }
+                _ => Box::new(futures::future::lazy(move || handle(request))),
+            };
+            let response = if gunzip {
+                Box::new(response.and_then(encoding::gunzip))
+            } else {
+                response
+            };
+            let response = match placeholders {
+                Some((route_index, client_headers)) => {
+                    Box::new(response.and_then(move |response| {
+                        let state = placeholder_state.clone();
+                        let fetch = move |path: &str, headers: &HeaderMap| {
+                            let request = placeholder::subrequest(
+                                &state.config.backend,
+                                state.origin().url(),
+                                path,
+                                headers,
+                            );
+                            limited_upstream_request(
+                                request,
+                                placeholder_upstream.clone(),
+                                state.clone(),
+                            )
+                        };
+                        placeholder::substitute(
+                            response,
+                            &placeholder_state.config.routes[route_index],
+                            client_headers,
+                            fetch,
+                        )
+                    })) as ResponseFuture
+                }
+                None => response,
+            };
+            let response = match rate_limit {
+                Some(rate_limit) => {
+                    Box::new(response.map(move |response| throttle::throttle(response, rate_limit)))
+                }
+                None => response,
+            };
+            let response = isolate_panics(response, description, service_metrics.clone());
+            match history {
+                Some((outcome, started, start, method, uri)) => {
+                    Box::new(response.then(move |result| {
+                        let status = result
+                            .as_ref()
+                            .ok()
+                            .map(|response| response.status().as_u16());
+                        history_state.history.record(Entry::new(
+                            started,
+                            method,
+                            uri,
+                            &outcome,
+                            status,
+                            start.elapsed(),
+                        ));
+                        result
+                    })) as ResponseFuture
+                }
+                None => response,
+            }
+        };
+        // Every response is tracked, also the ones of the administration API.
+        let service = service_fn(move |request| match delivery_timeout {
+            Some(_) => {
+                let deliveries = service_deliveries.clone();
+                Box::new(respond(request).map(move |response| deliveries.track(response)))
+            }
+            None => respond(request),
+        });
+        let metrics = metrics.clone();
+        let connection = Http::new()
+            .serve_connection(socket, service)
+            .with_upgrades();
+        let connection =
+            shutdown::GracefulConnection::new(connection, signal.clone(), |connection| {
+                connection.graceful_shutdown()
+            });
+        let connection = match delivery_timeout {
+            Some(limit) => Either::A(DeliveryTimeout::new(
+                connection,
+                deliveries,
+                limit,
+                metrics.clone(),
+            )),
+            None => Either::B(connection),
+        };
+        let connection = connection.then(move |result| {
+            metrics.connection_closed(result);
+            Ok(())
+        });
+        tokio::spawn(connection);
+        Ok(())
+    });
 
     println!("Listening on http://{}", address);
-    runtime.spawn(server);
+    runtime.spawn(server.map_err(|e| eprintln!("server error: {}", e)));
+    runtime.spawn(warm_up);
+    if failover_state.failover.is_some() {
+        runtime.spawn(failover::monitor(
+            failover_state,
+            failover_client,
+            failover_signal,
+        ));
+    }
 
-    Ok(runtime)
+    Ok((runtime, address, shutdown))
 }
 
 #[cfg(test)]
 mod tests {
 
     use crate::cache::MemorySizable;
-    use crate::CachedResponse;
-    use hyper::header::HeaderValue;
-    use hyper::{HeaderMap, StatusCode, Version};
+    use crate::vary::{Variants, VaryValues};
+    use crate::{
+        is_complete, key_host, request_id, secure_set_cookies, split_host, strip_cookies,
+        CachedResponse, Variant,
+    };
+    use bytes::Bytes;
+    use hyper::header::{HeaderValue, ACCEPT_LANGUAGE, CONTENT_LENGTH, COOKIE, SET_COOKIE};
+    use hyper::{Body, HeaderMap, Request, StatusCode, Version};
+    use std::time::{Duration, Instant, SystemTime};
 
-    fn example_cache_entry() -> CachedResponse {
-        CachedResponse {
+    fn example_variant() -> Variant {
+        Variant {
             status: StatusCode::OK,
             version: Version::HTTP_11,
             headers: HeaderMap::new(),
             body: "a".into(),
+            created: Instant::now(),
+            expires: Instant::now(),
+            stale_until: Instant::now(),
+            kept_until: Instant::now(),
+            checked_ban: 0,
+        }
+    }
+
+    fn example_cache_entry() -> CachedResponse {
+        let mut variants = Variants::new();
+        variants.insert(Vec::new(), example_variant(), 1);
+        CachedResponse {
+            key: String::new(),
+            variants,
+            hits: 0,
+            last_access: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    fn example_variant_mut(cache_entry: &mut CachedResponse) -> &mut Variant {
+        cache_entry.variants.values_mut().next().unwrap()
+    }
+
+    /// The values for an Accept-Language header.
+    fn language(language: &'static str) -> VaryValues {
+        vec![(ACCEPT_LANGUAGE, Some(HeaderValue::from_static(language)))]
+    }
+
+    /// A variant that is kept for a minute.
+    fn kept_variant() -> Variant {
+        Variant {
+            kept_until: Instant::now() + Duration::from_secs(60),
+            ..example_variant()
         }
     }
 
     #[test]
     fn cache_memory_size() {
         let cache_entry = example_cache_entry();
-        assert_eq!(129, cache_entry.get_memory_size());
+        assert_eq!(361, cache_entry.get_memory_size());
     }
 
     #[test]
     fn body_100_bytes() {
         let mut cache_entry = example_cache_entry();
-        cache_entry.body = vec![b'a'; 100];
-        assert_eq!(228, cache_entry.get_memory_size());
+        example_variant_mut(&mut cache_entry).body = Bytes::from(vec![b'a'; 100]);
+        assert_eq!(460, cache_entry.get_memory_size());
     }
 
     #[test]
     fn one_header_size() {
         let mut cache_entry = example_cache_entry();
-        cache_entry
+        example_variant_mut(&mut cache_entry)
             .headers
             .insert("a", HeaderValue::from_static("b"));
-        assert_eq!(131, cache_entry.get_memory_size());
+        assert_eq!(363, cache_entry.get_memory_size());
+    }
+
+    #[test]
+    fn cache_key_size() {
+        let mut cache_entry = example_cache_entry();
+        cache_entry.key = "http://example.com/".to_string();
+        assert_eq!(380, cache_entry.get_memory_size());
+    }
+
+    #[test]
+    fn variants_size() {
+        let mut cache_entry = example_cache_entry();
+        cache_entry.variants = Variants::new();
+        cache_entry
+            .variants
+            .insert(language("de"), example_variant(), 1);
+        assert_eq!(378, cache_entry.get_memory_size());
+    }
+
+    #[test]
+    fn variant_cap() {
+        let mut entry = CachedResponse {
+            variants: Variants::new(),
+            ..example_cache_entry()
+        };
+        let now = Instant::now();
+        assert_eq!(0, entry.add_variant(language("de"), kept_variant(), 2, now));
+        assert_eq!(0, entry.add_variant(language("en"), kept_variant(), 2, now));
+        // Replacing a variant does not evict another one.
+        assert_eq!(0, entry.add_variant(language("de"), kept_variant(), 2, now));
+        assert_eq!(2, entry.variants.iter().count());
+
+        // "en" was used least recently.
+        assert_eq!(1, entry.add_variant(language("fr"), kept_variant(), 2, now));
+        let languages: Vec<_> = entry
+            .variants
+            .iter()
+            .map(|(vary, _)| vary[0].1.clone().unwrap())
+            .collect();
+        assert_eq!(vec!["fr", "de"], languages);
+
+        // Expired variants are dropped without counting as evictions.
+        let later = now + Duration::from_secs(120);
+        assert_eq!(
+            0,
+            entry.add_variant(language("en"), kept_variant(), 2, later)
+        );
+        assert_eq!(1, entry.variants.iter().count());
+    }
+
+    #[test]
+    fn strip_analytics_cookies() {
+        let mut request = Request::builder()
+            .header(COOKIE, "_ga=GA1.2.3; SESSabc=123; _gid=GA1.4.5")
+            .body(Body::empty())
+            .unwrap();
+        strip_cookies(&mut request, &["_ga".to_string(), "_gid".to_string()]);
+        assert_eq!(request.headers().get(COOKIE).unwrap(), "SESSabc=123");
+    }
+
+    #[test]
+    fn strip_all_cookies_removes_header() {
+        let mut request = Request::builder()
+            .header(COOKIE, "_ga=GA1.2.3")
+            .body(Body::empty())
+            .unwrap();
+        strip_cookies(&mut request, &["_ga".to_string()]);
+        assert!(request.headers().get(COOKIE).is_none());
+    }
+
+    #[test]
+    fn secure_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, HeaderValue::from_static("a=1; Path=/"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("b=2; secure"));
+        secure_set_cookies(&mut headers);
+        let cookies: Vec<_> = headers.get_all(SET_COOKIE).iter().collect();
+        assert_eq!(vec!["a=1; Path=/; Secure", "b=2; secure"], cookies);
+    }
+
+    #[test]
+    fn complete_body() {
+        let mut headers = HeaderMap::new();
+        assert!(is_complete(&headers, 5));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("11"));
+        assert!(is_complete(&headers, 11));
+        assert!(!is_complete(&headers, 5));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("invalid"));
+        assert!(!is_complete(&headers, 5));
+    }
+
+    #[test]
+    fn request_ids() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(16, request_id(&headers).len());
+        headers.insert("x-request-id", HeaderValue::from_static("a-1_b.2"));
+        assert_eq!("a-1_b.2", request_id(&headers));
+        headers.insert("x-request-id", HeaderValue::from_static("a\"<b>"));
+        assert_ne!("a\"<b>", request_id(&headers));
+    }
+
+    #[test]
+    fn key_hosts() {
+        let host = HeaderValue::from_static("example.com:80");
+        assert_eq!("example.com", key_host(&host, false));
+        assert_eq!("example.com:80", key_host(&host, true));
+        let host = HeaderValue::from_static("example.com:443");
+        assert_eq!("example.com:443", key_host(&host, false));
+        assert_eq!("example.com", key_host(&host, true));
+    }
+
+    #[test]
+    fn split_hosts() {
+        assert_eq!(("/".into(), None), split_host("/"));
+        assert_eq!(
+            ("/a?b=1".into(), Some("example.com")),
+            split_host("/a?b=1 host:example.com")
+        );
+        assert_eq!(
+            ("/a session:abc".into(), Some("example.com:8080")),
+            split_host("/a host:example.com:8080 session:abc")
+        );
     }
 }
@@ -0,0 +1,231 @@
+//! Limit on concurrent requests to upstream. Only cache misses wait for a
+//! slot, so cache hits are never queued behind slow upstream requests.
+//!
+//! The limit is either fixed or adapted to the backend with AIMD: every
+//! response that arrives in time while the slots are in use raises the limit
+//! a little, every failure cuts it by a fraction. That way the limit settles
+//! at the parallelism the backend can sustain and drops quickly when the
+//! backend gets into trouble.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Upper bound of an adaptive limit without max_requests.
+const ADAPTIVE_MAX: usize = 1000;
+// Where an adaptive limit starts.
+const ADAPTIVE_INITIAL: usize = 20;
+// Share of the limit that is kept after a failure.
+const BACKOFF_RATIO: f64 = 0.9;
+
+/// Hands out slots for upstream requests.
+pub(crate) struct UpstreamLimiter {
+    // None means unlimited.
+    state: Option<Mutex<State>>,
+    // Responses slower than this are treated as overload. None means the
+    // limit is fixed.
+    adaptive_latency: Option<Duration>,
+    max: usize,
+}
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    waiters: Vec<Task>,
+}
+
+impl UpstreamLimiter {
+    pub(crate) fn new(limit: Option<usize>) -> UpstreamLimiter {
+        UpstreamLimiter {
+            state: limit.map(|limit| Mutex::new(State::new(limit))),
+            adaptive_latency: None,
+            max: limit.unwrap_or(0),
+        }
+    }
+
+    /// An adaptive limit between 1 and `max` (1000 if not given). Responses
+    /// slower than `latency` count as failures.
+    pub(crate) fn adaptive(max: Option<usize>, latency: Duration) -> UpstreamLimiter {
+        let max = max.unwrap_or(ADAPTIVE_MAX);
+        UpstreamLimiter {
+            state: Some(Mutex::new(State::new(ADAPTIVE_INITIAL.min(max)))),
+            adaptive_latency: Some(latency),
+            max,
+        }
+    }
+
+    /// The current number of allowed requests in flight, None if unlimited.
+    #[cfg(test)]
+    pub(crate) fn limit(&self) -> Option<usize> {
+        self.state
+            .as_ref()
+            .map(|state| state.lock().unwrap().current_limit())
+    }
+
+    /// Waits until an upstream request may be sent. The slot is freed when
+    /// the returned permit is dropped.
+    pub(crate) fn acquire(self: &Arc<Self>) -> Acquire {
+        Acquire {
+            limiter: self.clone(),
+        }
+    }
+
+    fn release(&self, started: Instant, failed: bool) {
+        let state = match self.state {
+            Some(ref state) => state,
+            None => return,
+        };
+        let mut state = state.lock().unwrap();
+        if let Some(latency) = self.adaptive_latency {
+            if failed || started.elapsed() > latency {
+                state.limit = (state.limit * BACKOFF_RATIO).max(1.0);
+            } else if state.in_flight * 2 >= state.current_limit() {
+                // Only grow while the slots are actually used, idle times
+                // tell nothing about the backend.
+                state.limit = (state.limit + 1.0 / state.limit).min(self.max as f64);
+            }
+        }
+        state.in_flight -= 1;
+        // Waiters that were dropped in the meantime don't pass on a wakeup,
+        // so everybody checks again.
+        for waiter in state.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
+}
+
+impl State {
+    fn new(limit: usize) -> State {
+        State {
+            limit: limit as f64,
+            in_flight: 0,
+            waiters: Vec::new(),
+        }
+    }
+
+    fn current_limit(&self) -> usize {
+        self.limit as usize
+    }
+}
+
+/// Future for a slot of the limiter.
+pub(crate) struct Acquire {
+    limiter: Arc<UpstreamLimiter>,
+}
+
+impl Future for Acquire {
+    type Item = UpstreamPermit;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<UpstreamPermit, hyper::Error> {
+        if let Some(ref state) = self.limiter.state {
+            let mut state = state.lock().unwrap();
+            if state.in_flight >= state.current_limit() {
+                state.waiters.push(task::current());
+                return Ok(Async::NotReady);
+            }
+            state.in_flight += 1;
+        }
+        Ok(Async::Ready(UpstreamPermit {
+            limiter: self.limiter.clone(),
+            started: Instant::now(),
+            failed: false,
+        }))
+    }
+}
+
+/// A slot for one upstream request.
+pub(crate) struct UpstreamPermit {
+    limiter: Arc<UpstreamLimiter>,
+    started: Instant,
+    failed: bool,
+}
+
+impl UpstreamPermit {
+    /// Frees the slot and tells an adaptive limit whether the backend
+    /// failed to answer properly.
+    pub(crate) fn finish(mut self, failed: bool) {
+        self.failed = failed;
+    }
+}
+
+impl Drop for UpstreamPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.started, self.failed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpstreamLimiter;
+    use futures::{Async, Future};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn limit() {
+        let limiter = Arc::new(UpstreamLimiter::new(Some(1)));
+        let first = limiter.acquire().wait().unwrap();
+        let mut second = limiter.acquire();
+        futures::future::lazy(|| {
+            assert!(second.poll().unwrap().is_not_ready());
+            drop(first);
+            match second.poll().unwrap() {
+                Async::Ready(_) => Ok::<_, ()>(()),
+                Async::NotReady => panic!("slot not freed"),
+            }
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = Arc::new(UpstreamLimiter::new(None));
+        let _permits: Vec<_> = (0..100)
+            .map(|_| limiter.acquire().wait().unwrap())
+            .collect();
+        assert_eq!(limiter.limit(), None);
+    }
+
+    #[test]
+    fn adaptive() {
+        let limiter = Arc::new(UpstreamLimiter::adaptive(Some(25), Duration::from_secs(60)));
+        assert_eq!(limiter.limit(), Some(20));
+
+        // Successes without load don't raise it.
+        for _ in 0..100 {
+            limiter.acquire().wait().unwrap().finish(false);
+        }
+        assert_eq!(limiter.limit(), Some(20));
+
+        // Successes with all slots in use raise the limit up to the maximum.
+        for _ in 0..1000 {
+            let permits: Vec<_> = (0..limiter.limit().unwrap())
+                .map(|_| limiter.acquire().wait().unwrap())
+                .collect();
+            for permit in permits {
+                permit.finish(false);
+            }
+        }
+        assert_eq!(limiter.limit(), Some(25));
+
+        // Failures cut it down, but never below one.
+        limiter.acquire().wait().unwrap().finish(true);
+        assert_eq!(limiter.limit(), Some(22));
+        for _ in 0..100 {
+            limiter.acquire().wait().unwrap().finish(true);
+        }
+        assert_eq!(limiter.limit(), Some(1));
+    }
+
+    #[test]
+    fn slow_responses_fail() {
+        let limiter = Arc::new(UpstreamLimiter::adaptive(None, Duration::from_millis(0)));
+        let permit = limiter.acquire().wait().unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        permit.finish(false);
+        assert_eq!(limiter.limit(), Some(18));
+    }
+}
@@ -0,0 +1,131 @@
+//! Socket options of the listener that accepts client connections.
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+/// Tuning knobs for the listening socket.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listener {
+    /// Allow several processes to listen on the same port (`SO_REUSEPORT`),
+    /// so the kernel balances connections between them.
+    pub reuse_port: bool,
+    /// Queue length for TCP Fast Open (`TCP_FASTOPEN`, Linux only), which
+    /// saves a round trip for returning clients. Disabled if not set.
+    pub fast_open_queue: Option<u32>,
+    /// Maximum number of connections waiting to be accepted.
+    pub backlog: i32,
+    /// Size of the kernel send buffer of accepted sockets in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the kernel receive buffer of accepted sockets in bytes.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for Listener {
+    fn default() -> Listener {
+        Listener {
+            reuse_port: false,
+            fast_open_queue: None,
+            backlog: 1024,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl Listener {
+    /// Creates a listening socket with the configured options.
+    pub(crate) fn bind(&self, address: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        // Like the standard library, so restarts don't fail on old
+        // connections in TIME_WAIT.
+        socket.set_reuse_address(true)?;
+        if self.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        if let Some(queue) = self.fast_open_queue {
+            set_fast_open(&socket, queue)?;
+        }
+        // Accepted sockets inherit the buffer sizes of the listener.
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        socket.bind(&address.into())?;
+        socket.listen(self.backlog)?;
+        Ok(socket.into())
+    }
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &Socket, queue: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let queue = queue as libc::c_int;
+    // socket2 has no setter for this option.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_socket: &Socket, _queue: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "TCP Fast Open is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listener;
+    use std::net::TcpStream;
+
+    #[test]
+    fn socket_options() {
+        let listener = Listener {
+            reuse_port: true,
+            fast_open_queue: Some(16),
+            backlog: 16,
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+        };
+        let first = listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = first.local_addr().unwrap();
+        // With SO_REUSEPORT a second socket can bind the same port.
+        let _second = listener.bind(address).unwrap();
+        assert!(TcpStream::connect(address).is_ok());
+    }
+}
@@ -1,18 +1,191 @@
 #![deny(warnings)]
 
+extern crate clap;
 extern crate error_chain;
 extern crate rustnish;
 
+use clap::{App, Arg};
+use error_chain::ChainedError;
+use rustnish::{Builder, Config, ServerHandle};
+use std::io::Write; // trait which holds `display`
+use std::net::SocketAddr;
+
 fn main() {
-    let port: u16 = 9090;
-    let upstream_port: u16 = 80;
+    let matches = App::new("rustnish")
+        .version(rustnish::VERSION)
+        .about("A caching reverse proxy")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads the settings from a TOML file"),
+        )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .value_name("FILE")
+                .conflicts_with_all(&["config", "listen", "upstream", "cache-mem", "workers"])
+                .help("Validates a TOML config file and exits"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .validator(|value| parse_listen(&value).map(|_| ()))
+                .help("Address and port to accept client connections on [default: 127.0.0.1:9090]"),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream")
+                .value_name("HOST[:PORT]")
+                .validator(|value| parse_upstream(&value).map(|_| ()))
+                .help("Backend to forward requests to [default: 127.0.0.1:80]"),
+        )
+        .arg(
+            Arg::with_name("cache-mem")
+                .long("cache-mem")
+                .value_name("SIZE")
+                .validator(|value| parse_size(&value).map(|_| ()))
+                .help("Memory for cached responses in bytes, or with a K, M or G suffix"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .value_name("N")
+                .validator(|value| parse_workers(&value).map(|_| ()))
+                .help("Number of worker threads [default: one per CPU core]"),
+        )
+        .get_matches();
+
+    if let Some(path) = matches.value_of("check-config") {
+        match Config::from_file(path) {
+            Ok(_) => {
+                println!("Config file {} is valid", path);
+                return;
+            }
+            Err(ref e) => exit_with_error(e),
+        }
+    }
+    let config = match matches.value_of("config") {
+        Some(path) => match Config::from_file(path) {
+            Ok(config) => config,
+            Err(ref e) => exit_with_error(e),
+        },
+        None => Config::default(),
+    };
 
-    if let Err(ref e) = rustnish::start_server_blocking(port, upstream_port) {
-        use error_chain::ChainedError;
-        use std::io::Write; // trait which holds `display`
-        let stderr = &mut ::std::io::stderr();
+    // The values were checked by the validators above. They take precedence
+    // over the settings of the config file.
+    let mut builder = Builder::new().config(config);
+    if let Some(listen) = matches.value_of("listen") {
+        builder = builder.listen(parse_listen(listen).unwrap());
+    }
+    if let Some(upstream) = matches.value_of("upstream") {
+        let (host, port) = parse_upstream(upstream).unwrap();
+        builder = builder.upstream(&host, port);
+    }
+    if let Some(size) = matches.value_of("cache-mem") {
+        builder = builder.memory_size(parse_size(size).unwrap());
+    }
+    if let Some(workers) = matches.value_of("workers") {
+        builder = builder.workers(parse_workers(workers).unwrap());
+    }
 
-        writeln!(stderr, "{}", e.display_chain()).expect("Error writing to stderr");
-        ::std::process::exit(1);
+    println!("rustnish {}", rustnish::VERSION);
+    if let Err(ref e) = builder.start().and_then(ServerHandle::wait) {
+        exit_with_error(e);
     };
 }
+
+fn parse_listen(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{:?} is not an address like 127.0.0.1:9090", value))
+}
+
+/// Parses "host", "host:port" or "[IPv6 address]:port". The port defaults to
+/// 80.
+fn parse_upstream(value: &str) -> Result<(String, u16), String> {
+    let error = || format!("{:?} is not a backend like 127.0.0.1:80", value);
+    let (host, port) = if let Some(rest) = value.strip_prefix('[') {
+        let end = rest.find(']').ok_or_else(error)?;
+        match &rest[end + 1..] {
+            "" => (&rest[..end], None),
+            port => (
+                &rest[..end],
+                Some(port.strip_prefix(':').ok_or_else(error)?),
+            ),
+        }
+    } else {
+        match value.find(':') {
+            // More than one colon is an IPv6 address without port.
+            Some(index) if value.rfind(':') == Some(index) => {
+                (&value[..index], Some(&value[index + 1..]))
+            }
+            _ => (value, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(error());
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| error())?,
+        None => 80,
+    };
+    Ok((host.to_string(), port))
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    let error = || format!("{:?} is not a size like 268435456 or 256M", value);
+    let (number, factor) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let number: usize = number.parse().map_err(|_| error())?;
+    number.checked_mul(factor).ok_or_else(error)
+}
+
+fn parse_workers(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(workers) if workers > 0 => Ok(workers),
+        _ => Err(format!("{:?} is not a number of workers above 0", value)),
+    }
+}
+
+fn exit_with_error<E: ChainedError>(e: &E) -> ! {
+    let stderr = &mut ::std::io::stderr();
+
+    writeln!(stderr, "{}", e.display_chain()).expect("Error writing to stderr");
+    ::std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, parse_upstream};
+
+    #[test]
+    fn upstreams() {
+        assert_eq!(Ok(("backend".to_string(), 80)), parse_upstream("backend"));
+        assert_eq!(
+            Ok(("10.0.0.1".to_string(), 8080)),
+            parse_upstream("10.0.0.1:8080")
+        );
+        assert_eq!(Ok(("::1".to_string(), 8080)), parse_upstream("[::1]:8080"));
+        assert_eq!(Ok(("::1".to_string(), 80)), parse_upstream("[::1]"));
+        assert_eq!(Ok(("::1".to_string(), 80)), parse_upstream("::1"));
+        assert!(parse_upstream("backend:http").is_err());
+        assert!(parse_upstream(":80").is_err());
+        assert!(parse_upstream("[::1]8080").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(Ok(1000), parse_size("1000"));
+        assert_eq!(Ok(64 * 1024 * 1024), parse_size("64M"));
+        assert_eq!(Ok(2 * 1024 * 1024 * 1024), parse_size("2g"));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1K").is_err());
+    }
+}
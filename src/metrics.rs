@@ -0,0 +1,140 @@
+//! Counters about the client and upstream connections and request handling of
+//! the proxy, exported by the administration API. They show problems on the
+//! connection layer separately from failed requests.
+
+use crate::backend::UpstreamConnection;
+use hyper::{Body, Response, Version};
+use serde::Serialize;
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters that are shared by all connections.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    accepted_connections: AtomicUsize,
+    active_connections: AtomicUsize,
+    handshake_errors: AtomicUsize,
+    connection_resets: AtomicUsize,
+    connection_errors: AtomicUsize,
+    http1_connections: AtomicUsize,
+    http2_connections: AtomicUsize,
+    request_panics: AtomicUsize,
+    slow_client_aborts: AtomicUsize,
+    upstream_new_connections: AtomicUsize,
+    upstream_reused_connections: AtomicUsize,
+}
+
+/// The values of the counters at one point in time.
+#[derive(Debug, Serialize)]
+pub(crate) struct MetricsSnapshot {
+    accepted_connections: usize,
+    // Connections that are open right now.
+    active_connections: usize,
+    // Connections closed because the client did not speak valid HTTP.
+    handshake_errors: usize,
+    // Connections reset by the client.
+    connection_resets: usize,
+    // Connections that failed for any other reason, for example timeouts.
+    connection_errors: usize,
+    // Connections by the protocol of their first request. HTTP/3 is not
+    // supported.
+    http1_connections: usize,
+    http2_connections: usize,
+    // Requests that were answered with a 500 because handling them panicked.
+    request_panics: usize,
+    // Connections aborted because a response took too long to deliver.
+    slow_client_aborts: usize,
+    // Upstream responses that came over a newly dialed connection and over a
+    // connection reused from the pool. Replayed and cached responses are not
+    // counted.
+    upstream_new_connections: usize,
+    upstream_reused_connections: usize,
+}
+
+impl Metrics {
+    pub(crate) fn connection_opened(&self) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a finished connection, by the error it ended with if any.
+    pub(crate) fn connection_closed(&self, result: Result<(), hyper::Error>) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        let error = match result {
+            Ok(()) => return,
+            Err(error) => error,
+        };
+        let counter = if error.is_parse() {
+            &self.handshake_errors
+        } else if is_reset(&error) {
+            &self.connection_resets
+        } else {
+            &self.connection_errors
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the protocol of a connection, called with its first request.
+    pub(crate) fn connection_protocol(&self, version: Version) {
+        let counter = if version == Version::HTTP_2 {
+            &self.http2_connections
+        } else {
+            &self.http1_connections
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_panicked(&self) {
+        self.request_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn slow_client_aborted(&self) {
+        self.slow_client_aborts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an upstream response by whether its connection was reused.
+    pub(crate) fn upstream_response(&self, response: &Response<Body>) {
+        let connection = match response.extensions().get::<UpstreamConnection>() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let counter = if connection.count_response() {
+            &self.upstream_reused_connections
+        } else {
+            &self.upstream_new_connections
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            accepted_connections: self.accepted_connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            handshake_errors: self.handshake_errors.load(Ordering::Relaxed),
+            connection_resets: self.connection_resets.load(Ordering::Relaxed),
+            connection_errors: self.connection_errors.load(Ordering::Relaxed),
+            http1_connections: self.http1_connections.load(Ordering::Relaxed),
+            http2_connections: self.http2_connections.load(Ordering::Relaxed),
+            request_panics: self.request_panics.load(Ordering::Relaxed),
+            slow_client_aborts: self.slow_client_aborts.load(Ordering::Relaxed),
+            upstream_new_connections: self.upstream_new_connections.load(Ordering::Relaxed),
+            upstream_reused_connections: self.upstream_reused_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Checks if the IO error behind a connection error is a reset by the peer.
+fn is_reset(error: &hyper::Error) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if let Some(io_error) = error.downcast_ref::<io::Error>() {
+            return matches!(
+                io_error.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+            );
+        }
+        source = error.source();
+    }
+    false
+}
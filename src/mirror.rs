@@ -0,0 +1,118 @@
+//! Mirroring of a share of the incoming requests to a shadow upstream, for
+//! testing a new backend version with real traffic. Responses of the shadow
+//! are discarded.
+
+use futures::{Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Settings for the shadow upstream.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    /// Host and port of the shadow upstream, for example "127.0.0.1:9092".
+    pub address: String,
+    /// Percentage of requests that are mirrored, spread evenly.
+    #[serde(default = "default_percentage")]
+    pub percentage: u8,
+}
+
+fn default_percentage() -> u8 {
+    100
+}
+
+impl Mirror {
+    /// Checks that the address can be used in a URL.
+    pub(crate) fn is_valid(&self) -> bool {
+        match format!("http://{}/", self.address).parse::<Uri>() {
+            Ok(uri) => uri.port_part().is_some() && self.percentage <= 100,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Sends copies of requests to the shadow upstream.
+pub(crate) struct MirrorClient {
+    settings: Mirror,
+    client: Client<HttpConnector>,
+    requests: AtomicUsize,
+}
+
+impl MirrorClient {
+    pub(crate) fn new(settings: Mirror) -> MirrorClient {
+        MirrorClient {
+            settings,
+            client: Client::new(),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// Decides if the next request is mirrored. With 30 percent, for example,
+    /// 3 of every 10 requests are selected.
+    pub(crate) fn should_mirror(&self) -> bool {
+        let count = self.requests.fetch_add(1, Ordering::Relaxed) % 100;
+        let percentage = usize::from(self.settings.percentage);
+        count * percentage / 100 != (count + 1) * percentage / 100
+    }
+
+    /// Sends a copy of the request to the shadow upstream in the background
+    /// and returns the request for normal processing. The body has to be
+    /// read completely for that.
+    pub(crate) fn mirror(
+        &self,
+        request: Request<Body>,
+    ) -> impl Future<Item = Request<Body>, Error = hyper::Error> {
+        let client = self.client.clone();
+        let address = self.settings.address.clone();
+        let (parts, body) = request.into_parts();
+        body.concat2().map(move |chunk| {
+            let body = chunk.into_bytes();
+            let path = parts
+                .uri
+                .path_and_query()
+                .map_or("/", |path_and_query| path_and_query.as_str());
+            if let Ok(uri) = format!("http://{}{}", address, path).parse() {
+                let mut copy = Request::new(Body::from(body.clone()));
+                *copy.method_mut() = parts.method.clone();
+                *copy.uri_mut() = uri;
+                *copy.headers_mut() = parts.headers.clone();
+                tokio::spawn(
+                    client
+                        .request(copy)
+                        .and_then(|response| response.into_body().for_each(|_| Ok(())))
+                        .map_err(|_| ()),
+                );
+            }
+            Request::from_parts(parts, Body::from(body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mirror, MirrorClient};
+
+    #[test]
+    fn percentage_spread() {
+        let client = MirrorClient::new(Mirror {
+            address: "127.0.0.1:9092".to_string(),
+            percentage: 30,
+        });
+        let selected: Vec<bool> = (0..10).map(|_| client.should_mirror()).collect();
+        assert_eq!(3, selected.iter().filter(|&&selected| selected).count());
+        assert_eq!(30, (10..100).filter(|_| client.should_mirror()).count() + 3);
+    }
+
+    #[test]
+    fn valid_address() {
+        let mut mirror = Mirror {
+            address: "shadow.local:8080".to_string(),
+            percentage: 100,
+        };
+        assert!(mirror.is_valid());
+        mirror.address = "shadow.local".to_string();
+        assert!(!mirror.is_valid());
+    }
+}
@@ -0,0 +1,139 @@
+//! The backends that requests are forwarded to: the origin, or the pools of
+//! a director. They can be switched at runtime for blue/green deployments:
+//! new requests go to the new backends while the ones in flight finish on
+//! the old ones, which are reported as draining until they are done.
+
+use serde::Serialize;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Backends like the origin "http://10.0.0.5:8080" and their requests in
+/// flight.
+#[derive(Debug)]
+pub(crate) struct Origin<T = String> {
+    pub(crate) backends: T,
+    in_flight: AtomicUsize,
+}
+
+impl Origin {
+    /// The URL of the origin.
+    pub(crate) fn url(&self) -> &str {
+        &self.backends
+    }
+}
+
+impl<T: Display> Origin<T> {
+    pub(crate) fn new(backends: T) -> Origin<T> {
+        Origin {
+            backends,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a request to this origin until the returned guard is dropped.
+    pub(crate) fn start(self: &Arc<Self>) -> InFlight<T> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    fn status(&self) -> OriginStatus {
+        OriginStatus {
+            origin: self.backends.to_string(),
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A request in flight to an origin.
+pub(crate) struct InFlight<T>(Arc<Origin<T>>);
+
+impl<T> Drop for InFlight<T> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The current origin and the replaced ones that still have requests in
+/// flight.
+#[derive(Debug)]
+pub(crate) struct Origins<T = String> {
+    current: RwLock<Arc<Origin<T>>>,
+    draining: Mutex<Vec<Arc<Origin<T>>>>,
+}
+
+/// An origin as reported by the administration API.
+#[derive(Debug, Serialize)]
+pub(crate) struct OriginStatus {
+    // The URL, or the pools of a director.
+    pub(crate) origin: String,
+    pub(crate) in_flight: usize,
+}
+
+/// The origins as reported by the administration API.
+#[derive(Debug, Serialize)]
+pub(crate) struct OriginsStatus {
+    pub(crate) current: OriginStatus,
+    // Replaced origins that still have requests in flight.
+    pub(crate) draining: Vec<OriginStatus>,
+}
+
+impl<T: Display> Origins<T> {
+    pub(crate) fn new(backends: T) -> Origins<T> {
+        Origins {
+            current: RwLock::new(Arc::new(Origin::new(backends))),
+            draining: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The origin for new requests.
+    pub(crate) fn current(&self) -> Arc<Origin<T>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Sends new requests to other backends and returns the previous ones.
+    pub(crate) fn switch(&self, backends: T) -> OriginStatus {
+        let new = Arc::new(Origin::new(backends));
+        let previous = std::mem::replace(&mut *self.current.write().unwrap(), new);
+        let status = previous.status();
+        let mut draining = self.draining.lock().unwrap();
+        draining.retain(|origin| origin.in_flight.load(Ordering::SeqCst) > 0);
+        if status.in_flight > 0 {
+            draining.push(previous);
+        }
+        status
+    }
+
+    pub(crate) fn status(&self) -> OriginsStatus {
+        let mut draining = self.draining.lock().unwrap();
+        draining.retain(|origin| origin.in_flight.load(Ordering::SeqCst) > 0);
+        OriginsStatus {
+            current: self.current().status(),
+            draining: draining.iter().map(|origin| origin.status()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Origins;
+
+    #[test]
+    fn switch() {
+        let origins = Origins::new("http://blue:80".to_string());
+        let blue = origins.current();
+        let request = blue.start();
+
+        let previous = origins.switch("http://green:80".to_string());
+        assert_eq!("http://blue:80", previous.origin);
+        assert_eq!(1, previous.in_flight);
+        assert_eq!("http://green:80", origins.current().url());
+        let status = origins.status();
+        assert_eq!(1, status.draining.len());
+
+        drop(request);
+        assert!(origins.status().draining.is_empty());
+        assert_eq!(0, origins.switch("http://blue:80".to_string()).in_flight);
+        assert!(origins.status().draining.is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+//! Parsers for the Cache-Control, Cookie and Accept-Encoding headers and for
+//! HTTP dates, which are also formatted here. They work on slices of the header values without allocating
+//! and never fail: parts that make no sense are skipped, headers that are not
+//! valid strings are ignored.
+
+use hyper::header::{ACCEPT_ENCODING, CACHE_CONTROL, COOKIE, PRAGMA};
+use hyper::HeaderMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The directives of the Cache-Control headers that decide about caching.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct CacheControl {
+    pub(crate) public: bool,
+    pub(crate) private: bool,
+    pub(crate) no_cache: bool,
+    pub(crate) no_store: bool,
+    // Invalid values count as missing.
+    pub(crate) max_age: Option<u64>,
+    // Lifetime in shared caches like this one, overrides max-age.
+    pub(crate) s_maxage: Option<u64>,
+    // Seconds that the response may be served stale when upstream fails,
+    // RFC 5861.
+    pub(crate) stale_if_error: Option<u64>,
+    // Request directives: the client wants a response that stays fresh for
+    // at least that many seconds, or accepts one that is stale for at most
+    // that long. A max-stale without value accepts any stale response.
+    pub(crate) min_fresh: Option<u64>,
+    pub(crate) max_stale: Option<u64>,
+}
+
+impl CacheControl {
+    /// Reads all Cache-Control headers, later directives win.
+    pub(crate) fn parse(headers: &HeaderMap) -> CacheControl {
+        let mut cache_control = CacheControl::default();
+        let values = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        for (name, value) in values.flat_map(directives) {
+            if name.eq_ignore_ascii_case("public") {
+                cache_control.public = true;
+            } else if name.eq_ignore_ascii_case("private") {
+                cache_control.private = true;
+            } else if name.eq_ignore_ascii_case("no-cache") {
+                cache_control.no_cache = true;
+            } else if name.eq_ignore_ascii_case("no-store") {
+                cache_control.no_store = true;
+            } else if name.eq_ignore_ascii_case("max-age") {
+                cache_control.max_age = value.and_then(seconds);
+            } else if name.eq_ignore_ascii_case("s-maxage") {
+                cache_control.s_maxage = value.and_then(seconds);
+            } else if name.eq_ignore_ascii_case("stale-if-error") {
+                cache_control.stale_if_error = value.and_then(seconds);
+            } else if name.eq_ignore_ascii_case("min-fresh") {
+                cache_control.min_fresh = value.and_then(seconds);
+            } else if name.eq_ignore_ascii_case("max-stale") {
+                cache_control.max_stale = match value {
+                    Some(value) => seconds(value),
+                    None => Some(u64::from(u32::MAX)),
+                };
+            }
+        }
+        cache_control
+    }
+
+    /// Reads the Cache-Control headers of a request. Without them a
+    /// "Pragma: no-cache" of HTTP/1.0 clients counts as no-cache.
+    pub(crate) fn parse_request(headers: &HeaderMap) -> CacheControl {
+        let mut cache_control = CacheControl::parse(headers);
+        if !headers.contains_key(CACHE_CONTROL) {
+            cache_control.no_cache = headers
+                .get_all(PRAGMA)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(directives)
+                .any(|(name, _)| name.eq_ignore_ascii_case("no-cache"));
+        }
+        cache_control
+    }
+
+    /// Seconds that a shared cache may store the response, s-maxage takes
+    /// precedence over max-age.
+    pub(crate) fn shared_max_age(&self) -> Option<u64> {
+        self.s_maxage.or(self.max_age)
+    }
+
+    /// Checks if a shared cache may store the response at all, which
+    /// "private" and "no-store" forbid.
+    pub(crate) fn is_shared_cachable(&self) -> bool {
+        !self.private && !self.no_store
+    }
+}
+
+/// Splits a Cache-Control value into directive names and values. Quotes
+/// around values are removed.
+fn directives(value: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    value.split(',').filter_map(|directive| {
+        let mut parts = directive.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() {
+            return None;
+        }
+        let value = parts.next().map(|value| {
+            let value = value.trim();
+            if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            }
+        });
+        Some((name, value))
+    })
+}
+
+/// Parses delta seconds, which are digits only.
+pub(crate) fn seconds(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    // Too large values mean "forever", capped like RFC 7234 suggests.
+    Some(value.parse().unwrap_or(u64::from(u32::MAX)))
+}
+
+/// Returns the name and value of every cookie in the Cookie headers. Cookies
+/// without "=" have an empty value.
+pub(crate) fn cookies(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| {
+            let mut parts = cookie.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            if name.is_empty() {
+                return None;
+            }
+            Some((name, parts.next().unwrap_or("").trim()))
+        })
+}
+
+/// Checks if the Accept-Encoding headers allow a content coding like "gzip",
+/// by name or by "*", with a quality above 0.
+pub(crate) fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let mut wildcard = None;
+    let codings = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let mut parts = coding.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let accepted = parts
+            .filter_map(|parameter| {
+                let mut parameter = parameter.splitn(2, '=');
+                let name = parameter.next()?.trim();
+                if !name.eq_ignore_ascii_case("q") {
+                    return None;
+                }
+                parameter.next()?.trim().parse::<f32>().ok()
+            })
+            .next()
+            .is_none_or(|quality| quality > 0.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return accepted;
+        }
+        if name == "*" {
+            wildcard = Some(accepted);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+/// Parses an HTTP date in any of the three formats of RFC 7231: IMF-fixdate
+/// like "Sun, 06 Nov 1994 08:49:37 GMT", the obsolete RFC 850 format like
+/// "Sunday, 06-Nov-94 08:49:37 GMT" and asctime like
+/// "Sun Nov  6 08:49:37 1994". Dates before 1970 are not supported.
+pub(crate) fn http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse().ok()?, *time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            // Two-digit years that look more than 50 years in the future
+            // are in the past, RFC 7231 section 7.1.1.1.
+            let year: i64 = year.parse().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, *time)
+        }
+        [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+    let day: i64 = day.parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|name| *name == month)? as i64
+        + 1;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 || year < 1970 {
+        return None;
+    }
+    // Days since 1970-01-01 of a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era - 719_468) as u64;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+/// Formats a time as IMF-fixdate like "Sun, 06 Nov 1994 08:49:37 GMT".
+pub(crate) fn format_http_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let (year, month, day) = civil_date(seconds);
+    let time_of_day = seconds % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday.
+        ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][(seconds / 86400 % 7) as usize],
+        day,
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"]
+            [month as usize - 1],
+        year,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+/// Returns the year, month and day in UTC of seconds since 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub(crate) fn civil_date(seconds: u64) -> (i64, i64, i64) {
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accepts_encoding, cookies, format_http_date, http_date, CacheControl};
+    use hyper::header::{HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, COOKIE, PRAGMA};
+    use hyper::HeaderMap;
+    use rand::Rng;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn cache_control(values: &[&'static str]) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(CACHE_CONTROL, HeaderValue::from_static(value));
+        }
+        CacheControl::parse(&headers)
+    }
+
+    #[test]
+    fn cache_control_directives() {
+        assert_eq!(
+            cache_control(&["public, max-age=60"]),
+            CacheControl {
+                public: true,
+                max_age: Some(60),
+                ..CacheControl::default()
+            }
+        );
+        assert_eq!(
+            cache_control(&["Private", "NO-STORE,no-cache", "max-age=\"5\""]),
+            CacheControl {
+                private: true,
+                no_cache: true,
+                no_store: true,
+                max_age: Some(5),
+                ..CacheControl::default()
+            }
+        );
+        assert_eq!(cache_control(&["max-age=-1"]).max_age, None);
+        assert_eq!(cache_control(&["max-age=1e3"]).max_age, None);
+        assert_eq!(cache_control(&["max-age"]).max_age, None);
+        assert_eq!(
+            cache_control(&["max-age=99999999999999999999999"]).max_age,
+            Some(u64::from(u32::MAX))
+        );
+        assert!(cache_control(&[",,=,public=,"]).public);
+        assert_eq!(
+            cache_control(&["max-age=60, stale-if-error=3600"]).stale_if_error,
+            Some(3600)
+        );
+    }
+
+    #[test]
+    fn s_maxage() {
+        let parsed = cache_control(&["public, max-age=3600, S-MAXAGE=0"]);
+        assert_eq!(Some(0), parsed.shared_max_age());
+        assert!(parsed.is_shared_cachable());
+        let parsed = cache_control(&["s-maxage=60"]);
+        assert_eq!(Some(60), parsed.shared_max_age());
+        assert!(parsed.is_shared_cachable());
+        let parsed = cache_control(&["max-age=60"]);
+        assert_eq!(Some(60), parsed.shared_max_age());
+        assert!(parsed.is_shared_cachable());
+        assert!(!cache_control(&["private, s-maxage=60"]).is_shared_cachable());
+        assert!(!cache_control(&["public, max-age=60, no-store"]).is_shared_cachable());
+    }
+
+    #[test]
+    fn request_directives() {
+        let parsed = cache_control(&["max-age=0, min-fresh=30, max-stale"]);
+        assert_eq!(Some(0), parsed.max_age);
+        assert_eq!(Some(30), parsed.min_fresh);
+        assert_eq!(Some(u64::from(u32::MAX)), parsed.max_stale);
+        assert_eq!(Some(5), cache_control(&["max-stale=5"]).max_stale);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(PRAGMA, HeaderValue::from_static("no-cache"));
+        assert!(CacheControl::parse_request(&headers).no_cache);
+        // Cache-Control takes precedence over Pragma.
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        assert!(!CacheControl::parse_request(&headers).no_cache);
+    }
+
+    #[test]
+    fn cookie_pairs() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("a=1; b = 2;;c; =x"));
+        headers.append(COOKIE, HeaderValue::from_static("d=e=f"));
+        let pairs: Vec<_> = cookies(&headers).collect();
+        assert_eq!(pairs, vec![("a", "1"), ("b", "2"), ("c", ""), ("d", "e=f")]);
+    }
+
+    #[test]
+    fn accepted_encodings() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts_encoding(&headers, "gzip")
+        };
+        assert!(accepts("gzip, deflate, br"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("*, gzip;q=0"));
+        assert!(!accepts("*;q=0"));
+        assert!(!accepts("identity"));
+        assert!(!accepts_encoding(&HeaderMap::new(), "gzip"));
+    }
+
+    // Random header values made from characters that matter to the parsers
+    // must never panic and only produce trimmed, non-empty names.
+    #[test]
+    fn malformed_input() {
+        let alphabet = b" \t,;=\"'SEpubliczmax-age0123456789";
+        let mut rng = rand::thread_rng();
+        for _ in 0..2000 {
+            let length = rng.gen_range(0, 40);
+            let value: String = (0..length)
+                .map(|_| alphabet[rng.gen_range(0, alphabet.len())] as char)
+                .collect();
+            let header = HeaderValue::from_str(&value).unwrap();
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_CONTROL, header.clone());
+            headers.insert(COOKIE, header);
+
+            let parsed = CacheControl::parse(&headers);
+            if parsed.max_age.is_some() {
+                assert!(value.to_ascii_lowercase().contains("max-age"), "{}", value);
+            }
+            if parsed.public {
+                assert!(value.contains("public"), "{}", value);
+            }
+            for (name, cookie_value) in cookies(&headers) {
+                assert!(!name.is_empty(), "{}", value);
+                assert_eq!(name, name.trim(), "{}", value);
+                assert!(!name.contains(';') && !name.contains('='), "{}", value);
+                assert!(!cookie_value.contains(';'), "{}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn http_dates() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784_111_777));
+        assert_eq!(expected, http_date("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!(expected, http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(expected, http_date("Sun Nov  6 08:49:37 1994"));
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(4_070_908_800)),
+            http_date("Thu, 01 Jan 2099 00:00:00 GMT")
+        );
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(951_868_800)),
+            http_date("Wed, 01 Mar 2000 00:00:00 GMT")
+        );
+        assert_eq!(None, http_date("Sun, 06 Nov 1994 08:49:37 CET"));
+        assert_eq!(None, http_date("Sun, 32 Nov 1994 08:49:37 GMT"));
+        assert_eq!(None, http_date("0"));
+        assert_eq!(None, http_date(""));
+    }
+
+    #[test]
+    fn formatted_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", format_http_date(time));
+        let time = UNIX_EPOCH + Duration::from_secs(951_868_800);
+        assert_eq!(Some(time), http_date(&format_http_date(time)));
+    }
+}
@@ -0,0 +1,238 @@
+//! Normalization of request URIs, so that spellings of the same URL share a
+//! cache entry and reach the same backend resource. Percent-encodings and
+//! host names are always normalized, slashes only if configured.
+
+use hyper::header::{HeaderValue, HOST};
+use hyper::{HeaderMap, Uri};
+use serde::Deserialize;
+use std::str;
+
+/// What happens with a trailing slash of request paths.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    /// Paths are passed on as they are.
+    #[default]
+    Keep,
+    /// "/foo/" becomes "/foo". The root path "/" is kept.
+    Strip,
+    /// "/foo" becomes "/foo/", except for paths whose last segment contains
+    /// a dot like "/style.css".
+    Add,
+}
+
+/// Returns the normalized URI, or None if it is already normalized. Encoded
+/// slashes like "%2F" are not touched.
+pub(crate) fn normalize(
+    uri: &Uri,
+    merge_slashes: bool,
+    trailing_slash: TrailingSlash,
+) -> Option<Uri> {
+    let path = normalize_percent_encoding(uri.path());
+    let mut normalized = if merge_slashes {
+        let mut merged = String::with_capacity(path.len());
+        for c in path.chars() {
+            if c != '/' || !merged.ends_with('/') {
+                merged.push(c);
+            }
+        }
+        merged
+    } else {
+        path
+    };
+    match trailing_slash {
+        TrailingSlash::Keep => {}
+        TrailingSlash::Strip => {
+            while normalized.len() > 1 && normalized.ends_with('/') {
+                normalized.pop();
+            }
+        }
+        TrailingSlash::Add => {
+            let last_segment = normalized.rsplit('/').next().unwrap_or("");
+            if !last_segment.is_empty() && !last_segment.contains('.') {
+                normalized.push('/');
+            }
+        }
+    }
+    let query = uri.query().map(normalize_percent_encoding);
+    // Only absolute URIs from proxy clients have an authority. User info is
+    // left alone.
+    let authority = uri
+        .authority_part()
+        .filter(|authority| !authority.as_str().contains('@'))
+        .map(|authority| with_port(&ascii_host(authority.host()), authority.port_u16()));
+    if normalized == uri.path()
+        && query.as_deref() == uri.query()
+        && authority.as_deref() == uri.authority_part().map(|authority| authority.as_str())
+    {
+        return None;
+    }
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(&query);
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(normalized.parse().ok()?);
+    if let Some(authority) = authority {
+        parts.authority = Some(authority.parse().ok()?);
+    }
+    Uri::from_parts(parts).ok()
+}
+
+/// Rewrites the Host header to the lowercase ASCII form of the host name, so
+/// that "Bücher.de" and "xn--bcher-kva.de" are the same site.
+pub(crate) fn normalize_host(headers: &mut HeaderMap) {
+    let host = match headers
+        .get(HOST)
+        .map(|host| str::from_utf8(host.as_bytes()))
+    {
+        Some(Ok(host)) => host,
+        _ => return,
+    };
+    let (name, port) = match host.rfind(':') {
+        Some(index) if host[index + 1..].bytes().all(|byte| byte.is_ascii_digit()) => {
+            (&host[..index], host[index + 1..].parse().ok())
+        }
+        _ => (host, None),
+    };
+    let normalized = with_port(&ascii_host(name), port);
+    if normalized != host {
+        if let Ok(value) = HeaderValue::from_str(&normalized) {
+            headers.insert(HOST, value);
+        }
+    }
+}
+
+/// The lowercase ASCII form of a host name, with internationalized names in
+/// punycode like "xn--bcher-kva.de". IPv6 addresses and invalid names are
+/// returned as they are.
+pub(crate) fn ascii_host(host: &str) -> String {
+    if host.starts_with('[') {
+        return host.to_string();
+    }
+    idna::domain_to_ascii(host).unwrap_or_else(|_| host.to_string())
+}
+
+fn with_port(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Normalizes percent-encodings like RFC 3986 section 6.2.2: hex digits in
+/// uppercase and unreserved characters decoded, so "%c3%a9" and "%C3%A9" or
+/// "%7E" and "~" are the same.
+fn normalize_percent_encoding(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut normalized = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| {
+            str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        });
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                    normalized.push(byte as char);
+                } else {
+                    normalized.push_str(&format!("%{:02X}", byte));
+                }
+                i += 3;
+            }
+            (byte, _) => {
+                // URIs are ASCII.
+                normalized.push(byte as char);
+                i += 1;
+            }
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ascii_host, normalize, normalize_host, TrailingSlash};
+    use hyper::header::{HeaderValue, HOST};
+    use hyper::{HeaderMap, Uri};
+
+    fn normalized(uri: &str, merge_slashes: bool, trailing_slash: TrailingSlash) -> String {
+        let uri: Uri = uri.parse().unwrap();
+        normalize(&uri, merge_slashes, trailing_slash)
+            .unwrap_or(uri)
+            .to_string()
+    }
+
+    #[test]
+    fn merge_slashes() {
+        assert_eq!(
+            "/foo/bar?a=//b",
+            normalized("//foo///bar?a=//b", true, TrailingSlash::Keep)
+        );
+        assert_eq!(
+            "/a%2F%2Fb/",
+            normalized("/a%2F%2Fb//", true, TrailingSlash::Keep)
+        );
+        assert_eq!(
+            "http://example.com/a/b",
+            normalized("http://example.com//a//b", true, TrailingSlash::Keep)
+        );
+        assert_eq!("//foo", normalized("//foo", false, TrailingSlash::Keep));
+    }
+
+    #[test]
+    fn trailing_slash() {
+        assert_eq!(
+            "/foo?a",
+            normalized("/foo//?a", false, TrailingSlash::Strip)
+        );
+        assert_eq!("/", normalized("/", true, TrailingSlash::Strip));
+        assert_eq!("/foo/", normalized("/foo", false, TrailingSlash::Add));
+        assert_eq!("/foo/", normalized("/foo/", false, TrailingSlash::Add));
+        assert_eq!(
+            "/a/style.css",
+            normalized("/a/style.css", false, TrailingSlash::Add)
+        );
+        assert_eq!("/", normalized("/", false, TrailingSlash::Add));
+    }
+
+    #[test]
+    fn percent_encoding() {
+        assert_eq!(
+            "/caf%C3%A9/~a?q=%C3%A9%2B%2F-",
+            normalized(
+                "/caf%c3%a9/%7E%61?q=%c3%a9%2b%2F%2D",
+                false,
+                TrailingSlash::Keep
+            )
+        );
+        assert_eq!(
+            "/100%25?a=%",
+            normalized("/100%25?a=%", false, TrailingSlash::Keep)
+        );
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!("xn--bcher-kva.de", ascii_host("Bücher.de"));
+        assert_eq!("xn--bcher-kva.de", ascii_host("XN--BCHER-KVA.de"));
+        assert_eq!("[::1]", ascii_host("[::1]"));
+        assert_eq!(
+            "http://xn--bcher-kva.de:8080/a",
+            normalized("http://XN--Bcher-kva.DE:8080/a", false, TrailingSlash::Keep)
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HOST,
+            HeaderValue::from_bytes("Bücher.de:8080".as_bytes()).unwrap(),
+        );
+        normalize_host(&mut headers);
+        assert_eq!("xn--bcher-kva.de:8080", headers[HOST]);
+        headers.insert(HOST, HeaderValue::from_static("[::1]:80"));
+        normalize_host(&mut headers);
+        assert_eq!("[::1]:80", headers[HOST]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Placeholders in cached pages that are filled in for every client, for
+//! example a CSRF token or the user name in an otherwise shared HTML page.
+//! The cache stores the page with the markers, every delivery replaces them
+//! with the bodies of backend subrequests made with the client's cookies.
+
+use crate::backend::Backend;
+use crate::encoding;
+use crate::routes::{find_bytes, replace_bytes, Route};
+use crate::ResponseFuture;
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use hyper::header::{
+    HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, COOKIE, ETAG, LAST_MODIFIED,
+};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::time::SystemTime;
+
+/// A marker that is replaced with the body of a backend response.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Placeholder {
+    /// The text that is replaced, for example "<!--USERNAME-->".
+    pub marker: String,
+    /// Backend path whose response replaces the marker, for example
+    /// "/fragments/username". It is requested with the Cookie and
+    /// Authorization headers of the client and never cached. The marker is
+    /// removed if the backend fails or answers with an error.
+    pub path: String,
+}
+
+/// The headers of a client request that are passed on to subrequests.
+pub(crate) fn client_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut forwarded = HeaderMap::new();
+    for name in &[COOKIE, AUTHORIZATION] {
+        for value in headers.get_all(name) {
+            forwarded.append(name, value.clone());
+        }
+    }
+    forwarded
+}
+
+/// Replaces the placeholders of the route in a successful response. `fetch`
+/// sends a subrequest for a backend path.
+pub(crate) fn substitute<F>(
+    response: Response<Body>,
+    route: &Route,
+    client_headers: HeaderMap,
+    fetch: F,
+) -> ResponseFuture
+where
+    F: Fn(&str, &HeaderMap) -> ResponseFuture + Send + 'static,
+{
+    if route.placeholders.is_empty()
+        || response.status() != StatusCode::OK
+        || !route.transforms_content_type(response.headers(), true)
+    {
+        return Box::new(future::ok(response));
+    }
+    let placeholders = route.placeholders.clone();
+    let (mut parts, body) = response.into_parts();
+    let gzip = encoding::is_gzip(&parts.headers);
+    Box::new(body.concat2().and_then(move |body| {
+        let page = if gzip {
+            match encoding::decompress(&body) {
+                Ok(page) => page,
+                Err(error) => {
+                    eprintln!("Failed to decompress cached page: {}", error);
+                    return Either::A(future::ok(Response::from_parts(parts, body.into())));
+                }
+            }
+        } else {
+            body.to_vec()
+        };
+        let used: Vec<Placeholder> = placeholders
+            .into_iter()
+            .filter(|placeholder| find_bytes(&page, placeholder.marker.as_bytes()).is_some())
+            .collect();
+        if used.is_empty() {
+            return Either::A(future::ok(Response::from_parts(parts, body.into())));
+        }
+        let fragments: Vec<_> = used
+            .iter()
+            .map(|placeholder| {
+                let path = placeholder.path.clone();
+                fetch(&placeholder.path, &client_headers)
+                    .and_then(|response| {
+                        let success = response.status().is_success();
+                        response.into_body().concat2().map(move |body| {
+                            if success {
+                                body.to_vec()
+                            } else {
+                                Vec::new()
+                            }
+                        })
+                    })
+                    .or_else(move |error| {
+                        eprintln!("Placeholder subrequest for {} failed: {}", path, error);
+                        Ok(Vec::new())
+                    })
+            })
+            .collect();
+        Either::B(future::join_all(fragments).map(move |fragments| {
+            let page = used
+                .iter()
+                .zip(fragments)
+                .fold(page, |page, (placeholder, fragment)| {
+                    replace_bytes(&page, placeholder.marker.as_bytes(), &fragment)
+                });
+            // The page is personal now. Its length and validators changed.
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.remove(ETAG);
+            parts.headers.remove(LAST_MODIFIED);
+            parts
+                .headers
+                .insert(CACHE_CONTROL, HeaderValue::from_static("private, no-cache"));
+            let page = if gzip {
+                encoding::compress(&page)
+            } else {
+                page
+            };
+            Response::from_parts(parts, Body::from(page))
+        }))
+    }))
+}
+
+/// Builds the subrequest for a placeholder path.
+pub(crate) fn subrequest(
+    backend: &Backend,
+    upstream_origin: &str,
+    path: &str,
+    headers: &HeaderMap,
+) -> Request<Body> {
+    let mut request = Request::get(format!("{}{}", upstream_origin, path))
+        .body(Body::empty())
+        .unwrap();
+    *request.headers_mut() = headers.clone();
+    if let Some(ref auth) = backend.auth {
+        auth.authorize(&mut request, SystemTime::now());
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{substitute, Placeholder};
+    use crate::routes::Route;
+    use crate::ResponseFuture;
+    use futures::{future, Future, Stream};
+    use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, COOKIE};
+    use hyper::{Body, HeaderMap, Response, StatusCode};
+
+    #[test]
+    fn placeholders() {
+        let route = Route {
+            placeholders: vec![
+                Placeholder {
+                    marker: "<!--USERNAME-->".to_string(),
+                    path: "/fragments/username".to_string(),
+                },
+                Placeholder {
+                    marker: "<!--CSRF-->".to_string(),
+                    path: "/fragments/csrf".to_string(),
+                },
+                Placeholder {
+                    marker: "<!--UNUSED-->".to_string(),
+                    path: "/fragments/unused".to_string(),
+                },
+            ],
+            ..Route::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, "session=alice".parse().unwrap());
+        let fetch = |path: &str, headers: &HeaderMap| -> ResponseFuture {
+            assert_ne!("/fragments/unused", path);
+            let response = match path {
+                "/fragments/username" => Response::new(Body::from(
+                    headers[COOKIE].to_str().unwrap()[8..].to_string(),
+                )),
+                _ => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("error"))
+                    .unwrap(),
+            };
+            Box::new(future::ok(response))
+        };
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .body(Body::from(
+                "<p>Hi <!--USERNAME-->!</p><input value=\"<!--CSRF-->\"><!--USERNAME-->",
+            ))
+            .unwrap();
+        let response = substitute(response, &route, headers, fetch).wait().unwrap();
+        assert_eq!("private, no-cache", response.headers()[CACHE_CONTROL]);
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&b"<p>Hi alice!</p><input value=\"\">alice"[..], &body[..]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Cache policies that override the caching headers of upstream responses
+//! depending on their content type.
+
+use crate::routes::content_type_matches;
+use hyper::header::CONTENT_TYPE;
+use hyper::HeaderMap;
+use serde::Deserialize;
+
+/// Caching rule for responses of one content type.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachePolicy {
+    /// Content type the policy applies to, for example "image/png". A trailing
+    /// "*" matches any content type with that prefix, like "image/*".
+    pub content_type: String,
+    /// Set to false to never cache responses of this content type.
+    #[serde(default = "default_cache")]
+    pub cache: bool,
+    /// Cache lifetime in seconds. Responses are cached for this long even if
+    /// upstream does not send a max-age.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Bodies larger than this many bytes are not cached, so that a few big
+    /// downloads cannot push many small pages out of the cache.
+    #[serde(default)]
+    pub max_size: Option<usize>,
+}
+
+fn default_cache() -> bool {
+    true
+}
+
+/// Cache lifetime for responses of one status code that don't say how long
+/// they are fresh, for example to cache 404s briefly.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusTtl {
+    pub status: u16,
+    /// Cache lifetime in seconds.
+    pub ttl: u64,
+}
+
+/// The default TTLs: missing pages are cached briefly, so that requests for
+/// them don't all reach the backend.
+pub(crate) fn default_status_ttls() -> Vec<StatusTtl> {
+    vec![
+        StatusTtl {
+            status: 404,
+            ttl: 10,
+        },
+        StatusTtl {
+            status: 410,
+            ttl: 60,
+        },
+    ]
+}
+
+/// Returns the first policy that applies to a response with these headers.
+pub(crate) fn find_policy<'a>(
+    policies: &'a [CachePolicy],
+    headers: &HeaderMap,
+) -> Option<&'a CachePolicy> {
+    let content_type = match headers.get(CONTENT_TYPE).map(|value| value.to_str()) {
+        Some(Ok(content_type)) => content_type,
+        _ => return None,
+    };
+    policies
+        .iter()
+        .find(|policy| content_type_matches(&policy.content_type, content_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_policy;
+    use crate::Config;
+    use hyper::header::{HeaderValue, CONTENT_TYPE};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn first_matching_policy() {
+        let config: Config = toml::from_str(
+            r#"
+            [[cache_policies]]
+            content_type = "application/octet-stream"
+            cache = false
+
+            [[cache_policies]]
+            content_type = "image/*"
+            ttl = 604800
+            "#,
+        )
+        .unwrap();
+        let policies = &config.cache_policies;
+
+        let mut headers = HeaderMap::new();
+        assert!(find_policy(policies, &headers).is_none());
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert_eq!(Some(604800), find_policy(policies, &headers).unwrap().ttl);
+
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        assert!(!find_policy(policies, &headers).unwrap().cache);
+    }
+}
@@ -0,0 +1,102 @@
+//! Opt-in caching of POST requests, for GraphQL and search APIs that send
+//! read-only queries in the request body. The body is read before the request
+//! is handled and a hash of its normalized form becomes part of the cache key.
+
+use crate::routes::content_type_matches;
+use crate::{graphql, CacheKey, Route};
+use futures::{Future, Stream};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Method, Request};
+use twox_hash::XxHash3_128;
+
+/// Checks if the body of a POST request should be read to find its cache
+/// key. Only bodies with a known length within the limit of the route are
+/// read, everything else is streamed to upstream uncached.
+pub(crate) fn wants_body_hash(request: &Request<Body>, route: Option<&Route>) -> bool {
+    let route = match route {
+        Some(route) if route.cache_post => route,
+        _ => return false,
+    };
+    if request.method() != Method::POST || request.extensions().get::<CacheKey>().is_some() {
+        return false;
+    }
+    request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok())
+        .is_some_and(|length| length <= route.cache_post_max_body)
+}
+
+/// Reads the body and attaches the cache key to the request. On GraphQL
+/// routes persisted queries get the same key as with GET.
+pub(crate) fn hash_body(
+    request: Request<Body>,
+    graphql: bool,
+) -> impl Future<Item = Request<Body>, Error = hyper::Error> {
+    let (mut parts, body) = request.into_parts();
+    body.concat2().map(move |body| {
+        let persisted_query = if graphql {
+            graphql::key_from_body(parts.uri.path(), &body)
+        } else {
+            None
+        };
+        let content_type = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("");
+        let key = persisted_query.unwrap_or_else(|| {
+            let normalized = normalize(content_type, &body);
+            format!(
+                "POST {} {:032x}",
+                parts.uri,
+                XxHash3_128::oneshot(&normalized)
+            )
+        });
+        parts.extensions.insert(CacheKey(key));
+        Request::from_parts(parts, Body::from(body))
+    })
+}
+
+/// Brings equivalent bodies into the same form: JSON objects get sorted keys
+/// and no whitespace, form fields are sorted. Other bodies are used as they
+/// are.
+fn normalize(content_type: &str, body: &[u8]) -> Vec<u8> {
+    if content_type_matches("application/json", content_type) {
+        // serde_json sorts object keys.
+        if let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) {
+            return serde_json::to_vec(&json).unwrap();
+        }
+    } else if content_type_matches("application/x-www-form-urlencoded", content_type) {
+        let mut fields: Vec<&[u8]> = body
+            .split(|byte| *byte == b'&')
+            .filter(|field| !field.is_empty())
+            .collect();
+        fields.sort();
+        return fields.join(&b'&');
+    }
+    body.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn normalized_bodies() {
+        assert_eq!(
+            normalize("application/json", br#"{ "b": 1, "a": [2, 3] }"#),
+            br#"{"a":[2,3],"b":1}"#.to_vec()
+        );
+        assert_eq!(
+            normalize("application/json; charset=utf-8", b"not json"),
+            b"not json".to_vec()
+        );
+        assert_eq!(
+            normalize("application/x-www-form-urlencoded", b"q=x&a=1&"),
+            b"a=1&q=x".to_vec()
+        );
+        assert_eq!(normalize("text/plain", b"b a"), b"b a".to_vec());
+    }
+}
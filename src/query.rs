@@ -0,0 +1,131 @@
+//! Normalization of query strings, so that URLs that only differ in the
+//! order of their parameters or in parameters that don't change the page
+//! share a cache entry.
+
+use serde::Deserialize;
+use std::borrow::Cow;
+
+/// How the query parameters of cache keys are normalized. Upstream still
+/// gets the query of the client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryNormalization {
+    /// Sort the parameters by name. Repeated parameters keep their order.
+    pub sort: bool,
+    /// Names of parameters that are removed, by default the ones of
+    /// marketing campaigns like "utm_source" and "gclid". A trailing "*"
+    /// matches any name with that prefix, like "utm_*".
+    pub strip: Vec<String>,
+    /// Names of the only parameters that are kept, all if not set. An empty
+    /// list ignores the query.
+    pub keep: Option<Vec<String>>,
+}
+
+impl Default for QueryNormalization {
+    fn default() -> QueryNormalization {
+        QueryNormalization {
+            sort: false,
+            strip: vec![
+                "utm_*".to_string(),
+                "gclid".to_string(),
+                "fbclid".to_string(),
+            ],
+            keep: None,
+        }
+    }
+}
+
+impl QueryNormalization {
+    fn is_noop(&self) -> bool {
+        !self.sort && self.strip.is_empty() && self.keep.is_none()
+    }
+
+    fn keeps(&self, name: &str) -> bool {
+        !self.strip.iter().any(|pattern| name_matches(pattern, name))
+            && self
+                .keep
+                .as_ref()
+                .is_none_or(|keep| keep.iter().any(|pattern| name_matches(pattern, name)))
+    }
+
+    /// Normalizes the query string of a URI or cache key. Anything after a
+    /// space, like the session of a cache key, is left alone.
+    pub(crate) fn normalize<'a>(&self, uri: &'a str) -> Cow<'a, str> {
+        let start = match uri.find('?') {
+            Some(start) if !self.is_noop() => start,
+            _ => return Cow::Borrowed(uri),
+        };
+        let (path, rest) = (&uri[..start], &uri[start + 1..]);
+        let (query, suffix) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+        let mut parameters: Vec<&str> = query
+            .split('&')
+            .filter(|parameter| !parameter.is_empty() && self.keeps(parameter_name(parameter)))
+            .collect();
+        if self.sort {
+            parameters.sort_by_key(|parameter| parameter_name(parameter));
+        }
+        if parameters.is_empty() {
+            Cow::Owned(format!("{}{}", path, suffix))
+        } else {
+            Cow::Owned(format!("{}?{}{}", path, parameters.join("&"), suffix))
+        }
+    }
+}
+
+fn parameter_name(parameter: &str) -> &str {
+    parameter.split('=').next().unwrap_or(parameter)
+}
+
+fn name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryNormalization;
+
+    #[test]
+    fn normalize() {
+        let sort = QueryNormalization {
+            sort: true,
+            strip: Vec::new(),
+            keep: None,
+        };
+        assert_eq!("/?a=1&b=2", sort.normalize("/?b=2&a=1"));
+        assert_eq!("/?a=2&a=1&b", sort.normalize("/?b&a=2&&a=1"));
+        assert_eq!(
+            "/?a=1&b=2 session:abc",
+            sort.normalize("/?b=2&a=1 session:abc")
+        );
+        assert_eq!("/page", sort.normalize("/page"));
+
+        let strip = QueryNormalization::default();
+        assert_eq!(
+            "/?id=5&gclid2=x",
+            strip.normalize("/?utm_source=news&id=5&gclid=abc&gclid2=x")
+        );
+        assert_eq!("/", strip.normalize("/?utm_medium=mail"));
+
+        let keep = QueryNormalization {
+            keep: Some(vec!["page".to_string()]),
+            ..QueryNormalization::default()
+        };
+        assert_eq!(
+            "/list?page=2",
+            keep.normalize("/list?sid=1&page=2&utm_id=3")
+        );
+        let ignore = QueryNormalization {
+            keep: Some(Vec::new()),
+            ..QueryNormalization::default()
+        };
+        assert_eq!("/list", ignore.normalize("/list?page=2"));
+        let noop = QueryNormalization {
+            strip: Vec::new(),
+            ..QueryNormalization::default()
+        };
+        assert_eq!("/?b=2&a=1", noop.normalize("/?b=2&a=1"));
+    }
+}
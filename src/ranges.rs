@@ -0,0 +1,192 @@
+//! Range requests served from the cache: one range as a plain 206 response,
+//! several ranges as a multipart/byteranges body, like PDF viewers and video
+//! players request them.
+
+use crate::ResponseFuture;
+use futures::{Future, Stream};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use hyper::{Body, Request, Response, StatusCode};
+use twox_hash::XxHash3_128;
+
+/// More ranges than this are answered with the full body, so that requests
+/// for many tiny ranges can't make us build huge multipart bodies.
+const MAX_RANGES: usize = 32;
+
+/// The ranges of a Range header for a body of a given length.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Ranges {
+    /// Inclusive byte positions, in the requested order.
+    Satisfiable(Vec<(u64, u64)>),
+    /// No range overlaps the body.
+    Unsatisfiable,
+}
+
+/// Parses a Range header value. None if it can't be parsed or is not in
+/// bytes, then the header is ignored.
+pub(crate) fn parse(value: &str, length: u64) -> Option<Ranges> {
+    let value = value.trim();
+    if value.len() < 6 || !value[..6].eq_ignore_ascii_case("bytes=") {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    let mut specs = 0;
+    for spec in value[6..].split(',').map(str::trim) {
+        if spec.is_empty() {
+            continue;
+        }
+        specs += 1;
+        let index = spec.find('-')?;
+        let (first, last) = (spec[..index].trim(), spec[index + 1..].trim());
+        let range = if first.is_empty() {
+            let suffix: u64 = last.parse().ok()?;
+            if suffix == 0 || length == 0 {
+                None
+            } else {
+                Some((length.saturating_sub(suffix), length - 1))
+            }
+        } else {
+            let first: u64 = first.parse().ok()?;
+            let last = if last.is_empty() {
+                None
+            } else {
+                Some(last.parse::<u64>().ok()?)
+            };
+            if last.is_some_and(|last| last < first) {
+                return None;
+            }
+            if first >= length {
+                None
+            } else {
+                Some((first, last.map_or(length - 1, |last| last.min(length - 1))))
+            }
+        };
+        ranges.extend(range);
+    }
+    if specs == 0 {
+        return None;
+    }
+    if ranges.is_empty() {
+        return Some(Ranges::Unsatisfiable);
+    }
+    Some(Ranges::Satisfiable(ranges))
+}
+
+/// Checks the If-Range condition: ranges are only served if the cached
+/// response is the one the client has parts of.
+fn if_range_matches(if_range: Option<&HeaderValue>, headers: &HeaderMap) -> bool {
+    let if_range = match if_range {
+        Some(if_range) => if_range,
+        None => return true,
+    };
+    if if_range.as_bytes().starts_with(b"\"") {
+        // Only strong validators, weak ETags never match.
+        headers.get(ETAG) == Some(if_range)
+    } else {
+        headers.get(LAST_MODIFIED) == Some(if_range)
+    }
+}
+
+/// Answers the Range header of a GET request from a complete cached
+/// response. Other requests and responses are passed on unchanged.
+pub(crate) fn respond(request: &Request<Body>, response: Response<Body>) -> ResponseFuture {
+    let range = match request.headers().get(RANGE) {
+        Some(range) if request.method() == hyper::Method::GET => range.clone(),
+        _ => return Box::new(futures::future::ok(response)),
+    };
+    if response.status() != StatusCode::OK
+        || !if_range_matches(request.headers().get(IF_RANGE), response.headers())
+    {
+        return Box::new(futures::future::ok(response));
+    }
+    let (mut parts, body) = response.into_parts();
+    Box::new(body.concat2().map(move |body| {
+        let length = body.len() as u64;
+        let ranges = match range.to_str().ok().and_then(|range| parse(range, length)) {
+            Some(Ranges::Satisfiable(ranges)) => ranges,
+            Some(Ranges::Unsatisfiable) => {
+                return Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", length))
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            None => return Response::from_parts(parts, body.into()),
+        };
+        if ranges.len() > MAX_RANGES {
+            return Response::from_parts(parts, body.into());
+        }
+
+        parts.status = StatusCode::PARTIAL_CONTENT;
+        let content = if let [(first, last)] = ranges[..] {
+            parts.headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", first, last, length)).unwrap(),
+            );
+            body[first as usize..=last as usize].to_vec()
+        } else {
+            // A hash of the body can't be part of the body.
+            let boundary = format!("{:032x}", XxHash3_128::oneshot(&body));
+            let content_type = parts.headers.remove(CONTENT_TYPE);
+            let mut content = Vec::new();
+            for (first, last) in ranges {
+                content.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                if let Some(ref content_type) = content_type {
+                    content.extend_from_slice(b"Content-Type: ");
+                    content.extend_from_slice(content_type.as_bytes());
+                    content.extend_from_slice(b"\r\n");
+                }
+                content.extend_from_slice(
+                    format!("Content-Range: bytes {}-{}/{}\r\n\r\n", first, last, length)
+                        .as_bytes(),
+                );
+                content.extend_from_slice(&body[first as usize..=last as usize]);
+                content.extend_from_slice(b"\r\n");
+            }
+            content.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+            parts.headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                    .unwrap(),
+            );
+            content
+        };
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(content.len()));
+        Response::from_parts(parts, Body::from(content))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Ranges};
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(
+            Some(Ranges::Satisfiable(vec![(0, 9)])),
+            parse("bytes=0-9", 100)
+        );
+        assert_eq!(
+            Some(Ranges::Satisfiable(vec![(90, 99), (50, 99), (0, 99)])),
+            parse("Bytes=-10, 50-, 0-1000", 100)
+        );
+        assert_eq!(
+            Some(Ranges::Satisfiable(vec![(0, 4)])),
+            parse("bytes=0-4,200-300", 100)
+        );
+        assert_eq!(
+            Some(Ranges::Satisfiable(vec![(0, 9)])),
+            parse("bytes=-100", 10)
+        );
+        assert_eq!(Some(Ranges::Unsatisfiable), parse("bytes=100-", 100));
+        assert_eq!(Some(Ranges::Unsatisfiable), parse("bytes=-0", 100));
+        assert_eq!(None, parse("bytes=9-0", 100));
+        assert_eq!(None, parse("bytes=a-b", 100));
+        assert_eq!(None, parse("bytes=", 100));
+        assert_eq!(None, parse("items=0-9", 100));
+    }
+}
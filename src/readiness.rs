@@ -0,0 +1,150 @@
+//! Readiness endpoint for orchestrators. The proxy only reports ready once
+//! its cache has been warmed up with a list of URLs and the backend answers
+//! its health check, so no traffic is sent to a cold or blind instance.
+
+use crate::backend::{Backend, UpstreamClient};
+use futures::future::{self, Either};
+use futures::{Future, Stream};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::timer::Timeout;
+
+/// Settings for the readiness endpoint.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Readiness {
+    /// Path that answers 200 when the proxy is ready and 503 otherwise, for
+    /// example "/readyz". It is never passed to upstream.
+    pub path: String,
+    /// Paths that are requested through the proxy after startup to fill the
+    /// cache. The proxy is not ready before all of them were answered.
+    #[serde(default)]
+    pub warmup_urls: Vec<String>,
+    /// Backend path that must answer with a 2xx status for the proxy to be
+    /// ready, checked on every readiness request. The backend is not checked
+    /// if not set.
+    #[serde(default)]
+    pub health_check_path: Option<String>,
+    /// How long the health check may take in milliseconds.
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub health_check_timeout_ms: u64,
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    2000
+}
+
+/// Requests the warm-up URLs one after another through the proxy listening
+/// on the address. The returned flag is set when all of them are done,
+/// whether they succeeded or not.
+pub(crate) fn warm_up(
+    mut address: SocketAddr,
+    urls: Vec<String>,
+) -> (Arc<AtomicBool>, impl Future<Item = (), Error = ()>) {
+    // A proxy listening on all interfaces is reachable on loopback.
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let warm = Arc::new(AtomicBool::new(false));
+    let done = warm.clone();
+    let client = Client::new();
+    let requests = futures::stream::iter_ok(urls).for_each(move |url| {
+        let uri = format!("http://{}{}", address, url);
+        let request = match uri.parse() {
+            Ok(uri) => client.get(uri),
+            Err(_) => {
+                eprintln!("Invalid warm-up URL {}", url);
+                return Either::A(future::ok(()));
+            }
+        };
+        Either::B(
+            request
+                .and_then(|response| response.into_body().concat2())
+                .then(move |result| {
+                    if let Err(e) = result {
+                        eprintln!("Warm-up request for {} failed: {}", url, e);
+                    }
+                    Ok(())
+                }),
+        )
+    });
+    (
+        warm,
+        requests.map(move |_| done.store(true, Ordering::SeqCst)),
+    )
+}
+
+/// Answers a readiness request: 200 if the cache is warm and the backend is
+/// healthy, 503 otherwise.
+pub(crate) fn response(
+    client: &UpstreamClient,
+    backend: &Backend,
+    upstream_origin: &str,
+    readiness: &Readiness,
+    warm: &AtomicBool,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+    if !warm.load(Ordering::SeqCst) {
+        return Either::A(future::ok(status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "warming up",
+        )));
+    }
+    let path = match readiness.health_check_path {
+        Some(ref path) => path,
+        None => return Either::A(future::ok(status(StatusCode::OK, "ready"))),
+    };
+    let timeout = Duration::from_millis(readiness.health_check_timeout_ms);
+    Either::B(
+        health_check(client, backend, upstream_origin, path, timeout).then(|healthy| {
+            Ok(if healthy == Ok(true) {
+                status(StatusCode::OK, "ready")
+            } else {
+                status(StatusCode::SERVICE_UNAVAILABLE, "backend unhealthy")
+            })
+        }),
+    )
+}
+
+/// Requests a health check path of a backend origin. True if it answered
+/// with a 2xx status within the timeout.
+pub(crate) fn health_check(
+    client: &UpstreamClient,
+    backend: &Backend,
+    origin: &str,
+    path: &str,
+    timeout: Duration,
+) -> impl Future<Item = bool, Error = ()> {
+    let method = if backend.head_probes {
+        Method::HEAD
+    } else {
+        Method::GET
+    };
+    let mut request = Request::builder()
+        .method(method)
+        .uri(format!("{}{}", origin, path))
+        .body(Body::empty())
+        .unwrap();
+    if let Some(ref auth) = backend.auth {
+        auth.authorize(&mut request, SystemTime::now());
+    }
+    Timeout::new(client.request(request), timeout).then(|result| {
+        Ok(match result {
+            Ok(ref response) => response.status().is_success(),
+            Err(_) => false,
+        })
+    })
+}
+
+fn status(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
@@ -0,0 +1,175 @@
+//! Recording of upstream exchanges to disk and replaying them instead of
+//! contacting upstream, for building test fixtures for client apps.
+//!
+//! Every exchange is stored as a JSON file named after a hash of the request
+//! method and URI. Files are read and written synchronously, so these modes
+//! are only meant for testing.
+
+use crate::{hash_key, hex_encode};
+use futures::{Future, Stream};
+use hyper::header::{
+    HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE,
+};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Headers that can contain secrets and are never written to disk.
+const SECRET_HEADERS: [HeaderName; 4] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE];
+
+/// A request and the response upstream gave to it.
+#[derive(Debug, Deserialize, Serialize)]
+struct Exchange {
+    method: String,
+    uri: String,
+    request_headers: Vec<(String, String)>,
+    status: u16,
+    response_headers: Vec<(String, String)>,
+    body: String,
+    // Binary bodies are stored hex encoded.
+    #[serde(default)]
+    body_hex: bool,
+}
+
+/// The parts of a request that are needed to record the exchange.
+pub(crate) struct RecordedRequest {
+    method: Method,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RecordedRequest {
+    pub(crate) fn new(request: &Request<Body>) -> RecordedRequest {
+        RecordedRequest {
+            method: request.method().clone(),
+            uri: path_and_query(request),
+            headers: sanitize(request.headers()),
+        }
+    }
+}
+
+/// Reads the response body and writes the exchange to the directory. The
+/// response is passed on unchanged.
+pub(crate) fn record(
+    directory: String,
+    request: RecordedRequest,
+    response: Response<Body>,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+    let (parts, body) = response.into_parts();
+    body.concat2().map(move |chunk| {
+        let body = chunk.into_bytes();
+        let (body_string, body_hex) = match std::str::from_utf8(&body) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (hex_encode(&body), true),
+        };
+        let exchange = Exchange {
+            method: request.method.to_string(),
+            uri: request.uri,
+            request_headers: request.headers,
+            status: parts.status.as_u16(),
+            response_headers: sanitize(&parts.headers),
+            body: body_string,
+            body_hex,
+        };
+        let path = file_path(&directory, &request.method, &exchange.uri);
+        let json = serde_json::to_string_pretty(&exchange).unwrap();
+        if let Err(error) = fs::write(&path, json) {
+            eprintln!("Failed to record {}: {}", path.display(), error);
+        }
+        Response::from_parts(parts, Body::from(body))
+    })
+}
+
+/// Answers a request from a recorded exchange, or with 404 if there is none.
+pub(crate) fn replay(directory: &str, request: &Request<Body>) -> Response<Body> {
+    let path = file_path(directory, request.method(), &path_and_query(request));
+    let exchange: Exchange = match fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+    {
+        Some(exchange) => exchange,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("No recording found for this request."))
+                .unwrap()
+        }
+    };
+    let body = if exchange.body_hex {
+        hex_decode(&exchange.body).unwrap_or_default()
+    } else {
+        exchange.body.into_bytes()
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::OK);
+    for (name, value) in exchange.response_headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response
+}
+
+fn path_and_query(request: &Request<Body>) -> String {
+    request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str())
+        .to_string()
+}
+
+fn file_path(directory: &str, method: &Method, uri: &str) -> PathBuf {
+    let hash = hash_key(&format!("{} {}", method, uri));
+    Path::new(directory).join(format!("{:032x}.json", hash))
+}
+
+/// Returns the headers without secrets like cookies.
+fn sanitize(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !SECRET_HEADERS.contains(name))
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|value| (name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex_decode, hex_encode, sanitize};
+    use hyper::header::{HeaderValue, CONTENT_TYPE, COOKIE};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn hex() {
+        let bytes = vec![0, 15, 16, 255];
+        assert_eq!("000f10ff", hex_encode(&bytes));
+        assert_eq!(Some(bytes), hex_decode("000f10ff"));
+        assert_eq!(None, hex_decode("0g"));
+    }
+
+    #[test]
+    fn secrets_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("SESS1=secret"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        assert_eq!(
+            vec![("content-type".to_string(), "text/html".to_string())],
+            sanitize(&headers)
+        );
+    }
+}
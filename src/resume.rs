@@ -0,0 +1,210 @@
+//! Completeness checks for upstream response bodies. Hyper can end a body
+//! silently when the upstream connection breaks off, so bodies with a
+//! Content-Length are counted. Incomplete bodies are turned into errors or,
+//! if configured, completed with Range requests for the missing rest.
+
+use crate::backend::{UpstreamAbort, UpstreamClient};
+use crate::ResponseFuture;
+use futures::{Async, Future, Poll, Stream};
+use hyper::client::ResponseFuture as ClientResponseFuture;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use hyper::{Body, Chunk, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use std::error::Error;
+use std::io;
+
+// How often one body is resumed before the error is passed on.
+const MAX_ATTEMPTS: usize = 3;
+
+type BodyError = Box<dyn Error + Send + Sync>;
+
+/// Sends a request to upstream. Response bodies that are shorter than their
+/// Content-Length end in an error. With `UpstreamAbort::Resume` the body of a
+/// successful GET response that has a validator is resumed instead.
+pub(crate) fn request(
+    client: &UpstreamClient,
+    request: Request<Body>,
+    abort: UpstreamAbort,
+) -> ResponseFuture {
+    if request.method() == Method::HEAD {
+        return Box::new(client.request(request));
+    }
+    let resumable = if abort == UpstreamAbort::Resume && request.method() == Method::GET {
+        Some((
+            client.clone(),
+            request.uri().clone(),
+            request.headers().clone(),
+        ))
+    } else {
+        None
+    };
+    Box::new(client.request(request).map(move |response| {
+        let expected = match content_length(&response) {
+            Some(expected) => expected,
+            None => return response,
+        };
+        let resume = match resumable {
+            Some((client, uri, headers)) if response.status() == StatusCode::OK => {
+                validator(&response).map(|validator| Resume {
+                    client,
+                    uri,
+                    headers,
+                    validator,
+                })
+            }
+            _ => None,
+        };
+        let (parts, body) = response.into_parts();
+        let body = CheckedBody {
+            expected,
+            received: 0,
+            resume,
+            attempts: 0,
+            error: None,
+            state: State::Streaming(body),
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }))
+}
+
+/// Returns the announced length of a response body, if it has one.
+pub(crate) fn content_length(response: &Response<Body>) -> Option<u64> {
+    if response.status().is_informational()
+        || response.status() == StatusCode::NO_CONTENT
+        || response.status() == StatusCode::NOT_MODIFIED
+    {
+        return None;
+    }
+    response
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Returns the value for If-Range, which must be a strong ETag or a
+/// modification date. None if the response cannot be resumed.
+fn validator(response: &Response<Body>) -> Option<HeaderValue> {
+    let headers = response.headers();
+    if headers
+        .get(ACCEPT_RANGES)
+        .is_some_and(|ranges| ranges == "none")
+    {
+        return None;
+    }
+    match headers.get(ETAG) {
+        Some(etag) if !etag.as_bytes().starts_with(b"W/") => Some(etag.clone()),
+        _ => headers.get(LAST_MODIFIED).cloned(),
+    }
+}
+
+/// Checks that a partial response continues exactly where the body broke off
+/// and belongs to the same version of the resource.
+fn continues_at(response: &Response<Body>, offset: u64) -> bool {
+    response.status() == StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .is_some_and(|range| range.starts_with(&format!("bytes {}-", offset)))
+}
+
+enum State {
+    Streaming(Body),
+    Resuming(ClientResponseFuture),
+}
+
+/// What is needed to ask upstream for the rest of a body.
+struct Resume {
+    client: UpstreamClient,
+    uri: Uri,
+    headers: HeaderMap,
+    validator: HeaderValue,
+}
+
+impl Resume {
+    fn range_request(&self, offset: u64) -> Request<Body> {
+        let mut request = Request::get(self.uri.clone()).body(Body::empty()).unwrap();
+        *request.headers_mut() = self.headers.clone();
+        let headers = request.headers_mut();
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&format!("bytes={}-", offset)).unwrap(),
+        );
+        headers.insert(IF_RANGE, self.validator.clone());
+        request
+    }
+}
+
+/// A response body that fails if it ends early, or asks upstream for the
+/// rest.
+struct CheckedBody {
+    expected: u64,
+    // Bytes passed on so far.
+    received: u64,
+    // None if the body cannot be resumed.
+    resume: Option<Resume>,
+    attempts: usize,
+    // The error that broke off the body, returned if resuming fails.
+    error: Option<BodyError>,
+    state: State,
+}
+
+impl CheckedBody {
+    /// Starts a Range request for the rest of the body, or gives up with the
+    /// error.
+    fn resume(&mut self, error: BodyError) -> Result<State, BodyError> {
+        match self.resume {
+            Some(ref resume) if self.attempts < MAX_ATTEMPTS => {
+                self.attempts += 1;
+                self.error = Some(error);
+                Ok(State::Resuming(
+                    resume.client.request(resume.range_request(self.received)),
+                ))
+            }
+            _ => Err(error),
+        }
+    }
+}
+
+impl Stream for CheckedBody {
+    type Item = Chunk;
+    type Error = BodyError;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, BodyError> {
+        loop {
+            let next = match self.state {
+                State::Streaming(ref mut body) => match body.poll() {
+                    Ok(Async::Ready(Some(chunk))) => {
+                        self.received += chunk.len() as u64;
+                        return Ok(Async::Ready(Some(chunk)));
+                    }
+                    Ok(Async::Ready(None)) if self.received >= self.expected => {
+                        return Ok(Async::Ready(None));
+                    }
+                    Ok(Async::Ready(None)) => self.resume(Box::new(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "upstream body ended after {} of {} bytes",
+                            self.received, self.expected
+                        ),
+                    )))?,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(error) => self.resume(Box::new(error))?,
+                },
+                State::Resuming(ref mut response) => match response.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(ref response)) if !continues_at(response, self.received) => {
+                        return Err(self.error.take().unwrap());
+                    }
+                    Ok(Async::Ready(response)) => State::Streaming(response.into_body()),
+                    Err(error) => return Err(Box::new(error)),
+                },
+            };
+            self.state = next;
+        }
+    }
+}
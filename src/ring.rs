@@ -0,0 +1,63 @@
+//! Ring buffer of the most recent items, for keeping the last requests in
+//! memory without growing.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+/// The last `capacity` items. A new item replaces the oldest one when the
+/// ring is full.
+#[derive(Debug)]
+pub(crate) struct Ring<T> {
+    capacity: usize,
+    items: Mutex<VecDeque<T>>,
+}
+
+impl<T> Ring<T> {
+    /// A ring of at most `capacity` items, disabled with 0.
+    pub(crate) fn new(capacity: usize) -> Ring<T> {
+        Ring {
+            capacity,
+            items: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Adds an item and drops the oldest one when the ring is full.
+    pub(crate) fn push(&self, item: T) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut items = self.items.lock().unwrap();
+        if items.len() == self.capacity {
+            items.pop_front();
+        }
+        items.push_back(item);
+    }
+
+    /// The items, oldest first. New items wait while they are locked.
+    pub(crate) fn items(&self) -> MutexGuard<'_, VecDeque<T>> {
+        self.items.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+
+    #[test]
+    fn ring() {
+        let ring = Ring::new(2);
+        for item in 1..=3 {
+            ring.push(item);
+        }
+        assert_eq!(vec![2, 3], ring.items().iter().cloned().collect::<Vec<_>>());
+
+        let disabled = Ring::new(0);
+        disabled.push(1);
+        assert!(!disabled.is_enabled());
+        assert!(disabled.items().is_empty());
+    }
+}
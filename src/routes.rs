@@ -0,0 +1,708 @@
+//! Routes apply settings to requests depending on their URL path.
+
+use crate::encoding;
+use crate::placeholder::Placeholder;
+use crate::security::SecurityHeaders;
+use crate::sniff::ContentTypeGuard;
+use futures::future::{self, Either};
+use futures::{Async, Future, Poll, Stream};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use hyper::{Body, Chunk, HeaderMap, Request, Response, StatusCode, Uri};
+use regex::bytes::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Settings for all requests whose path starts with a prefix.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Route {
+    /// Path prefix of the requests this route applies to, for example
+    /// "/blog/". The first route with a matching prefix is used.
+    pub path_prefix: String,
+    /// Path prefix that replaces `path_prefix` when requests are forwarded,
+    /// for example "/wordpress/" to serve the backend's /wordpress/* as
+    /// /blog/*. With "/" the prefix is stripped. Location headers that point
+    /// into it are mapped back. Cache keys use the public path.
+    pub upstream_path_prefix: Option<String>,
+    /// Content types whose bodies are transformed, for example "text/html".
+    /// A trailing "*" matches any content type with that prefix.
+    pub transform_content_types: Vec<String>,
+    /// Transformations of request bodies before they are forwarded.
+    pub request_body_transforms: Vec<BodyTransform>,
+    /// Transformations of response bodies, applied before caching so that the
+    /// transformed body is stored once.
+    pub response_body_transforms: Vec<BodyTransform>,
+    /// URL prefixes of the upstream host that are replaced with the public
+    /// host in href and src attributes and in Location headers, like Apache's
+    /// `ProxyHTMLURLMap`.
+    pub rewrite_links: Vec<LinkRewrite>,
+    /// Markers in responses of `transform_content_types` that are replaced
+    /// for every client when the response is delivered, also from the
+    /// cache. Pages with placeholders are marked private.
+    pub placeholders: Vec<Placeholder>,
+    /// Name of a director whose backend pools answer requests on this route
+    /// instead of the configured backend.
+    pub director: Option<String>,
+    /// Body of the 502 response when upstream fails, for example a JSON error
+    /// for API routes. "{request_id}" is replaced with the ID of the request.
+    pub error_body: Option<String>,
+    /// Content type of `error_body`, "text/plain; charset=utf-8" if not set.
+    pub error_content_type: Option<String>,
+    /// Cache POST requests, for GraphQL or search APIs whose requests only
+    /// read. The cache key contains a hash of the request body, with JSON
+    /// and form bodies normalized. Only enable this if POST requests on the
+    /// route never change anything.
+    pub cache_post: bool,
+    /// Largest POST body in bytes that is read for the cache key. Bigger
+    /// bodies and bodies without Content-Length are not cached.
+    pub cache_post_max_body: usize,
+    /// Recognize GraphQL persisted queries (Apollo APQ) and key them by
+    /// operation hash, operation name and variables. GET requests for the
+    /// same operation share an entry regardless of the parameter order. POST
+    /// requests are only cached with `cache_post`, because a persisted
+    /// mutation cannot be told apart from a query.
+    pub graphql: bool,
+    /// Maximum bytes per second of every response body on this route, for
+    /// example to cap large downloads. Unlimited if not set.
+    pub bytes_per_second: Option<u64>,
+    /// Largest upstream response body in bytes that is passed on, for API
+    /// routes with misbehaving backends. Responses that announce a bigger
+    /// Content-Length are answered with a 502, others break off at the
+    /// limit. Unlimited if not set.
+    pub max_response_size: Option<u64>,
+    /// Add "X-Content-Type-Options: nosniff" to responses, so that browsers
+    /// trust their Content-Type.
+    pub nosniff: bool,
+    /// Check the start of upstream response bodies against their
+    /// Content-Type and flag or reject obvious mismatches. Disabled if not
+    /// set.
+    pub content_type_guard: Option<ContentTypeGuard>,
+    /// Security headers for HTML responses on this route, instead of the
+    /// global `security_headers`.
+    pub security_headers: Option<SecurityHeaders>,
+    /// Query parameters that version assets, for example "v" or "build".
+    /// If set, only these parameters are part of the cache key and other
+    /// query parameters are ignored for caching, so a deploy that bumps the
+    /// version gets new entries while tracking noise shares one.
+    pub cache_busting_params: Vec<String>,
+    /// Cache large files on this route in chunks of this many bytes, for
+    /// video and download origins. Chunks are fetched from upstream with
+    /// Range requests when a client needs them, so files are never cached
+    /// or loaded as a whole. Upstream must support Range requests, other
+    /// responses are passed on uncached. Disabled if not set.
+    pub chunk_size: Option<u64>,
+    /// With `chunk_size`, fetch this many chunks after the one a client
+    /// reads in the background, to hide the latency of upstream for
+    /// sequential video playback.
+    pub prefetch_chunks: u64,
+}
+
+/// Maps links pointing to one URL prefix to another one.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkRewrite {
+    /// The URL prefix used by upstream, for example "http://backend.local".
+    pub from: String,
+    /// The public URL prefix, for example "https://www.example.com".
+    pub to: String,
+}
+
+impl LinkRewrite {
+    /// Rewrites all href and src attribute values in an HTML body that start
+    /// with the upstream prefix.
+    pub fn apply(&self, body: Vec<u8>) -> Vec<u8> {
+        self.apply_with(&self.pattern(), body)
+    }
+
+    /// Compiles the pattern that finds the attribute values to rewrite.
+    pub(crate) fn pattern(&self) -> Regex {
+        let pattern = format!(
+            r#"(?i)(\b(?:href|src)\s*=\s*["']?){}"#,
+            regex::escape(&self.from)
+        );
+        Regex::new(&pattern).unwrap()
+    }
+
+    /// Like `apply()` with the pattern compiled in advance.
+    pub(crate) fn apply_with(&self, pattern: &Regex, body: Vec<u8>) -> Vec<u8> {
+        let replacement = format!("${{1}}{}", self.to.replace('$', "$$"));
+        pattern
+            .replace_all(&body, replacement.as_bytes())
+            .into_owned()
+    }
+
+    /// Rewrites a URL header value like Location if it starts with the
+    /// upstream prefix.
+    pub fn apply_url(&self, url: &str) -> Option<String> {
+        if url.starts_with(&self.from) {
+            Some(format!("{}{}", self.to, &url[self.from.len()..]))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Route {
+    fn default() -> Route {
+        Route {
+            path_prefix: "/".to_string(),
+            upstream_path_prefix: None,
+            transform_content_types: vec!["text/html".to_string()],
+            request_body_transforms: Vec::new(),
+            response_body_transforms: Vec::new(),
+            rewrite_links: Vec::new(),
+            placeholders: Vec::new(),
+            director: None,
+            error_body: None,
+            error_content_type: None,
+            cache_post: false,
+            cache_post_max_body: 64 * 1024,
+            graphql: false,
+            bytes_per_second: None,
+            max_response_size: None,
+            nosniff: false,
+            content_type_guard: None,
+            security_headers: None,
+            cache_busting_params: Vec::new(),
+            chunk_size: None,
+            prefetch_chunks: 0,
+        }
+    }
+}
+
+/// A function that rewrites a body. Can be used by embedders for
+/// transformations that are not built in.
+pub type BodyHook = Arc<dyn Fn(Vec<u8>) -> Vec<u8> + Send + Sync>;
+
+/// A rewrite of a request or response body.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BodyTransform {
+    /// Replaces all occurrences of a string.
+    Replace { from: String, to: String },
+    /// Inserts content after the first occurrence of a marker, for example a
+    /// banner after "<body>".
+    InsertAfter { marker: String, content: String },
+    /// Custom transformation function, can only be set in code.
+    #[serde(skip)]
+    Hook(BodyHook),
+}
+
+impl fmt::Debug for BodyTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyTransform::Replace { from, to } => f
+                .debug_struct("Replace")
+                .field("from", from)
+                .field("to", to)
+                .finish(),
+            BodyTransform::InsertAfter { marker, content } => f
+                .debug_struct("InsertAfter")
+                .field("marker", marker)
+                .field("content", content)
+                .finish(),
+            BodyTransform::Hook(_) => f.write_str("Hook"),
+        }
+    }
+}
+
+impl BodyTransform {
+    /// Applies the transformation to a body.
+    pub fn apply(&self, body: Vec<u8>) -> Vec<u8> {
+        match self {
+            BodyTransform::Replace { from, to } => {
+                replace_bytes(&body, from.as_bytes(), to.as_bytes())
+            }
+            BodyTransform::InsertAfter { marker, content } => {
+                match find_bytes(&body, marker.as_bytes()) {
+                    Some(position) => {
+                        let split = position + marker.len();
+                        let mut result = Vec::with_capacity(body.len() + content.len());
+                        result.extend_from_slice(&body[..split]);
+                        result.extend_from_slice(content.as_bytes());
+                        result.extend_from_slice(&body[split..]);
+                        result
+                    }
+                    None => body,
+                }
+            }
+            BodyTransform::Hook(hook) => hook(body),
+        }
+    }
+}
+
+impl Route {
+    /// Checks if this route applies to a request path.
+    pub fn matches(&self, path: &str) -> bool {
+        path.starts_with(&self.path_prefix)
+    }
+
+    /// The path that a request for `path` is forwarded to.
+    pub(crate) fn upstream_path(&self, path: &str) -> String {
+        match self.upstream_path_prefix {
+            Some(ref prefix) if path.starts_with(&self.path_prefix) => {
+                format!("{}{}", prefix, &path[self.path_prefix.len()..])
+            }
+            _ => path.to_string(),
+        }
+    }
+
+    /// Maps Location headers of upstream that point into the upstream path
+    /// prefix back to the public one, relative ones and ones on the given
+    /// upstream origins.
+    pub(crate) fn location_rewrites(&self, origins: &[LinkRewrite]) -> Vec<LinkRewrite> {
+        let prefix = match self.upstream_path_prefix {
+            Some(ref prefix) => prefix,
+            None => return Vec::new(),
+        };
+        let mut rewrites: Vec<LinkRewrite> = origins
+            .iter()
+            .map(|origin| LinkRewrite {
+                from: format!("{}{}", origin.from, prefix),
+                to: format!("{}{}", origin.to, self.path_prefix),
+            })
+            .collect();
+        rewrites.push(LinkRewrite {
+            from: prefix.clone(),
+            to: self.path_prefix.clone(),
+        });
+        rewrites
+    }
+
+    /// The cache key of a GET request with only the cache-busting parameters
+    /// in the query, in their original order. None if the route has no
+    /// cache-busting parameters.
+    pub(crate) fn cache_busting_key(&self, uri: &Uri) -> Option<String> {
+        if self.cache_busting_params.is_empty() {
+            return None;
+        }
+        let params: Vec<&str> = uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or("");
+                self.cache_busting_params
+                    .iter()
+                    .any(|busting| busting == name)
+            })
+            .collect();
+        if params.is_empty() {
+            Some(uri.path().to_string())
+        } else {
+            Some(format!("{}?{}", uri.path(), params.join("&")))
+        }
+    }
+
+    /// Builds the response for a failed upstream request from the configured
+    /// error body, if there is one.
+    pub(crate) fn error_response(&self, request_id: &str) -> Option<Response<Body>> {
+        let body = self.error_body.as_ref()?;
+        let content_type = self
+            .error_content_type
+            .as_ref()
+            .map_or("text/plain; charset=utf-8", |content_type| {
+                content_type.as_str()
+            });
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body.replace("{request_id}", request_id)))
+            .ok()
+    }
+
+    /// Applies the maximum response size to an upstream response. Returns
+    /// None if the response is too big already by its Content-Length.
+    pub(crate) fn cap_response_size(&self, response: Response<Body>) -> Option<Response<Body>> {
+        let max_size = match self.max_response_size {
+            Some(max_size) => max_size,
+            None => return Some(response),
+        };
+        let length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        match length {
+            Some(length) if length > max_size => None,
+            // The length was checked, and a body longer than announced fails
+            // anyway.
+            Some(_) => Some(response),
+            None => Some(response.map(|body| {
+                Body::wrap_stream(SizeCapped {
+                    body,
+                    remaining: max_size,
+                })
+            })),
+        }
+    }
+
+    /// Checks if a message with these headers should be transformed. Encoded
+    /// bodies are left alone, except for gzip if `gunzip` is set.
+    pub(crate) fn transforms_content_type(&self, headers: &HeaderMap, gunzip: bool) -> bool {
+        if headers.contains_key(CONTENT_ENCODING) && !(gunzip && encoding::is_gzip(headers)) {
+            return false;
+        }
+        let content_type = match headers.get(CONTENT_TYPE).map(|v| v.to_str()) {
+            Some(Ok(content_type)) => content_type,
+            _ => return false,
+        };
+        self.transform_content_types
+            .iter()
+            .any(|pattern| content_type_matches(pattern, content_type))
+    }
+}
+
+/// Returns the first route that applies to the path.
+pub(crate) fn find_route<'a>(routes: &'a [Route], path: &str) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(path))
+}
+
+/// A body that fails when it gets bigger than a limit.
+struct SizeCapped {
+    body: Body,
+    remaining: u64,
+}
+
+impl Stream for SizeCapped {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        let chunk = match self.body.poll() {
+            Ok(Async::Ready(Some(chunk))) => chunk,
+            Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(error) => return Err(io::Error::other(error)),
+        };
+        match self.remaining.checked_sub(chunk.len() as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(Async::Ready(Some(chunk)))
+            }
+            None => {
+                eprintln!("Upstream response exceeds the maximum response size");
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Upstream response exceeds the maximum response size",
+                ))
+            }
+        }
+    }
+}
+
+/// Checks if a content type header value matches a pattern like "text/html"
+/// or "image/*", ignoring parameters like the charset.
+pub(crate) fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    if pattern.ends_with('*') {
+        mime.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        mime == pattern
+    }
+}
+
+/// Reads the whole body and passes it through the transformation function.
+pub(crate) fn transform_body<F>(
+    body: Body,
+    transform: F,
+) -> impl Future<Item = Body, Error = hyper::Error>
+where
+    F: FnOnce(Vec<u8>) -> Vec<u8>,
+{
+    body.concat2()
+        .map(move |chunk| Body::from(transform(chunk.to_vec())))
+}
+
+/// Applies transformations in order.
+fn apply_all(transforms: &[BodyTransform], body: Vec<u8>) -> Vec<u8> {
+    transforms
+        .iter()
+        .fold(body, |body, transform| transform.apply(body))
+}
+
+/// Applies the request body transformations of the route if the content type
+/// matches.
+pub(crate) fn transform_request(
+    route: Option<&Route>,
+    request: Request<Body>,
+) -> impl Future<Item = Request<Body>, Error = hyper::Error> {
+    match route {
+        Some(route)
+            if !route.request_body_transforms.is_empty()
+                && route.transforms_content_type(request.headers(), false) =>
+        {
+            let (mut parts, body) = request.into_parts();
+            // The length changes, hyper sets the new one.
+            parts.headers.remove(CONTENT_LENGTH);
+            let transforms = route.request_body_transforms.clone();
+            Either::A(
+                transform_body(body, move |body| apply_all(&transforms, body))
+                    .map(move |body| Request::from_parts(parts, body)),
+            )
+        }
+        _ => Either::B(future::ok(request)),
+    }
+}
+
+/// Applies the response body transformations of the route if the content type
+/// matches. Gzip bodies are decompressed for the transformations and
+/// compressed again, so the response keeps the encoding that its Vary header
+/// was chosen for.
+pub(crate) fn transform_response(
+    route: Option<&Route>,
+    link_patterns: &[Regex],
+    mut response: Response<Body>,
+) -> impl Future<Item = Response<Body>, Error = hyper::Error> {
+    let route = match route {
+        Some(route) => route,
+        None => return Either::B(future::ok(response)),
+    };
+
+    rewrite_location(&route.rewrite_links, response.headers_mut());
+
+    if (route.response_body_transforms.is_empty() && route.rewrite_links.is_empty())
+        || !route.transforms_content_type(response.headers(), true)
+    {
+        return Either::B(future::ok(response));
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // The length changes, hyper sets the new one.
+    parts.headers.remove(CONTENT_LENGTH);
+    let rewrites = route.rewrite_links.clone();
+    let patterns = link_patterns.to_vec();
+    let transforms = route.response_body_transforms.clone();
+    let gzip = encoding::is_gzip(&parts.headers);
+    Either::A(
+        transform_body(body, move |body| {
+            let body = if gzip {
+                match encoding::decompress(&body) {
+                    Ok(decoded) => decoded,
+                    Err(error) => {
+                        // Passed on untouched, the client may cope with it.
+                        eprintln!("Failed to decompress upstream response: {}", error);
+                        return body;
+                    }
+                }
+            } else {
+                body
+            };
+            let body = rewrites
+                .iter()
+                .zip(&patterns)
+                .fold(body, |body, (rewrite, pattern)| {
+                    rewrite.apply_with(pattern, body)
+                });
+            let body = apply_all(&transforms, body);
+            if gzip {
+                encoding::compress(&body)
+            } else {
+                body
+            }
+        })
+        .map(move |body| Response::from_parts(parts, body)),
+    )
+}
+
+/// Points a Location header that refers to upstream to the public host.
+pub(crate) fn rewrite_location(rewrites: &[LinkRewrite], headers: &mut HeaderMap) {
+    let location = match headers.get(LOCATION).map(|value| value.to_str()) {
+        Some(Ok(location)) => location.to_string(),
+        _ => return,
+    };
+    for rewrite in rewrites {
+        if let Some(new_location) = rewrite.apply_url(&location) {
+            if let Ok(value) = HeaderValue::from_str(&new_location) {
+                headers.insert(LOCATION, value);
+            }
+            return;
+        }
+    }
+}
+
+pub(crate) fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+pub(crate) fn replace_bytes(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(haystack.len());
+    let mut rest = haystack;
+    while let Some(position) = find_bytes(rest, from) {
+        result.extend_from_slice(&rest[..position]);
+        result.extend_from_slice(to);
+        rest = &rest[position + from.len()..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_type_matches, transform_response, BodyTransform, LinkRewrite, Route};
+    use crate::encoding;
+    use futures::{Future, Stream};
+    use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+    use hyper::{Body, Response};
+    use std::sync::Arc;
+
+    #[test]
+    fn replace() {
+        let transform = BodyTransform::Replace {
+            from: "http://backend".to_string(),
+            to: "https://example.com".to_string(),
+        };
+        assert_eq!(
+            b"<a href=\"https://example.com/a\">https://example.com</a>".to_vec(),
+            transform.apply(b"<a href=\"http://backend/a\">http://backend</a>".to_vec())
+        );
+    }
+
+    #[test]
+    fn insert_after() {
+        let transform = BodyTransform::InsertAfter {
+            marker: "<body>".to_string(),
+            content: "<p>Banner</p>".to_string(),
+        };
+        assert_eq!(
+            b"<body><p>Banner</p>text</body>".to_vec(),
+            transform.apply(b"<body>text</body>".to_vec())
+        );
+        assert_eq!(b"text".to_vec(), transform.apply(b"text".to_vec()));
+    }
+
+    #[test]
+    fn hook() {
+        let transform = BodyTransform::Hook(Arc::new(|mut body| {
+            body.reverse();
+            body
+        }));
+        assert_eq!(b"cba".to_vec(), transform.apply(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn rewrite_links() {
+        let rewrite = LinkRewrite {
+            from: "http://backend:8080".to_string(),
+            to: "https://example.com".to_string(),
+        };
+        assert_eq!(
+            b"<a href=\"https://example.com/a\">http://backend:8080</a><img SRC='https://example.com/i.png'>"
+                .to_vec(),
+            rewrite.apply(
+                b"<a href=\"http://backend:8080/a\">http://backend:8080</a><img SRC='http://backend:8080/i.png'>"
+                    .to_vec()
+            )
+        );
+        assert_eq!(
+            Some("https://example.com/login".to_string()),
+            rewrite.apply_url("http://backend:8080/login")
+        );
+        assert_eq!(None, rewrite.apply_url("http://other/login"));
+    }
+
+    #[test]
+    fn transform_gzip_response() {
+        let route = Route {
+            response_body_transforms: vec![BodyTransform::Replace {
+                from: "backend".to_string(),
+                to: "example.com".to_string(),
+            }],
+            ..Route::default()
+        };
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(encoding::compress(
+                b"<a href=\"http://backend/\">",
+            )))
+            .unwrap();
+        let response = transform_response(Some(&route), &[], response)
+            .wait()
+            .unwrap();
+        assert_eq!("gzip", response.headers()[CONTENT_ENCODING]);
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(
+            b"<a href=\"http://example.com/\">".to_vec(),
+            encoding::decompress(&body).unwrap()
+        );
+
+        // Other encodings are not touched.
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CONTENT_ENCODING, "br")
+            .body(Body::from("backend"))
+            .unwrap();
+        let response = transform_response(Some(&route), &[], response)
+            .wait()
+            .unwrap();
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&b"backend"[..], &body[..]);
+    }
+
+    #[test]
+    fn content_types() {
+        assert!(content_type_matches(
+            "text/html",
+            "text/html; charset=utf-8"
+        ));
+        assert!(content_type_matches("image/*", "image/png"));
+        assert!(!content_type_matches("text/html", "text/plain"));
+    }
+
+    #[test]
+    fn cache_busting_key() {
+        let route = Route {
+            cache_busting_params: vec!["v".to_string(), "build".to_string()],
+            ..Route::default()
+        };
+        let key = |uri: &str| route.cache_busting_key(&uri.parse().unwrap());
+        assert_eq!(
+            Some("/app.js?build=7&v=2".to_string()),
+            key("/app.js?utm_source=x&build=7&v=2&fbclid=abc")
+        );
+        assert_eq!(Some("/app.js".to_string()), key("/app.js?utm_source=x"));
+        assert_eq!(Some("/app.js".to_string()), key("/app.js"));
+        assert_eq!(Some("/app.js?v".to_string()), key("/app.js?v&version=3"));
+        assert_eq!(
+            None,
+            Route::default().cache_busting_key(&"/a?v=1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn upstream_path() {
+        let route = Route {
+            path_prefix: "/blog/".to_string(),
+            upstream_path_prefix: Some("/".to_string()),
+            ..Route::default()
+        };
+        assert_eq!("/2024/post", route.upstream_path("/blog/2024/post"));
+        assert_eq!("/about", Route::default().upstream_path("/about"));
+
+        let origins = [LinkRewrite {
+            from: "http://backend".to_string(),
+            to: "https://example.com".to_string(),
+        }];
+        let rewrites = route.location_rewrites(&origins);
+        assert_eq!(
+            Some("https://example.com/blog/new".to_string()),
+            rewrites[0].apply_url("http://backend/new")
+        );
+        assert_eq!(Some("/blog/new".to_string()), rewrites[1].apply_url("/new"));
+        assert!(Route::default().location_rewrites(&origins).is_empty());
+    }
+}
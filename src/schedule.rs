@@ -0,0 +1,190 @@
+//! Cache policy overrides that are only active at certain times, for example
+//! longer TTLs during a known traffic spike.
+
+use crate::policy::CachePolicy;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Settings that replace the normal cache behavior while a cron expression
+/// matches the current time.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    /// Minutes in which the schedule is active, for example "* 18-21 * * 5"
+    /// for Fridays from 18:00 to 21:59 UTC.
+    pub cron: Cron,
+    /// Responses are cached at least this many seconds while the schedule is
+    /// active.
+    #[serde(default)]
+    pub min_ttl: Option<u64>,
+    /// Cache policies that take precedence over the normal ones while the
+    /// schedule is active.
+    #[serde(default)]
+    pub cache_policies: Vec<CachePolicy>,
+}
+
+/// A cron expression with the five fields minute, hour, day of month, month
+/// and day of week (0 is Sunday), evaluated in UTC. Fields can be "*", a
+/// number, a range like "9-17", a step like "*/15" or a comma separated list
+/// of those. All fields must match.
+#[derive(Clone, PartialEq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+}
+
+impl Cron {
+    /// Checks if the point in time is inside one of the matching minutes.
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => return false,
+        };
+        let days = seconds / 86400;
+        let minute = seconds / 60 % 60;
+        let hour = seconds / 3600 % 24;
+        let weekday = (days + 4) % 7;
+        let (month, day) = month_and_day(days);
+        self.minutes & (1 << minute) != 0
+            && self.hours & (1 << hour) != 0
+            && self.days & (1 << day) != 0
+            && self.months & (1 << month) != 0
+            && self.weekdays & (1 << weekday) != 0
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Cron, String> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Cron expression needs 5 fields, got {}: {:?}",
+                fields.len(),
+                source
+            ));
+        }
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays: parse_field(fields[4], 0, 6)?,
+            source,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cron {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Cron, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Cron::try_from(source).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Debug for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cron({:?})", self.source)
+    }
+}
+
+/// Parses one cron field into a bit set of the allowed values.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(index) => (&part[..index], parse_number(&part[index + 1..])?),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(index) = range.find('-') {
+            (
+                parse_number(&range[..index])?,
+                parse_number(&range[index + 1..])?,
+            )
+        } else {
+            let value = parse_number(range)?;
+            (value, value)
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(format!("Invalid cron field {:?}", field));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_number(number: &str) -> Result<u64, String> {
+    number
+        .parse()
+        .map_err(|_| format!("Invalid number {:?} in cron expression", number))
+}
+
+/// Converts days since the Unix epoch to the month and day of the month.
+fn month_and_day(days: u64) -> (u64, u64) {
+    // Algorithm from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    (month, day)
+}
+
+/// Returns the first schedule that is active at the point in time.
+pub(crate) fn active_schedule(schedules: &[Schedule], time: SystemTime) -> Option<&Schedule> {
+    schedules
+        .iter()
+        .find(|schedule| schedule.cron.matches(time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cron;
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    // Friday, 2019-03-01 18:30 UTC.
+    fn friday_evening() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_551_465_000)
+    }
+
+    #[test]
+    fn matches() {
+        let cron = Cron::try_from("* 18-21 * * 5".to_string()).unwrap();
+        assert!(cron.matches(friday_evening()));
+        assert!(!cron.matches(friday_evening() + Duration::from_secs(4 * 3600)));
+
+        let cron = Cron::try_from("0,30 18 1 3 *".to_string()).unwrap();
+        assert!(cron.matches(friday_evening()));
+        assert!(!cron.matches(friday_evening() + Duration::from_secs(60)));
+
+        let cron = Cron::try_from("*/15 * * 2 *".to_string()).unwrap();
+        assert!(!cron.matches(friday_evening()));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Cron::try_from("* * * *".to_string()).is_err());
+        assert!(Cron::try_from("60 * * * *".to_string()).is_err());
+        assert!(Cron::try_from("*/0 * * * *".to_string()).is_err());
+        assert!(Cron::try_from("5-1 * * * *".to_string()).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Security headers that are added to HTML responses at the edge, for sites
+//! whose backend does not send them.
+
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, CONTENT_TYPE, REFERRER_POLICY,
+    X_FRAME_OPTIONS,
+};
+use serde::Deserialize;
+
+/// Header values of the bundle. An empty value leaves the header out.
+/// Headers that upstream sends itself are kept.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeaders {
+    /// X-Frame-Options, against clickjacking.
+    pub x_frame_options: String,
+    /// Referrer-Policy.
+    pub referrer_policy: String,
+    /// Permissions-Policy, the browser features pages may use.
+    pub permissions_policy: String,
+    /// Content-Security-Policy. Depends on the site, so there is no default.
+    pub content_security_policy: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders {
+            x_frame_options: "SAMEORIGIN".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            permissions_policy: "camera=(), microphone=(), geolocation=()".to_string(),
+            content_security_policy: String::new(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// The headers to add, None if a value is not a valid header value.
+    pub(crate) fn header_values(&self) -> Option<Vec<(HeaderName, HeaderValue)>> {
+        let headers = [
+            (X_FRAME_OPTIONS, &self.x_frame_options),
+            (REFERRER_POLICY, &self.referrer_policy),
+            (
+                HeaderName::from_static("permissions-policy"),
+                &self.permissions_policy,
+            ),
+            (CONTENT_SECURITY_POLICY, &self.content_security_policy),
+        ];
+        headers
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| Some((name.clone(), HeaderValue::from_str(value).ok()?)))
+            .collect()
+    }
+}
+
+/// Adds the headers to an HTML response, unless upstream sent them.
+pub(crate) fn add_headers(security_headers: &[(HeaderName, HeaderValue)], headers: &mut HeaderMap) {
+    let is_html = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim())
+        .is_some_and(|mime| {
+            mime.eq_ignore_ascii_case("text/html")
+                || mime.eq_ignore_ascii_case("application/xhtml+xml")
+        });
+    if !is_html {
+        return;
+    }
+    for (name, value) in security_headers {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_headers, SecurityHeaders};
+    use hyper::header::{HeaderValue, CONTENT_TYPE, X_FRAME_OPTIONS};
+    use hyper::HeaderMap;
+
+    #[test]
+    fn html_only() {
+        let bundle = SecurityHeaders {
+            content_security_policy: "default-src 'self'".to_string(),
+            referrer_policy: String::new(),
+            ..SecurityHeaders::default()
+        }
+        .header_values()
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("Text/HTML; charset=utf-8"),
+        );
+        headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        add_headers(&bundle, &mut headers);
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(
+            headers["permissions-policy"],
+            "camera=(), microphone=(), geolocation=()"
+        );
+        assert_eq!(headers["content-security-policy"], "default-src 'self'");
+        assert!(!headers.contains_key("referrer-policy"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        add_headers(&bundle, &mut headers);
+        assert_eq!(1, headers.len());
+    }
+
+    #[test]
+    fn invalid_value() {
+        let bundle = SecurityHeaders {
+            x_frame_options: "DENY\n".to_string(),
+            ..SecurityHeaders::default()
+        };
+        assert!(bundle.header_values().is_none());
+    }
+}
@@ -0,0 +1,95 @@
+//! Graceful shutdown: the proxy stops accepting connections and lets open
+//! connections finish their in-flight requests.
+
+use futures::future::Shared;
+use futures::sync::oneshot::{self, Receiver, Sender};
+use futures::{Async, Future, Poll, Stream};
+
+/// Tells the accept loop and all connections that the proxy shuts down.
+#[derive(Clone)]
+pub(crate) struct Signal {
+    // None after the shutdown was seen or when the sender is dropped without
+    // a shutdown, then the proxy runs until its runtime is dropped.
+    receiver: Option<Shared<Receiver<()>>>,
+}
+
+impl Signal {
+    pub(crate) fn new() -> (Sender<()>, Signal) {
+        let (sender, receiver) = oneshot::channel();
+        let signal = Signal {
+            receiver: Some(receiver.shared()),
+        };
+        (sender, signal)
+    }
+
+    /// Checks if the shutdown started, returns true only once. Otherwise
+    /// the current task is notified when it starts.
+    fn poll_triggered(&mut self) -> bool {
+        let triggered = match self.receiver {
+            Some(ref mut receiver) => match receiver.poll() {
+                Ok(Async::NotReady) => return false,
+                Ok(Async::Ready(_)) => true,
+                Err(_) => false,
+            },
+            None => return false,
+        };
+        self.receiver = None;
+        triggered
+    }
+}
+
+/// Accepted connections until the shutdown starts.
+pub(crate) struct UntilShutdown<S> {
+    stream: S,
+    signal: Signal,
+}
+
+impl<S> UntilShutdown<S> {
+    pub(crate) fn new(stream: S, signal: Signal) -> UntilShutdown<S> {
+        UntilShutdown { stream, signal }
+    }
+}
+
+impl<S: Stream> Stream for UntilShutdown<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if self.signal.poll_triggered() {
+            return Ok(Async::Ready(None));
+        }
+        self.stream.poll()
+    }
+}
+
+/// A client connection that finishes its in-flight requests and then closes
+/// when the shutdown starts. Idle keep-alive connections close right away.
+pub(crate) struct GracefulConnection<F, G> {
+    connection: F,
+    signal: Signal,
+    // Calls graceful_shutdown() of the hyper connection, whose type can't be
+    // named here.
+    graceful_shutdown: G,
+}
+
+impl<F, G: FnMut(&mut F)> GracefulConnection<F, G> {
+    pub(crate) fn new(connection: F, signal: Signal, graceful_shutdown: G) -> Self {
+        GracefulConnection {
+            connection,
+            signal,
+            graceful_shutdown,
+        }
+    }
+}
+
+impl<F: Future, G: FnMut(&mut F)> Future for GracefulConnection<F, G> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        if self.signal.poll_triggered() {
+            (self.graceful_shutdown)(&mut self.connection);
+        }
+        self.connection.poll()
+    }
+}
@@ -0,0 +1,159 @@
+//! Cache efficiency simulator: the recent cachable requests are kept in a
+//! ring buffer and can be replayed against hypothetical cache settings, like
+//! another TTL or query normalization, to predict their hit ratio without
+//! trying them in production.
+
+use crate::query::QueryNormalization;
+use crate::ring::Ring;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// A cachable request as kept for the simulator.
+#[derive(Debug)]
+struct LoggedRequest {
+    at: Instant,
+    cache_key: String,
+    hit: bool,
+    // Seconds the response was fresh for, None if it was not cachable.
+    ttl: Option<u64>,
+}
+
+/// The most recent cachable requests.
+#[derive(Debug)]
+pub(crate) struct RequestLog {
+    requests: Ring<LoggedRequest>,
+}
+
+/// Hypothetical cache settings to replay the requests against.
+#[derive(Debug, Default)]
+pub(crate) struct Scenario {
+    /// Seconds that every cachable response is fresh for, instead of its
+    /// own TTL.
+    pub(crate) ttl: Option<u64>,
+    pub(crate) query: QueryNormalization,
+}
+
+/// Actual and predicted hits of the logged requests.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Simulation {
+    requests: usize,
+    // Seconds between the first and the last logged request.
+    period_seconds: u64,
+    actual_hits: usize,
+    actual_ratio: Option<f64>,
+    actual_keys: usize,
+    predicted_hits: usize,
+    predicted_ratio: Option<f64>,
+    // Distinct cache keys with the hypothetical normalization.
+    predicted_keys: usize,
+}
+
+impl RequestLog {
+    /// A log of at most `capacity` requests, disabled with 0.
+    pub(crate) fn new(capacity: usize) -> RequestLog {
+        RequestLog {
+            requests: Ring::new(capacity),
+        }
+    }
+
+    /// Adds a request and drops the oldest one when the log is full.
+    pub(crate) fn record(&self, cache_key: &str, at: Instant, hit: bool, ttl: Option<u64>) {
+        self.requests.push(LoggedRequest {
+            at,
+            cache_key: cache_key.to_string(),
+            hit,
+            ttl,
+        });
+    }
+
+    /// Replays the logged requests against an unlimited cache with the
+    /// settings of the scenario.
+    pub(crate) fn simulate(&self, scenario: &Scenario) -> Simulation {
+        let requests = self.requests.items();
+        let mut expiries: HashMap<String, Instant> = HashMap::new();
+        let mut actual_keys = HashSet::new();
+        let mut predicted_hits = 0;
+        for request in requests.iter() {
+            actual_keys.insert(request.cache_key.as_str());
+            let key = scenario.query.normalize(&request.cache_key);
+            if expiries
+                .get(key.as_ref())
+                .is_some_and(|expires| *expires > request.at)
+            {
+                predicted_hits += 1;
+                continue;
+            }
+            let ttl = request.ttl.map(|ttl| scenario.ttl.unwrap_or(ttl));
+            if let Some(ttl) = ttl.filter(|ttl| *ttl > 0) {
+                expiries.insert(key.into_owned(), request.at + Duration::from_secs(ttl));
+            }
+        }
+        let actual_hits = requests.iter().filter(|request| request.hit).count();
+        let period = match (requests.front(), requests.back()) {
+            (Some(first), Some(last)) => last.at.saturating_duration_since(first.at),
+            _ => Duration::from_secs(0),
+        };
+        Simulation {
+            requests: requests.len(),
+            period_seconds: period.as_secs(),
+            actual_hits,
+            actual_ratio: ratio(actual_hits, requests.len()),
+            actual_keys: actual_keys.len(),
+            predicted_hits,
+            predicted_ratio: ratio(predicted_hits, requests.len()),
+            predicted_keys: expiries.len(),
+        }
+    }
+}
+
+fn ratio(hits: usize, requests: usize) -> Option<f64> {
+    Some(requests)
+        .filter(|requests| *requests > 0)
+        .map(|requests| hits as f64 / requests as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestLog, Scenario};
+    use crate::query::QueryNormalization;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn simulate() {
+        let log = RequestLog::new(5);
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        // Dropped once the log is full.
+        log.record("/old", at(0), false, Some(60));
+        log.record("/?b=2&a=1", at(1), false, Some(10));
+        log.record("/?a=1&b=2", at(2), false, Some(10));
+        log.record("/?b=2&a=1", at(5), true, Some(10));
+        log.record("/?b=2&a=1", at(30), false, Some(10));
+        log.record("/private", at(31), false, None);
+
+        let actual = log.simulate(&Scenario::default());
+        assert_eq!(5, actual.requests);
+        assert_eq!(30, actual.period_seconds);
+        assert_eq!(1, actual.actual_hits);
+        assert_eq!(3, actual.actual_keys);
+        assert_eq!(1, actual.predicted_hits);
+        assert_eq!(Some(0.2), actual.predicted_ratio);
+
+        let scenario = Scenario {
+            ttl: Some(60),
+            query: QueryNormalization {
+                sort: true,
+                ..QueryNormalization::default()
+            },
+        };
+        let predicted = log.simulate(&scenario);
+        assert_eq!(3, predicted.predicted_hits);
+        // Responses that were not cachable stay uncachable.
+        assert_eq!(1, predicted.predicted_keys);
+
+        let disabled = RequestLog::new(0);
+        disabled.record("/", at(0), false, Some(60));
+        assert_eq!(None, disabled.simulate(&scenario).predicted_ratio);
+    }
+}
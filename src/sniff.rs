@@ -0,0 +1,162 @@
+//! Detects response bodies that obviously don't match their Content-Type,
+//! for example HTML served as an image, as a safety net for misconfigured
+//! origins. Browsers that sniff such bodies can be tricked into running
+//! scripts.
+
+use crate::routes::Route;
+use futures::{stream, Future, Stream};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response};
+use serde::Deserialize;
+
+/// What happens to responses whose body does not match their Content-Type.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentTypeGuard {
+    /// Pass them on with an X-Rustnish-Content-Mismatch header naming the
+    /// detected type, and log them.
+    Flag,
+    /// Answer them with a 502 and log them.
+    Reject,
+}
+
+/// Signatures at the start of bodies and the types they identify.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+];
+
+/// Tags that HTML documents start with, after whitespace.
+const HTML_STARTS: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<body",
+    b"<script",
+    b"<iframe",
+];
+
+/// Detects the type of a body from its first bytes, if it is obvious.
+fn sniff(body: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| body.starts_with(signature))
+    {
+        return Some(mime);
+    }
+    let start = body.iter().position(|byte| !byte.is_ascii_whitespace())?;
+    let text = &body[start..];
+    if HTML_STARTS
+        .iter()
+        .any(|tag| text.len() >= tag.len() && text[..tag.len()].eq_ignore_ascii_case(tag))
+    {
+        return Some("text/html");
+    }
+    None
+}
+
+/// Checks if a detected type contradicts the declared one. Related types like
+/// two image formats are not a mismatch.
+fn mismatches(declared: &str, detected: &str) -> bool {
+    let declared = declared
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if declared == detected {
+        return false;
+    }
+    if detected == "text/html" {
+        return declared != "application/xhtml+xml";
+    }
+    if declared.starts_with("image/") && detected.starts_with("image/") {
+        return false;
+    }
+    // A binary format declared as text.
+    declared.starts_with("text/")
+        || declared.ends_with("json")
+        || declared.ends_with("xml")
+        || declared.ends_with("javascript")
+}
+
+/// Checks the start of the body of an upstream response against its
+/// Content-Type with the guard of the route. Fails if the response is
+/// rejected or its body breaks off.
+pub(crate) fn guard(
+    route: Option<&Route>,
+    response: Response<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = ()> + Send> {
+    let guard = match route.and_then(|route| route.content_type_guard) {
+        Some(guard) => guard,
+        None => return Box::new(futures::future::ok(response)),
+    };
+    let declared = match response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(declared) => declared.to_string(),
+        None => return Box::new(futures::future::ok(response)),
+    };
+    let (mut parts, body) = response.into_parts();
+    Box::new(
+        body.into_future()
+            .map_err(|_| ())
+            .and_then(move |(first, rest)| {
+                let detected = first
+                    .as_ref()
+                    .and_then(|chunk| sniff(chunk))
+                    .filter(|detected| mismatches(&declared, detected));
+                if let Some(detected) = detected {
+                    eprintln!(
+                        "Upstream response body looks like {} but is declared as {}",
+                        detected, declared
+                    );
+                    if guard == ContentTypeGuard::Reject {
+                        return Err(());
+                    }
+                    parts.headers.insert(
+                        HeaderName::from_static("x-rustnish-content-mismatch"),
+                        HeaderValue::from_static(detected),
+                    );
+                }
+                // Put the inspected chunk back in front of the rest.
+                let body = Body::wrap_stream(stream::iter_ok(first).chain(rest));
+                Ok(Response::from_parts(parts, body))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mismatches, sniff};
+
+    #[test]
+    fn detected_types() {
+        assert_eq!(Some("image/png"), sniff(b"\x89PNG\r\n\x1a\n...."));
+        assert_eq!(Some("application/pdf"), sniff(b"%PDF-1.4"));
+        assert_eq!(Some("text/html"), sniff(b"\n  <!DOCTYPE html><html>"));
+        assert_eq!(Some("text/html"), sniff(b"<SCRIPT>alert(1)</SCRIPT>"));
+        assert_eq!(None, sniff(b"{\"html\": \"<html>\"}"));
+        assert_eq!(None, sniff(b"  "));
+        assert_eq!(None, sniff(b""));
+    }
+
+    #[test]
+    fn mismatching_types() {
+        assert!(mismatches("image/png", "text/html"));
+        assert!(mismatches("application/json", "text/html"));
+        assert!(mismatches("text/html; charset=utf-8", "image/png"));
+        assert!(mismatches("application/json", "application/zip"));
+        assert!(!mismatches("Text/HTML; charset=utf-8", "text/html"));
+        assert!(!mismatches("image/jpeg", "image/png"));
+        assert!(!mismatches("application/octet-stream", "application/pdf"));
+        assert!(!mismatches("application/xhtml+xml", "text/html"));
+    }
+}
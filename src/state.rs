@@ -0,0 +1,128 @@
+//! State shared by all requests: the configuration and values derived from
+//! it once at startup instead of for every request.
+
+use crate::config::PublicBase;
+use crate::director::Pools;
+use crate::failover::FailoverState;
+use crate::history::History;
+use crate::hit_ratio::HitRatios;
+use crate::origin::{Origin, Origins};
+use crate::simulator::RequestLog;
+use crate::Config;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::Version;
+use regex::bytes::Regex;
+use regex::RegexSet;
+use std::sync::Arc;
+
+/// The configuration with its precomputed parts.
+pub(crate) struct ProxyState {
+    pub(crate) config: Config,
+    // Where requests are forwarded to, like "http://127.0.0.1:8080". The
+    // administration API can switch it.
+    pub(crate) origins: Origins,
+    pub(crate) failover: Option<FailoverState>,
+    pub(crate) public_base: Option<PublicBase>,
+    pub(crate) refresh_header: HeaderName,
+    pub(crate) dry_run_header: Option<HeaderName>,
+    // Matches the names of session cookies.
+    pub(crate) session_cookies: RegexSet,
+    // Compiled patterns of the link rewrites of every route, by route index.
+    pub(crate) link_patterns: Vec<Vec<Regex>>,
+    // Security headers for HTML responses, by route index and for requests
+    // without a route override.
+    pub(crate) route_security_headers: Vec<Option<Vec<(HeaderName, HeaderValue)>>>,
+    pub(crate) security_headers: Vec<(HeaderName, HeaderValue)>,
+    // The pools of every director, by director index. The administration
+    // API can switch them.
+    pub(crate) director_pools: Vec<Origins<Pools>>,
+    // Cache hits and misses by route and content type.
+    pub(crate) hit_ratios: HitRatios,
+    // Recent cachable requests for the simulator.
+    pub(crate) request_log: RequestLog,
+    // The last requests for debugging.
+    pub(crate) history: History,
+}
+
+impl ProxyState {
+    /// Prepares the state. The configuration must have been validated.
+    pub(crate) fn new(config: Config, upstream_port: u16) -> ProxyState {
+        ProxyState {
+            origins: Origins::new(config.backend.origin(upstream_port)),
+            failover: config
+                .failover
+                .as_ref()
+                .map(|failover| FailoverState::new(failover, &config.backend)),
+            public_base: config.public_base(),
+            refresh_header: HeaderName::from_bytes(config.refresh_header.as_bytes()).unwrap(),
+            dry_run_header: config
+                .dry_run_header
+                .as_ref()
+                .map(|header| HeaderName::from_bytes(header.as_bytes()).unwrap()),
+            session_cookies: RegexSet::new(&config.session_cookies).unwrap(),
+            link_patterns: config
+                .routes
+                .iter()
+                .map(|route| {
+                    route
+                        .rewrite_links
+                        .iter()
+                        .map(|rewrite| rewrite.pattern())
+                        .collect()
+                })
+                .collect(),
+            route_security_headers: config
+                .routes
+                .iter()
+                .map(|route| {
+                    route
+                        .security_headers
+                        .as_ref()
+                        .map(|headers| headers.header_values().unwrap())
+                })
+                .collect(),
+            security_headers: config
+                .security_headers
+                .as_ref()
+                .map_or_else(Vec::new, |headers| headers.header_values().unwrap()),
+            director_pools: config
+                .directors
+                .iter()
+                .map(|director| Origins::new(Pools::new(director.pools.clone())))
+                .collect(),
+            hit_ratios: HitRatios::new(&config.routes),
+            request_log: RequestLog::new(config.request_log_size),
+            history: History::new(config.request_log_size),
+            config,
+        }
+    }
+
+    /// The origin for new requests: the standby while the primary failed,
+    /// the current origin otherwise.
+    pub(crate) fn origin(&self) -> Arc<Origin> {
+        self.failover
+            .as_ref()
+            .and_then(|failover| failover.active_standby())
+            .unwrap_or_else(|| self.origins.current())
+    }
+
+    /// The security headers of a route.
+    pub(crate) fn security_headers(
+        &self,
+        route_index: Option<usize>,
+    ) -> &[(HeaderName, HeaderValue)] {
+        route_index
+            .and_then(|index| self.route_security_headers[index].as_deref())
+            .unwrap_or(&self.security_headers)
+    }
+}
+
+/// The Via header value for the HTTP version of an upstream response.
+pub(crate) fn via(version: Version) -> HeaderValue {
+    HeaderValue::from_static(match version {
+        Version::HTTP_09 => "0.9 rustnish-0.0.1",
+        Version::HTTP_10 => "1.0 rustnish-0.0.1",
+        Version::HTTP_2 => "2.0 rustnish-0.0.1",
+        _ => "1.1 rustnish-0.0.1",
+    })
+}
@@ -0,0 +1,112 @@
+//! Response bodies that stream to the client while a copy is collected for
+//! the cache, so that clients don't wait for the whole body and uncachable
+//! bodies are never held in memory.
+
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use hyper::{Body, Chunk};
+
+/// Passes a body on and calls `complete` with a copy of it once it ended
+/// without errors. Bodies bigger than `max_size` are not copied, nor are
+/// bodies that the client stops reading.
+pub(crate) struct Tee<F> {
+    body: Body,
+    copy: Option<Vec<u8>>,
+    max_size: usize,
+    // The announced length of the body. hyper stops reading a body once it
+    // has sent that many bytes, so the end of the stream is never seen.
+    length: Option<u64>,
+    complete: Option<F>,
+}
+
+impl<F: FnOnce(Bytes)> Tee<F> {
+    pub(crate) fn new(body: Body, length: Option<u64>, max_size: usize, complete: F) -> Tee<F> {
+        let mut tee = Tee {
+            body,
+            copy: Some(Vec::new()),
+            max_size,
+            length,
+            complete: Some(complete),
+        };
+        // An empty body is not even polled.
+        if length == Some(0) {
+            tee.finish();
+        }
+        tee
+    }
+
+    fn finish(&mut self) {
+        if let (Some(copy), Some(complete)) = (self.copy.take(), self.complete.take()) {
+            complete(copy.into());
+        }
+    }
+}
+
+impl<F: FnOnce(Bytes)> Stream for Tee<F> {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        match self.body.poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(Some(chunk)) => {
+                if let Some(ref mut copy) = self.copy {
+                    if copy.len() + chunk.len() > self.max_size {
+                        self.copy = None;
+                        self.complete = None;
+                    } else {
+                        copy.extend_from_slice(&chunk);
+                        if self.length == Some(copy.len() as u64) {
+                            self.finish();
+                        }
+                    }
+                }
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Async::Ready(None) => {
+                self.finish();
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tee;
+    use bytes::Bytes;
+    use futures::{Future, Stream};
+    use hyper::{Body, Chunk};
+    use std::sync::{Arc, Mutex};
+
+    fn tee(
+        chunks: Vec<&'static str>,
+        length: Option<u64>,
+        max_size: usize,
+    ) -> (Chunk, Option<Bytes>) {
+        let copy = Arc::new(Mutex::new(None));
+        let completed = copy.clone();
+        let body = Body::wrap_stream(futures::stream::iter_ok::<_, hyper::Error>(chunks));
+        let tee = Tee::new(body, length, max_size, move |body| {
+            *completed.lock().unwrap() = Some(body);
+        });
+        let body = tee.concat2().wait().unwrap();
+        let copy = copy.lock().unwrap().take();
+        (body, copy)
+    }
+
+    #[test]
+    fn copies() {
+        let (body, copy) = tee(vec!["ab", "cd", "e"], None, 5);
+        assert_eq!(&b"abcde"[..], &body[..]);
+        assert_eq!(Some(Bytes::from("abcde")), copy);
+
+        let (body, copy) = tee(vec!["ab", "cd", "e"], None, 4);
+        assert_eq!(&b"abcde"[..], &body[..]);
+        assert_eq!(None, copy);
+
+        // Complete once the announced length arrived.
+        let (_, copy) = tee(vec!["ab", "cd", "e"], Some(4), 5);
+        assert_eq!(Some(Bytes::from("abcd")), copy);
+    }
+}
@@ -0,0 +1,182 @@
+//! Helpers to test the proxy in-process: dummy upstream servers, free ports
+//! and blocking clients. Enabled with the "test_util" feature, for embedders
+//! that want to integration-test their configuration against a real proxy.
+
+use futures::{Future, Stream};
+use hyper::service::service_fn_ok;
+use hyper::{Body, Chunk, Request, Response};
+use hyper::{Client, Server, Uri};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use tokio::runtime::Runtime;
+
+/// Returns the received request in the response body for testing purposes.
+pub fn echo_request(request: Request<Body>) -> Response<Body> {
+    Response::builder()
+        .body(Body::from(format!("{:?}", request)))
+        .unwrap()
+}
+
+/// Starts a dummy server in a separate thread.
+pub fn start_dummy_server<F>(port: u16, response_function: F) -> Runtime
+where
+    F: Fn(Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+{
+    let address = "127.0.0.1:".to_owned() + &port.to_string();
+    let addr = address.parse().unwrap();
+
+    let new_svc = move || service_fn_ok(response_function.clone());
+
+    let server = Server::bind(&addr).serve(new_svc).map_err(|_| ());
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server);
+    runtime
+}
+
+/// Starts a dummy HTTPS server in a separate thread, with the certificate and
+/// private key from the given PEM files. Failed handshakes are ignored.
+pub fn start_tls_dummy_server<F>(
+    port: u16,
+    certificate_file: &str,
+    key_file: &str,
+    response_function: F,
+) -> Runtime
+where
+    F: Fn(Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+{
+    let certificate = std::fs::read(certificate_file).unwrap();
+    let key = std::fs::read(key_file).unwrap();
+    let identity = native_tls::Identity::from_pkcs8(&certificate, &key).unwrap();
+    let acceptor = tokio_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+    let addr = ("127.0.0.1:".to_owned() + &port.to_string())
+        .parse()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind(&addr).unwrap();
+    let incoming = listener
+        .incoming()
+        .and_then(move |stream| acceptor.accept(stream).then(|result| Ok(result.ok())))
+        .filter_map(|stream| stream);
+
+    let new_svc = move || service_fn_ok(response_function.clone());
+    let server = Server::builder(incoming).serve(new_svc).map_err(|_| ());
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server);
+    runtime
+}
+
+/// Starts a plain TCP server that answers one connection with a fixed response
+/// and passes on the raw request, for checks that Hyper would hide like header
+/// casing. Request bodies are only read if they have a Content-Length.
+pub fn start_raw_server(port: u16, response: &'static [u8]) -> Receiver<String> {
+    start_raw_server_sequence(port, vec![response])
+}
+
+/// Like start_raw_server, but answers one connection after the other with the
+/// given responses. Each connection is closed after its response.
+pub fn start_raw_server_sequence(port: u16, responses: Vec<&'static [u8]>) -> Receiver<String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            loop {
+                if let Some(head_end) = request.windows(4).position(|window| window == b"\r\n\r\n")
+                {
+                    let head = String::from_utf8_lossy(&request[..head_end]).to_lowercase();
+                    let body_length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |length| length.trim().parse().unwrap());
+                    if request.len() >= head_end + 4 + body_length {
+                        break;
+                    }
+                }
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(response).unwrap();
+            sender
+                .send(String::from_utf8_lossy(&request).into_owned())
+                .unwrap();
+        }
+    });
+    receiver
+}
+
+/// Sends a GET request and blocks until the response head is received.
+pub fn client_get(url: Uri) -> Response<Body> {
+    let client = Client::new();
+    let work = client.get(url).and_then(Ok);
+
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(work).unwrap()
+}
+
+/// Fetches the whole body while the runtime is still alive, for bodies that
+/// arrive in several parts. client_get() only works for bodies that are
+/// already received when the runtime is dropped.
+pub fn client_get_body(url: Uri) -> Chunk {
+    let client = Client::new();
+    let work = client
+        .get(url)
+        .and_then(|response| response.into_body().concat2());
+
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(work).unwrap()
+}
+
+/// Sends a POST request with the given body and blocks until the response
+/// head is received.
+pub fn client_post(url: Uri, body: &'static str) -> Response<Body> {
+    let client = Client::new();
+
+    let req = Request::builder()
+        .method("POST")
+        .uri(url)
+        .body(Body::from(body))
+        .unwrap();
+
+    let work = client.request(req).and_then(Ok);
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(work).unwrap()
+}
+
+/// Sends any request and blocks until the response head is received.
+pub fn client_request(request: Request<Body>) -> Response<Body> {
+    let client = Client::new();
+    let work = client.request(request).and_then(Ok);
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(work).unwrap()
+}
+
+/// Sends any request and fetches the whole response body while the runtime
+/// is still alive, like client_get_body().
+pub fn client_request_body(request: Request<Body>) -> Response<Chunk> {
+    let client = Client::new();
+    let work = client.request(request).and_then(|response| {
+        let (parts, body) = response.into_parts();
+        body.concat2().map(|body| Response::from_parts(parts, body))
+    });
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(work).unwrap()
+}
+
+/// Returns a local port number that has not been used yet in parallel test
+/// threads. Ports are handed out counting up from 9090.
+pub fn get_free_port() -> u16 {
+    static PORT_NR: AtomicUsize = AtomicUsize::new(0);
+
+    PORT_NR.fetch_add(1, Ordering::SeqCst) as u16 + 9090
+}
@@ -0,0 +1,194 @@
+//! Egress bandwidth limits for response bodies, per route or per class of
+//! client addresses, for example to cap large downloads on a shared link.
+
+use crate::routes::Route;
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use hyper::{Body, Chunk, Response};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// A bandwidth limit for clients from some networks.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientClass {
+    /// Client networks in CIDR notation like "10.0.0.0/8" or "::1/128", or
+    /// single addresses.
+    pub networks: Vec<String>,
+    /// Maximum bytes per second of every response body to these clients.
+    pub bytes_per_second: u64,
+}
+
+impl ClientClass {
+    /// Checks that the networks can be parsed and a limit is set.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.bytes_per_second > 0
+            && self
+                .networks
+                .iter()
+                .all(|network| parse_network(network).is_some())
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .filter_map(|network| parse_network(network))
+            .any(|(network, prefix)| in_network(address, network, prefix))
+    }
+}
+
+/// Parses "address/prefix length" or a single address.
+fn parse_network(network: &str) -> Option<(IpAddr, u32)> {
+    let mut parts = network.splitn(2, '/');
+    let address: IpAddr = parts.next()?.trim().parse().ok()?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.trim().parse().ok()?,
+        None => max_prefix,
+    };
+    if prefix > max_prefix {
+        return None;
+    }
+    Some((address, prefix))
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u32) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// The bandwidth limit of a request: the lower one of its route and the
+/// first client class of the client.
+pub(crate) fn rate_limit(
+    route: Option<&Route>,
+    client_classes: &[ClientClass],
+    client: IpAddr,
+) -> Option<u64> {
+    let route_limit = route.and_then(|route| route.bytes_per_second);
+    let client_limit = client_classes
+        .iter()
+        .find(|class| class.contains(client))
+        .map(|class| class.bytes_per_second);
+    match (route_limit, client_limit) {
+        (Some(route_limit), Some(client_limit)) => Some(route_limit.min(client_limit)),
+        (limit, None) | (None, limit) => limit,
+    }
+}
+
+/// Sends the body of a response with at most the given bytes per second.
+pub(crate) fn throttle(response: Response<Body>, bytes_per_second: u64) -> Response<Body> {
+    // The Content-Length header still applies to the wrapped body.
+    response.map(|body| {
+        Body::wrap_stream(Throttled {
+            body,
+            bytes_per_second,
+            // Chunks that are sent at once, about a tenth of a second worth,
+            // so that big chunks don't go out in bursts.
+            slice_size: (bytes_per_second / 10).max(1) as usize,
+            pending: Bytes::new(),
+            start: None,
+            sent: 0,
+            delay: None,
+        })
+    })
+}
+
+struct Throttled {
+    body: Body,
+    bytes_per_second: u64,
+    slice_size: usize,
+    // The rest of a chunk that was too big to send at once.
+    pending: Bytes,
+    start: Option<Instant>,
+    sent: u64,
+    delay: Option<Delay>,
+}
+
+impl Stream for Throttled {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        // Wait until the bytes sent so far are within the limit.
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let due = start + Duration::from_millis(self.sent * 1000 / self.bytes_per_second);
+        if due > Instant::now() {
+            let delay = self.delay.get_or_insert_with(|| Delay::new(due));
+            delay.reset(due);
+            // Timer errors only happen on shutdown, then there is no need to
+            // wait anyway.
+            if let Ok(Async::NotReady) = delay.poll() {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        if self.pending.is_empty() {
+            match self.body.poll()? {
+                Async::Ready(Some(chunk)) => self.pending = chunk.into_bytes(),
+                other => return Ok(other),
+            }
+        }
+        let slice = if self.pending.len() > self.slice_size {
+            self.pending.split_to(self.slice_size)
+        } else {
+            std::mem::replace(&mut self.pending, Bytes::new())
+        };
+        self.sent += slice.len() as u64;
+        Ok(Async::Ready(Some(Chunk::from(slice))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_network, rate_limit, ClientClass};
+    use crate::routes::Route;
+
+    #[test]
+    fn networks() {
+        let class = ClientClass {
+            networks: vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()],
+            bytes_per_second: 1000,
+        };
+        assert!(class.is_valid());
+        assert!(class.contains("10.1.2.3".parse().unwrap()));
+        assert!(!class.contains("11.0.0.1".parse().unwrap()));
+        assert!(class.contains("2001:db8::1".parse().unwrap()));
+        assert!(!class.contains("2001:db9::1".parse().unwrap()));
+
+        assert_eq!(
+            Some(("127.0.0.1".parse().unwrap(), 32)),
+            parse_network("127.0.0.1")
+        );
+        assert!(parse_network("0.0.0.0/0").is_some());
+        assert!(parse_network("10.0.0.0/33").is_none());
+        assert!(parse_network("example.com/8").is_none());
+    }
+
+    #[test]
+    fn lowest_limit() {
+        let route = Route {
+            bytes_per_second: Some(500),
+            ..Route::default()
+        };
+        let classes = vec![ClientClass {
+            networks: vec!["0.0.0.0/0".to_string()],
+            bytes_per_second: 1000,
+        }];
+        let client = "127.0.0.1".parse().unwrap();
+        assert_eq!(Some(500), rate_limit(Some(&route), &classes, client));
+        assert_eq!(Some(1000), rate_limit(None, &classes, client));
+        assert_eq!(Some(500), rate_limit(Some(&route), &[], client));
+        assert_eq!(None, rate_limit(None, &[], client));
+    }
+}
@@ -0,0 +1,144 @@
+//! Forward proxy mode for `CONNECT host:port` requests, which tunnels TCP
+//! connections to allowed destinations.
+
+use crate::{Config, ResponseFuture};
+use futures::future::{self, Future};
+use hyper::client::connect::dns::{Name, Resolve, TokioThreadpoolGaiResolver};
+use hyper::{Body, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use tokio::io::{copy, shutdown, AsyncRead};
+use tokio::net::TcpStream;
+
+/// Answers a CONNECT request and tunnels the connection in the background
+/// once the client has received the response. The destination is resolved
+/// on the blocking pool of the runtime first.
+pub(crate) fn connect(request: Request<Body>, config: &Config) -> ResponseFuture {
+    let (host, port) = match request.uri().authority_part() {
+        Some(authority) => match authority.port_u16() {
+            Some(port) => (authority.host().to_string(), port),
+            None => {
+                return Box::new(future::ok(error(
+                    StatusCode::BAD_REQUEST,
+                    "CONNECT needs host:port",
+                )))
+            }
+        },
+        None => {
+            return Box::new(future::ok(error(
+                StatusCode::BAD_REQUEST,
+                "CONNECT needs host:port",
+            )))
+        }
+    };
+    if !config
+        .connect_allowlist
+        .iter()
+        .any(|pattern| destination_matches(pattern, &host, port))
+    {
+        return Box::new(future::ok(error(
+            StatusCode::FORBIDDEN,
+            "Destination not allowed",
+        )));
+    }
+    let name = match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<Name>()
+    {
+        Ok(name) => name,
+        Err(_) => {
+            return Box::new(future::ok(error(
+                StatusCode::BAD_REQUEST,
+                "CONNECT needs host:port",
+            )))
+        }
+    };
+
+    Box::new(
+        TokioThreadpoolGaiResolver::new()
+            .resolve(name)
+            .then(move |addresses| {
+                let address = match addresses.ok().and_then(|mut addresses| addresses.next()) {
+                    Some(address) => SocketAddr::new(address, port),
+                    None => return Ok(error(StatusCode::BAD_GATEWAY, "Destination not found")),
+                };
+                tokio::spawn(tunnel(request, address));
+                Ok(Response::new(Body::empty()))
+            }),
+    )
+}
+
+/// Copies data both ways between the upgraded client connection and the
+/// destination.
+fn tunnel(request: Request<Body>, address: SocketAddr) -> impl Future<Item = (), Error = ()> {
+    request
+        .into_body()
+        .on_upgrade()
+        .map_err(|e| eprintln!("CONNECT upgrade failed: {}", e))
+        .and_then(move |client| {
+            TcpStream::connect(&address)
+                .map_err(move |e| eprintln!("CONNECT to {} failed: {}", address, e))
+                .and_then(move |server| {
+                    let (client_read, client_write) = client.split();
+                    let (server_read, server_write) = server.split();
+                    let upload = copy(client_read, server_write)
+                        .and_then(|(_, _, server_write)| shutdown(server_write));
+                    let download = copy(server_read, client_write)
+                        .and_then(|(_, _, client_write)| shutdown(client_write));
+                    upload.join(download).map(|_| ()).map_err(|_| ())
+                })
+        })
+}
+
+/// Checks if a destination matches an allowlist pattern like
+/// "example.com:443", "*.example.com:443" or "example.com:*".
+fn destination_matches(pattern: &str, host: &str, port: u16) -> bool {
+    let (pattern_host, pattern_port) = match pattern.rfind(':') {
+        Some(index) => (&pattern[..index], &pattern[index + 1..]),
+        None => return false,
+    };
+    let port_matches = pattern_port == "*" || pattern_port.parse() == Ok(port);
+    let host = host.to_ascii_lowercase();
+    let pattern_host = pattern_host.to_ascii_lowercase();
+    let host_matches = if pattern_host.starts_with("*.") {
+        host.ends_with(&pattern_host[1..])
+    } else {
+        host == pattern_host
+    };
+    port_matches && host_matches
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::destination_matches;
+
+    #[test]
+    fn allowlist_patterns() {
+        assert!(destination_matches("example.com:443", "Example.com", 443));
+        assert!(!destination_matches("example.com:443", "example.com", 80));
+        assert!(destination_matches(
+            "*.example.com:443",
+            "api.example.com",
+            443
+        ));
+        assert!(!destination_matches(
+            "*.example.com:443",
+            "example.com",
+            443
+        ));
+        assert!(!destination_matches(
+            "*.example.com:443",
+            "evilexample.com",
+            443
+        ));
+        assert!(destination_matches("example.com:*", "example.com", 8080));
+        assert!(!destination_matches("example.com", "example.com", 443));
+    }
+}
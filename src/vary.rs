@@ -0,0 +1,249 @@
+//! Selection of cached variants by the request headers that upstream names
+//! in the Vary response header.
+
+use hyper::header::{HeaderName, HeaderValue, ACCEPT_ENCODING, VARY};
+use hyper::HeaderMap;
+use std::collections::HashMap;
+
+/// The request header values a variant was stored for, by the header names
+/// in Vary.
+pub(crate) type VaryValues = Vec<(HeaderName, Option<HeaderValue>)>;
+
+/// The cached variants of one URL, by the request header values that select
+/// them. They live under the cache entry of the URL, so that removing the
+/// entry invalidates all of them at once.
+#[derive(Clone)]
+pub(crate) struct Variants<V> {
+    // The request headers that upstream named in Vary.
+    names: Vec<HeaderName>,
+    // The variants with the count of uses when they were used last.
+    variants: HashMap<VaryValues, (u64, V)>,
+    uses: u64,
+}
+
+impl<V> Variants<V> {
+    pub(crate) fn new() -> Variants<V> {
+        Variants {
+            names: Vec::new(),
+            variants: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// The values of the request headers that select a variant.
+    fn request_values(&self, request: &HeaderMap) -> VaryValues {
+        self.names
+            .iter()
+            .map(|name| (name.clone(), request_value(request, name)))
+            .collect()
+    }
+
+    /// The variant that the request headers select.
+    pub(crate) fn get(&self, request: &HeaderMap) -> Option<&V> {
+        self.variants
+            .get(&self.request_values(request))
+            .map(|(_, variant)| variant)
+    }
+
+    /// The variant that the request headers select, to be changed.
+    pub(crate) fn get_mut(&mut self, request: &HeaderMap) -> Option<&mut V> {
+        let values = self.request_values(request);
+        self.variants.get_mut(&values).map(|(_, variant)| variant)
+    }
+
+    /// The variant that the request headers select, which counts as the
+    /// most recently used one now.
+    pub(crate) fn select(&mut self, request: &HeaderMap) -> Option<&mut V> {
+        let values = self.request_values(request);
+        let (used, variant) = self.variants.get_mut(&values)?;
+        self.uses += 1;
+        *used = self.uses;
+        Some(variant)
+    }
+
+    /// Adds a variant as the most recently used one, replacing the variant
+    /// for the same values. The least recently used variants make room when
+    /// there are more than `max_variants`, returns how many.
+    pub(crate) fn insert(&mut self, values: VaryValues, variant: V, max_variants: usize) -> usize {
+        let names: Vec<_> = values.iter().map(|(name, _)| name.clone()).collect();
+        // Variants by other headers are left over from before upstream
+        // changed its Vary header.
+        if names != self.names {
+            self.variants.clear();
+            self.names = names;
+        }
+        self.uses += 1;
+        self.variants.insert(values, (self.uses, variant));
+        let mut evicted = 0;
+        while self.variants.len() > max_variants.max(1) {
+            let oldest = self
+                .variants
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(values, _)| values.clone())
+                .unwrap();
+            self.variants.remove(&oldest);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Keeps only the variants for which `keep` returns true.
+    pub(crate) fn retain<F: FnMut(&V) -> bool>(&mut self, mut keep: F) {
+        self.variants.retain(|_, (_, variant)| keep(variant));
+    }
+
+    /// The variants with their values, the most recently used first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&VaryValues, &V)> {
+        let mut variants: Vec<_> = self.variants.iter().collect();
+        variants.sort_unstable_by_key(|(_, (used, _))| std::cmp::Reverse(*used));
+        variants
+            .into_iter()
+            .map(|(values, (_, variant))| (values, variant))
+    }
+
+    pub(crate) fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.variants.values_mut().map(|(_, variant)| variant)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.variants.is_empty()
+    }
+}
+
+/// Collects the request header values that a response varies by. None if
+/// the response must not be cached because it varies by everything or by
+/// an invalid header name.
+///
+/// With a backend that is always asked for gzip, Accept-Encoding does not
+/// select a variant.
+pub(crate) fn vary_values(
+    response: &HeaderMap,
+    request: &HeaderMap,
+    normalized_encoding: bool,
+) -> Option<VaryValues> {
+    let mut values: VaryValues = Vec::new();
+    for header in response.get_all(VARY) {
+        for name in header.to_str().ok()?.split(',').map(str::trim) {
+            if name.is_empty() {
+                continue;
+            }
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            if (normalized_encoding && name == ACCEPT_ENCODING)
+                || values.iter().any(|(other, _)| *other == name)
+            {
+                continue;
+            }
+            let value = request_value(request, &name);
+            values.push((name, value));
+        }
+    }
+    Some(values)
+}
+
+/// The value of a request header, several fields joined with commas.
+fn request_value(headers: &HeaderMap, name: &HeaderName) -> Option<HeaderValue> {
+    let mut fields = headers.get_all(name).iter();
+    let first = fields.next()?;
+    let mut joined = first.as_bytes().to_vec();
+    for field in fields {
+        joined.extend_from_slice(b", ");
+        joined.extend_from_slice(field.as_bytes());
+    }
+    HeaderValue::from_bytes(&joined).ok()
+}
+
+/// Describes a variant for the administration API, like
+/// "accept-encoding: gzip".
+pub(crate) fn describe(values: &[(HeaderName, Option<HeaderValue>)]) -> String {
+    values
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .as_ref()
+                .map_or("", |value| value.to_str().unwrap_or("?"));
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{vary_values, Variants, VaryValues};
+    use hyper::header::{HeaderValue, ACCEPT_ENCODING, ACCEPT_LANGUAGE, VARY};
+    use hyper::HeaderMap;
+
+    /// Checks if a request selects the variant with the given values.
+    fn matches(values: &VaryValues, request: &HeaderMap) -> bool {
+        let mut variants = Variants::new();
+        variants.insert(values.clone(), (), 1);
+        variants.get(request).is_some()
+    }
+
+    #[test]
+    fn select_variants() {
+        let mut response = HeaderMap::new();
+        response.insert(
+            VARY,
+            HeaderValue::from_static("Accept-Encoding, accept-language"),
+        );
+        let mut request = HeaderMap::new();
+        request.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let values = vary_values(&response, &request, false).unwrap();
+        assert_eq!(2, values.len());
+        assert!(matches(&values, &request));
+
+        let mut other = request.clone();
+        other.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("de"));
+        assert!(!matches(&values, &other));
+        assert!(!matches(&values, &HeaderMap::new()));
+
+        // The backend always gets gzip, so the encoding doesn't matter.
+        let values = vary_values(&response, &request, true).unwrap();
+        assert_eq!(1, values.len());
+        assert!(matches(&values, &HeaderMap::new()));
+
+        response.append(VARY, HeaderValue::from_static("*"));
+        assert!(vary_values(&response, &request, false).is_none());
+    }
+
+    #[test]
+    fn variants_by_values() {
+        let mut response = HeaderMap::new();
+        response.insert(VARY, HeaderValue::from_static("Accept-Language"));
+        let mut de = HeaderMap::new();
+        de.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("de"));
+        let mut en = HeaderMap::new();
+        en.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("en"));
+        let fr = HeaderMap::new();
+
+        let mut variants = Variants::new();
+        let values = |request| vary_values(&response, request, false).unwrap();
+        assert_eq!(0, variants.insert(values(&de), "de", 2));
+        assert_eq!(0, variants.insert(values(&en), "en", 2));
+        assert_eq!(Some(&"de"), variants.get(&de));
+        assert_eq!(None, variants.get(&fr));
+
+        // "en" was used least recently.
+        assert_eq!(Some(&mut "de"), variants.select(&de));
+        assert_eq!(1, variants.insert(values(&fr), "none", 2));
+        assert_eq!(
+            vec!["none", "de"],
+            variants
+                .iter()
+                .map(|(_, variant)| *variant)
+                .collect::<Vec<_>>()
+        );
+
+        // Another Vary header replaces all variants.
+        response.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+        let values = vary_values(&response, &de, false).unwrap();
+        assert_eq!(0, variants.insert(values, "identity", 2));
+        assert_eq!(1, variants.iter().count());
+        assert_eq!(Some(&"identity"), variants.get(&en));
+    }
+}
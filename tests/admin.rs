@@ -0,0 +1,1338 @@
+use futures::{Future, Stream};
+use hyper::header::{
+    ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, HOST, LOCATION,
+    VARY,
+};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use rustnish::{AdminScope, AdminToken, Config, Director, Failover, Listener, PoolBackend, Route};
+use serde_json::Value;
+use socket2::SockRef;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+fn get_json(url: Uri) -> Value {
+    let response = common::client_get(url);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().concat2().wait().unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+// Tests that the preview command reports cache metadata without the body.
+#[test]
+fn preview() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(CONTENT_TYPE, "text/html")
+            .body(Body::from("secret body"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let preview_url: Uri = format!(
+        "http://127.0.0.1:{}/_rustnish/preview?url=%2Fpage%3Fa%3D1",
+        port
+    )
+    .parse()
+    .unwrap();
+    let preview = get_json(preview_url.clone());
+    assert_eq!(preview["cache_key"], "/page?a=1");
+    assert_eq!(preview["hit"], false);
+    assert_eq!(preview["ttl_remaining"], Value::Null);
+
+    common::client_get(
+        format!("http://127.0.0.1:{}/page?a=1", port)
+            .parse()
+            .unwrap(),
+    );
+
+    let preview = get_json(preview_url);
+    assert_eq!(preview["hit"], true);
+    assert_eq!(preview["variants"][0], "/page?a=1");
+    assert!(preview["ttl_remaining"].as_u64().unwrap() > 1790);
+    assert_eq!(preview["policy"], "cache-control");
+    assert!(!preview.to_string().contains("secret body"));
+}
+
+// Tests that unknown admin commands are rejected.
+#[test]
+fn unknown_command() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, common::echo_request);
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let url = format!("http://127.0.0.1:{}/_rustnish/unknown", port)
+        .parse()
+        .unwrap();
+    assert_eq!(common::client_get(url).status(), StatusCode::NOT_FOUND);
+}
+
+// Tests that public paths which only start like the admin path reach the
+// origin.
+#[test]
+fn public_paths_next_to_admin_path() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server =
+        common::start_dummy_server(upstream_port, |_| Response::new(Body::from("origin")));
+    let config = Config {
+        admin_path: Some("/admin".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    for path in &["/administrator", "/admin-panel/login"] {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(&b"origin"[..], &body[..]);
+    }
+    let stats = get_json(
+        format!("http://127.0.0.1:{}/admin/stats", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(stats["entries"], 0);
+}
+
+// Tests that the stats command reports the sizes of cached entries.
+#[test]
+fn stats() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |request| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from("x".repeat(request.uri().path().len() * 1000)))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let stats_url: Uri = format!("http://127.0.0.1:{}/_rustnish/stats", port)
+        .parse()
+        .unwrap();
+    let stats = get_json(stats_url.clone());
+    assert_eq!(stats["entries"], 0);
+    assert_eq!(stats["entry_size_max"], 0);
+
+    for path in &["/a", "/bb", "/ccc"] {
+        common::client_get(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+    }
+
+    let stats = get_json(stats_url);
+    assert_eq!(stats["entries"], 3);
+    let p50 = stats["entry_size_p50"].as_u64().unwrap();
+    let max = stats["entry_size_max"].as_u64().unwrap();
+    assert!(p50 > 3000 && p50 < 4000, "{}", stats);
+    assert!(max > 4000 && max < 5000, "{}", stats);
+    assert!(stats["accounted_memory"].as_u64().unwrap() > 9000);
+    assert_eq!(stats["memory_limit"], 256 * 1024 * 1024);
+    assert_eq!(stats["dropped_inserts"], 0);
+}
+
+// Tests that the stats command counts variants that made room for newer
+// variants of their URL.
+#[test]
+fn variant_evictions() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(VARY, "Accept-Language")
+            .body(Body::from("hello"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        max_variants: 2,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    for language in &["de", "en", "de", "fr", "es"] {
+        let request = Request::get(format!("http://127.0.0.1:{}/", port))
+            .header(ACCEPT_LANGUAGE, *language)
+            .body(Body::empty())
+            .unwrap();
+        common::client_request_body(request);
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let stats = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/stats", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(stats["entries"], 1);
+    assert_eq!(stats["variant_evictions"], 2);
+}
+
+// Tests that the entries command lists cache entries by hits.
+#[test]
+fn entries() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from("cached"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    // The first request for each path is a miss.
+    for (path, requests) in &[("/one", 2), ("/three", 4), ("/none", 1)] {
+        for _ in 0..*requests {
+            common::client_get(
+                format!("http://127.0.0.1:{}{}", port, path)
+                    .parse()
+                    .unwrap(),
+            );
+        }
+    }
+
+    let entries = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/entries", port)
+            .parse()
+            .unwrap(),
+    );
+    let listed: Vec<(&str, u64)> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["cache_key"].as_str().unwrap(),
+                entry["hits"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(listed, vec![("/three", 3), ("/one", 1), ("/none", 0)]);
+    assert!(entries[0]["size"].as_u64().unwrap() > 0);
+    assert!(entries[0]["last_access"].as_u64().unwrap() > 1_500_000_000);
+
+    let entries = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/entries?limit=1", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+}
+
+// Tests that pinned entries survive eviction.
+#[test]
+fn pin() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from("x".repeat(500)))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        // Room for a few entries only.
+        memory_size: 3000,
+        cache_shards: 1,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str| {
+        common::client_get(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+    };
+    let admin = |command: &str| {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("http://127.0.0.1:{}/_rustnish/{}", port, command))
+            .body(Body::empty())
+            .unwrap();
+        let response = common::client_request(request);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().concat2().wait().unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()["entries"].clone()
+    };
+    let hit = |path: &str| {
+        get_json(
+            format!("http://127.0.0.1:{}/_rustnish/preview?url={}", port, path)
+                .parse()
+                .unwrap(),
+        )["hit"]
+            .as_bool()
+            .unwrap()
+    };
+
+    get("/home");
+    assert_eq!(admin("pin?key=%2Fhome"), 1);
+    // Pins by prefix also apply to entries stored later.
+    assert_eq!(admin("pin?prefix=%2Fassets%2F"), 0);
+    get("/assets/style.css");
+    for page in 0..10 {
+        get(&format!("/page{}", page));
+    }
+    assert!(hit("/home"));
+    assert!(hit("/assets/style.css"));
+    assert!(!hit("/page0"));
+
+    assert_eq!(admin("unpin?key=%2Fhome"), 1);
+    for page in 0..10 {
+        get(&format!("/page{}", page));
+    }
+    assert!(!hit("/home"));
+    assert!(hit("/assets/style.css"));
+}
+
+// Tests that a pinned entry stays cached when a new variant would make it
+// too large for the cache.
+#[test]
+fn pinned_entry_grows() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let upstream_requests = Arc::new(AtomicUsize::new(0));
+    let counter = upstream_requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(VARY, "Accept-Language")
+            .body(Body::from("x".repeat(500)))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        // Room for a few variants only.
+        memory_size: 3000,
+        cache_shards: 1,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |language: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/home", port))
+            .header(ACCEPT_LANGUAGE, language)
+            .body(Body::empty())
+            .unwrap();
+        common::client_request_body(request);
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    get("de");
+    let request = Request::builder()
+        .method("POST")
+        .uri(format!(
+            "http://127.0.0.1:{}/_rustnish/pin?key=%2Fhome",
+            port
+        ))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(common::client_request(request).status(), StatusCode::OK);
+    for language in &["en", "fr", "es", "it", "nl", "pl", "cs", "sv"] {
+        get(language);
+    }
+
+    let stats = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/stats", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(stats["entries"], 1);
+    let before = upstream_requests.load(Ordering::SeqCst);
+    get("de");
+    assert_eq!(upstream_requests.load(Ordering::SeqCst), before);
+}
+
+// Tests that the expiry of a cache entry can be changed.
+#[test]
+fn expiry() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=60")
+            .body(Body::from("cached"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let set_expiry = |key: &str, ttl: u64| {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!(
+                "http://127.0.0.1:{}/_rustnish/expiry?key={}&ttl={}",
+                port, key, ttl
+            ))
+            .body(Body::empty())
+            .unwrap();
+        common::client_request(request).status()
+    };
+    let preview_url: Uri = format!("http://127.0.0.1:{}/_rustnish/preview?url=%2Fpage", port)
+        .parse()
+        .unwrap();
+
+    assert_eq!(set_expiry("%2Fpage", 3600), StatusCode::NOT_FOUND);
+    common::client_get(format!("http://127.0.0.1:{}/page", port).parse().unwrap());
+
+    assert_eq!(set_expiry("%2Fpage", 3600), StatusCode::OK);
+    assert!(
+        get_json(preview_url.clone())["ttl_remaining"]
+            .as_u64()
+            .unwrap()
+            > 3590
+    );
+
+    assert_eq!(set_expiry("%2Fpage", 0), StatusCode::OK);
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(get_json(preview_url)["hit"], false);
+}
+
+// Tests that bans invalidate matching entries that were stored before them.
+#[test]
+fn ban() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let tags = if request.uri().path().starts_with("/products/") {
+            "product"
+        } else {
+            "page"
+        };
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header("x-tags", tags)
+            .body(Body::from("cached"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str| {
+        common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(20));
+        requests.load(Ordering::SeqCst)
+    };
+    let ban = |parameters: &str| {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!(
+                "http://127.0.0.1:{}/_rustnish/ban?{}",
+                port, parameters
+            ))
+            .body(Body::empty())
+            .unwrap();
+        common::client_request(request).status()
+    };
+
+    get("/news/1");
+    get("/news/2");
+    assert_eq!(3, get("/products/1"));
+    assert_eq!(3, get("/news/1"));
+
+    assert_eq!(StatusCode::OK, ban("key=%5E%2Fnews%2F1%24"));
+    assert_eq!(4, get("/news/1"));
+    // Responses stored after the ban are not affected.
+    assert_eq!(4, get("/news/1"));
+    assert_eq!(4, get("/news/2"));
+
+    assert_eq!(StatusCode::OK, ban("header=X-Tags&value=product"));
+    assert_eq!(4, get("/news/2"));
+    assert_eq!(5, get("/products/1"));
+
+    let bans = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/bans", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(bans[0]["key"], "^/news/1$");
+    assert_eq!(bans[1]["header"], "x-tags");
+
+    assert_eq!(StatusCode::BAD_REQUEST, ban("key=%28"));
+    assert_eq!(StatusCode::BAD_REQUEST, ban("header=x-tags"));
+}
+
+// Tests that the backend can be switched at runtime, with the cache entries
+// of a route purged.
+#[test]
+fn switch_backend() {
+    let port = common::get_free_port();
+    let blue_port = common::get_free_port();
+    let green_port = common::get_free_port();
+
+    // Backends on this machine redirect to themselves as localhost.
+    let server = |name: &'static str, port: u16| {
+        move |_| {
+            Response::builder()
+                .header(CACHE_CONTROL, "public,max-age=1800")
+                .header(LOCATION, format!("http://localhost:{}/login", port))
+                .body(Body::from(name))
+                .unwrap()
+        }
+    };
+    let _blue_server = common::start_dummy_server(blue_port, server("blue", blue_port));
+    let _green_server = common::start_dummy_server(green_port, server("green", green_port));
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, blue_port, config);
+
+    let get = |path: &str| {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(20));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    assert_eq!("blue", get("/app/1"));
+    assert_eq!("blue", get("/static/1"));
+
+    let request = Request::post(format!(
+        "http://127.0.0.1:{}/_rustnish/switch?host=127.0.0.1&port={}&purge=%2Fapp%2F",
+        port, green_port
+    ))
+    .body(Body::empty())
+    .unwrap();
+    let response = common::client_request(request);
+    assert_eq!(StatusCode::OK, response.status());
+    let body = response.into_body().concat2().wait().unwrap();
+    let switched: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        switched["origin"],
+        format!("http://127.0.0.1:{}", green_port)
+    );
+    assert_eq!(
+        switched["previous"]["origin"],
+        format!("http://127.0.0.1:{}", blue_port)
+    );
+    assert_eq!(switched["purged"], 1);
+
+    assert_eq!("green", get("/app/1"));
+    assert_eq!("blue", get("/static/1"));
+    assert_eq!("green", get("/static/2"));
+    let response = common::client_get(
+        format!("http://127.0.0.1:{}/static/3", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(
+        response.headers()[LOCATION],
+        format!("http://127.0.0.1:{}/login", port)
+    );
+
+    let status = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/backend", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(status["current"]["in_flight"], 0);
+    assert_eq!(status["draining"], Value::Array(Vec::new()));
+
+    let request = Request::post(format!(
+        "http://127.0.0.1:{}/_rustnish/switch?host=a%2Fb&port=80",
+        port
+    ))
+    .body(Body::empty())
+    .unwrap();
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        common::client_request(request).status()
+    );
+}
+
+// Tests that the pools of a director can be switched by route, to several
+// backends that take turns.
+#[test]
+fn switch_director() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+    let blue_port = common::get_free_port();
+    let green_ports = [common::get_free_port(), common::get_free_port()];
+
+    let server = |name: &'static str| move |_| Response::new(Body::from(name));
+    let _upstream_server = common::start_dummy_server(upstream_port, server("origin"));
+    let _blue_server = common::start_dummy_server(blue_port, server("blue"));
+    let _green_server = common::start_dummy_server(green_ports[0], server("green"));
+    let _green_server2 = common::start_dummy_server(green_ports[1], server("green2"));
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        directors: vec![Director {
+            name: "app".to_string(),
+            pools: vec![vec![PoolBackend::from(
+                format!("127.0.0.1:{}", blue_port).as_str(),
+            )]],
+            fallback: None,
+        }],
+        routes: vec![
+            Route {
+                path_prefix: "/app/".to_string(),
+                director: Some("app".to_string()),
+                ..Route::default()
+            },
+            Route {
+                path_prefix: "/static/".to_string(),
+                ..Route::default()
+            },
+        ],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str| {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    let switch = |parameters: &str| {
+        let request = Request::post(format!(
+            "http://127.0.0.1:{}/_rustnish/switch?{}",
+            port, parameters
+        ))
+        .body(Body::empty())
+        .unwrap();
+        common::client_request(request)
+    };
+    assert_eq!("blue", get("/app/1"));
+
+    let backends = format!("127.0.0.1:{},127.0.0.1:{}", green_ports[0], green_ports[1]);
+    let response = switch(&format!(
+        "route=%2Fapp%2F&backends={}",
+        backends.replace(':', "%3A").replace(',', "%2C")
+    ));
+    assert_eq!(StatusCode::OK, response.status());
+    let body = response.into_body().concat2().wait().unwrap();
+    let switched: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(switched["origin"], backends);
+    assert_eq!(
+        switched["previous"]["origin"],
+        format!("127.0.0.1:{}", blue_port)
+    );
+
+    let mut bodies = vec![get("/app/1"), get("/app/2")];
+    bodies.sort();
+    assert_eq!(vec!["green", "green2"], bodies);
+    assert_eq!("origin", get("/other"));
+
+    let status = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/backend", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(status["directors"]["app"]["current"]["origin"], backends);
+    assert_eq!(
+        status["current"]["origin"],
+        format!("http://127.0.0.1:{}", upstream_port)
+    );
+
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        switch("director=app&backends=127.0.0.1").status()
+    );
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        switch("route=%2Fstatic%2F&backends=127.0.0.1%3A80").status()
+    );
+    assert_eq!(
+        StatusCode::NOT_FOUND,
+        switch("director=api&backends=127.0.0.1%3A80").status()
+    );
+}
+
+// Tests that management actions are appended to the audit log.
+#[test]
+fn audit_log() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+    let log = std::env::temp_dir().join(format!("rustnish-audit-{}.log", port));
+    // Left over from an earlier failed run maybe.
+    let _ = fs::remove_file(&log);
+    let log = log.to_str().unwrap().to_string();
+
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        admin_audit_log: Some(log.clone()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let admin = |method: &str, command: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}/_rustnish/{}", port, command))
+            .body(Body::empty())
+            .unwrap();
+        common::client_request(request);
+    };
+    admin("POST", "purge?prefix=%2Fnews");
+    admin("POST", "purge");
+    // Reading is not a management action.
+    admin("GET", "stats");
+
+    let contents = fs::read_to_string(&log).unwrap();
+    let entries: Vec<Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(2, entries.len());
+    assert_eq!("purge", entries[0]["action"]);
+    assert_eq!("prefix=%2Fnews", entries[0]["parameters"]);
+    assert_eq!("127.0.0.1", entries[0]["client"]);
+    assert_eq!(Value::Null, entries[0]["identity"]);
+    assert_eq!(200, entries[0]["status"]);
+    assert!(entries[0]["timestamp"].as_u64().unwrap() > 0);
+    assert_eq!(400, entries[1]["status"]);
+
+    fs::remove_file(&log).unwrap();
+}
+
+// Tests that admin tokens only allow the commands of their scopes.
+#[test]
+fn tokens() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+    let log = std::env::temp_dir().join(format!("rustnish-audit-{}.log", port));
+    let _ = fs::remove_file(&log);
+    let log = log.to_str().unwrap().to_string();
+
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        admin_tokens: vec![
+            AdminToken {
+                name: "monitoring".to_string(),
+                token: "secret1".to_string(),
+                scopes: vec![AdminScope::ReadStats],
+            },
+            AdminToken {
+                name: "ops".to_string(),
+                token: "secret2".to_string(),
+                scopes: vec![AdminScope::ReadStats, AdminScope::Purge],
+            },
+        ],
+        admin_audit_log: Some(log.clone()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let admin = |method: &str, command: &str, token: Option<&str>| {
+        let mut request = Request::builder();
+        request
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}/_rustnish/{}", port, command));
+        if let Some(token) = token {
+            request.header(AUTHORIZATION, format!("Bearer {}", token).as_str());
+        }
+        common::client_request(request.body(Body::empty()).unwrap()).status()
+    };
+    assert_eq!(StatusCode::UNAUTHORIZED, admin("GET", "stats", None));
+    assert_eq!(
+        StatusCode::UNAUTHORIZED,
+        admin("GET", "stats", Some("secret"))
+    );
+    assert_eq!(StatusCode::OK, admin("GET", "stats", Some("secret1")));
+    assert_eq!(
+        StatusCode::FORBIDDEN,
+        admin("POST", "purge?prefix=%2F", Some("secret1"))
+    );
+    assert_eq!(
+        StatusCode::OK,
+        admin("POST", "purge?prefix=%2F", Some("secret2"))
+    );
+
+    let contents = fs::read_to_string(&log).unwrap();
+    let entries: Vec<Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(2, entries.len());
+    assert_eq!("monitoring", entries[0]["identity"]);
+    assert_eq!(403, entries[0]["status"]);
+    assert_eq!("ops", entries[1]["identity"]);
+    assert_eq!(200, entries[1]["status"]);
+
+    fs::remove_file(&log).unwrap();
+}
+
+// Tests that connection errors are counted separately from requests.
+#[test]
+fn connection_metrics() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::new(Body::from(vec![b'x'; 10_000_000]))
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    // Not HTTP at all.
+    let mut garbage = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    garbage.write_all(b"garbage\r\n\r\n").unwrap();
+    let mut response = String::new();
+    let _ = garbage.read_to_string(&mut response);
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+    // Aborted while the response is sent.
+    let mut reset = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    reset.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    reset.read_exact(&mut [0; 100]).unwrap();
+    SockRef::from(&reset)
+        .set_linger(Some(Duration::from_secs(0)))
+        .unwrap();
+    drop(reset);
+
+    let url: Uri = format!("http://127.0.0.1:{}/_rustnish/metrics", port)
+        .parse()
+        .unwrap();
+    let mut metrics = get_json(url.clone());
+    for _ in 0..50 {
+        if metrics["connection_resets"] == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        metrics = get_json(url.clone());
+    }
+    assert_eq!(1, metrics["handshake_errors"], "{}", metrics);
+    assert_eq!(1, metrics["connection_resets"], "{}", metrics);
+    assert!(metrics["accepted_connections"].as_u64().unwrap() >= 3);
+    assert!(metrics["active_connections"].as_u64().unwrap() >= 1);
+    assert!(metrics["http1_connections"].as_u64().unwrap() >= 1);
+    assert_eq!(0, metrics["http2_connections"]);
+}
+
+// Tests that upstream responses are counted by whether their connection was
+// reused from the pool.
+#[test]
+fn upstream_connection_reuse() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server =
+        common::start_dummy_server(upstream_port, |_| Response::new(Body::from("uncached")));
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    for _ in 0..3 {
+        let body = common::client_get_body(format!("http://127.0.0.1:{}/", port).parse().unwrap());
+        assert_eq!(&body[..], b"uncached");
+    }
+
+    let metrics = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/metrics", port)
+            .parse()
+            .unwrap(),
+    );
+    let new = metrics["upstream_new_connections"].as_u64().unwrap();
+    let reused = metrics["upstream_reused_connections"].as_u64().unwrap();
+    assert_eq!(3, new + reused, "{}", metrics);
+    assert!(reused >= 1, "{}", metrics);
+}
+
+// Tests that a client that stops reading its response is disconnected.
+#[test]
+fn slow_client_abort() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::new(Body::from(vec![b'x'; 1_000_000]))
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        client_delivery_timeout_ms: Some(1000),
+        // Small enough that the response does not fit into it.
+        listener: Listener {
+            send_buffer_size: Some(16_384),
+            ..Listener::default()
+        },
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    // Reading clients are not affected.
+    let body = common::client_get_body(format!("http://127.0.0.1:{}/", port).parse().unwrap());
+    assert_eq!(1_000_000, body.len());
+
+    let mut slow = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    slow.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut head = [0; 100];
+    slow.read_exact(&mut head).unwrap();
+    assert!(String::from_utf8_lossy(&head).contains("content-length: 1000000"));
+    thread::sleep(Duration::from_millis(2000));
+    // The connection was closed before the whole body was sent.
+    slow.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut rest = Vec::new();
+    let _ = slow.read_to_end(&mut rest);
+    assert!(rest.len() < 1_000_000, "{}", rest.len());
+
+    let metrics = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/metrics", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(1, metrics["slow_client_aborts"], "{}", metrics);
+}
+
+// Tests that requests go to the standby backend while the primary fails its
+// health checks, and back once it recovered.
+#[test]
+fn failover() {
+    let port = common::get_free_port();
+    let primary_port = common::get_free_port();
+    let standby_port = common::get_free_port();
+
+    let healthy = Arc::new(AtomicBool::new(true));
+    let primary_healthy = healthy.clone();
+    let _primary = common::start_dummy_server(primary_port, move |request| {
+        if request.uri().path() == "/health" && !primary_healthy.load(Ordering::SeqCst) {
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap();
+        }
+        Response::new(Body::from("primary"))
+    });
+    let _standby =
+        common::start_dummy_server(standby_port, |_| Response::new(Body::from("standby")));
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        failover: Some(Failover {
+            standby_host: "127.0.0.1".to_string(),
+            standby_port,
+            health_check_path: "/health".to_string(),
+            interval_ms: 20,
+            timeout_ms: 500,
+            failures: 2,
+            recoveries: 3,
+        }),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, primary_port, config);
+
+    let get = || {
+        let body = common::client_get_body(format!("http://127.0.0.1:{}/", port).parse().unwrap());
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    let stats = || {
+        get_json(
+            format!("http://127.0.0.1:{}/_rustnish/stats", port)
+                .parse()
+                .unwrap(),
+        )
+    };
+    assert_eq!("primary", get());
+    assert_eq!(stats()["failover"]["active"], "primary");
+
+    healthy.store(false, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!("standby", get());
+    let failover = &stats()["failover"];
+    assert_eq!(failover["active"], "standby");
+    assert_eq!(failover["failovers"], 1);
+    assert_eq!(
+        failover["standby"],
+        format!("http://127.0.0.1:{}", standby_port)
+    );
+
+    healthy.store(true, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!("primary", get());
+    assert_eq!(stats()["failover"]["active"], "primary");
+}
+
+// Tests that the stats report cache hits and misses by route and by content
+// type.
+#[test]
+fn hit_ratios() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=60")
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from("page"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        routes: vec![Route {
+            path_prefix: "/blog/".to_string(),
+            ..Route::default()
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str| {
+        common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+    get("/blog/post");
+    get("/blog/post");
+    get("/blog/post");
+    get("/about");
+
+    let stats = get_json(
+        format!("http://127.0.0.1:{}/_rustnish/stats", port)
+            .parse()
+            .unwrap(),
+    );
+    let ratios = &stats["hit_ratios"];
+    assert_eq!(ratios["routes"]["/blog/"]["hits"], 2, "{}", stats);
+    assert_eq!(ratios["routes"]["/blog/"]["misses"], 1);
+    assert_eq!(ratios["routes"]["(no route)"]["misses"], 1);
+    assert_eq!(ratios["routes"]["(no route)"]["ratio"], 0.0);
+    assert_eq!(ratios["content_types"]["text/html"]["hits"], 2);
+    assert_eq!(ratios["content_types"]["text/html"]["misses"], 2);
+}
+
+// Tests that the simulator predicts the hit ratio of the logged requests
+// with other cache settings.
+#[test]
+fn simulate() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=60")
+            .body(Body::from("page"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    for path in &["/?b=2&a=1", "/?a=1&b=2", "/?a=1&b=2&ref=mail", "/?b=2&a=1"] {
+        common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    let simulate = |query: &str| {
+        get_json(
+            format!("http://127.0.0.1:{}/_rustnish/simulate?{}", port, query)
+                .parse()
+                .unwrap(),
+        )
+    };
+    let actual = simulate("");
+    assert_eq!(actual["requests"], 4, "{}", actual);
+    assert_eq!(actual["actual_hits"], 1);
+    assert_eq!(actual["predicted_hits"], 1);
+    assert_eq!(actual["actual_keys"], 3);
+
+    let predicted = simulate("sort_query=1&strip_query=ref");
+    assert_eq!(predicted["predicted_hits"], 3, "{}", predicted);
+    assert_eq!(predicted["predicted_ratio"], 0.75);
+    assert_eq!(predicted["predicted_keys"], 1);
+    assert_eq!(simulate("ttl=0")["predicted_hits"], 0);
+
+    let response = common::client_get(
+        format!("http://127.0.0.1:{}/_rustnish/simulate?ttl=soon", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+// Tests that the history lists the last requests with their cache decision,
+// newest first, and can be filtered.
+#[test]
+fn history() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server =
+        common::start_dummy_server(upstream_port, |request| match request.uri().path() {
+            "/page" => Response::builder()
+                .header(CACHE_CONTROL, "public, max-age=60")
+                .body(Body::from("page"))
+                .unwrap(),
+            "/broken" => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::empty())
+                .unwrap(),
+            _ => Response::new(Body::from("private")),
+        });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    // POST requests are not cachable.
+    for (method, path) in &[
+        ("GET", "/page"),
+        ("GET", "/page"),
+        ("POST", "/account"),
+        ("GET", "/broken"),
+    ] {
+        common::client_request(
+            Request::builder()
+                .method(*method)
+                .uri(format!("http://127.0.0.1:{}{}", port, path))
+                .body(Body::empty())
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    let history = |query: &str| {
+        get_json(
+            format!("http://127.0.0.1:{}/_rustnish/history?{}", port, query)
+                .parse()
+                .unwrap(),
+        )
+    };
+    let all = history("");
+    assert_eq!(4, all.as_array().unwrap().len(), "{}", all);
+    assert_eq!(all[0]["uri"], "/broken");
+    assert_eq!(all[0]["status"], 503);
+    assert_eq!(
+        all[0]["backend"],
+        format!("http://127.0.0.1:{}", upstream_port)
+    );
+    assert_eq!(all[1]["decision"], "pass");
+    assert_eq!(all[2]["decision"], "hit");
+    assert_eq!(all[2]["cache_key"], "/page");
+    assert_eq!(all[3]["decision"], "miss");
+
+    assert_eq!(history("decision=hit").as_array().unwrap().len(), 1);
+    let errors = history("status=5xx");
+    assert_eq!(1, errors.as_array().unwrap().len());
+    assert_eq!(errors[0]["uri"], "/broken");
+    assert_eq!(history("path=/page&limit=1").as_array().unwrap().len(), 1);
+
+    let response = common::client_get(
+        format!("http://127.0.0.1:{}/_rustnish/history?decision=maybe", port)
+            .parse()
+            .unwrap(),
+    );
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+}
+
+// Tests that admin commands take cache keys without host for the entries of
+// all virtual hosts, and that a host parameter selects one of them.
+#[test]
+fn virtual_hosts() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from("cached"))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |host: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/page", port))
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        common::client_request_body(request);
+        thread::sleep(Duration::from_millis(20));
+        requests.load(Ordering::SeqCst)
+    };
+    let admin = |method: &str, command: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}/_rustnish/{}", port, command))
+            .body(Body::empty())
+            .unwrap();
+        let body = common::client_request(request)
+            .into_body()
+            .concat2()
+            .wait()
+            .unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()
+    };
+
+    get("one.example.com");
+    assert_eq!(2, get("two.example.com"));
+
+    let preview = admin("GET", "preview?url=%2Fpage");
+    assert_eq!(preview["cache_key"], "/page");
+    assert_eq!(preview["hosts"].as_array().unwrap().len(), 2);
+    let preview = admin("GET", "preview?url=%2Fpage&host=One.example.com");
+    assert_eq!(preview["cache_key"], "/page host:one.example.com");
+    assert_eq!(preview["hosts"][0], "one.example.com");
+    assert_eq!(preview["variants"][0], "/page");
+
+    let entries = admin("GET", "entries");
+    assert_eq!(entries[0]["cache_key"], "/page");
+    assert!(entries[0]["host"]
+        .as_str()
+        .unwrap()
+        .ends_with(".example.com"));
+
+    let purged = admin("POST", "purge?key=%2Fpage&host=one.example.com");
+    assert_eq!(purged["entries"], 1);
+    assert_eq!(3, get("one.example.com"));
+    assert_eq!(3, get("two.example.com"));
+
+    admin("POST", "ban?key=%5E%2Fpage%24");
+    assert_eq!(4, get("one.example.com"));
+    assert_eq!(5, get("two.example.com"));
+}
+
+// Tests that purges and bans remove all variants of a URL at once, so that no
+// stale encoding is left behind.
+#[test]
+fn invalidate_variants() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let encoding = request.headers()[ACCEPT_ENCODING].clone();
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(VARY, "Accept-Encoding")
+            .body(Body::from(encoding.to_str().unwrap().to_string()))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |encoding: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/page", port))
+            .header(ACCEPT_ENCODING, encoding)
+            .body(Body::empty())
+            .unwrap();
+        let body = common::client_request_body(request).into_body();
+        assert_eq!(encoding.as_bytes(), &body[..]);
+        thread::sleep(Duration::from_millis(20));
+        requests.load(Ordering::SeqCst)
+    };
+    let admin = |method: &str, command: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}/_rustnish/{}", port, command))
+            .body(Body::empty())
+            .unwrap();
+        let body = common::client_request(request)
+            .into_body()
+            .concat2()
+            .wait()
+            .unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()
+    };
+    let cache_both = || {
+        get("gzip");
+        get("identity");
+        let before = get("gzip");
+        assert_eq!(before, get("identity"));
+        let preview = admin("GET", "preview?url=%2Fpage");
+        assert_eq!(preview["variants"].as_array().unwrap().len(), 2);
+        before
+    };
+
+    assert_eq!(2, cache_both());
+    let purged = admin("POST", "purge?key=%2Fpage");
+    assert_eq!(purged["entries"], 1);
+    assert_eq!(admin("GET", "preview?url=%2Fpage")["hit"], false);
+    assert_eq!(3, get("identity"));
+    assert_eq!(4, get("gzip"));
+
+    assert_eq!(4, cache_both());
+    admin("POST", "ban?key=%5E%2Fpage%24");
+    assert_eq!(admin("GET", "preview?url=%2Fpage")["hit"], false);
+    assert_eq!(5, get("gzip"));
+    assert_eq!(6, get("identity"));
+}
@@ -1,10 +1,25 @@
 use crate::common::echo_request;
-use futures::Future;
-use hyper::header::{CACHE_CONTROL, COOKIE};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{Future, Stream};
+use hyper::header::{
+    ACCEPT_ENCODING, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, COOKIE, ETAG, HOST, IF_NONE_MATCH, IF_RANGE, RANGE, SET_COOKIE,
+    VARY,
+};
 use hyper::Uri;
-use hyper::{Body, Request, StatusCode};
+use hyper::{Body, Request, Response, StatusCode};
+use rustnish::clock::ManualClock;
+use rustnish::{
+    Backend, ChecksumAlgorithm, Config, QueryNormalization, Readiness, Route, StatusTtl,
+    TrailingSlash,
+};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 mod common;
 
@@ -68,6 +83,379 @@ fn no_max_age_means_uncachable() {
     assert_eq!(response2.status(), StatusCode::BAD_GATEWAY);
 }
 
+// Tests that responses with only a Last-Modified header are cached if
+// heuristic freshness is enabled.
+#[test]
+fn heuristic_freshness() {
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        Response::builder()
+            .header("last-modified", "Thu, 01 Jan 2015 00:00:00 GMT")
+            .body(Body::from(
+                counter.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+            .unwrap()
+    });
+    let get =
+        |port: u16| common::client_get_body(format!("http://127.0.0.1:{}/", port).parse().unwrap());
+
+    let port = common::get_free_port();
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+    assert_eq!(&b"0"[..], &get(port)[..]);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(&b"1"[..], &get(port)[..]);
+
+    let port = common::get_free_port();
+    let config = Config {
+        heuristic_freshness: true,
+        ..Config::default()
+    };
+    let _heuristic_proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    assert_eq!(&b"2"[..], &get(port)[..]);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(&b"2"[..], &get(port)[..]);
+}
+
+// Tests that clients can skip the cache or ask for fresher or staler
+// responses with Cache-Control request directives, if the config allows it.
+#[test]
+fn request_directives() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=60, stale-if-error=300")
+            .body(Body::from(
+                counter.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+            .unwrap()
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        request_cache_control: true,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |header: Option<(&str, &str)>| {
+        let mut request = Request::builder();
+        request.uri(format!("http://127.0.0.1:{}/", port));
+        if let Some((name, value)) = header {
+            request.header(name, value);
+        }
+        let body = common::client_request_body(request.body(Body::empty()).unwrap()).into_body();
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    assert_eq!("0", get(None));
+    assert_eq!("1", get(Some(("cache-control", "no-cache"))));
+    assert_eq!("1", get(None));
+    assert_eq!("2", get(Some(("pragma", "no-cache"))));
+
+    clock.advance(Duration::from_secs(30));
+    assert_eq!("2", get(None));
+    assert_eq!("3", get(Some(("cache-control", "max-age=10"))));
+    assert_eq!("3", get(Some(("cache-control", "min-fresh=50"))));
+    clock.advance(Duration::from_secs(20));
+    assert_eq!("4", get(Some(("cache-control", "min-fresh=50"))));
+
+    clock.advance(Duration::from_secs(90));
+    assert_eq!("4", get(Some(("cache-control", "max-stale=60"))));
+    assert_eq!("5", get(None));
+}
+
+// Tests that expired responses are revalidated with their ETag and renewed
+// Tests that the Cache-Control directives of clients are ignored by default.
+#[test]
+fn request_directives_ignored() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=60")
+            .body(Body::from(
+                counter.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+            .unwrap()
+    });
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+
+    let get = |name: &str, value: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/", port))
+            .header(name, value)
+            .body(Body::empty())
+            .unwrap();
+        let body = common::client_request_body(request).into_body();
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    assert_eq!("0", get("cache-control", "no-cache"));
+    assert_eq!("0", get("cache-control", "no-cache"));
+    assert_eq!("0", get("cache-control", "max-age=0"));
+    assert_eq!("0", get("pragma", "no-cache"));
+}
+
+// by a 304 without the body.
+#[test]
+fn conditional_revalidation() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        let condition = request
+            .headers()
+            .get(IF_NONE_MATCH)
+            .map(|value| value.to_str().unwrap().to_string());
+        upstream_requests.lock().unwrap().push(condition.clone());
+        let (status, version, body) = match condition {
+            Some(_) => (StatusCode::NOT_MODIFIED, "2", ""),
+            None => (StatusCode::OK, "1", "page"),
+        };
+        Response::builder()
+            .status(status)
+            .header(CACHE_CONTROL, "public, max-age=10")
+            .header(ETAG, "\"v1\"")
+            .header("x-version", version)
+            .body(Body::from(body))
+            .unwrap()
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        keep: 60,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = || {
+        let response = common::client_request_body(
+            Request::get(format!("http://127.0.0.1:{}/", port))
+                .body(Body::empty())
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+        response
+    };
+    let response = get();
+    assert_eq!(&b"page"[..], &response.body()[..]);
+    assert_eq!("1", response.headers()["x-version"]);
+
+    clock.advance(Duration::from_secs(20));
+    let response = get();
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(&b"page"[..], &response.body()[..]);
+    // Headers of the 304 replace the stored ones.
+    assert_eq!("2", response.headers()["x-version"]);
+    assert_eq!(
+        vec![None, Some("\"v1\"".to_string())],
+        *requests.lock().unwrap()
+    );
+
+    // The renewed response is fresh again.
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(&b"page"[..], &get().body()[..]);
+    assert_eq!(2, requests.lock().unwrap().len());
+
+    // After the keep time the expired response is gone.
+    clock.advance(Duration::from_secs(100));
+    assert_eq!(&b"page"[..], &get().body()[..]);
+    assert_eq!(None, requests.lock().unwrap()[2]);
+}
+
+// Tests that backends with HEAD probes are revalidated and health checked
+// with HEAD requests.
+#[test]
+fn head_probes() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let version = Arc::new(AtomicUsize::new(1));
+    let upstream_version = version.clone();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.lock().unwrap().push(format!(
+            "{} {}",
+            request.method(),
+            request.uri().path()
+        ));
+        let etag = format!("\"v{}\"", upstream_version.load(Ordering::SeqCst));
+        let unchanged = request
+            .headers()
+            .get(IF_NONE_MATCH)
+            .is_some_and(|value| value == etag.as_str());
+        Response::builder()
+            .status(if unchanged {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::OK
+            })
+            .header(CACHE_CONTROL, "public, max-age=10")
+            .header(ETAG, etag.as_str())
+            .body(Body::from(etag.clone()))
+            .unwrap()
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        keep: 60,
+        backend: Backend {
+            head_probes: true,
+            ..Backend::default()
+        },
+        readiness: Some(Readiness {
+            path: "/readyz".to_string(),
+            warmup_urls: Vec::new(),
+            health_check_path: Some("/health".to_string()),
+            health_check_timeout_ms: 1000,
+        }),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str| {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    assert_eq!("\"v1\"", get("/"));
+    clock.advance(Duration::from_secs(20));
+    assert_eq!("\"v1\"", get("/"));
+    version.store(2, Ordering::SeqCst);
+    clock.advance(Duration::from_secs(20));
+    assert_eq!("\"v2\"", get("/"));
+    assert_eq!("ready", get("/readyz"));
+    assert_eq!(
+        vec!["GET /", "HEAD /", "HEAD /", "GET /", "HEAD /health"],
+        *requests.lock().unwrap()
+    );
+}
+
+// Tests that HEAD requests are answered from cached GET responses, and that
+// responses to HEAD requests are cached apart from them.
+#[test]
+fn head_requests() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.lock().unwrap().push(format!(
+            "{} {}",
+            request.method(),
+            request.uri().path()
+        ));
+        Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=60")
+            .body(Body::from("page"))
+            .unwrap()
+    });
+    let config = Config {
+        cache_head: true,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let send = |method: &str, path: &str| {
+        let response = common::client_request_body(
+            Request::builder()
+                .method(method)
+                .uri(format!("http://127.0.0.1:{}{}", port, path))
+                .body(Body::empty())
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+        response
+    };
+    assert_eq!(&b"page"[..], &send("GET", "/page").body()[..]);
+    let response = send("HEAD", "/page");
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("4", response.headers()[CONTENT_LENGTH]);
+    assert!(response.body().is_empty());
+
+    send("HEAD", "/other");
+    send("HEAD", "/other");
+    // A cached HEAD response has no body for GET requests.
+    assert_eq!(&b"page"[..], &send("GET", "/other").body()[..]);
+    assert_eq!(
+        vec!["GET /page", "HEAD /other", "GET /other"],
+        *requests.lock().unwrap()
+    );
+}
+
+// Tests that 404s are cached for their default TTL and that responses with
+// other status codes are only cached if they are in the list.
+#[test]
+fn negative_caching() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let (status, cache_control) = match request.uri().path() {
+            "/moved" => (StatusCode::MOVED_PERMANENTLY, "public, max-age=60"),
+            "/unavailable" => (StatusCode::SERVICE_UNAVAILABLE, "public, max-age=60"),
+            _ => (StatusCode::NOT_FOUND, ""),
+        };
+        let mut response = Response::builder();
+        response.status(status);
+        if !cache_control.is_empty() {
+            response.header(CACHE_CONTROL, cache_control);
+        }
+        response.body(Body::empty()).unwrap()
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        status_ttls: vec![StatusTtl {
+            status: 404,
+            ttl: 5,
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str| {
+        let response = common::client_get(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+        response.status()
+    };
+    let upstream = || requests.load(Ordering::SeqCst);
+    assert_eq!(StatusCode::NOT_FOUND, get("/missing"));
+    assert_eq!(StatusCode::NOT_FOUND, get("/missing"));
+    assert_eq!(1, upstream());
+    clock.advance(Duration::from_secs(6));
+    get("/missing");
+    assert_eq!(2, upstream());
+
+    assert_eq!(StatusCode::MOVED_PERMANENTLY, get("/moved"));
+    get("/moved");
+    assert_eq!(3, upstream());
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, get("/unavailable"));
+    get("/unavailable");
+    assert_eq!(5, upstream());
+}
+
 // A response must not be cached longer than the max-age cache-control headers
 // says.
 #[test]
@@ -101,6 +489,46 @@ fn max_age_expired() {
     assert_eq!(response2.status(), StatusCode::BAD_GATEWAY);
 }
 
+// Tests that an injected clock controls cache expiry, so no waiting is needed.
+#[test]
+fn max_age_expired_manual_clock() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let upstream_server = common::start_dummy_server(upstream_port, |request| {
+        let mut response = echo_request(request);
+        {
+            let headers = response.headers_mut();
+            headers.append(CACHE_CONTROL, "public,max-age=1800".parse().unwrap());
+        }
+        response
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let url: Uri = ("http://127.0.0.1:".to_string() + &port.to_string())
+        .parse()
+        .unwrap();
+    // This request should populate the cache.
+    common::client_get(url.clone());
+
+    upstream_server.shutdown_now().wait().unwrap();
+
+    // Shortly before the max age the response is still cached.
+    clock.advance(Duration::from_secs(1799));
+    let response2 = common::client_get(url.clone());
+    assert_eq!(response2.status(), StatusCode::OK);
+
+    // After the max age the cache must have expired this response.
+    clock.advance(Duration::from_secs(2));
+    let response3 = common::client_get(url);
+    assert_eq!(response3.status(), StatusCode::BAD_GATEWAY);
+}
+
 // If a request contains a session cookie then it should bypass the cache.
 #[test]
 fn session_cookie_bypass() {
@@ -133,6 +561,41 @@ fn session_cookie_bypass() {
     assert_eq!(response2.status(), StatusCode::BAD_GATEWAY);
 }
 
+// Tests that configured session cookie patterns replace the default one.
+#[test]
+fn session_cookie_patterns() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let upstream_counter = counter.clone();
+    let _dummy_server = common::start_dummy_server(upstream_port, move |_| {
+        let count = upstream_counter.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=1800")
+            .body(Body::from(count.to_string()))
+            .unwrap()
+    });
+    let config = Config {
+        session_cookies: vec!["^wordpress_logged_in_".to_string()],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |cookie: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/", port))
+            .header(COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap();
+        common::client_request_body(request).into_body()
+    };
+    assert_eq!(&b"0"[..], &get("_ga=1")[..]);
+    thread::sleep(Duration::from_millis(50));
+    // Drupal's cookies are no session cookies anymore.
+    assert_eq!(&b"0"[..], &get("SESS1234567=xyz")[..]);
+    assert_eq!(&b"1"[..], &get("wordpress_logged_in_abc=alice")[..]);
+}
+
 // Tests that a very small cache of 10 bytes can never hold an HTTP response.
 #[test]
 fn insufficient_cache_size() {
@@ -161,3 +624,1110 @@ fn insufficient_cache_size() {
     let response2 = common::client_get(url);
     assert_eq!(response2.status(), StatusCode::BAD_GATEWAY);
 }
+
+// Tests that content type policies force or prevent caching regardless of
+// Cache-Control.
+#[test]
+fn content_type_policies() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let upstream_server = common::start_dummy_server(upstream_port, |request| {
+        let content_type = if request.uri().path().ends_with(".png") {
+            "image/png"
+        } else {
+            "application/octet-stream"
+        };
+        let mut response = echo_request(request);
+        {
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+            headers.insert(CACHE_CONTROL, "public,max-age=1800".parse().unwrap());
+        }
+        response
+    });
+    let config: Config = toml::from_str(
+        r#"
+        [[cache_policies]]
+        content_type = "application/octet-stream"
+        cache = false
+
+        [[cache_policies]]
+        content_type = "image/*"
+        ttl = 604800
+        "#,
+    )
+    .unwrap();
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        ..config
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let image: Uri = format!("http://127.0.0.1:{}/a.png", port).parse().unwrap();
+    let download: Uri = format!("http://127.0.0.1:{}/a.bin", port).parse().unwrap();
+    common::client_get(image.clone());
+    common::client_get(download.clone());
+
+    upstream_server.shutdown_now().wait().unwrap();
+
+    // The image policy TTL of 7 days is used instead of the max-age.
+    clock.advance(Duration::from_secs(24 * 60 * 60));
+    assert_eq!(common::client_get(image).status(), StatusCode::OK);
+    assert_eq!(
+        common::client_get(download).status(),
+        StatusCode::BAD_GATEWAY
+    );
+}
+
+// Tests that a scheduled override extends the TTL inside its time window.
+#[test]
+fn scheduled_min_ttl() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let upstream_server = common::start_dummy_server(upstream_port, |request| {
+        let mut response = echo_request(request);
+        {
+            let headers = response.headers_mut();
+            headers.insert(CACHE_CONTROL, "public,max-age=60".parse().unwrap());
+        }
+        response
+    });
+    let config: Config = toml::from_str(
+        r#"
+        [[schedules]]
+        cron = "* 18-21 * * 5"
+        min_ttl = 86400
+        "#,
+    )
+    .unwrap();
+    // Friday, 2019-03-01 18:30 UTC.
+    let clock = ManualClock::at(UNIX_EPOCH + Duration::from_secs(1_551_465_000));
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        ..config
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let url: Uri = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+    common::client_get(url.clone());
+
+    upstream_server.shutdown_now().wait().unwrap();
+
+    clock.advance(Duration::from_secs(3600));
+    assert_eq!(common::client_get(url).status(), StatusCode::OK);
+}
+
+// Tests that the secret refresh header replaces the cached response and that
+// a wrong secret is ignored.
+#[test]
+fn refresh_header() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    // Every upstream response has a new number.
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(
+                counter.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+            .unwrap()
+    });
+    let config = Config {
+        refresh_token: Some("secret".to_string()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |token: Option<&str>| {
+        let mut request = Request::builder();
+        request.uri(format!("http://127.0.0.1:{}/", port));
+        if let Some(token) = token {
+            request.header("X-Rustnish-Refresh", token);
+        }
+        let response = common::client_request(request.body(Body::empty()).unwrap());
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!("0", get(None));
+    assert_eq!("0", get(Some("wrong")));
+    assert_eq!("1", get(Some("secret")));
+    assert_eq!("1", get(None));
+}
+
+// Tests that cache hits are answered while all upstream slots are taken by
+// slow requests.
+#[test]
+fn hits_bypass_upstream_limit() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |request| {
+        if request.uri().path() == "/slow" {
+            thread::sleep(Duration::from_millis(1000));
+            return Response::new(Body::from("slow"));
+        }
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from("hot"))
+            .unwrap()
+    });
+    let mut config = Config::default();
+    config.backend.max_requests = Some(1);
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let hot: Uri = format!("http://127.0.0.1:{}/hot", port).parse().unwrap();
+    common::client_get(hot.clone());
+
+    let slow_requests = thread::spawn(move || {
+        let slow: Uri = format!("http://127.0.0.1:{}/slow", port).parse().unwrap();
+        let second = {
+            let slow = slow.clone();
+            thread::spawn(move || common::client_get(slow))
+        };
+        common::client_get(slow);
+        second.join().unwrap();
+    });
+    // Give the slow requests time to take the upstream slot.
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let response = common::client_get(hot);
+    assert!(start.elapsed() < Duration::from_millis(500));
+    let body = response.into_body().concat2().wait().unwrap();
+    assert_eq!(&body[..], b"hot");
+
+    slow_requests.join().unwrap();
+}
+
+// Tests that a response is not cached if upstream closes the connection in
+// the middle of the body.
+#[test]
+fn truncated_response_not_cached() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let upstream = common::start_raw_server_sequence(
+        upstream_port,
+        vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: public,max-age=1800\r\nContent-Length: 11\r\n\r\nhello",
+            b"HTTP/1.1 200 OK\r\nCache-Control: public,max-age=1800\r\nContent-Length: 11\r\n\r\nhello world",
+        ],
+    );
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+
+    let url: Uri = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+    // The body streams through, so the status is sent before upstream breaks
+    // off.
+    assert_eq!(common::client_get(url.clone()).status(), StatusCode::OK);
+
+    // The second request goes to upstream again and gets the whole body.
+    assert_eq!(&common::client_get_body(url)[..], b"hello world");
+    upstream.recv_timeout(Duration::from_secs(5)).unwrap();
+    upstream.recv_timeout(Duration::from_secs(5)).unwrap();
+}
+
+// Tests that a chunked response is not cached if upstream closes the
+// connection before the last chunk.
+#[test]
+fn truncated_chunked_response_not_cached() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let upstream = common::start_raw_server_sequence(
+        upstream_port,
+        vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: public,max-age=1800\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n",
+            b"HTTP/1.1 200 OK\r\nCache-Control: public,max-age=1800\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        ],
+    );
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+
+    let url: Uri = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+    common::client_get(url.clone());
+
+    assert_eq!(&common::client_get_body(url)[..], b"hello world");
+    upstream.recv_timeout(Duration::from_secs(5)).unwrap();
+    upstream.recv_timeout(Duration::from_secs(5)).unwrap();
+}
+
+// Tests that POST requests are cached by their normalized body on routes that
+// opted in, and that they can be purged.
+#[test]
+fn post_requests() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(
+                counter.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+            .unwrap()
+    });
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        routes: vec![Route {
+            path_prefix: "/graphql".to_string(),
+            cache_post: true,
+            ..Route::default()
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let post = |path: &str, body: &'static str| {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("http://127.0.0.1:{}{}", port, path))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let response = common::client_request(request);
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!(
+        "0",
+        post("/graphql", r#"{"query": "{ a }", "variables": {"x": 1}}"#)
+    );
+    assert_eq!(
+        "0",
+        post("/graphql", r#"{"variables":{"x":1},"query":"{ a }"}"#)
+    );
+    assert_eq!("1", post("/graphql", r#"{"query": "{ b }"}"#));
+    // Other routes are not cached.
+    assert_eq!("2", post("/form", r#"{"query": "{ a }"}"#));
+    assert_eq!("3", post("/form", r#"{"query": "{ a }"}"#));
+
+    let purged = post("/_rustnish/purge?prefix=POST+%2Fgraphql", "");
+    assert!(purged.contains("\"entries\": 2"), "{}", purged);
+    assert_eq!("4", post("/graphql", r#"{"query": "{ b }"}"#));
+}
+
+// Tests that GraphQL persisted queries share cache entries between GET and
+// POST requests and different parameter orders.
+#[test]
+fn graphql_persisted_queries() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(
+                counter.fetch_add(1, Ordering::SeqCst).to_string(),
+            ))
+            .unwrap()
+    });
+    let config = Config {
+        routes: vec![Route {
+            path_prefix: "/graphql".to_string(),
+            graphql: true,
+            cache_post: true,
+            ..Route::default()
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |query: &str| {
+        let url = format!("http://127.0.0.1:{}/graphql?{}", port, query);
+        String::from_utf8(common::client_get_body(url.parse().unwrap()).to_vec()).unwrap()
+    };
+    let extensions =
+        "extensions=%7B%22persistedQuery%22%3A%7B%22version%22%3A1%2C%22sha256Hash%22%3A%22abc%22%7D%7D";
+
+    assert_eq!(
+        "0",
+        get(&format!(
+            "operationName=Items&variables=%7B%22a%22%3A1%7D&{}",
+            extensions
+        ))
+    );
+    assert_eq!(
+        "0",
+        get(&format!(
+            "{}&variables=%7B%22a%22%3A1%7D&operationName=Items",
+            extensions
+        ))
+    );
+    assert_eq!(
+        "1",
+        get(&format!(
+            "operationName=Items&variables=%7B%22a%22%3A2%7D&{}",
+            extensions
+        ))
+    );
+
+    let request = Request::builder()
+        .method("POST")
+        .uri(format!("http://127.0.0.1:{}/graphql", port))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            r#"{"operationName":"Items","variables":{"a":1},
+                "extensions":{"persistedQuery":{"version":1,"sha256Hash":"abc"}}}"#,
+        ))
+        .unwrap();
+    let body = common::client_request(request)
+        .into_body()
+        .concat2()
+        .wait()
+        .unwrap();
+    assert_eq!(&body[..], b"0");
+}
+
+// Tests that responses for logged in users are cached briefly and only for
+// the same session.
+#[test]
+fn micro_caching() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        let count = counter.fetch_add(1, Ordering::SeqCst);
+        let mut response = Response::builder();
+        response.header(CACHE_CONTROL, "private, max-age=0");
+        if request.uri().path() == "/login" {
+            response.header(SET_COOKIE, "SESS1=new");
+        }
+        response.body(Body::from(count.to_string())).unwrap()
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        micro_cache_ttl: Some(2),
+        clock: Arc::new(clock.clone()),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let get = |path: &str, cookie: Option<&str>| {
+        let mut request = Request::builder();
+        request.uri(format!("http://127.0.0.1:{}{}", port, path));
+        if let Some(cookie) = cookie {
+            request.header(COOKIE, cookie);
+        }
+        let response = common::client_request(request.body(Body::empty()).unwrap());
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!("0", get("/", Some("SESSa=1; _ga=2")));
+    assert_eq!("0", get("/", Some("_ga=3; SESSa=1")));
+    // Other sessions and anonymous users don't see it.
+    assert_eq!("1", get("/", Some("SESSa=2")));
+    assert_eq!("2", get("/", None));
+    assert_eq!("3", get("/", None));
+    // Responses that set cookies are not cached.
+    assert_eq!("4", get("/login", Some("SESSa=1")));
+    assert_eq!("5", get("/login", Some("SESSa=1")));
+
+    clock.advance(Duration::from_secs(3));
+    assert_eq!("6", get("/", Some("SESSa=1")));
+}
+
+// Tests that only cache-busting parameters are part of the cache key.
+#[test]
+fn cache_busting_params() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::empty())
+            .unwrap()
+    });
+    let config = Config {
+        routes: vec![Route {
+            path_prefix: "/assets/".to_string(),
+            cache_busting_params: vec!["v".to_string()],
+            ..Route::default()
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |path: &str| {
+        common::client_get(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        requests.load(Ordering::SeqCst)
+    };
+
+    assert_eq!(1, get("/assets/app.js?v=1&utm_source=mail"));
+    assert_eq!(1, get("/assets/app.js?utm_source=web&v=1"));
+    // A new version is fetched.
+    assert_eq!(2, get("/assets/app.js?v=2"));
+    assert_eq!(2, get("/assets/app.js?v=2"));
+    // Other routes keep the whole query in the key.
+    assert_eq!(3, get("/page?a=1"));
+    assert_eq!(4, get("/page?a=2"));
+}
+
+// Tests that the backend is always asked for gzip and clients that don't
+// accept it get the cached gzip response decompressed.
+#[test]
+fn normalized_accept_encoding() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(request.headers()[ACCEPT_ENCODING], "gzip");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"compressed").unwrap();
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(encoder.finish().unwrap()))
+            .unwrap()
+    });
+    let config = Config {
+        backend: Backend {
+            normalize_accept_encoding: true,
+            ..Backend::default()
+        },
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |accept_encoding: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/", port))
+            .header(ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        let response = common::client_request(request);
+        let encoding = response.headers().get(CONTENT_ENCODING).cloned();
+        let body = response.into_body().concat2().wait().unwrap();
+        (encoding, body.to_vec())
+    };
+
+    let (encoding, body) = get("br, gzip");
+    assert_eq!(encoding.unwrap(), "gzip");
+    let mut decoded = String::new();
+    GzDecoder::new(&body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!("compressed", decoded);
+
+    let (encoding, body) = get("identity");
+    assert!(encoding.is_none());
+    assert_eq!(b"compressed".to_vec(), body);
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+}
+
+// Tests that single and multiple byte ranges are served from the cache.
+#[test]
+fn cached_ranges() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream_server = common::start_dummy_server(upstream_port, |_| {
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(CONTENT_TYPE, "application/pdf")
+            .header(ETAG, "\"v1\"")
+            .body(Body::from("0123456789abcdefghij"))
+            .unwrap()
+    });
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+    let get_range = |range: &str, if_range: &str| {
+        let mut request = Request::get(format!("http://127.0.0.1:{}/doc.pdf", port));
+        request.header(RANGE, range);
+        if !if_range.is_empty() {
+            request.header(IF_RANGE, if_range);
+        }
+        let response = common::client_request(request.body(Body::empty()).unwrap());
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    // The first response comes from upstream and is complete.
+    let (status, _, body) = get_range("bytes=0-3", "");
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("0123456789abcdefghij", body);
+
+    let (status, headers, body) = get_range("bytes=0-3", "");
+    assert_eq!(StatusCode::PARTIAL_CONTENT, status);
+    assert_eq!(headers[CONTENT_RANGE], "bytes 0-3/20");
+    assert_eq!(headers[CONTENT_TYPE], "application/pdf");
+    assert_eq!("0123", body);
+
+    let (status, headers, body) = get_range("bytes=2-4, -3", "\"v1\"");
+    assert_eq!(StatusCode::PARTIAL_CONTENT, status);
+    let content_type = headers[CONTENT_TYPE].to_str().unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    let expected = format!(
+        "--{0}\r\nContent-Type: application/pdf\r\nContent-Range: bytes 2-4/20\r\n\r\n234\r\n\
+         --{0}\r\nContent-Type: application/pdf\r\nContent-Range: bytes 17-19/20\r\n\r\nhij\r\n\
+         --{0}--\r\n",
+        boundary
+    );
+    assert_eq!(expected, body);
+    assert_eq!(headers[CONTENT_LENGTH], expected.len().to_string().as_str());
+
+    // Another version of the document gets the full response.
+    let (status, _, body) = get_range("bytes=2-4, -3", "\"v0\"");
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("0123456789abcdefghij", body);
+
+    let (status, headers, _) = get_range("bytes=20-", "");
+    assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, status);
+    assert_eq!(headers[CONTENT_RANGE], "bytes */20");
+}
+
+// Tests that large files are cached in chunks that are fetched with Range
+// requests.
+#[test]
+fn chunked_caching() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let file: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let upstream_file = file.clone();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let range = request.headers()[RANGE].to_str().unwrap();
+        let range = range.trim_start_matches("bytes=");
+        let index = range.find('-').unwrap();
+        let first: usize = range[..index].parse().unwrap();
+        let last: usize = range[index + 1..].parse().unwrap();
+        if first >= upstream_file.len() {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .body(Body::empty())
+                .unwrap();
+        }
+        let last = last.min(upstream_file.len() - 1);
+        Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(ETAG, "\"v1\"")
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", first, last, upstream_file.len()),
+            )
+            .body(Body::from(upstream_file[first..=last].to_vec()))
+            .unwrap()
+    });
+    let config = Config {
+        routes: vec![Route {
+            path_prefix: "/media/".to_string(),
+            chunk_size: Some(100),
+            ..Route::default()
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |range: Option<&str>| {
+        let mut request = Request::get(format!("http://127.0.0.1:{}/media/video.mp4", port));
+        if let Some(range) = range {
+            request.header(RANGE, range);
+        }
+        let response = common::client_request_body(request.body(Body::empty()).unwrap());
+        let status = response.status();
+        let headers = response.headers().clone();
+        (status, headers, response.into_body().to_vec())
+    };
+
+    let (status, headers, body) = get(Some("bytes=150-349"));
+    assert_eq!(StatusCode::PARTIAL_CONTENT, status);
+    assert_eq!(headers[CONTENT_RANGE], "bytes 150-349/1000");
+    assert_eq!(&file[150..350], &body[..]);
+    assert_eq!(3, requests.load(Ordering::SeqCst));
+
+    // The chunks are cached now.
+    let (_, _, body) = get(Some("bytes=150-349"));
+    assert_eq!(&file[150..350], &body[..]);
+    assert_eq!(3, requests.load(Ordering::SeqCst));
+
+    // The whole file needs the other 7 chunks.
+    let (status, headers, body) = get(None);
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!(headers[CONTENT_LENGTH], "1000");
+    assert_eq!(file, body);
+    assert_eq!(10, requests.load(Ordering::SeqCst));
+
+    let (status, headers, body) = get(Some("bytes=-50"));
+    assert_eq!(StatusCode::PARTIAL_CONTENT, status);
+    assert_eq!(headers[CONTENT_RANGE], "bytes 950-999/1000");
+    assert_eq!(&file[950..], &body[..]);
+    assert_eq!(10, requests.load(Ordering::SeqCst));
+
+    let (status, _, _) = get(Some("bytes=5000-"));
+    assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, status);
+}
+
+// Tests that the chunks after the one a client reads are fetched ahead.
+#[test]
+fn chunk_prefetching() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let upstream_requested = requested.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        let range = request.headers()[RANGE].to_str().unwrap();
+        let range = range.trim_start_matches("bytes=");
+        let first: usize = range[..range.find('-').unwrap()].parse().unwrap();
+        upstream_requested.lock().unwrap().push(first);
+        Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/1000", first, first + 99),
+            )
+            .body(Body::from(vec![b'a'; 100]))
+            .unwrap()
+    });
+    let config = Config {
+        routes: vec![Route {
+            path_prefix: "/media/".to_string(),
+            chunk_size: Some(100),
+            prefetch_chunks: 2,
+            ..Route::default()
+        }],
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |range: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/media/video.mp4", port))
+            .header(RANGE, range)
+            .body(Body::empty())
+            .unwrap();
+        common::client_request_body(request).into_body().len()
+    };
+
+    assert_eq!(100, get("bytes=0-99"));
+    let start = Instant::now();
+    while requested.lock().unwrap().len() < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    // Prefetching runs in parallel.
+    requested.lock().unwrap().sort();
+    assert_eq!(vec![0, 100, 200], *requested.lock().unwrap());
+
+    // The next chunk comes from the cache, once it is inserted.
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(100, get("bytes=100-199"));
+    assert_eq!(
+        1,
+        requested
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|first| **first == 100)
+            .count()
+    );
+}
+
+// Tests that responses with a Vary header are cached per variant and that the
+// least recently used variant makes room when there are too many.
+#[test]
+fn vary_variants() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let language = request
+            .headers()
+            .get(ACCEPT_LANGUAGE)
+            .map_or("none".to_string(), |value| {
+                value.to_str().unwrap().to_string()
+            });
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header(VARY, "Accept-Language")
+            .body(Body::from(language))
+            .unwrap()
+    });
+    let config = Config {
+        max_variants: 2,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |language: Option<&str>| {
+        let mut request = Request::get(format!("http://127.0.0.1:{}/", port))
+            .body(Body::empty())
+            .unwrap();
+        if let Some(language) = language {
+            request
+                .headers_mut()
+                .insert(ACCEPT_LANGUAGE, language.parse().unwrap());
+        }
+        let body = common::client_request_body(request).into_body();
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!("de", get(Some("de")));
+    assert_eq!("en", get(Some("en")));
+    assert_eq!("de", get(Some("de")));
+    assert_eq!("en", get(Some("en")));
+    assert_eq!(2, requests.load(Ordering::SeqCst));
+
+    // A third variant evicts "de", which was used less recently than "en".
+    assert_eq!("none", get(None));
+    assert_eq!(3, requests.load(Ordering::SeqCst));
+    assert_eq!("en", get(Some("en")));
+    assert_eq!("none", get(None));
+    assert_eq!(3, requests.load(Ordering::SeqCst));
+    assert_eq!("de", get(Some("de")));
+    assert_eq!(4, requests.load(Ordering::SeqCst));
+}
+
+// Test that request bodies get checksums and that responses which don't
+// match their checksums are not cached.
+#[test]
+fn checksums() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let body = match request.uri().path() {
+            "/upload" => request.headers()["content-md5"]
+                .to_str()
+                .unwrap()
+                .to_string(),
+            "/corrupt" => "hellO".to_string(),
+            _ => "hello".to_string(),
+        };
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .header("content-md5", "XUFAKrxLKna5cZ2REBfFkg==")
+            .body(Body::from(body))
+            .unwrap()
+    });
+    let config = Config {
+        backend: Backend {
+            request_checksums: vec![ChecksumAlgorithm::Md5],
+            verify_response_checksums: true,
+            ..Backend::default()
+        },
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let request = Request::post(format!("http://127.0.0.1:{}/upload", port))
+        .body(Body::from("hello"))
+        .unwrap();
+    let response = common::client_request_body(request);
+    assert_eq!(&b"XUFAKrxLKna5cZ2REBfFkg=="[..], &response.body()[..]);
+
+    let get = |path: &str| {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    requests.store(0, Ordering::SeqCst);
+    assert_eq!("hello", get("/intact"));
+    assert_eq!("hello", get("/intact"));
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+    // The corrupted body is passed on, but fetched again next time.
+    assert_eq!("hellO", get("/corrupt"));
+    assert_eq!("hellO", get("/corrupt"));
+    assert_eq!(3, requests.load(Ordering::SeqCst));
+}
+
+// Test that expired responses are served during their grace period when
+// upstream fails.
+#[test]
+fn grace() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let failing = Arc::new(AtomicUsize::new(0));
+    let upstream_failing = failing.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        if upstream_failing.load(Ordering::SeqCst) == 1 {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("down"))
+                .unwrap();
+        }
+        let cache_control = match request.uri().path() {
+            "/short" => "public, max-age=60, stale-if-error=10",
+            _ => "public, max-age=60",
+        };
+        Response::builder()
+            .header(CACHE_CONTROL, cache_control)
+            .body(Body::from("fresh"))
+            .unwrap()
+    });
+    let clock = ManualClock::new();
+    let config = Config {
+        clock: Arc::new(clock.clone()),
+        grace: 300,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |path: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}{}", port, path))
+            .body(Body::empty())
+            .unwrap();
+        common::client_request_body(request)
+    };
+
+    assert_eq!(&b"fresh"[..], &get("/").body()[..]);
+    assert_eq!(&b"fresh"[..], &get("/short").body()[..]);
+    // Give the cache thread time to store the responses.
+    thread::sleep(Duration::from_millis(50));
+    failing.store(1, Ordering::SeqCst);
+
+    clock.advance(Duration::from_secs(61));
+    let response = get("/");
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!(
+        "111 - \"Revalidation Failed\"",
+        response.headers()["warning"]
+    );
+    assert_eq!(&b"fresh"[..], &response.body()[..]);
+    assert_eq!(StatusCode::OK, get("/short").status());
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, get("/other").status());
+
+    // stale-if-error of the response is shorter than the grace period.
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(StatusCode::OK, get("/").status());
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, get("/short").status());
+
+    clock.advance(Duration::from_secs(290));
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, get("/").status());
+}
+
+// Test that spellings of a path with extra slashes share a cache entry.
+#[test]
+fn slash_normalization() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(request.uri().to_string()))
+            .unwrap()
+    });
+    let config = Config {
+        merge_slashes: true,
+        trailing_slash: TrailingSlash::Strip,
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |path: &str| {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!("/foo/bar?a=1", get("//foo///bar/?a=1"));
+    assert_eq!("/foo/bar?a=1", get("/foo/bar?a=1"));
+    assert_eq!("/foo/bar?a=1", get("/foo//bar//?a=1"));
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+}
+
+// Test that different percent-encodings of a URL share a cache entry and that
+// internationalized host names are passed on in punycode.
+#[test]
+fn uri_normalization() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let host = request.headers()[HOST].to_str().unwrap();
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(format!("{} {}", host, request.uri())))
+            .unwrap()
+    });
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+    let get = |path: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}{}", port, path))
+            .header(HOST, "Bücher.de".as_bytes())
+            .body(Body::empty())
+            .unwrap();
+        let body = common::client_request_body(request).into_body();
+        // Give the cache thread time to store the response.
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    let expected = "xn--bcher-kva.de /caf%C3%A9?q=~%2F";
+    assert_eq!(expected, get("/caf%c3%a9?q=%7e%2f"));
+    assert_eq!(expected, get("/caf%C3%A9?q=~%2F"));
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+}
+
+// Tests that URLs whose queries only differ in the order of the parameters or
+// in marketing parameters share a cache entry, while upstream gets the query
+// of the client.
+#[test]
+fn query_normalization() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(request.uri().to_string()))
+            .unwrap()
+    });
+    let config = Config {
+        query_normalization: QueryNormalization {
+            sort: true,
+            ..QueryNormalization::default()
+        },
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+    let get = |path: &str| {
+        let body = common::client_get_body(
+            format!("http://127.0.0.1:{}{}", port, path)
+                .parse()
+                .unwrap(),
+        );
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!("/?b=2&a=1&gclid=x", get("/?b=2&a=1&gclid=x"));
+    assert_eq!("/?b=2&a=1&gclid=x", get("/?a=1&b=2"));
+    assert_eq!("/?b=2&a=1&gclid=x", get("/?utm_source=mail&a=1&b=2"));
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+    assert_eq!("/?a=2&b=2", get("/?a=2&b=2"));
+}
+
+// Tests that virtual hosts with the same paths have their own cache entries,
+// also with the default port or uppercase letters in the Host header. Only
+// the default port of the scheme counts as the same host.
+#[test]
+fn virtual_hosts() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::from(
+                request.headers()[HOST].to_str().unwrap().to_string(),
+            ))
+            .unwrap()
+    });
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+    let get = |host: &str| {
+        let request = Request::get(format!("http://127.0.0.1:{}/index.html", port))
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        let body = common::client_request_body(request).into_body();
+        thread::sleep(Duration::from_millis(50));
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    assert_eq!("one.example.com", get("one.example.com"));
+    assert_eq!("two.example.com", get("two.example.com"));
+    assert_eq!("one.example.com", get("One.Example.com:80"));
+    assert_eq!(2, requests.load(Ordering::SeqCst));
+    assert_eq!("one.example.com:8080", get("one.example.com:8080"));
+    // Plain HTTP on the https port is another site.
+    assert_eq!("two.example.com:443", get("two.example.com:443"));
+    assert_eq!(4, requests.load(Ordering::SeqCst));
+}
+
+// Test that a cachable body streams to the client while upstream still sends
+// it, and is cached once it is complete.
+#[test]
+fn streamed_response_cached() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream_requests = requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |_| {
+        upstream_requests.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = futures::sync::mpsc::unbounded();
+        thread::spawn(move || {
+            sender.unbounded_send("hello").unwrap();
+            thread::sleep(Duration::from_millis(500));
+            sender.unbounded_send(" world").unwrap();
+        });
+        Response::builder()
+            .header(CACHE_CONTROL, "public,max-age=1800")
+            .body(Body::wrap_stream(
+                receiver.map_err(|()| std::io::Error::other("unreachable")),
+            ))
+            .unwrap()
+    });
+    let _proxy = rustnish::start_server_background(port, upstream_port);
+    let url: Uri = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+
+    let start = Instant::now();
+    let work = hyper::Client::new()
+        .get(url.clone())
+        .and_then(move |response| {
+            let head = start.elapsed();
+            response.into_body().concat2().map(move |body| (head, body))
+        });
+    let (head, body) = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(work)
+        .unwrap();
+    assert!(head < Duration::from_millis(400));
+    assert_eq!(&body[..], b"hello world");
+
+    // Give the cache thread time to store the response.
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(&common::client_get_body(url)[..], b"hello world");
+    assert_eq!(1, requests.load(Ordering::SeqCst));
+}
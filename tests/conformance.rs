@@ -0,0 +1,71 @@
+// Conformance of the proxy cache to HTTP caching semantics, with the test
+// definitions of the http-cache-tests suite (https://cache-tests.fyi/) that
+// are vendored in the cache-tests directory. The conformance admin command
+// runs them, the results are compared to the known failures, so regressions
+// and fixes both show up. Set CONFORMANCE_REPORT to a file path to get the
+// report, in the format of the suite's result files.
+
+use rustnish::Config;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+mod common;
+
+// Tests the proxy does not pass yet. Remove a test here once it is fixed.
+const KNOWN_FAILURES: &[&str] = &[
+    // Responses that must be revalidated are not stored.
+    "cc-resp-no-cache-revalidate",
+    // Only the cacheable_statuses are stored, even with explicit freshness.
+    "status-204-fresh",
+    "status-299-fresh",
+    "status-400-fresh",
+    "status-499-fresh",
+    "status-500-fresh",
+    "status-502-fresh",
+    "status-503-fresh",
+    "status-504-fresh",
+    "status-599-fresh",
+];
+
+#[test]
+fn cache_tests() {
+    let port = common::get_free_port();
+    let config = Config {
+        admin_path: Some("/_rustnish".to_string()),
+        ..Config::default()
+    };
+    // The tests run against a proxy of their own, the upstream of this one
+    // is never asked.
+    let _proxy = rustnish::start_server_background_config(port, common::get_free_port(), config);
+
+    // The report is only sent when all tests ran.
+    let body = common::client_get_body(
+        format!("http://127.0.0.1:{}/_rustnish/conformance", port)
+            .parse()
+            .unwrap(),
+    );
+    let results: BTreeMap<String, Value> = serde_json::from_slice(&body).unwrap();
+
+    if let Ok(path) = std::env::var("CONFORMANCE_REPORT") {
+        fs::write(path, &body).unwrap();
+    }
+
+    assert!(results.contains_key("freshness-max-age"));
+    assert!(results.contains_key("status-500-fresh"));
+    let regressions: Vec<_> = results
+        .iter()
+        .filter(|(id, result)| **result != true && !KNOWN_FAILURES.contains(&id.as_str()))
+        .collect();
+    let fixed: Vec<_> = results
+        .iter()
+        .filter(|(id, result)| **result == true && KNOWN_FAILURES.contains(&id.as_str()))
+        .map(|(id, _)| id)
+        .collect();
+    assert!(regressions.is_empty(), "Failing tests: {:?}", regressions);
+    assert!(
+        fixed.is_empty(),
+        "Passing tests listed as known failures: {:?}",
+        fixed
+    );
+}
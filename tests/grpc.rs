@@ -0,0 +1,110 @@
+use futures::future::poll_fn;
+use futures::{Async, Future, Poll};
+use hyper::body::Payload;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn_ok;
+use hyper::{Body, Chunk, Client, HeaderMap, Request, Response, Server, StatusCode};
+use rustnish::{Backend, Config};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::runtime::Runtime;
+
+mod common;
+
+// A gRPC response body: one message followed by the status trailers.
+struct GrpcBody {
+    message: Option<Chunk>,
+    trailers: Option<HeaderMap>,
+}
+
+impl Payload for GrpcBody {
+    type Data = Chunk;
+    type Error = hyper::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        Ok(Async::Ready(self.message.take()))
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<HeaderMap>, hyper::Error> {
+        Ok(Async::Ready(self.trailers.take()))
+    }
+}
+
+static UPSTREAM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+// Starts an HTTP/2 only upstream that answers every call with the same message.
+fn start_grpc_server(port: u16) -> Runtime {
+    let address = ([127, 0, 0, 1], port).into();
+    let server = Server::bind(&address)
+        .http2_only(true)
+        .serve(|| {
+            service_fn_ok(|_| {
+                UPSTREAM_CALLS.fetch_add(1, Ordering::SeqCst);
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                Response::builder()
+                    .header(CONTENT_TYPE, "application/grpc")
+                    .header(CACHE_CONTROL, "public, max-age=3600")
+                    .body(GrpcBody {
+                        message: Some(Chunk::from("\0\0\0\0\x05hello")),
+                        trailers: Some(trailers),
+                    })
+                    .unwrap()
+            })
+        })
+        .map_err(|_| ());
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server);
+    runtime
+}
+
+// Reads the body and the trailers of a response.
+fn read_body_and_trailers(mut body: Body) -> (Vec<u8>, Option<HeaderMap>) {
+    let mut data = Vec::new();
+    let mut runtime = Runtime::new().unwrap();
+    runtime
+        .block_on(poll_fn(move || {
+            while let Some(chunk) = futures::try_ready!(body.poll_data()) {
+                data.extend_from_slice(&chunk);
+            }
+            let trailers = futures::try_ready!(body.poll_trailers());
+            Ok::<_, hyper::Error>(Async::Ready((data.clone(), trailers)))
+        }))
+        .unwrap()
+}
+
+// Tests that gRPC calls pass through over HTTP/2 with their trailers and are
+// not cached.
+#[test]
+fn grpc_passthrough() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let _upstream = start_grpc_server(upstream_port);
+    let config = Config {
+        backend: Backend {
+            http2: true,
+            ..Backend::default()
+        },
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let client = Client::builder().http2_only(true).build_http::<Body>();
+    for call in 1..=2 {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("http://127.0.0.1:{}/echo.Echo/UnaryEcho", port))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from("\0\0\0\0\x05hello"))
+            .unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        let response = runtime.block_on(client.request(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (body, trailers) = read_body_and_trailers(response.into_body());
+        assert_eq!(b"\0\0\0\0\x05hello".to_vec(), body);
+        assert_eq!(trailers.unwrap().get("grpc-status").unwrap(), "0");
+        assert_eq!(call, UPSTREAM_CALLS.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,79 @@
+use futures::{Future, Stream};
+use hyper::header::CACHE_CONTROL;
+use hyper::{Body, Response, StatusCode};
+use rustnish::{Config, Readiness};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+// Tests that the proxy only gets ready after warm-up with a healthy backend.
+#[test]
+fn warm_up_and_health_check() {
+    let port = common::get_free_port();
+    let upstream_port = common::get_free_port();
+
+    let healthy = Arc::new(AtomicBool::new(false));
+    let warm_requests = Arc::new(AtomicUsize::new(0));
+    let upstream_healthy = healthy.clone();
+    let upstream_warm_requests = warm_requests.clone();
+    let _upstream_server = common::start_dummy_server(upstream_port, move |request| {
+        let mut response = Response::builder();
+        if request.uri().path() == "/health" {
+            if !upstream_healthy.load(Ordering::SeqCst) {
+                response.status(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        } else {
+            upstream_warm_requests.fetch_add(1, Ordering::SeqCst);
+            // Slow enough to see the proxy warming up.
+            thread::sleep(Duration::from_millis(300));
+            response.header(CACHE_CONTROL, "public,max-age=1800");
+        }
+        response.body(Body::empty()).unwrap()
+    });
+    let config = Config {
+        readiness: Some(Readiness {
+            path: "/readyz".to_string(),
+            warmup_urls: vec!["/".to_string(), "/news".to_string()],
+            health_check_path: Some("/health".to_string()),
+            health_check_timeout_ms: 1000,
+        }),
+        ..Config::default()
+    };
+    let _proxy = rustnish::start_server_background_config(port, upstream_port, config);
+
+    let readyz = || {
+        let response =
+            common::client_get(format!("http://127.0.0.1:{}/readyz", port).parse().unwrap());
+        let status = response.status();
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    assert_eq!(
+        (StatusCode::SERVICE_UNAVAILABLE, "warming up".to_string()),
+        readyz()
+    );
+    let mut waited = 0;
+    while readyz().1 == "warming up" {
+        assert!(waited < 50, "warm-up did not finish");
+        waited += 1;
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "backend unhealthy".to_string()
+        ),
+        readyz()
+    );
+
+    healthy.store(true, Ordering::SeqCst);
+    assert_eq!((StatusCode::OK, "ready".to_string()), readyz());
+
+    // The warm-up filled the cache.
+    common::client_get(format!("http://127.0.0.1:{}/news", port).parse().unwrap());
+    assert_eq!(2, warm_requests.load(Ordering::SeqCst));
+}